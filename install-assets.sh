@@ -6,6 +6,13 @@ PREFIX="${PREFIX:-/usr/local}"
 install -d "${PREFIX}/libexec/ciel-plugin"
 install -Dvm755 plugins/* "${PREFIX}/libexec/ciel-plugin"
 
+# install the D-Bus policy of `ciel daemon`
+install -Dvm644 dbus-xml/io.aosc.Ciel1.conf "${PREFIX}/share/dbus-1/system.d/io.aosc.Ciel1.conf"
+
+# install the polkit actions of `ciel daemon` (and the rules for the `ciel` group)
+install -Dvm644 dbus-xml/io.aosc.ciel1.policy "${PREFIX}/share/polkit-1/actions/io.aosc.ciel1.policy"
+install -Dvm644 dbus-xml/io.aosc.ciel1.rules "${PREFIX}/share/polkit-1/rules.d/50-io.aosc.ciel1.rules"
+
 # install completions
 install -dv "${PREFIX}/share/zsh/functions/Completion/Linux/"
 install -Dvm644 completions/_ciel "${PREFIX}/share/zsh/functions/Completion/Linux/"
@@ -0,0 +1,131 @@
+//! Moving a workspace between machines (`ciel export-workspace` / `ciel import-workspace`)
+use anyhow::{anyhow, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{common::CIEL_VERSION_FILE, info, migrate, progress};
+
+use super::{container_down, for_each_instance};
+
+// runtime state that should not be carried over
+const EXCLUDED: &[&str] = &[
+    ".ciel/log",
+    ".ciel/events.sock",
+    ".ciel/backup",
+    ".ciel/trash",
+];
+// options for preserving the overlay whiteouts, xattrs and ownership of the layers
+const TAR_OPTIONS: &[&str] = &[
+    "--auto-compress",
+    "--xattrs",
+    "--xattrs-include=*",
+    "--acls",
+    "--numeric-owner",
+];
+
+/// The paths archived in addition to `.ciel`: the tree, outputs (with the repository metadata)
+/// and build logs. Sources are left out since they can be downloaded again.
+fn list_workspace_contents() -> Result<Vec<PathBuf>> {
+    let mut contents = vec![PathBuf::from(".ciel")];
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_dir()
+            && (name == "TREE" || name == "LOGS" || name == "OUTPUT" || name.starts_with("OUTPUT-"))
+        {
+            contents.push(PathBuf::from(name.as_ref()));
+        }
+    }
+
+    Ok(contents)
+}
+
+#[inline]
+fn run_tar(command: &mut Command) -> Result<()> {
+    let status = command
+        .status()
+        .map_err(|e| anyhow!("Unable to run tar: {}", e))?;
+    if !status.success() {
+        return Err(anyhow!("tar exited with {}", status));
+    }
+
+    Ok(())
+}
+
+/// Archive the workspace (base system, instances, configuration, tree and outputs) into `path`,
+/// the compression is decided by the file extension (e.g. `.tar.zst`, `.tar.xz`)
+pub fn export_workspace(path: &Path) -> Result<()> {
+    let path = std::env::current_dir()?.join(path);
+    info!("Shutting down all the instances...");
+    for_each_instance(&container_down)?;
+    let contents = list_workspace_contents()?;
+    info!(
+        "Archiving {} into {} ...",
+        contents
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        path.display()
+    );
+    let spinner = progress::spinner("Exporting the workspace...");
+    let mut command = Command::new("tar");
+    command
+        .arg("--create")
+        .args(TAR_OPTIONS)
+        .arg("--one-file-system")
+        .arg("--file")
+        .arg(&path);
+    for exclude in EXCLUDED {
+        command.arg(format!("--exclude={}", exclude));
+    }
+    let result = run_tar(command.args(&contents));
+    spinner.finish_and_clear();
+    result?;
+    info!(
+        "Workspace exported to {} ({}).",
+        path.display(),
+        indicatif::HumanBytes(fs::metadata(&path)?.len())
+    );
+
+    Ok(())
+}
+
+/// Extract the exported workspace into the current directory (which must not be a workspace yet)
+pub fn import_workspace(path: &Path) -> Result<()> {
+    if Path::new(".ciel").exists() {
+        return Err(anyhow!(
+            "This directory is already a Ciel workspace, please import into an empty directory."
+        ));
+    }
+    if !path.is_file() {
+        return Err(anyhow!("{} does not exist", path.display()));
+    }
+    let spinner = progress::spinner("Importing the workspace...");
+    let result = run_tar(
+        Command::new("tar")
+            .arg("--extract")
+            .args(TAR_OPTIONS)
+            .arg("--same-owner")
+            .arg("--same-permissions")
+            .arg("--file")
+            .arg(path),
+    );
+    spinner.finish_and_clear();
+    result?;
+    if !Path::new(CIEL_VERSION_FILE).is_file() {
+        return Err(anyhow!(
+            "{} does not look like an exported Ciel workspace",
+            path.display()
+        ));
+    }
+    // the archive may come from an older version of Ciel
+    migrate::migrate_workspace()?;
+    info!("Workspace imported from {}.", path.display());
+
+    Ok(())
+}
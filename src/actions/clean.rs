@@ -0,0 +1,211 @@
+//! Garbage collection of the workspace (`ciel clean`)
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    capture::CAPTURE_DIR,
+    common::{get_dir_size, remove_workspace_dir, CIEL_INST_DIR},
+    info, overlayfs, progress,
+};
+
+use super::logs::{prune_build_log_index, LOGS_DIR};
+
+// where the build checkpoints are saved
+const CHECKPOINTS_DIR: &str = "STATES";
+
+/// What to clean up in addition to the outputs and source caches
+#[derive(Debug, Default)]
+pub struct CleanOptions {
+    /// Also remove the tarball caches, old checkpoints and logs, and orphaned work directories
+    pub all: bool,
+    /// Only show what would be removed
+    pub dry_run: bool,
+    /// Checkpoints and logs older than this (in days) are considered stale
+    pub max_age: u64,
+}
+
+struct Garbage {
+    category: &'static str,
+    path: PathBuf,
+    size: u64,
+}
+
+impl Garbage {
+    fn new(category: &'static str, path: PathBuf) -> Self {
+        let size = if path.is_dir() {
+            get_dir_size(&path)
+        } else {
+            fs::metadata(&path).map(|m| m.len()).unwrap_or(0)
+        };
+
+        Garbage {
+            category,
+            path,
+            size,
+        }
+    }
+}
+
+#[inline]
+fn is_older_than(path: &Path, deadline: SystemTime) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map(|t| t < deadline)
+        .unwrap_or(false)
+}
+
+/// Output directories of the other branches and the sources cache (what `ciel clean` always removes)
+fn find_outputs() -> Result<Vec<Garbage>> {
+    let mut garbage = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && entry.file_name().to_string_lossy().starts_with("OUTPUT-")
+        {
+            garbage.push(Garbage::new("outputs", entry.path()));
+        }
+    }
+    if Path::new("./SRCS").is_dir() {
+        garbage.push(Garbage::new("sources", PathBuf::from("./SRCS")));
+    }
+
+    Ok(garbage)
+}
+
+/// The base system tarballs downloaded by `ciel load-os`
+fn find_tarballs() -> Result<Vec<Garbage>> {
+    let mut garbage = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_file()
+            && name.starts_with("aosc-os_")
+            && (name.ends_with(".tar.xz")
+                || name.ends_with(".tar.zst")
+                || name.ends_with(".squashfs")
+                // interrupted downloads
+                || name.ends_with(".part"))
+        {
+            garbage.push(Garbage::new("tarballs", entry.path()));
+        }
+    }
+
+    Ok(garbage)
+}
+
+/// Checkpoints and logs last modified before `deadline`
+fn find_stale_files(deadline: SystemTime) -> Vec<Garbage> {
+    let sources: &[(&'static str, &str, &str)] = &[
+        ("checkpoints", CHECKPOINTS_DIR, "ciel-ckpt"),
+        ("logs", LOGS_DIR, "log"),
+        ("logs", CAPTURE_DIR, "log"),
+    ];
+    let mut garbage = Vec::new();
+    for (category, dir, extension) in sources {
+        for entry in WalkDir::new(dir).into_iter().flatten() {
+            let path = entry.path();
+            if entry.file_type().is_file()
+                && path.extension() == Some(OsStr::new(extension))
+                && is_older_than(path, deadline)
+            {
+                garbage.push(Garbage::new(category, path.to_owned()));
+            }
+        }
+    }
+
+    garbage
+}
+
+/// Leftovers in the overlay work directories of the instances that are not mounted
+/// (those with the dirty flag are kept for the mount-time check)
+fn find_orphaned_work_dirs() -> Result<Vec<Garbage>> {
+    let mut garbage = Vec::new();
+    let entries = match fs::read_dir(CIEL_INST_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(garbage),
+    };
+    let root = std::env::current_dir()?;
+    for entry in entries {
+        let entry = entry?;
+        let work = entry.path().join(overlayfs::WORK_DIR);
+        if !work.is_dir()
+            || work.join("work/incompat").exists()
+            || overlayfs::is_mounted(&root.join(entry.file_name()), OsStr::new("overlay"))?
+        {
+            continue;
+        }
+        for item in fs::read_dir(&work)? {
+            garbage.push(Garbage::new("work directories", item?.path()));
+        }
+    }
+
+    Ok(garbage)
+}
+
+fn print_report(garbage: &[Garbage], dry_run: bool) {
+    if dry_run {
+        info!("The following would be removed:");
+    } else {
+        info!("The following will be removed:");
+    }
+    let mut categories: Vec<&str> = garbage.iter().map(|g| g.category).collect();
+    categories.dedup();
+    for category in categories {
+        let items: Vec<&Garbage> = garbage.iter().filter(|g| g.category == category).collect();
+        eprintln!(
+            "  {} ({} items, {})",
+            style(category).bold(),
+            items.len(),
+            HumanBytes(items.iter().map(|g| g.size).sum())
+        );
+        for item in items {
+            eprintln!("    {} ({})", item.path.display(), HumanBytes(item.size));
+        }
+    }
+    eprintln!(
+        "  {}: {}",
+        style("Total").bold(),
+        HumanBytes(garbage.iter().map(|g| g.size).sum())
+    );
+}
+
+/// Clean up the output directories and source caches (and more if `options.all` is set)
+pub fn clean_workspace(options: &CleanOptions) -> Result<()> {
+    let mut garbage = find_outputs()?;
+    if options.all {
+        let deadline = SystemTime::now() - Duration::from_secs(options.max_age * 24 * 60 * 60);
+        garbage.extend(find_tarballs()?);
+        garbage.extend(find_stale_files(deadline));
+        garbage.extend(find_orphaned_work_dirs()?);
+    }
+    if garbage.is_empty() {
+        info!("Nothing to clean up.");
+        return Ok(());
+    }
+    print_report(&garbage, options.dry_run);
+    if options.dry_run {
+        return Ok(());
+    }
+    let spinner = progress::spinner("Removing...");
+    for item in garbage.iter() {
+        if item.path.is_dir() {
+            remove_workspace_dir(&item.path)?;
+        } else {
+            fs::remove_file(&item.path)?;
+        }
+    }
+    if options.all {
+        prune_build_log_index()?;
+    }
+    spinner.finish_with_message("Done.");
+
+    Ok(())
+}
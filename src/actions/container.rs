@@ -1,24 +1,39 @@
 use anyhow::{anyhow, Result};
-use console::{style, user_attended};
+use console::style;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input};
 use git2::Repository;
-use nix::unistd::sync;
+use indicatif::HumanBytes;
+use nix::{
+    mount::{umount2, MntFlags},
+    unistd::sync,
+};
 use rand::random;
 use std::{
     ffi::OsStr,
     fs,
     path::{Path, PathBuf},
+    process::Command,
+    time::Instant,
 };
 
 use crate::{
+    audit::audited,
+    binfmt, bwrap,
+    capture::Capture,
     common::*,
-    config, ensure_host_sanity, error, info,
-    machine::{self, get_container_ns_name, inspect_instance, spawn_container},
-    network::download_file_progress,
-    overlayfs, warn,
+    config, debug, ensure_host_sanity, error, events, info, mac,
+    machine::{self, get_container_ns_name, inspect_instance, spawn_container, CielInstance},
+    netpolicy,
+    network::{download_file, download_file_progress},
+    overlayfs, privsep, progress, trace, warn,
 };
 
-use super::{for_each_instance, DEFAULT_MOUNTS, UPDATE_SCRIPT};
+use super::{
+    dist::{dist_info_path, record_base_system, record_base_system_update},
+    for_each_instance, get_update_script, secrets, staging, trash, DEFAULT_MOUNTS,
+    FORWARDED_GIT_CONFIG, FORWARDED_SSH_AGENT_SOCK, LAST_UPDATE_FILE, SIMULATE_UPDATE_OUTPUT,
+    SIMULATE_UPDATE_SCRIPT, UPDATE_SNAPSHOT_DIR,
+};
 
 /// Get the branch name of the workspace TREE repository
 #[inline]
@@ -32,30 +47,66 @@ fn get_branch_name() -> Result<String> {
         .to_owned())
 }
 
-/// Determine the output directory name
+/// Get the architecture of the base system (as recorded by dpkg)
+pub fn get_dist_arch() -> Option<String> {
+    get_rootfs_arch(Path::new(CIEL_DIST_DIR))
+}
+
+/// Get the architecture of the system at `root` (from the dpkg database)
+pub fn get_rootfs_arch(root: &Path) -> Option<String> {
+    let status = fs::read_to_string(root.join("var/lib/dpkg/status")).ok()?;
+    let stanza = status
+        .split("\n\n")
+        .find(|stanza| stanza.lines().any(|line| line == "Package: dpkg"))?;
+
+    stanza
+        .lines()
+        .find_map(|line| line.strip_prefix("Architecture: "))
+        .map(|arch| arch.trim().to_string())
+}
+
+/// Determine the output directory name: `OUTPUT`, `OUTPUT-<branch>`, `OUTPUT-<arch>`
+/// or `OUTPUT-<branch>-<arch>`
 #[inline]
-pub fn get_output_directory(sep_mount: bool) -> String {
+pub fn get_output_directory(sep_mount: bool, sep_arch: bool) -> String {
+    let mut name = "OUTPUT".to_string();
     if sep_mount {
-        format!(
-            "OUTPUT-{}",
-            get_branch_name().unwrap_or_else(|_| "HEAD".to_string())
-        )
-    } else {
-        "OUTPUT".to_string()
+        name.push('-');
+        name.push_str(&get_branch_name().unwrap_or_else(|_| "HEAD".to_string()));
     }
+    let arch = get_dist_arch();
+    // the packages built in foreign-architecture instances are always kept apart
+    if sep_arch || matches!(arch.as_deref(), Some(arch) if binfmt::is_foreign_arch(arch)) {
+        name.push('-');
+        name.push_str(&arch.unwrap_or_else(|| "unknown".to_string()));
+    }
+
+    name
 }
 
-fn commit(instance: &str) -> Result<()> {
+fn commit(instance: &str, keep_upper: bool) -> Result<()> {
     get_instance_ns_name(instance)?;
     info!("Un-mounting all the instances...");
     // Un-mount all the instances
     for_each_instance(&container_down)?;
     info!("{}: committing instance...", instance);
-    let spinner = create_spinner("Committing upper layer...", 200);
+    let start = Instant::now();
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.commit()?;
+    man.commit(keep_upper)?;
+    let spinner = progress::spinner("Syncing filesystems...");
     sync();
     spinner.finish_and_clear();
+    let duration = start.elapsed().as_secs();
+    info!(
+        "{}: changes committed in {}.",
+        instance,
+        format_duration(duration)
+    );
+    events::emit(
+        events::INSTANCE_COMMITTED,
+        instance,
+        serde_json::json!({ "duration": duration }),
+    );
 
     Ok(())
 }
@@ -64,7 +115,7 @@ fn commit(instance: &str) -> Result<()> {
 fn rollback(instance: &str) -> Result<()> {
     get_instance_ns_name(instance)?;
     info!("{}: rolling back instance...", instance);
-    let spinner = create_spinner("Removing upper layer...", 200);
+    let spinner = progress::spinner("Removing upper layer...");
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
     man.rollback()?;
     sync();
@@ -73,15 +124,106 @@ fn rollback(instance: &str) -> Result<()> {
     Ok(())
 }
 
-/// Remove everything in the current workspace
-pub fn farewell(path: &Path) -> Result<()> {
-    if !user_attended() {
-        eprintln!("DELETE THIS CIEL WORKSPACE?");
-        info!("Not controlled by an user. Automatically confirmed.");
-        // Un-mount all the instances
-        for_each_instance(&container_down)?;
-        fs::remove_dir_all(path.join(".ciel"))?;
-        return Ok(());
+fn remove_paths(paths: &[PathBuf]) -> Result<()> {
+    let spinner = progress::spinner("Removing the workspace...");
+    for path in paths {
+        // TREE may be a symlink
+        if fs::symlink_metadata(path)?.is_dir() {
+            remove_workspace_dir(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    spinner.finish_and_clear();
+
+    Ok(())
+}
+
+/// Show what is going to be removed, and which running instances are going to be stopped
+fn print_removal_summary(paths: &[PathBuf], instances: &[CielInstance]) {
+    info!("The following will be permanently removed:");
+    for path in paths {
+        let full_path = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        eprintln!(
+            "    {} ({})",
+            style(full_path.display()).bold(),
+            HumanBytes(get_dir_size(path))
+        );
+    }
+    if !instances.is_empty() {
+        let names: Vec<&str> = instances.iter().map(|i| i.name.as_str()).collect();
+        info!("Instances to be removed: {}", names.join(", "));
+    }
+    let running: Vec<&str> = instances
+        .iter()
+        .filter(|i| i.running)
+        .map(|i| i.name.as_str())
+        .collect();
+    if !running.is_empty() {
+        warn!(
+            "Running instances to be stopped: {}",
+            style(running.join(", ")).yellow()
+        );
+    }
+}
+
+/// What to remove in `farewell`
+#[derive(Debug, Default)]
+pub struct FarewellOptions {
+    /// Do not ask for confirmation
+    pub force: bool,
+    /// Only remove the instances, keeping the base system and the configuration
+    pub instances_only: bool,
+    /// Also remove the tree, the outputs and the sources
+    pub purge: bool,
+    /// Keep the tree when purging
+    pub keep_tree: bool,
+    /// Keep the outputs when purging
+    pub keep_output: bool,
+}
+
+/// List the paths (in the workspace at `path`) to be removed by `farewell`
+fn list_farewell_paths(path: &Path, options: &FarewellOptions) -> Result<Vec<PathBuf>> {
+    if options.instances_only {
+        return Ok(machine::list_instances_simple()?
+            .iter()
+            .map(|name| path.join(CIEL_INST_DIR).join(name))
+            .collect());
+    }
+    let mut paths = vec![path.join(".ciel")];
+    if !options.purge {
+        return Ok(paths);
+    }
+    for entry in fs::read_dir(path)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        let is_output = name == "OUTPUT" || name.starts_with("OUTPUT-");
+        if (name == "TREE" && !options.keep_tree)
+            || (is_output && !options.keep_output)
+            || name == "SRCS"
+        {
+            paths.push(path.join(name.as_ref()));
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Remove the workspace (or the parts selected by `options`) at `path`
+pub fn farewell(path: &Path, options: &FarewellOptions) -> Result<()> {
+    let paths = list_farewell_paths(path, options)?;
+    print_removal_summary(&paths, &machine::list_instances()?);
+    if !options.instances_only && !options.purge {
+        info!("The tree, outputs and sources are kept (use `--purge` to remove them as well).");
+    }
+    if options.force {
+        info!("Removing the workspace without confirmation...");
+        return destroy_workspace(&paths, options);
+    }
+    if !is_interactive() {
+        return Err(anyhow!(
+            "Refusing to delete the workspace without confirmation, use `--force` in batch mode."
+        ));
     }
     let theme = ColorfulTheme::default();
     let delete = Confirm::with_theme(&theme)
@@ -106,12 +248,20 @@ pub fn farewell(path: &Path) -> Result<()> {
     }
 
     info!("... as you wish. Commencing destruction ...");
-    info!("Un-mounting all the instances...");
-    // Un-mount all the instances
-    for_each_instance(&container_down)?;
-    fs::remove_dir_all(path.join(".ciel"))?;
+    destroy_workspace(&paths, options)
+}
 
-    Ok(())
+/// Un-mount all the instances and then remove the paths
+fn destroy_workspace(paths: &[PathBuf], options: &FarewellOptions) -> Result<()> {
+    if options.instances_only {
+        return for_each_instance(&remove_instance);
+    }
+    audited("farewell", None, || {
+        info!("Un-mounting all the instances...");
+        detach_stale_mounts(None)?;
+        for_each_instance(&container_down)?;
+        remove_paths(paths)
+    })
 }
 
 /// Download the OS tarball and then extract it for use as the base layer
@@ -123,19 +273,40 @@ pub fn load_os(url: &str, sha256: Option<String>) -> Result<()> {
         .to_str()
         .ok_or_else(|| anyhow!("Unable to decode path string"))?;
     let total;
+    let checksum;
     if !Path::new(path).is_file() {
-        total = download_file_progress(url, path)?;
+        let start = Instant::now();
+        // only keep the complete downloads (which are reused next time)
+        let partial = format!("{}.part", path);
+        let (size, sha256) = download_file_progress(url, fs::File::create(&partial)?)?;
+        info!(
+            "Downloaded {} in {}.",
+            HumanBytes(size),
+            format_duration(start.elapsed().as_secs())
+        );
+        fs::rename(&partial, path)?;
+        total = size;
+        checksum = Some(sha256);
     } else {
         let tarball = fs::File::open(path)?;
         total = tarball.metadata()?.len();
+        checksum = None;
     }
+    // the checksum is only known without verification if the tarball was just downloaded
+    let recorded = sha256.clone().or_else(|| checksum.clone());
     if let Some(sha256) = sha256 {
-        info!("Verifying tarball checksum...");
-        let tarball = fs::File::open(path)?;
-        let checksum = sha256sum(tarball)?;
+        let checksum = match checksum {
+            Some(checksum) => checksum,
+            None => {
+                info!("Verifying tarball checksum...");
+                sha256sum_progress(Path::new(path))?
+            }
+        };
         if sha256 == checksum {
             info!("Checksum verified.");
         } else {
+            // remove the broken file so that it is downloaded again next time
+            fs::remove_file(path)?;
             return Err(anyhow!(
                 "Checksum mismatch: expected {} but got {}",
                 sha256,
@@ -144,6 +315,7 @@ pub fn load_os(url: &str, sha256: Option<String>) -> Result<()> {
         }
     }
     extract_system_tarball(&PathBuf::from(path), total)?;
+    record_base_system(url, recorded)?;
 
     Ok(())
 }
@@ -185,7 +357,11 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
             false
         };
         if volatile_changed {
-            warn!("You have changed the volatile mount option, please save your work and\x1b[1m\x1b[93m rollback \x1b[4mall the instances\x1b[0m.");
+            warn!(
+                "You have changed the volatile mount option, please save your work and {} {}.",
+                style("rollback").bold().yellow(),
+                style("all the instances").bold().yellow().underlined()
+            );
             return Ok(());
         }
         warn!(
@@ -203,21 +379,110 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
     Ok(())
 }
 
-/// Mount the filesystem of the instance
+/// Shut down the affected instance(s) and return the configuration layer to be modified
+fn prepare_config_layer(instance: Option<&str>) -> Result<PathBuf> {
+    if let Some(instance) = instance {
+        get_instance_ns_name(instance)?;
+        container_down(instance)?;
+        let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+        return man.get_config_layer();
+    }
+    for_each_instance(&container_down)?;
+
+    Ok(PathBuf::from(CIEL_DIST_DIR))
+}
+
+/// Add an extra APT repository to the base system or the specified instance
+pub fn config_repo_add(instance: Option<&str>, source: &str, key: Option<&str>) -> Result<()> {
+    let key = if let Some(key) = key {
+        if key.starts_with("https://") || key.starts_with("http://") {
+            info!("Downloading signing key...");
+            Some(privsep::run_unprivileged("signing key download", || {
+                Ok(download_file(key)?.error_for_status()?.bytes()?.to_vec())
+            })?)
+        } else {
+            Some(fs::read(key)?)
+        }
+    } else {
+        None
+    };
+    let root = prepare_config_layer(instance)?;
+    if config::add_apt_source(root, source, key.as_deref())? {
+        info!("Repository added: {}", source);
+    } else {
+        warn!("Repository already exists: {}", source);
+    }
+
+    Ok(())
+}
+
+/// Remove an extra APT repository from the base system or the specified instance
+pub fn config_repo_remove(instance: Option<&str>, source: &str) -> Result<()> {
+    let root = prepare_config_layer(instance)?;
+    if !config::remove_apt_source(root, source)? {
+        return Err(anyhow!("No such repository: {}", source));
+    }
+    info!("Repository removed: {}", source);
+
+    Ok(())
+}
+
+/// Check the workspace configuration and report the problems found
+pub fn check_config() -> Result<()> {
+    let config = config::read_config().map_err(|e| {
+        anyhow!(
+            "Unable to read the configuration: {}. Try `ciel config -g`.",
+            e
+        )
+    })?;
+    let problems = config::validate_config(&config, Path::new(CIEL_DIST_DIR));
+    if problems.is_empty() {
+        info!("No problems found in the configuration.");
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        error!("{}", problem);
+    }
+
+    Err(anyhow!(
+        "{} problem(s) found in the configuration.",
+        problems.len()
+    ))
+}
+
+/// Mount the filesystem of the instance (if it is not mounted yet)
 pub fn mount_fs(instance: &str) -> Result<()> {
     let config = config::read_config()?;
+    ensure_local_filesystem(".ciel")?;
+    // the handlers do not survive a reboot
+    if let Some(arch) = config::InstanceConfig::load(instance)?.arch {
+        if binfmt::is_foreign_arch(&arch) {
+            binfmt::ensure_binfmt(binfmt::qemu_target(&arch)?)?;
+        }
+    }
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
     man.set_volatile(config.volatile_mount)?;
-    machine::mount_layers(man, instance)?;
+    mac::check_config(&config.mac)?;
+    man.set_mount_options(mac::get_mount_options(&config.mac))?;
+    if !machine::mount_layers(man, instance)? {
+        debug!("{}: filesystem already mounted.", instance);
+        return Ok(());
+    }
     info!("{}: filesystem mounted.", instance);
+    events::emit(events::INSTANCE_MOUNTED, instance, serde_json::Value::Null);
 
     Ok(())
 }
 
 /// Un-mount the filesystem of the container
 pub fn unmount_fs(instance: &str) -> Result<()> {
+    staging::unmount_staging(instance)?;
+    secrets::unmount_secrets(instance)?;
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
     let target = std::env::current_dir()?.join(instance);
+    if !man.is_mounted(&target)? {
+        return Ok(());
+    }
     let mut retry = 0usize;
     while man.is_mounted(&target)? {
         retry += 1;
@@ -227,6 +492,11 @@ pub fn unmount_fs(instance: &str) -> Result<()> {
         man.unmount(&target)?;
     }
     info!("{}: filesystem un-mounted.", instance);
+    events::emit(
+        events::INSTANCE_UNMOUNTED,
+        instance,
+        serde_json::Value::Null,
+    );
 
     Ok(())
 }
@@ -274,20 +544,87 @@ fn get_instance_ns_name(instance: &str) -> Result<String> {
     get_container_ns_name(instance, legacy)
 }
 
-/// Start the container/instance, also mounting the container filesystem prior to the action
-pub fn start_container(instance: &str) -> Result<String> {
-    let ns_name = get_instance_ns_name(instance)?;
-    let inst = inspect_instance(instance, &ns_name)?;
-    let (mut extra_options, mounts) = ensure_host_sanity!();
+/// nspawn options and bind mounts (host path, container path) of the container
+type ContainerOptions = (Vec<String>, Vec<(String, &'static str)>);
+
+/// Collect the nspawn options and bind mounts for the container
+fn get_container_options(instance: &str) -> Result<ContainerOptions> {
+    let (mut extra_options, mut mounts) = ensure_host_sanity!();
+    staging::apply_staging_mounts(instance, &mut mounts)?;
+    let inst_config = config::InstanceConfig::load(instance)?;
     if std::env::var("CIEL_OFFLINE").is_ok() {
         // FIXME: does not work with current version of systemd
         // add the offline option (private-network means don't share the host network)
         extra_options.push("--private-network".to_string());
         info!("{}: network disconnected.", instance);
+    } else if netpolicy::is_restricted() {
+        // the container is started in the network namespace of the builds
+        info!("{}: network restricted to the allowed hosts.", instance);
+    } else {
+        extra_options.extend(machine::get_network_options(
+            inst_config.network,
+            &inst_config.publish,
+        )?);
     }
-    if !inst.mounted {
-        mount_fs(instance)?;
+    let config = config::read_config().unwrap_or_default();
+    extra_options.extend(machine::get_security_options(
+        &inst_config,
+        &config.seccomp_profiles,
+    )?);
+    extra_options.extend(mac::get_nspawn_options(&config.mac));
+
+    Ok((extra_options, mounts))
+}
+
+/// Collect the bwrap options and bind mounts for the container (fallback backend)
+fn get_bwrap_options(instance: &str) -> Result<ContainerOptions> {
+    let (nspawn_options, mut mounts) = ensure_host_sanity!();
+    staging::apply_staging_mounts(instance, &mut mounts)?;
+    if !nspawn_options.is_empty() {
+        warn!("nspawn-extra-options are ignored by the bwrap backend.");
+    }
+    let inst_config = config::InstanceConfig::load(instance)?;
+    if !inst_config.capabilities.is_empty()
+        || !inst_config.drop_capabilities.is_empty()
+        || !inst_config.system_call_filter.is_empty()
+        || inst_config.seccomp_profile.is_some()
+    {
+        warn!("Capability and system call filter settings are ignored by the bwrap backend.");
+    }
+    let mut extra_options = mac::get_bwrap_options(&config::read_config().unwrap_or_default().mac);
+    if std::env::var("CIEL_OFFLINE").is_ok() {
+        extra_options.push("--unshare-net".to_string());
+        info!("{}: network disconnected.", instance);
+    } else if netpolicy::is_restricted() {
+        info!("{}: network restricted to the allowed hosts.", instance);
+    } else {
+        match inst_config.network {
+            config::NetworkMode::Host => (),
+            config::NetworkMode::None => extra_options.push("--unshare-net".to_string()),
+            config::NetworkMode::Private => {
+                return Err(anyhow!(
+                    "{}: private network is not supported by the bwrap backend.",
+                    instance
+                ))
+            }
+        }
+    }
+
+    Ok((extra_options, mounts))
+}
+
+/// Start the container/instance, also mounting the container filesystem prior to the action
+pub fn start_container(instance: &str) -> Result<String> {
+    if bwrap::is_enabled() {
+        return Err(anyhow!(
+            "{}: booting containers requires systemd, which is not used on this host.",
+            instance
+        ));
     }
+    let ns_name = get_instance_ns_name(instance)?;
+    let inst = inspect_instance(instance, &ns_name)?;
+    let (extra_options, mounts) = get_container_options(instance)?;
+    mount_fs(instance)?;
     if !inst.started {
         spawn_container(&ns_name, instance, &extra_options, &mounts)?;
     }
@@ -295,14 +632,244 @@ pub fn start_container(instance: &str) -> Result<String> {
     Ok(ns_name)
 }
 
+/// Collect the host environment variables that are allowed to be passed into the container
+fn get_passthrough_env() -> Vec<(String, String)> {
+    let allowlist = config::read_config()
+        .map(|c| c.env_passthrough)
+        .unwrap_or_default();
+    std::env::vars()
+        .filter(|(name, _)| {
+            allowlist.iter().any(|pattern| {
+                if let Some(prefix) = pattern.strip_suffix('*') {
+                    name.starts_with(prefix)
+                } else {
+                    name == pattern
+                }
+            })
+        })
+        .collect()
+}
+
+/// Find the home directory of the host user (the one who invoked sudo, if any)
+fn get_host_user_home() -> Option<PathBuf> {
+    if let Ok(user) = std::env::var("SUDO_USER") {
+        if let Ok(Some(user)) = nix::unistd::User::from_name(&user) {
+            return Some(user.dir);
+        }
+    }
+
+    std::env::var_os("HOME").map(PathBuf::from)
+}
+
+/// Files to bind-mount into the container (host path, container path, read-only)
+type ForwardedFiles = Vec<(PathBuf, &'static str, bool)>;
+
+/// Collect the host SSH agent socket and git config to be forwarded into the container.
+/// Returns the files to bind-mount and the environment variables pointing to them.
+fn get_identity_forwarding() -> (ForwardedFiles, Vec<(String, String)>) {
+    let mut files = Vec::new();
+    let mut env = Vec::new();
+    match std::env::var_os("SSH_AUTH_SOCK").map(PathBuf::from) {
+        Some(sock) if sock.exists() => {
+            files.push((sock, FORWARDED_SSH_AGENT_SOCK, false));
+            env.push((
+                "SSH_AUTH_SOCK".to_string(),
+                FORWARDED_SSH_AGENT_SOCK.to_string(),
+            ));
+        }
+        _ => {
+            warn!("SSH agent not found, not forwarding the SSH agent.");
+            warn!("If you are using sudo, try `sudo --preserve-env=SSH_AUTH_SOCK`.");
+        }
+    }
+    match get_host_user_home().map(|home| home.join(".gitconfig")) {
+        Some(gitconfig) if gitconfig.is_file() => {
+            files.push((gitconfig, FORWARDED_GIT_CONFIG, true));
+            env.push((
+                "GIT_CONFIG_GLOBAL".to_string(),
+                FORWARDED_GIT_CONFIG.to_string(),
+            ));
+        }
+        _ => {
+            warn!("~/.gitconfig not found, not forwarding the git identity.");
+        }
+    }
+
+    (files, env)
+}
+
+/// Options for executing commands in the container
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Override the boot mode of the instance
+    pub boot: Option<bool>,
+    /// Run the command as this user (created on demand) instead of root
+    pub user: Option<String>,
+    /// Extra environment variables
+    pub env: Vec<(String, String)>,
+    /// Forward the host SSH agent and git config into the container
+    pub forward_identity: bool,
+    /// Also save the output of the command into a log file named after this (see `capture`)
+    pub log_name: Option<String>,
+    /// Save the output into this file instead (takes precedence over `log_name`)
+    pub log_file: Option<PathBuf>,
+    /// Bind-mount the secrets in the directory (see `BuildSecrets`) read-only
+    pub secrets: Option<PathBuf>,
+}
+
 /// Execute the specified command in the container
+#[inline]
 pub fn run_in_container<S: AsRef<OsStr>>(instance: &str, args: &[S]) -> Result<i32> {
+    run_in_container_with_options(instance, args, &RunOptions::default())
+}
+
+/// Check if the user name is acceptable by `useradd`
+#[inline]
+fn is_valid_user_name(user: &str) -> bool {
+    let mut chars = user.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// Create the user in the container if it does not exist yet
+fn ensure_container_user(instance: &str, user: &str, boot: Option<bool>) -> Result<()> {
+    if !is_valid_user_name(user) {
+        return Err(anyhow!("Invalid user name: {}", user));
+    }
+    let script = format!(
+        "id -u {0} > /dev/null 2>&1 || useradd -m -s /bin/bash {0}",
+        user
+    );
+    let options = RunOptions {
+        boot,
+        ..Default::default()
+    };
+    let status = run_in_container_with_options(instance, &["/bin/bash", "-ec", &script], &options)?;
+    if status != 0 {
+        return Err(anyhow!("{}: failed to create user `{}`.", instance, user));
+    }
+
+    Ok(())
+}
+
+/// Execute the specified command in the container with the specified options
+pub fn run_in_container_with_options<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    options: &RunOptions,
+) -> Result<i32> {
+    let mut env = get_passthrough_env();
+    env.extend_from_slice(&options.env);
+    trace!("Environment variables for the container: {:?}", env);
+    let mut forwarded_files = Vec::new();
+    if options.forward_identity {
+        let (files, identity_env) = get_identity_forwarding();
+        forwarded_files = files;
+        env.extend(identity_env);
+    }
+    if let Some(secrets) = &options.secrets {
+        forwarded_files.push((secrets.clone(), secrets::FORWARDED_SECRETS_DIR, true));
+    }
+    let boot = if bwrap::is_enabled() {
+        // the fallback backend can only run lightweight containers
+        false
+    } else if let Some(boot) = options.boot {
+        boot
+    } else {
+        config::InstanceConfig::load(instance)?.boot
+    };
+    if let Some(user) = &options.user {
+        ensure_container_user(instance, user, Some(boot))?;
+    }
+    let user = options.user.as_deref();
+    let capture = match (&options.log_file, &options.log_name) {
+        (Some(path), _) => Some(Capture::start_at(path.clone())),
+        (None, Some(name)) => Some(Capture::start(instance, name)?),
+        (None, None) => None,
+    };
+    if let Some(capture) = &capture {
+        info!(
+            "{}: saving the output to {}",
+            instance,
+            capture.path.display()
+        );
+    }
+    let ns_name = get_instance_ns_name(instance)?;
+    let inst = inspect_instance(instance, &ns_name)?;
+    if inst.started && inst.booted == Some(false) {
+        return Err(anyhow!(
+            "{}: instance is busy running a command in non-boot mode.",
+            instance
+        ));
+    }
+    if bwrap::is_enabled() {
+        let (mut extra_options, mounts) = get_bwrap_options(instance)?;
+        for (source, dest, read_only) in forwarded_files.iter() {
+            let bind = if *read_only { "--ro-bind" } else { "--bind" };
+            extra_options.push(bind.to_string());
+            extra_options.push(source.to_string_lossy().to_string());
+            extra_options.push(dest.to_string());
+        }
+        mount_fs(instance)?;
+        return bwrap::execute_container_command(
+            instance,
+            instance,
+            args,
+            &extra_options,
+            &mounts,
+            &env,
+            user,
+        );
+    }
+    if !boot && !inst.started {
+        let (mut extra_options, mounts) = get_container_options(instance)?;
+        for (source, dest, read_only) in forwarded_files.iter() {
+            let bind = if *read_only { "--bind-ro" } else { "--bind" };
+            extra_options.push(format!("{}={}:{}", bind, source.display(), dest));
+        }
+        mount_fs(instance)?;
+        return machine::execute_container_command_direct(
+            &ns_name,
+            instance,
+            args,
+            &extra_options,
+            &mounts,
+            &env,
+            user,
+        );
+    }
     let ns_name = start_container(instance)?;
-    let status = machine::execute_container_command(&ns_name, args)?;
+    if forwarded_files.is_empty() {
+        return machine::execute_container_command(&ns_name, args, &env, user);
+    }
+    let status = machine::bind_mount_files(&ns_name, &forwarded_files)
+        .and_then(|_| machine::execute_container_command(&ns_name, args, &env, user));
+    // the instance keeps running, the later sessions (of the other users too) must not
+    // be able to use the forwarded agent and files
+    let unbound = machine::unbind_mount_files(&ns_name, &forwarded_files);
+    let status = status?;
+    unbound?;
 
     Ok(status)
 }
 
+/// Attach to the console of the running container/instance
+pub fn attach_container(instance: &str) -> Result<()> {
+    let ns_name = get_instance_ns_name(instance)?;
+    let inst = inspect_instance(instance, &ns_name)?;
+    if !inst.started {
+        return Err(anyhow!("{}: instance is not running!", instance));
+    }
+    if inst.booted != Some(true) {
+        return Err(anyhow!(
+            "{}: instance is not booted, there is no console to attach to.",
+            instance
+        ));
+    }
+
+    machine::attach_container(&ns_name)
+}
+
 /// Stop the container/instance (without un-mounting the filesystem)
 pub fn stop_container(instance: &str) -> Result<()> {
     let ns_name = get_instance_ns_name(instance)?;
@@ -312,8 +879,12 @@ pub fn stop_container(instance: &str) -> Result<()> {
         return Ok(());
     }
     info!("{}: stopping...", instance);
-    machine::terminate_container_by_name(&ns_name)?;
-    machine::clean_child_process();
+    if bwrap::is_enabled() {
+        bwrap::terminate_container(instance)?;
+    } else {
+        machine::terminate_container_by_name(&ns_name)?;
+        machine::clean_child_process();
+    }
     info!("{}: instance stopped.", instance);
 
     Ok(())
@@ -328,10 +899,98 @@ pub fn container_down(instance: &str) -> Result<()> {
     Ok(())
 }
 
-/// Commit the container/instance upper layer changes to the base layer of the filesystem
-pub fn commit_container(instance: &str) -> Result<()> {
-    container_down(instance)?;
-    commit(instance)?;
+/// Detach the filesystems left mounted under the workspace by crashed runs,
+/// only the mounts of `instance` are detached if specified
+pub fn detach_stale_mounts(instance: Option<&str>) -> Result<()> {
+    let root = std::env::current_dir()?;
+    let mounts = overlayfs::find_stale_mounts(&root, &machine::list_instances_simple()?)?;
+    for mount in mounts {
+        if let Some(instance) = instance {
+            if instance != mount.instance {
+                continue;
+            }
+        }
+        info!("Detaching stale mount {} ...", mount.mount_point.display());
+        umount2(&mount.mount_point, MntFlags::MNT_DETACH)?;
+    }
+
+    Ok(())
+}
+
+/// Shutdown and un-mount all the instances, ignoring the errors, and then
+/// detach everything left mounted under the workspace
+pub fn force_down_all() -> Result<()> {
+    // the nested mounts keep the instance filesystems busy, detach them first
+    detach_stale_mounts(None)?;
+    for_each_instance(&|instance: &str| {
+        if let Err(e) = container_down(instance) {
+            warn!("{}: unable to shutdown the instance: {}", instance, e);
+        }
+        Ok(())
+    })?;
+    detach_stale_mounts(None)?;
+
+    Ok(())
+}
+
+/// Clean up what crashed runs (or changes behind ciel's back) left behind before it gets in
+/// the way: the filesystems of the removed instances still mounted, the containers registered
+/// without their filesystems and the PID files of the gone bwrap containers.
+/// Only what is certainly stale is cleaned up, the rest is left for `ciel doctor`.
+pub fn reconcile_workspace() -> Result<()> {
+    let root = std::env::current_dir()?;
+    let instances = machine::list_instances_simple()?;
+    // the nested mounts come first
+    for mount in overlayfs::find_stale_mounts(&root, &instances)? {
+        if !mount.orphaned {
+            continue;
+        }
+        info!(
+            "Detaching {} (instance {} no longer exists) ...",
+            mount.mount_point.display(),
+            mount.instance
+        );
+        if let Err(e) = umount2(&mount.mount_point, MntFlags::MNT_DETACH) {
+            warn!("Unable to detach {}: {}", mount.mount_point.display(), e);
+        }
+    }
+    if bwrap::is_enabled() {
+        for instance in instances.iter() {
+            if bwrap::remove_stale_pid_file(instance)? {
+                debug!("{}: removed the stale PID file.", instance);
+            }
+        }
+        return Ok(());
+    }
+    if is_legacy_workspace()? {
+        return Ok(());
+    }
+    for instance in instances.iter() {
+        let ns_name = get_container_ns_name(instance, false)?;
+        if !machine::is_machine_registered(&ns_name)
+            || overlayfs::is_mounted(&root.join(instance), OsStr::new("overlay"))?
+        {
+            continue;
+        }
+        info!(
+            "{}: terminating the container left without its filesystem ...",
+            instance
+        );
+        if let Err(e) = machine::terminate_machine(&ns_name) {
+            warn!("{}: unable to terminate the container: {}", instance, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Commit the container/instance upper layer changes to the base layer of the filesystem,
+/// the changes are also kept in the instance if `keep_upper` is set
+pub fn commit_container(instance: &str, keep_upper: bool) -> Result<()> {
+    audited("commit", Some(instance), || {
+        container_down(instance)?;
+        commit(instance, keep_upper)
+    })?;
     info!("{}: instance has been committed.", instance);
 
     Ok(())
@@ -339,46 +998,485 @@ pub fn commit_container(instance: &str) -> Result<()> {
 
 /// Clear the upper layer of the container/instance filesystem
 pub fn rollback_container(instance: &str) -> Result<()> {
-    container_down(instance)?;
-    rollback(instance)?;
+    audited("rollback", Some(instance), || {
+        container_down(instance)?;
+        rollback(instance)
+    })?;
     info!("{}: instance has been rolled back.", instance);
+    events::emit(
+        events::INSTANCE_ROLLED_BACK,
+        instance,
+        serde_json::Value::Null,
+    );
 
     Ok(())
 }
 
+/// Set up qemu-user for the instance if the base system is of a foreign architecture,
+/// returns the emulated architecture
+fn setup_emulation(instance: &str) -> Result<Option<String>> {
+    let arch = match get_dist_arch() {
+        Some(arch) if binfmt::is_foreign_arch(&arch) => arch,
+        _ => return Ok(None),
+    };
+    let target = binfmt::qemu_target(&arch)?;
+    binfmt::ensure_binfmt(target)?;
+    binfmt::install_emulator(
+        target,
+        &Path::new(CIEL_INST_DIR)
+            .join(instance)
+            .join(overlayfs::LOWER_DIR),
+    )?;
+    info!(
+        "{}: {} binaries will be run with qemu-user.",
+        instance, arch
+    );
+
+    Ok(Some(arch))
+}
+
 /// Create a new instance
 #[inline]
 pub fn add_instance(instance: &str) -> Result<()> {
+    let arch = setup_emulation(instance)?.or_else(get_dist_arch);
     overlayfs::create_new_instance_fs(CIEL_INST_DIR, instance)?;
+    if arch.is_some() {
+        let config = config::InstanceConfig {
+            arch,
+            ..Default::default()
+        };
+        config.save(instance)?;
+    }
     info!("{}: instance created.", instance);
 
     Ok(())
 }
 
+/// Create a new instance with the given configuration
+/// (`config.arch`, if set, must match the architecture of the base system)
+pub fn add_instance_with_config(instance: &str, mut config: config::InstanceConfig) -> Result<()> {
+    // validate the network and security settings
+    machine::get_network_options(config.network, &config.publish)?;
+    machine::get_security_options(
+        &config,
+        &config::read_config().unwrap_or_default().seccomp_profiles,
+    )?;
+    let dist_arch = get_dist_arch();
+    if let (Some(arch), Some(dist_arch)) = (&config.arch, &dist_arch) {
+        if arch != dist_arch {
+            return Err(anyhow!(
+                "The base system is for {}, load a {} one with `ciel load-os --arch {}` first.",
+                dist_arch,
+                arch,
+                arch
+            ));
+        }
+    }
+    config.arch = setup_emulation(instance)?.or(dist_arch);
+    overlayfs::create_new_instance_fs(CIEL_INST_DIR, instance)?;
+    config.save(instance)?;
+    info!("{}: instance created.", instance);
+
+    Ok(())
+}
+
+/// Create a new instance with a copy of the changes and the configuration of `source`,
+/// so that what is installed there is available right away
+pub fn clone_instance(instance: &str, source: &str, description: Option<String>) -> Result<()> {
+    let ns_name = get_instance_ns_name(source)?;
+    if is_instance_exists(instance) {
+        return Err(anyhow!("Instance `{}` already exists.", instance));
+    }
+    // the changes of a running container may be half-written
+    if inspect_instance(source, &ns_name)?.running {
+        return Err(anyhow!(
+            "{} is running, stop it first with `ciel stop -i {}`.",
+            source,
+            source
+        ));
+    }
+    let mut config = config::InstanceConfig::load(source)?;
+    if !config.publish.is_empty() {
+        // the ports can only be forwarded to one of them
+        warn!(
+            "{}: the ports published by {} are not forwarded to this instance.",
+            instance, source
+        );
+        config.publish.clear();
+    }
+    if description.is_some() {
+        config.description = description;
+    }
+    let spinner = progress::spinner(format!("Copying {}...", source));
+    overlayfs::clone_instance_fs(CIEL_INST_DIR, source, instance)?;
+    spinner.finish_and_clear();
+    config.save(instance)?;
+    info!("{}: instance created from {}.", instance, source);
+
+    Ok(())
+}
+
+/// Pick an instance of `arch` to build in (`instance` if specified, which must match),
+/// a new one is created if there is no instance recorded to be of `arch`
+pub fn pick_instance_for_arch(arch: &str, instance: Option<&str>) -> Result<String> {
+    let dist_arch = get_dist_arch().ok_or_else(|| anyhow!("No base system is loaded."))?;
+    if dist_arch != arch {
+        return Err(anyhow!(
+            "The base system is for {}, load a {} one with `ciel load-os --arch {}` first.",
+            dist_arch,
+            arch,
+            arch
+        ));
+    }
+    // the cross instances build for their target instead
+    let recorded_arch = |name: &str| {
+        config::InstanceConfig::load(name)
+            .ok()
+            .filter(|c| c.cross.is_none())
+            .map(|c| c.arch)
+    };
+    if let Some(instance) = instance {
+        get_instance_ns_name(instance)?;
+        let found = recorded_arch(instance).map(|arch| arch.unwrap_or_else(|| dist_arch.clone()));
+        match found {
+            Some(found) if found == arch => (),
+            Some(found) => {
+                return Err(anyhow!(
+                    "{} is an instance for {}, not {}",
+                    instance,
+                    found,
+                    arch
+                ))
+            }
+            None => {
+                return Err(anyhow!(
+                    "{} is a cross instance, it can not build for {}",
+                    instance,
+                    arch
+                ))
+            }
+        }
+        return Ok(instance.to_string());
+    }
+    // the instances without a recorded architecture may have been set up for another
+    // base system, which was switched away from since
+    let mut instances = machine::list_instances_simple()?;
+    instances.sort_unstable();
+    if let Some(instance) = instances
+        .into_iter()
+        .find(|name| recorded_arch(name).flatten().as_deref() == Some(arch))
+    {
+        info!("Building in {} ({}).", style(&instance).cyan(), arch);
+        return Ok(instance);
+    }
+    let mut instance = format!("build-{}", arch);
+    let mut suffix = 1;
+    while is_instance_exists(&instance) {
+        suffix += 1;
+        instance = format!("build-{}-{}", arch, suffix);
+    }
+    info!("No instance for {} found, creating {} ...", arch, instance);
+    add_instance(&instance)?;
+
+    Ok(instance)
+}
+
+/// Update the configuration of an existing instance
+pub fn update_instance_config(instance: &str, config: &config::InstanceConfig) -> Result<()> {
+    get_instance_ns_name(instance)?;
+    machine::get_network_options(config.network, &config.publish)?;
+    machine::get_security_options(
+        config,
+        &config::read_config().unwrap_or_default().seccomp_profiles,
+    )?;
+    config.save(instance)?;
+    info!("{}: instance configuration updated.", instance);
+    warn!(
+        "Please stop {} for the new config to take effect!",
+        instance
+    );
+
+    Ok(())
+}
+
+/// Remove the instance after showing what is going to be removed
+/// (without confirmation if `force` is set). The instance is moved to the trash
+/// unless `purge` is set or the trash is disabled (`trash-retention = 0`).
+pub fn delete_instance(instance: &str, force: bool, purge: bool) -> Result<()> {
+    get_instance_ns_name(instance)?;
+    let retention = config::read_config()
+        .map(|c| c.trash_retention)
+        .unwrap_or(trash::DEFAULT_TRASH_RETENTION);
+    let purge = purge || retention == 0;
+    let instances: Vec<CielInstance> = machine::list_instances()?
+        .into_iter()
+        .filter(|i| i.name == instance)
+        .collect();
+    if purge {
+        print_removal_summary(&[Path::new(CIEL_INST_DIR).join(instance)], &instances);
+    } else {
+        info!(
+            "Instance `{}` will be moved to the trash (kept for {} days).",
+            instance, retention
+        );
+    }
+    if !force {
+        if !is_interactive() {
+            return Err(anyhow!(
+                "Refusing to remove the instance without confirmation, use `--force` in batch mode."
+            ));
+        }
+        let delete = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Remove instance `{}`?", instance))
+            .default(false)
+            .interact()?;
+        if !delete {
+            info!("Not confirmed.");
+            return Ok(());
+        }
+    }
+    if purge {
+        return remove_instance(instance);
+    }
+    trash::purge_expired_trash(retention)?;
+
+    trash::trash_instance(instance)
+}
+
 /// Remove the container/instance and its filesystem from the host filesystem
 pub fn remove_instance(instance: &str) -> Result<()> {
-    container_down(instance)?;
-    info!("{}: removing instance...", instance);
-    let spinner = create_spinner("Removing the instance...", 200);
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.destroy()?;
-    spinner.finish_and_clear();
-    info!("{}: instance removed.", instance);
+    audited("del", Some(instance), || {
+        detach_stale_mounts(Some(instance))?;
+        container_down(instance)?;
+        info!("{}: removing instance...", instance);
+        let spinner = progress::spinner("Removing the instance...");
+        let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+        man.destroy()?;
+        spinner.finish_and_clear();
+        info!("{}: instance removed.", instance);
+
+        Ok(())
+    })
+}
+
+/// Take a snapshot of the base system by hardlinking, replacing the previous one.
+/// The files are never modified in place by committing (only replaced), so the snapshot stays intact.
+fn snapshot_base_system() -> Result<()> {
+    let snapshot = Path::new(UPDATE_SNAPSHOT_DIR);
+    if snapshot.exists() {
+        remove_workspace_dir(snapshot)?;
+    }
+    info!("Taking a snapshot of the base system...");
+    let status = Command::new("cp")
+        .args(&["-a", "--link", "--no-target-directory", CIEL_DIST_DIR])
+        .arg(snapshot)
+        .status()?;
+    if !status.success() {
+        remove_workspace_dir(snapshot).ok();
+        return Err(anyhow!(
+            "Unable to take a snapshot of the base system: cp exited with {}",
+            status
+        ));
+    }
+    let info = dist_info_path(Path::new(CIEL_DIST_DIR));
+    if info.is_file() {
+        fs::copy(info, dist_info_path(snapshot))?;
+    }
 
     Ok(())
 }
 
+/// Revert the base system to the snapshot taken before the last `update-os`
+pub fn undo_update_os() -> Result<()> {
+    let snapshot = Path::new(UPDATE_SNAPSHOT_DIR);
+    if !snapshot.is_dir() {
+        return Err(anyhow!("No snapshot found, nothing to undo."));
+    }
+    audited("update-os-undo", None, || {
+        info!("Shutting down all the instances...");
+        for_each_instance(&container_down)?;
+        let updated = format!("{}.undo", CIEL_DIST_DIR);
+        fs::rename(CIEL_DIST_DIR, &updated)?;
+        fs::rename(snapshot, CIEL_DIST_DIR)?;
+        let info = dist_info_path(snapshot);
+        if info.is_file() {
+            fs::rename(info, dist_info_path(Path::new(CIEL_DIST_DIR)))?;
+        }
+        let spinner = progress::spinner("Removing the updated base system...");
+        remove_workspace_dir(&updated)?;
+        spinner.finish_and_clear();
+        info!("Base system has been reverted to the state before the last update.");
+        warn!("Changes in the instances made after the update may not work with the reverted system, consider rolling them back.");
+
+        Ok(())
+    })
+}
+
 /// Update AOSC OS in the container/instance
 pub fn update_os() -> Result<()> {
     info!("Updating base OS...");
     let instance = format!("update-{:x}", random::<u32>());
-    add_instance(&instance)?;
-    let status = run_in_container(&instance, &["/bin/bash", "-ec", UPDATE_SCRIPT])?;
+    audited("update-os", Some(&instance), || {
+        add_instance(&instance)?;
+        let options = RunOptions {
+            log_name: Some("update-os".to_string()),
+            ..Default::default()
+        };
+        let status = run_in_container_with_options(
+            &instance,
+            &["/bin/bash", "-ec", &get_update_script()],
+            &options,
+        )?;
+        if status != 0 {
+            return Err(anyhow!("Failed to update OS: {}", status));
+        }
+        snapshot_base_system()?;
+        commit_container(&instance, false)?;
+        remove_instance(&instance)?;
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        fs::write(LAST_UPDATE_FILE, now.as_secs().to_string())?;
+        record_base_system_update()?;
+
+        Ok(())
+    })
+}
+
+/// A package changed by the (simulated) update
+#[derive(Debug, PartialEq, Eq)]
+pub struct PackageChange {
+    pub name: String,
+    /// The installed version, `None` for the new packages
+    pub old: Option<String>,
+    /// The version to be installed, `None` for the packages to be removed
+    pub new: Option<String>,
+}
+
+/// Parse the output of `apt-get -s`, e.g. `Inst foo [1.0] (1.1 AOSC OS:stable [amd64])`
+/// or `Remv bar [2.0]`
+fn parse_apt_simulation(output: &str) -> Vec<PackageChange> {
+    let mut changes = Vec::new();
+    for line in output.lines() {
+        let (action, rest) = match line.split_once(' ') {
+            Some((action @ ("Inst" | "Remv"), rest)) => (action, rest),
+            _ => continue,
+        };
+        let mut words = rest.splitn(2, ' ');
+        let name = match words.next() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let rest = words.next().unwrap_or_default();
+        let old = rest
+            .strip_prefix('[')
+            .and_then(|s| s.split_once(']'))
+            .map(|(version, _)| version.to_string());
+        let new = if action == "Inst" {
+            rest.split_once('(')
+                .and_then(|(_, s)| s.split_whitespace().next())
+                .map(|version| version.to_string())
+        } else {
+            None
+        };
+        changes.push(PackageChange { name, old, new });
+    }
+
+    changes
+}
+
+fn run_update_simulation(instance: &str) -> Result<Vec<PackageChange>> {
+    let status = run_in_container(instance, &["/bin/bash", "-ec", SIMULATE_UPDATE_SCRIPT])?;
     if status != 0 {
-        return Err(anyhow!("Failed to update OS: {}", status));
+        return Err(anyhow!("Failed to simulate the update: {}", status));
+    }
+    let output = fs::read_to_string(Path::new(instance).join(SIMULATE_UPDATE_OUTPUT))?;
+
+    Ok(parse_apt_simulation(&output))
+}
+
+/// Show which packages `update-os` would change in the base system, without modifying it
+pub fn simulate_update_os() -> Result<()> {
+    info!("Simulating the base OS update...");
+    if matches!(config::read_config(), Ok(c) if !c.update_commands.is_empty()) {
+        warn!(
+            "The simulation uses apt-get, which may differ from the configured `update-commands`."
+        );
     }
-    commit_container(&instance)?;
+    let instance = format!("update-{:x}", random::<u32>());
+    add_instance(&instance)?;
+    let changes = run_update_simulation(&instance);
     remove_instance(&instance)?;
+    let changes = changes?;
+    if changes.is_empty() {
+        info!("The base OS is up to date.");
+        return Ok(());
+    }
+    let count = |f: fn(&PackageChange) -> bool| changes.iter().filter(|c| f(c)).count();
+    info!(
+        "{} packages would be upgraded, {} newly installed and {} removed:",
+        count(|c| c.old.is_some() && c.new.is_some()),
+        count(|c| c.old.is_none()),
+        count(|c| c.new.is_none())
+    );
+    for change in changes.iter() {
+        match (&change.old, &change.new) {
+            (Some(old), Some(new)) => eprintln!(
+                "  {} {} -> {}",
+                style(&change.name).bold(),
+                old,
+                style(new).green()
+            ),
+            (None, Some(new)) => eprintln!(
+                "  {} {} {}",
+                style(&change.name).bold(),
+                style(new).green(),
+                style("(new)").cyan()
+            ),
+            (old, None) => eprintln!(
+                "  {} {} {}",
+                style(&change.name).bold(),
+                old.as_deref().unwrap_or_default(),
+                style("(removed)").red()
+            ),
+        }
+    }
 
     Ok(())
 }
+
+#[test]
+fn test_parse_apt_simulation() {
+    let output = "Reading package lists...\n\
+        Inst gcc [13.2.0] (14.1.0 AOSC OS:stable [amd64])\n\
+        Inst libnew (1.0 AOSC OS:stable [amd64])\n\
+        Conf gcc (14.1.0 AOSC OS:stable [amd64])\n\
+        Remv libold [0.9]\n";
+    assert_eq!(
+        parse_apt_simulation(output),
+        vec![
+            PackageChange {
+                name: "gcc".to_string(),
+                old: Some("13.2.0".to_string()),
+                new: Some("14.1.0".to_string()),
+            },
+            PackageChange {
+                name: "libnew".to_string(),
+                old: None,
+                new: Some("1.0".to_string()),
+            },
+            PackageChange {
+                name: "libold".to_string(),
+                old: Some("0.9".to_string()),
+                new: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_valid_user_name() {
+    assert!(is_valid_user_name("builder"));
+    assert!(is_valid_user_name("_build-1"));
+    assert!(!is_valid_user_name("Builder"));
+    assert!(!is_valid_user_name("1builder"));
+    assert!(!is_valid_user_name("build; rm -rf /"));
+}
@@ -0,0 +1,139 @@
+//! Cross-compilation toolchains (`ciel cross-setup`)
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{fs, path::Path, process::Command};
+
+use crate::{
+    audit::audited,
+    common::{is_instance_exists, CIEL_INST_DIR},
+    config::{self, InstanceConfig},
+    info, overlayfs, warn,
+};
+
+use super::{
+    add_instance_with_config, container_down, get_dist_arch, rollback_container,
+    run_in_container_with_options, RunOptions,
+};
+
+// used if `cross-packages` is not configured
+const DEFAULT_CROSS_PACKAGES: &[&str] = &[
+    "binutils+cross-{arch}",
+    "gcc+cross-{arch}",
+    "glibc+cross-{arch}",
+    "linux+api+cross-{arch}",
+];
+// sourced by the login shells, so that acbs builds for the target in interactive sessions too
+const CROSS_PROFILE: &str = "etc/profile.d/ciel-cross.sh";
+
+fn get_cross_packages(arch: &str) -> Vec<String> {
+    let configured = config::read_config()
+        .map(|c| c.cross_packages)
+        .unwrap_or_default();
+    let packages = if configured.is_empty() {
+        DEFAULT_CROSS_PACKAGES
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    } else {
+        configured
+    };
+
+    packages.iter().map(|p| p.replace("{arch}", arch)).collect()
+}
+
+/// The environment for building packages for `arch` with acbs
+pub fn get_cross_env(arch: &str) -> Vec<(String, String)> {
+    vec![("ABHOST".to_string(), arch.to_string())]
+}
+
+/// Move the changes of the instance into its own layer, so that they are kept on rollback
+/// (but not committed into the base system)
+fn keep_changes(instance: &str) -> Result<()> {
+    container_down(instance)?;
+    let inst = Path::new(CIEL_INST_DIR).join(instance);
+    let status = Command::new("cp")
+        .args(&["-a", "--no-target-directory"])
+        .arg(inst.join(overlayfs::UPPER_DIR))
+        .arg(inst.join(overlayfs::LOWER_DIR))
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!(
+            "Unable to move the toolchain into the instance layer: cp exited with {}",
+            status
+        ));
+    }
+
+    rollback_container(instance)
+}
+
+/// Install the cross toolchain for `arch` into `instance` (`cross-<arch>` if not specified),
+/// the instance is created if it does not exist
+pub fn cross_setup(arch: &str, instance: Option<&str>) -> Result<()> {
+    let dist_arch = get_dist_arch().ok_or_else(|| anyhow!("No base system is loaded."))?;
+    if arch == dist_arch {
+        return Err(anyhow!(
+            "The base system is already for {}, no cross toolchain is needed.",
+            arch
+        ));
+    }
+    let instance = instance.map_or_else(|| format!("cross-{}", arch), String::from);
+    if !is_instance_exists(&instance) {
+        let config = InstanceConfig {
+            description: Some(format!("cross toolchain for {}", arch)),
+            ..Default::default()
+        };
+        add_instance_with_config(&instance, config)?;
+    }
+    let packages = get_cross_packages(arch);
+    audited("cross-setup", Some(&instance), || {
+        info!(
+            "{}: installing the cross toolchain for {} ({}) ...",
+            instance,
+            arch,
+            packages.join(", ")
+        );
+        let script = format!(
+            "export DEBIAN_FRONTEND=noninteractive\napt-get update -y\napt-get install -y {}",
+            packages.join(" ")
+        );
+        let options = RunOptions {
+            log_name: Some("cross-setup".to_string()),
+            ..Default::default()
+        };
+        let status =
+            run_in_container_with_options(&instance, &["/bin/bash", "-ec", &script], &options)?;
+        if status != 0 {
+            return Err(anyhow!(
+                "Failed to install the cross toolchain: {} (see `cross-packages` in the config)",
+                status
+            ));
+        }
+        keep_changes(&instance)?;
+        let local = Path::new(CIEL_INST_DIR)
+            .join(&instance)
+            .join(overlayfs::LOWER_DIR);
+        let profile = local.join(CROSS_PROFILE);
+        fs::create_dir_all(profile.parent().unwrap())?;
+        let exports = get_cross_env(arch)
+            .iter()
+            .map(|(name, value)| format!("export {}={}\n", name, value))
+            .collect::<String>();
+        fs::write(profile, exports)?;
+        let mut config = InstanceConfig::load(&instance)?;
+        config.cross = Some(arch.to_string());
+        config.save(&instance)?;
+
+        Ok(())
+    })?;
+    info!(
+        "{}: ready for cross builds, use `ciel build -i {} PACKAGES`.",
+        instance,
+        style(&instance).cyan()
+    );
+    warn!(
+        "Run `ciel cross-setup {}` again after `ciel update-os` to keep the toolchain in sync with the base system.",
+        arch
+    );
+
+    Ok(())
+}
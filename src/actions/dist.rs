@@ -0,0 +1,411 @@
+//! Keeping the previous base systems around (`ciel load-os --switch`)
+use anyhow::{anyhow, Result};
+use console::style;
+use dialoguer::{theme::ColorfulTheme, Select};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::{
+    common::{get_dir_size, is_interactive, remove_workspace_dir, CIEL_DIST_DIR},
+    info, progress, warn,
+};
+
+use super::{
+    container::{get_dist_arch, get_rootfs_arch},
+    container_down, for_each_instance, UPDATE_SNAPSHOT_DIR,
+};
+
+/// Where the archived base systems are kept (relative to the workspace)
+pub const DIST_ARCHIVE_DIR: &str = ".ciel/container/dists";
+
+/// Where the base system came from, recorded at load time (`<dist>.toml`)
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DistInfo {
+    /// URL, tarball or directory the base system was loaded from
+    pub source: String,
+    #[serde(default)]
+    pub variant: Option<String>,
+    /// Build date of the tarball (`YYYYMMDD`)
+    #[serde(default)]
+    pub date: Option<String>,
+    #[serde(default)]
+    pub arch: Option<String>,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// When the base system was loaded (UNIX timestamp)
+    #[serde(default)]
+    pub loaded: Option<i64>,
+    /// When the base system was last updated by `ciel update-os` (UNIX timestamp)
+    #[serde(default)]
+    pub updated: Option<i64>,
+}
+
+impl DistInfo {
+    /// A one-line summary, e.g. `buildkit 20240102 (amd64)`
+    pub fn describe(&self) -> String {
+        let mut description = match (&self.variant, &self.date) {
+            (Some(variant), Some(date)) => format!("{} {}", variant, date),
+            _ => self.source.clone(),
+        };
+        if let Some(arch) = &self.arch {
+            description.push_str(&format!(" ({})", arch));
+        }
+        if let Some(updated) = self.updated.and_then(format_date) {
+            description.push_str(&format!(", updated on {}", updated));
+        }
+
+        description
+    }
+}
+
+#[inline]
+fn format_date(timestamp: i64) -> Option<String> {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()?
+        .format(format_description!("[year]-[month]-[day]"))
+        .ok()
+}
+
+#[inline]
+pub(super) fn dist_info_path(dist: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.toml", dist.display()))
+}
+
+/// Read the recorded information of the base system at `dist`
+pub fn read_dist_info(dist: &Path) -> Option<DistInfo> {
+    toml::from_str(&fs::read_to_string(dist_info_path(dist)).ok()?).ok()
+}
+
+fn write_dist_info(dist: &Path, info: &DistInfo) -> Result<()> {
+    fs::write(dist_info_path(dist), toml::to_string(info)?)?;
+
+    Ok(())
+}
+
+/// Move the recorded information along with the base system
+fn rename_dist_info(from: &Path, to: &Path) -> Result<()> {
+    let from = dist_info_path(from);
+    if from.is_file() {
+        fs::rename(from, dist_info_path(to))?;
+    }
+
+    Ok(())
+}
+
+/// Split a tarball name like `aosc-os_buildkit_20240102_amd64.tar.xz`
+/// into the variant, date and architecture
+fn parse_tarball_name(name: &str) -> Option<(&str, &str, &str)> {
+    let parts = name
+        .strip_prefix("aosc-os_")?
+        .split('_')
+        .collect::<Vec<_>>();
+    if parts.len() < 3 {
+        return None;
+    }
+    let date = parts[1];
+    if date.len() < 8 || !date[..8].bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let arch = parts[parts.len() - 1].split('.').next()?;
+
+    Some((parts[0], date, arch))
+}
+
+/// Record where the just loaded base system came from
+pub fn record_base_system(source: &str, sha256: Option<String>) -> Result<()> {
+    let name = Path::new(source)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let parsed = parse_tarball_name(&name);
+    let info = DistInfo {
+        source: source.to_string(),
+        variant: parsed.map(|p| p.0.to_string()),
+        date: parsed.map(|p| p.1.to_string()),
+        arch: get_dist_arch().or_else(|| parsed.map(|p| p.2.to_string())),
+        sha256,
+        loaded: Some(OffsetDateTime::now_utc().unix_timestamp()),
+        updated: None,
+    };
+    write_dist_info(Path::new(CIEL_DIST_DIR), &info)
+}
+
+/// Note down the time of the update in the base system information
+pub fn record_base_system_update() -> Result<()> {
+    let dist = Path::new(CIEL_DIST_DIR);
+    let mut info = read_dist_info(dist).unwrap_or_else(|| DistInfo {
+        source: "unknown".to_string(),
+        arch: get_dist_arch(),
+        ..Default::default()
+    });
+    info.updated = Some(OffsetDateTime::now_utc().unix_timestamp());
+    write_dist_info(dist, &info)
+}
+
+/// Show the information of the current base system (`ciel version --os`)
+pub fn print_base_system_info(output: &mut dyn Write) -> Result<()> {
+    let dist = Path::new(CIEL_DIST_DIR);
+    if !has_base_system() {
+        return Err(anyhow!("No base system is loaded in this workspace."));
+    }
+    writeln!(output, "{}", describe_base_system(dist))?;
+    let info = match read_dist_info(dist) {
+        Some(info) => info,
+        None => {
+            writeln!(output, "(loaded before Ciel started to record the source)")?;
+            return Ok(());
+        }
+    };
+    writeln!(output, "Source: {}", info.source)?;
+    if let Some(variant) = &info.variant {
+        writeln!(output, "Variant: {}", variant)?;
+    }
+    if let Some(date) = &info.date {
+        writeln!(output, "Date: {}", date)?;
+    }
+    if let Some(arch) = &info.arch {
+        writeln!(output, "Architecture: {}", arch)?;
+    }
+    if let Some(sha256) = &info.sha256 {
+        writeln!(output, "SHA256: {}", sha256)?;
+    }
+    if let Some(loaded) = info.loaded.and_then(format_date) {
+        writeln!(output, "Loaded: {}", loaded)?;
+    }
+    if let Some(updated) = info.updated.and_then(format_date) {
+        writeln!(output, "Updated: {}", updated)?;
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn has_base_system() -> bool {
+    fs::read_dir(CIEL_DIST_DIR)
+        .ok()
+        .and_then(|mut d| d.next())
+        .is_some()
+}
+
+/// Describe the base system at `path` using its `os-release` (and the recorded information)
+fn describe_base_system(path: &Path) -> String {
+    let os_release = fs::read_to_string(path.join("etc/os-release")).unwrap_or_default();
+    let name = os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+        .unwrap_or_else(|| "unknown system".to_string());
+    match read_dist_info(path) {
+        Some(info) => format!("{}, {}", name, info.describe()),
+        None => name,
+    }
+}
+
+/// The update snapshot belongs to the current base system
+fn remove_update_snapshot() -> Result<()> {
+    if Path::new(UPDATE_SNAPSHOT_DIR).exists() {
+        info!("Removing the snapshot of the last update...");
+        remove_workspace_dir(UPDATE_SNAPSHOT_DIR)?;
+        fs::remove_file(dist_info_path(Path::new(UPDATE_SNAPSHOT_DIR))).ok();
+    }
+
+    Ok(())
+}
+
+/// Move the current base system into the archive, returns the name of the archived one
+fn archive_base_system() -> Result<String> {
+    let timestamp = OffsetDateTime::now_utc().format(format_description!(
+        "[year][month][day]-[hour][minute][second]"
+    ))?;
+    // base systems may be switched back and forth within a second (e.g. by `build --matrix`)
+    let name = (0..)
+        .map(|i| match i {
+            0 => timestamp.clone(),
+            i => format!("{}-{}", timestamp, i),
+        })
+        .find(|name| !Path::new(DIST_ARCHIVE_DIR).join(name).exists())
+        .unwrap();
+    let dest = Path::new(DIST_ARCHIVE_DIR).join(&name);
+    fs::create_dir_all(DIST_ARCHIVE_DIR)?;
+    remove_update_snapshot()?;
+    fs::rename(CIEL_DIST_DIR, &dest)?;
+    rename_dist_info(Path::new(CIEL_DIST_DIR), &dest)?;
+    info!(
+        "Archived the current base system ({}) as {}.",
+        describe_base_system(&dest),
+        style(&name).cyan()
+    );
+
+    Ok(name)
+}
+
+/// Make room for loading a new base system: the current one (if any) is archived,
+/// or removed if `replace` is set (or the user chooses to)
+pub fn prepare_base_system(replace: bool) -> Result<()> {
+    if !has_base_system() {
+        return Ok(());
+    }
+    let mut replace = replace;
+    if !replace && is_interactive() {
+        let choice = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!(
+                "There is already a base system ({}), what to do with it?",
+                describe_base_system(Path::new(CIEL_DIST_DIR))
+            ))
+            .items(&[
+                "Archive it (switch back with `ciel load-os --switch`)",
+                "Replace it",
+                "Cancel",
+            ])
+            .default(0)
+            .interact()?;
+        match choice {
+            0 => (),
+            1 => replace = true,
+            _ => return Err(anyhow!("Cancelled.")),
+        }
+    }
+    info!("Shutting down all the instances...");
+    for_each_instance(&container_down)?;
+    if !replace {
+        archive_base_system()?;
+        return Ok(());
+    }
+    remove_update_snapshot()?;
+    let spinner = progress::spinner("Removing the current base system...");
+    remove_workspace_dir(CIEL_DIST_DIR)?;
+    fs::remove_file(dist_info_path(Path::new(CIEL_DIST_DIR))).ok();
+    spinner.finish_and_clear();
+
+    Ok(())
+}
+
+/// Names of the archived base systems, newest first
+fn list_archived_names() -> Vec<String> {
+    let mut names = match fs::read_dir(DIST_ARCHIVE_DIR) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    names.sort_unstable();
+    names.reverse();
+
+    names
+}
+
+/// Return the path of the archived base system `name`, if it is one of them
+fn archived_base_system_path(name: &str) -> Option<PathBuf> {
+    // `..` would be the directory of the current base system and all the instances
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return None;
+    }
+    if !list_archived_names().iter().any(|n| n == name) {
+        return None;
+    }
+
+    Some(Path::new(DIST_ARCHIVE_DIR).join(name))
+}
+
+/// Find the newest archived base system for `arch`
+pub fn find_archived_base_system(arch: &str) -> Option<String> {
+    list_archived_names().into_iter().find(|name| {
+        let path = Path::new(DIST_ARCHIVE_DIR).join(name);
+        let found = read_dist_info(&path)
+            .and_then(|info| info.arch)
+            .or_else(|| get_rootfs_arch(&path));
+        found.as_deref() == Some(arch)
+    })
+}
+
+/// Show the archived base systems
+pub fn list_base_systems() -> Result<()> {
+    eprintln!(
+        "{}\t(current)\t{}",
+        describe_base_system(Path::new(CIEL_DIST_DIR)),
+        HumanBytes(get_dir_size(CIEL_DIST_DIR))
+    );
+    for name in list_archived_names().iter() {
+        let path = Path::new(DIST_ARCHIVE_DIR).join(name);
+        eprintln!(
+            "{}\t{}\t{}",
+            describe_base_system(&path),
+            style(name).cyan(),
+            HumanBytes(get_dir_size(&path))
+        );
+    }
+
+    Ok(())
+}
+
+/// Switch to the archived base system `name`, the current one is archived in exchange
+/// (returns the name it is archived as)
+pub fn switch_base_system(name: &str) -> Result<Option<String>> {
+    let source = archived_base_system_path(name).ok_or_else(|| {
+        anyhow!(
+            "Archived base system `{}` is not found, see `ciel load-os --list-archived`.",
+            name
+        )
+    })?;
+    info!("Shutting down all the instances...");
+    for_each_instance(&container_down)?;
+    let mut archived = None;
+    if has_base_system() {
+        archived = Some(archive_base_system()?);
+    } else if Path::new(CIEL_DIST_DIR).exists() {
+        fs::remove_dir(CIEL_DIST_DIR)?;
+    }
+    fs::rename(&source, CIEL_DIST_DIR)?;
+    rename_dist_info(&source, Path::new(CIEL_DIST_DIR))?;
+    info!(
+        "Switched to {} ({}).",
+        style(name).cyan(),
+        describe_base_system(Path::new(CIEL_DIST_DIR))
+    );
+    warn!("Changes in the instances were made on top of the previous base system, consider rolling them back.");
+
+    Ok(archived)
+}
+
+/// Permanently remove the archived base system `name`
+pub fn remove_base_system(name: &str) -> Result<()> {
+    let path = archived_base_system_path(name)
+        .ok_or_else(|| anyhow!("Archived base system `{}` is not found.", name))?;
+    let spinner = progress::spinner("Removing the archived base system...");
+    remove_workspace_dir(&path)?;
+    fs::remove_file(dist_info_path(&path)).ok();
+    spinner.finish_and_clear();
+    info!("Removed {}.", name);
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_tarball_name() {
+    assert_eq!(
+        parse_tarball_name("aosc-os_buildkit_20240102_amd64.tar.xz"),
+        Some(("buildkit", "20240102", "amd64"))
+    );
+    assert_eq!(
+        parse_tarball_name("aosc-os_base_20231130.1_loongarch64.squashfs"),
+        Some(("base", "20231130.1", "loongarch64"))
+    );
+    assert!(parse_tarball_name("aosc-os_buildkit_latest_amd64.tar.xz").is_none());
+    assert!(parse_tarball_name("rootfs.tar.xz").is_none());
+}
+
+#[test]
+fn test_archived_base_system_path() {
+    assert!(archived_base_system_path("..").is_none());
+    assert!(archived_base_system_path(".").is_none());
+    assert!(archived_base_system_path("").is_none());
+    assert!(archived_base_system_path("../dist").is_none());
+}
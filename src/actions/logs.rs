@@ -0,0 +1,203 @@
+//! History of the package build logs (`ciel logs`)
+use anyhow::{anyhow, Result};
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+
+use crate::common::format_duration;
+
+/// Where the per-package build logs are kept (relative to the workspace)
+pub const LOGS_DIR: &str = "LOGS";
+// one JSON object per line, oldest first
+const LOGS_INDEX: &str = "LOGS/index.jsonl";
+
+/// A package build recorded in the log index
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildLogEntry {
+    pub package: String,
+    pub instance: String,
+    /// When the build was started (RFC 3339)
+    pub started: String,
+    pub success: bool,
+    pub exit_code: i32,
+    /// Duration of the build in seconds
+    pub duration: u64,
+    /// Path of the log file (relative to the workspace)
+    pub path: PathBuf,
+    #[serde(flatten, default)]
+    pub usage: BuildUsage,
+}
+
+/// Resource accounting of a package build
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct BuildUsage {
+    /// CPU time used by the build in seconds (0 if unknown)
+    #[serde(default)]
+    pub cpu_time: u64,
+    /// Architecture the package is built for
+    #[serde(default)]
+    pub arch: Option<String>,
+    /// Whether the build ran under qemu-user emulation
+    #[serde(default)]
+    pub emulated: bool,
+}
+
+/// Allocate the log file for a new build of `package` in `instance`
+pub fn new_build_log_path(package: &str, instance: &str) -> Result<PathBuf> {
+    let dir = Path::new(LOGS_DIR).join(package.replace('/', "_"));
+    fs::create_dir_all(&dir)?;
+    let timestamp = OffsetDateTime::now_utc().format(format_description!(
+        "[year][month][day]-[hour][minute][second]"
+    ))?;
+
+    Ok(dir.join(format!("{}-{}.log", timestamp, instance)))
+}
+
+/// Append the finished build to the log index
+pub fn record_build_log(
+    package: &str,
+    instance: &str,
+    path: &Path,
+    exit_code: i32,
+    duration: u64,
+    usage: &BuildUsage,
+) -> Result<()> {
+    let started = OffsetDateTime::now_utc() - time::Duration::seconds(duration as i64);
+    let entry = BuildLogEntry {
+        package: package.to_string(),
+        instance: instance.to_string(),
+        started: started.format(&Rfc3339)?,
+        success: exit_code == 0,
+        exit_code,
+        duration,
+        path: path.to_owned(),
+        usage: usage.clone(),
+    };
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(LOGS_INDEX)?;
+    writeln!(index, "{}", serde_json::to_string(&entry)?)?;
+
+    Ok(())
+}
+
+/// Read the log index, newest first (broken lines are skipped)
+fn read_build_logs() -> Result<Vec<BuildLogEntry>> {
+    let index = match fs::File::open(LOGS_INDEX) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = BufReader::new(index)
+        .lines()
+        .filter_map(|line| serde_json::from_str(&line.ok()?).ok())
+        .collect::<Vec<BuildLogEntry>>();
+    entries.reverse();
+
+    Ok(entries)
+}
+
+/// Drop the entries of the removed log files from the index
+pub fn prune_build_log_index() -> Result<()> {
+    let mut entries = read_build_logs()?;
+    let total = entries.len();
+    entries.retain(|e| e.path.is_file());
+    if entries.len() == total {
+        return Ok(());
+    }
+    let mut index = String::new();
+    for entry in entries.iter().rev() {
+        index.push_str(&serde_json::to_string(entry)?);
+        index.push('\n');
+    }
+    fs::write(LOGS_INDEX, index)?;
+
+    Ok(())
+}
+
+/// CPU time of the latest successful native build of the package (if recorded)
+pub fn find_native_cpu_time(package: &str) -> Option<u64> {
+    read_build_logs()
+        .ok()?
+        .into_iter()
+        .find(|e| e.package == package && e.success && !e.usage.emulated && e.usage.cpu_time > 0)
+        .map(|e| e.usage.cpu_time)
+}
+
+#[inline]
+fn matches_package(entry: &BuildLogEntry, package: &str) -> bool {
+    entry.package == package || entry.package.rsplit('/').next() == Some(package)
+}
+
+/// Get the recorded builds (of the package if specified), newest first
+pub fn get_build_logs(package: Option<&str>) -> Result<Vec<BuildLogEntry>> {
+    let mut entries = read_build_logs()?;
+    if let Some(package) = package {
+        entries.retain(|e| matches_package(e, package));
+    }
+
+    Ok(entries)
+}
+
+/// List the recorded builds (of the package if specified)
+pub fn list_build_logs(package: Option<&str>) -> Result<()> {
+    let entries = get_build_logs(package)?;
+    eprintln!("STARTED\t\t\t\tPACKAGE\t\tINSTANCE\tRESULT\tDURATION\tCPU TIME");
+    for entry in entries {
+        let result = if entry.success {
+            style("ok".to_string()).green()
+        } else {
+            style(format!("failed ({})", entry.exit_code)).red()
+        };
+        let cpu_time = match entry.usage.cpu_time {
+            0 => "-".to_string(),
+            cpu_time if entry.usage.emulated => format!("{} (emulated)", format_duration(cpu_time)),
+            cpu_time => format_duration(cpu_time),
+        };
+        eprintln!(
+            "{}\t{}\t\t{}\t\t{}\t{}\t{}",
+            entry.started,
+            entry.package,
+            entry.instance,
+            result,
+            format_duration(entry.duration),
+            cpu_time
+        );
+    }
+
+    Ok(())
+}
+
+/// Find the recorded build of the package, `previous` is the number of builds to go back
+/// (0 is the latest one)
+pub fn find_build_log(package: &str, previous: usize) -> Result<BuildLogEntry> {
+    let mut entries = get_build_logs(Some(package))?;
+    if entries.is_empty() {
+        return Err(anyhow!("No build logs found for {}", package));
+    }
+    if previous >= entries.len() {
+        return Err(anyhow!(
+            "Only {} build logs found for {} (use `--previous 0` to `--previous {}`)",
+            entries.len(),
+            package,
+            entries.len() - 1
+        ));
+    }
+
+    Ok(entries.swap_remove(previous))
+}
+
+/// Copy the log of the package build (see `find_build_log`) to `output`
+pub fn show_build_log(entry: &BuildLogEntry, output: &mut dyn Write) -> Result<()> {
+    let mut log = fs::File::open(&entry.path)
+        .map_err(|e| anyhow!("Unable to open {}: {}", entry.path.display(), e))?;
+    io::copy(&mut log, output)?;
+
+    Ok(())
+}
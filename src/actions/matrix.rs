@@ -0,0 +1,149 @@
+//! Building the packages for several architectures in one go (`ciel build --matrix`)
+use anyhow::{anyhow, Result};
+use console::style;
+use serde::Serialize;
+use std::time::Instant;
+
+use crate::{common::format_duration, error, info};
+
+use super::{
+    container::{get_dist_arch, pick_instance_for_arch},
+    dist::{find_archived_base_system, switch_base_system},
+    packaging::{package_build, BuildSettings, BuildSummary},
+};
+
+/// Result of the builds for one architecture
+#[derive(Debug, Clone, Serialize)]
+pub struct MatrixResult {
+    pub arch: String,
+    pub instance: String,
+    #[serde(flatten)]
+    pub summary: BuildSummary,
+}
+
+/// Decide where the base system of each architecture comes from (`None` for the current one),
+/// the current architecture goes first to save a switch
+fn plan_matrix<'a>(
+    arches: &[&'a str],
+    current: &str,
+    find_archived: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<(&'a str, Option<String>)>> {
+    let mut plan = Vec::new();
+    for arch in arches.iter() {
+        if plan.iter().any(|(a, _)| a == arch) {
+            continue;
+        }
+        if *arch == current {
+            plan.insert(0, (*arch, None));
+            continue;
+        }
+        let name = find_archived(arch).ok_or_else(|| {
+            anyhow!(
+                "There is no base system for {}, load one with `ciel load-os --arch {}` (the current one is archived).",
+                arch,
+                arch
+            )
+        })?;
+        plan.push((*arch, Some(name)));
+    }
+
+    Ok(plan)
+}
+
+fn print_report(results: &[MatrixResult], duration: u64) {
+    eprintln!("ARCH\t\tINSTANCE\tRESULT\t\tDURATION");
+    for result in results {
+        let status = if result.summary.success {
+            style("ok".to_string()).green()
+        } else {
+            style(format!("failed ({})", result.summary.exit_code)).red()
+        };
+        eprintln!(
+            "{}\t\t{}\t{}\t\t{}",
+            result.arch,
+            result.instance,
+            status,
+            format_duration(result.summary.duration)
+        );
+    }
+    let failed = results.iter().filter(|r| !r.summary.success).count();
+    if failed > 0 {
+        eprintln!(
+            "{} - {} of {} architectures failed",
+            style("BUILD FAILED").bold().red(),
+            failed,
+            results.len()
+        );
+    } else {
+        eprintln!(
+            "{} - {} architectures in {}",
+            style("BUILD SUCCESSFUL").bold().green(),
+            results.len(),
+            format_duration(duration)
+        );
+    }
+}
+
+/// Build the packages for each of the architectures, in an instance picked (or created) for
+/// each of them. The base systems are switched as needed and the current one is restored
+/// afterwards.
+pub fn matrix_build(
+    arches: &[&str],
+    packages: &[&str],
+    settings: &BuildSettings,
+) -> Result<Vec<MatrixResult>> {
+    let current = get_dist_arch().ok_or_else(|| anyhow!("No base system is loaded."))?;
+    let plan = plan_matrix(arches, &current, find_archived_base_system)?;
+    let mut results = Vec::new();
+    let mut original = None;
+    let start = Instant::now();
+    let outcome = (|| -> Result<()> {
+        for (arch, base) in plan.iter() {
+            if let Some(name) = base {
+                info!("Switching to the base system for {} ...", arch);
+                let archived = switch_base_system(name)?;
+                if original.is_none() {
+                    original = archived;
+                }
+            }
+            info!("Building for {} ...", style(arch).cyan());
+            let instance = pick_instance_for_arch(arch, None)?;
+            let summary = package_build(&instance, packages.iter().copied(), None, settings)?;
+            if !summary.success {
+                error!("Build for {} failed, continuing with the others.", arch);
+            }
+            results.push(MatrixResult {
+                arch: arch.to_string(),
+                instance,
+                summary,
+            });
+        }
+
+        Ok(())
+    })();
+    if let Some(name) = original {
+        info!("Switching back to the base system for {} ...", current);
+        switch_base_system(&name)?;
+    }
+    outcome?;
+    print_report(&results, start.elapsed().as_secs());
+
+    Ok(results)
+}
+
+#[test]
+fn test_plan_matrix() {
+    let archived = |arch: &str| match arch {
+        "arm64" => Some("20240102-030405".to_string()),
+        _ => None,
+    };
+    let plan = plan_matrix(&["arm64", "amd64", "arm64"], "amd64", archived).unwrap();
+    assert_eq!(
+        plan,
+        vec![
+            ("amd64", None),
+            ("arm64", Some("20240102-030405".to_string()))
+        ]
+    );
+    assert!(plan_matrix(&["amd64", "riscv64"], "amd64", archived).is_err());
+}
@@ -1,23 +1,71 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use console::style;
+use rayon::{prelude::*, ThreadPoolBuilder};
 
-use crate::machine;
+use crate::{config, error, logging, machine, progress};
 
+mod archive;
+mod clean;
 mod container;
+mod cross;
+mod dist;
+mod logs;
+mod matrix;
 mod onboarding;
 mod packaging;
+mod remote;
+mod secrets;
+mod staging;
+mod status;
+mod trash;
+mod ui;
 
 // re-export all the functions from the sub
+pub use self::archive::{export_workspace, import_workspace};
+pub use self::clean::{clean_workspace, CleanOptions};
 pub use self::container::*;
+pub use self::cross::cross_setup;
+pub use self::dist::{
+    list_base_systems, prepare_base_system, print_base_system_info, read_dist_info,
+    record_base_system, remove_base_system, switch_base_system, DistInfo,
+};
+pub use self::logs::{
+    find_build_log, get_build_logs, list_build_logs, show_build_log, BuildLogEntry, BuildUsage,
+};
+pub use self::matrix::{matrix_build, MatrixResult};
 pub use self::onboarding::onboarding;
 pub use self::packaging::*;
+pub use self::remote::{list_remotes, remote_add, remote_build, remote_remove};
+pub use self::secrets::BuildSecret;
+pub use self::status::{
+    get_workspace_status, print_status, OutputStatus, TreeStatus, WorkspaceStatus,
+};
+pub use self::trash::{print_trash, restore_instance};
+pub use self::ui::run_ui;
 
 const DEFAULT_MOUNTS: &[(&str, &str)] = &[
     ("OUTPUT/debs/", "/debs/"),
     ("TREE", "/tree"),
     ("SRCS", "/var/cache/acbs/tarballs"),
 ];
-const UPDATE_SCRIPT: &str = r#"export DEBIAN_FRONTEND=noninteractive;apt-get update -y --allow-releaseinfo-change && apt-get -y -o Dpkg::Options::="--force-confnew" full-upgrade --autoremove --purge && apt clean"#;
+// where the forwarded SSH agent socket and git config are placed inside the container
+const FORWARDED_SSH_AGENT_SOCK: &str = "/run/ciel/ssh-agent.sock";
+const FORWARDED_GIT_CONFIG: &str = "/run/ciel/gitconfig";
+// records the time of the last successful `update-os`
+const LAST_UPDATE_FILE: &str = ".ciel/data/last-update-os";
+// hardlink copy of the base system taken before `update-os`, for `update-os --undo`
+const UPDATE_SNAPSHOT_DIR: &str = ".ciel/container/dist.pre-update";
+// the simulated transaction is saved into the instance for `update-os --dry-run`
+const SIMULATE_UPDATE_OUTPUT: &str = "var/tmp/ciel-update-simulation";
+const SIMULATE_UPDATE_SCRIPT: &str = r#"export DEBIAN_FRONTEND=noninteractive;apt-get update -y --allow-releaseinfo-change && apt-get -s -o Dpkg::Options::="--force-confnew" full-upgrade --autoremove --purge > /var/tmp/ciel-update-simulation"#;
+// how many instances `for_each_instance` operates on at the same time
+const MAX_PARALLEL_INSTANCES: usize = 8;
+// used if `update-commands` is not configured
+const DEFAULT_UPDATE_COMMANDS: &[&str] = &[
+    "apt-get update -y --allow-releaseinfo-change",
+    r#"apt-get -y -o Dpkg::Options::="--force-confnew" full-upgrade --autoremove --purge"#,
+    "apt clean",
+];
 
 /// Ensure that the directories exist and mounted
 #[macro_export]
@@ -30,12 +78,17 @@ macro_rules! ensure_host_sanity {
             .collect();
         if let Ok(c) = config::read_config() {
             extra_options = c.extra_options;
+            if let Some(cache) = c.sources_cache {
+                // use the shared sources cache instead of SRCS
+                mounts[2].0 = cache;
+            }
             if !c.local_sources {
                 // remove SRCS
                 mounts.swap_remove(2);
             }
-            if c.sep_mount {
-                mounts.push((format!("{}/debs", get_output_directory(true)), "/debs/"));
+            let output = get_output_directory(c.sep_mount, c.sep_arch);
+            if output != "OUTPUT" {
+                mounts.push((format!("{}/debs", output), "/debs/"));
                 mounts.swap_remove(0);
             }
         } else {
@@ -50,13 +103,75 @@ macro_rules! ensure_host_sanity {
     }};
 }
 
-/// A convenience function for iterating over all the instances while executing the actions
-#[inline]
-pub fn for_each_instance<F: Fn(&str) -> Result<()>>(func: &F) -> Result<()> {
+/// The script run by `update-os` (and before building), made of `update-commands` in the config
+fn get_update_script() -> String {
+    let mut commands = config::read_config()
+        .map(|c| c.update_commands)
+        .unwrap_or_default();
+    if commands.is_empty() {
+        commands = DEFAULT_UPDATE_COMMANDS
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+    }
+
+    format!(
+        "export DEBIAN_FRONTEND=noninteractive\n{}",
+        commands.join("\n")
+    )
+}
+
+/// A convenience function for iterating over all the instances while executing the actions.
+/// The instances are operated on in parallel (at most `MAX_PARALLEL_INSTANCES` at a time),
+/// the messages and the progress indicators are prefixed with the instance they belong to.
+/// Unlike operating on the instances one by one, a failure does not stop the others: all
+/// of them are tried and the failures are reported together at the end.
+pub fn for_each_instance<F: Fn(&str) -> Result<()> + Sync>(func: &F) -> Result<()> {
     let instances = machine::list_instances_simple()?;
-    for instance in instances {
-        eprintln!("{} {}", style(">>>").bold(), style(&instance).cyan().bold());
-        func(&instance)?;
+    if instances.len() == 1 {
+        eprintln!(
+            "{} {}",
+            style(">>>").bold(),
+            style(&instances[0]).cyan().bold()
+        );
+        logging::set_current_instance(Some(&instances[0]));
+        let result = func(&instances[0]);
+        logging::set_current_instance(None);
+        return result;
+    }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(instances.len().min(MAX_PARALLEL_INSTANCES))
+        .build()?;
+    let group = progress::group(instances.len() as u64, "Operating on all the instances...");
+    logging::set_instance_prefix(true);
+    let results = pool.install(|| {
+        instances
+            .par_iter()
+            .map(|instance| {
+                logging::set_current_instance(Some(instance));
+                let result = func(instance);
+                logging::set_current_instance(None);
+                group.inc();
+                result
+            })
+            .collect::<Vec<_>>()
+    });
+    logging::set_instance_prefix(false);
+    drop(group);
+    let mut failed = Vec::new();
+    for (instance, result) in instances.iter().zip(results) {
+        if let Err(e) = result {
+            error!("{}: {:?}", instance, e);
+            failed.push(instance.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "Failed on {} of {} instances: {}",
+            failed.len(),
+            instances.len(),
+            failed.join(", ")
+        ));
     }
 
     Ok(())
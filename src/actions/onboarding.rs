@@ -1,5 +1,4 @@
 use anyhow::{anyhow, Result};
-use console::{style, user_attended};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input};
 use std::{fs, path::Path};
 
@@ -12,10 +11,11 @@ use crate::{
     warn,
 };
 
-use super::{load_os, mount_fs};
+use super::{get_dist_arch, load_os, mount_fs};
 
 /// Show interactive onboarding guide, triggered by issuing `ciel new`
-pub fn onboarding() -> Result<()> {
+/// (the instance and the tarball can be specified beforehand to avoid the prompts)
+pub fn onboarding(instance: Option<&str>, tarball: Option<&str>) -> Result<()> {
     let theme = ColorfulTheme::default();
     info!("Welcome to ciel!");
     if Path::new(".ciel").exists() {
@@ -25,8 +25,13 @@ pub fn onboarding() -> Result<()> {
     }
     info!("Before continuing, I need to ask you a few questions:");
     let config = config::ask_for_config(None)?;
-    let mut init_instance: Option<String> = None;
-    if user_attended()
+    let mut init_instance: Option<String> = instance.map(String::from);
+    if let Some(name) = &init_instance {
+        info!(
+            "`{}` will be created after initialization is finished.",
+            name
+        );
+    } else if is_interactive()
         && Confirm::with_theme(&theme)
             .with_prompt("Do you want to add a new instance now?")
             .interact()?
@@ -48,14 +53,26 @@ pub fn onboarding() -> Result<()> {
     info!("Initializing container OS...");
     let tarball_url;
     let tarball_sha256;
-    info!("Searching for latest AOSC OS buildkit release...");
-    if let Ok(tarball) = pick_latest_tarball() {
+    let latest = if tarball.is_none() {
+        info!("Searching for latest AOSC OS buildkit release...");
+        pick_latest_tarball(None, None).ok()
+    } else {
+        None
+    };
+    if let Some(tarball) = tarball {
+        tarball_sha256 = None;
+        tarball_url = tarball.to_string();
+    } else if let Some(tarball) = latest {
         info!(
             "Ciel has picked buildkit for {}, released on {}",
             tarball.arch, tarball.date
         );
         tarball_sha256 = Some(tarball.sha256sum);
         tarball_url = format!("https://releases.aosc.io/{}", tarball.path);
+    } else if !is_interactive() {
+        return Err(anyhow!(
+            "Ciel was unable to find a suitable buildkit release. Please specify one with `--tarball`."
+        ));
     } else {
         warn!(
             "Ciel was unable to find a suitable buildkit release. Please specify the URL manually."
@@ -84,7 +101,7 @@ pub fn onboarding() -> Result<()> {
     let cwd = std::env::current_dir()?;
     if config.local_repo {
         info!("Setting up local repository ...");
-        refresh_repo(&cwd.join("OUTPUT"))?;
+        refresh_repo(&cwd.join("OUTPUT"), get_dist_arch().as_deref())?;
         info!("Local repository ready.");
     }
     if let Some(init_instance) = init_instance {
@@ -92,7 +109,11 @@ pub fn onboarding() -> Result<()> {
         info!("{}: instance initialized.", init_instance);
         if config.local_repo {
             mount_fs(&init_instance)?;
-            init_repo(&cwd.join("OUTPUT"), &cwd.join(&init_instance))?;
+            init_repo(
+                &cwd.join("OUTPUT"),
+                &cwd.join(&init_instance),
+                get_dist_arch().as_deref(),
+            )?;
             info!("{}: local repository initialized.", init_instance);
         }
     }
@@ -1,24 +1,77 @@
 use anyhow::{anyhow, Result};
 use console::style;
 use dialoguer::{theme::ColorfulTheme, Select};
+use indicatif::HumanBytes;
 use nix::unistd::gethostname;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{
     fs::{self, File},
     io::{BufRead, BufReader, Write},
-    path::Path,
+    path::{Path, PathBuf},
     thread::sleep,
     time::{Duration, Instant},
 };
-use walkdir::WalkDir;
 
-use crate::{common::create_spinner, config, error, info, repo, warn};
+use crate::{
+    binfmt::is_foreign_arch,
+    common::{
+        ensure_free_space, format_duration, is_interactive, MIN_BUILD_SPACE,
+        RECOMMENDED_BUILD_SPACE,
+    },
+    config::{self, ArchProfile, CielConfig, NetworkPolicy},
+    error, events, info,
+    machine::get_cpu_time,
+    netpolicy, repo, warn,
+};
 
 use super::{
-    container::{get_output_directory, mount_fs, rollback_container, run_in_container},
-    UPDATE_SCRIPT,
+    container::{
+        get_dist_arch, get_output_directory, mount_fs, rollback_container,
+        run_in_container_with_options, RunOptions,
+    },
+    cross::get_cross_env,
+    get_update_script,
+    logs::{find_native_cpu_time, new_build_log_path, record_build_log, BuildUsage},
+    secrets::{BuildSecret, BuildSecrets},
+    staging::BuildStaging,
 };
 
+/// Build settings specified on the command line
+#[derive(Debug, Clone, Default)]
+pub struct BuildSettings {
+    /// Disable network access during the build
+    pub offline: bool,
+    /// Network access during the build (overrides the configuration)
+    pub network_policy: Option<NetworkPolicy>,
+    /// Number of parallel jobs for each package build (overrides the configuration)
+    pub jobs: Option<usize>,
+    /// Forward the build events of the remote builders as JSON lines on stdout, the local builds
+    /// write them to the progress stream if set (see [`crate::events::set_progress_stream`])
+    pub progress_json: bool,
+    /// Secrets available to the builds in `/run/ciel/secrets`
+    pub secrets: Vec<BuildSecret>,
+}
+
+/// Outcome of a build (printed with `--json`)
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildSummary {
+    pub success: bool,
+    pub exit_code: i32,
+    pub packages: Vec<String>,
+    /// Number of packages built successfully
+    pub built: usize,
+    pub failed_package: Option<String>,
+    /// Checkpoint to resume the failed build from
+    pub checkpoint: Option<PathBuf>,
+    pub duration: u64,
+    /// CPU time used by the builds in seconds
+    pub cpu_time: u64,
+    pub emulated: bool,
+    /// CPU time of the emulated builds relative to their native builds (if any)
+    pub emulation_overhead: Option<f64>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BuildCheckPoint {
     packages: Vec<String>,
@@ -33,7 +86,7 @@ pub fn load_build_checkpoint<P: AsRef<Path>>(path: P) -> Result<BuildCheckPoint>
     Ok(bincode::deserialize_from(f)?)
 }
 
-fn dump_build_checkpoint(checkpoint: &BuildCheckPoint) -> Result<()> {
+fn dump_build_checkpoint(checkpoint: &BuildCheckPoint) -> Result<PathBuf> {
     let save_state = bincode::serialize(checkpoint)?;
     let last_package = checkpoint
         .packages
@@ -49,17 +102,7 @@ fn dump_build_checkpoint(checkpoint: &BuildCheckPoint) -> Result<()> {
     f.write_all(&save_state)?;
     info!("Ciel created a check-point: {}", path.display());
 
-    Ok(())
-}
-
-#[inline]
-fn format_duration(seconds: u64) -> String {
-    format!(
-        "{:02}:{:02}:{:02}",
-        seconds / 3600,
-        (seconds / 60) % 60,
-        seconds % 60
-    )
+    Ok(path)
 }
 
 fn read_package_list<P: AsRef<Path>>(filename: P, depth: usize) -> Result<Vec<String>> {
@@ -115,11 +158,137 @@ fn expand_package_list<'a, I: IntoIterator<Item = &'a str>>(packages: I) -> Vec<
     expanded
 }
 
+/// CPU time accounting of the builds, for estimating the overhead of emulation
+#[derive(Debug, Default)]
+struct BuildAccounting {
+    arch: Option<String>,
+    emulated: bool,
+    cpu_time: u64,
+    // CPU time of the emulated builds that have a native build to compare with,
+    // and of the native builds
+    compared: u64,
+    native: u64,
+}
+
+impl BuildAccounting {
+    fn new(instance: &str) -> Self {
+        let (arch, emulated) =
+            get_build_target(&config::InstanceConfig::load(instance).unwrap_or_default());
+
+        BuildAccounting {
+            arch,
+            emulated,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, package: &str, cpu_time: u64) -> BuildUsage {
+        self.cpu_time += cpu_time;
+        if self.emulated {
+            if let Some(native) = find_native_cpu_time(package) {
+                self.compared += cpu_time;
+                self.native += native;
+            }
+        }
+
+        BuildUsage {
+            cpu_time,
+            arch: self.arch.clone(),
+            emulated: self.emulated,
+        }
+    }
+
+    /// How many times the CPU time of the native builds the emulated builds took
+    fn overhead(&self) -> Option<f64> {
+        if self.native == 0 {
+            return None;
+        }
+
+        Some(self.compared as f64 / self.native as f64)
+    }
+
+    fn report(&self, duration: u64) {
+        info!(
+            "CPU time: {} ({:.1} cores on average)",
+            format_duration(self.cpu_time),
+            self.cpu_time as f64 / duration.max(1) as f64
+        );
+        if !self.emulated {
+            return;
+        }
+        match self.overhead() {
+            Some(overhead) => info!(
+                "Emulation overhead: the builds took {:.1}x the CPU time of their native builds.",
+                overhead
+            ),
+            None => {
+                info!("Emulation overhead: no native builds of these packages to compare with.")
+            }
+        }
+    }
+}
+
+/// The architecture the packages are built for in the instance, and whether the builds run
+/// under emulation
+fn get_build_target(inst_config: &config::InstanceConfig) -> (Option<String>, bool) {
+    let target = inst_config
+        .cross
+        .clone()
+        .or_else(|| inst_config.arch.clone())
+        .or_else(get_dist_arch);
+    let emulated = matches!(&inst_config.arch, Some(arch) if is_foreign_arch(arch));
+
+    (target, emulated)
+}
+
+/// Generate the environment variables for the build (e.g. `DEB_BUILD_OPTIONS`)
+fn get_build_env(
+    conf: &CielConfig,
+    settings: &BuildSettings,
+    instance: &str,
+) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    let inst_config = config::InstanceConfig::load(instance).unwrap_or_default();
+    if let Some(arch) = &inst_config.cross {
+        env.extend(get_cross_env(arch));
+    }
+    // the profile of the architecture the packages are built for
+    let (target, emulated) = get_build_target(&inst_config);
+    let default_profile = ArchProfile::default();
+    let profile = match target.and_then(|a| conf.arch_profiles.get_key_value(&a)) {
+        Some((arch, profile)) => {
+            info!("{}: using the build profile for {}", instance, arch);
+            profile
+        }
+        None => &default_profile,
+    };
+    let mut options = Vec::new();
+    if profile.build_nocheck.unwrap_or(conf.build_nocheck) || (emulated && profile.nocheck_emulated)
+    {
+        options.push("nocheck".to_string());
+    }
+    if conf.build_debug {
+        options.push("debug nostrip".to_string());
+    }
+    env.extend(profile.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+    if let Some(jobs) = settings.jobs.or(profile.build_jobs).or(conf.build_jobs) {
+        options.push(format!("parallel={}", jobs));
+        env.push(("ABTHREADS".to_string(), jobs.to_string()));
+    }
+    if !options.is_empty() {
+        env.push(("DEB_BUILD_OPTIONS".to_string(), options.join(" ")));
+    }
+
+    env
+}
+
 #[inline]
 fn package_build_inner<P: AsRef<Path>>(
     packages: &[String],
     instance: &str,
     root: P,
+    build_options: &RunOptions,
+    accounting: &mut BuildAccounting,
 ) -> Result<(i32, usize)> {
     let total = packages.len();
     let mut buf = [0u8; 64];
@@ -127,22 +296,48 @@ fn package_build_inner<P: AsRef<Path>>(
         .map_or_else(|_| "unknown", |s| s.to_str().unwrap_or_else(|_| "unknown"));
     for (index, package) in packages.iter().enumerate() {
         // set terminal title, \r is for hiding the message if the terminal does not support the sequence
-        eprint!(
-            "\x1b]0;ciel: [{}/{}] {} ({}@{})\x07\r",
-            index + 1,
-            total,
-            package,
-            instance,
-            hostname
-        );
+        if console::colors_enabled_stderr() {
+            eprint!(
+                "\x1b]0;ciel: [{}/{}] {} ({}@{})\x07\r",
+                index + 1,
+                total,
+                package,
+                instance,
+                hostname
+            );
+        }
         // hopefully the sequence gets flushed together with the `info!` below
         info!("[{}/{}] Building {}...", index + 1, total, package);
+        let start = Instant::now();
+        let phase = |phase| {
+            events::emit(
+                events::BUILD_PHASE,
+                instance,
+                json!({ "packages": [package], "index": index, "total": total, "phase": phase }),
+            )
+        };
+        phase(events::PHASE_PREPARE);
         mount_fs(instance)?;
         info!("Refreshing local repository...");
-        repo::init_repo(root.as_ref(), Path::new(instance))?;
+        repo::init_repo(
+            root.as_ref(),
+            Path::new(instance),
+            get_dist_arch().as_deref(),
+        )?;
+        phase(events::PHASE_UPDATE);
         let mut status = -1;
+        let update_script = get_update_script();
         for i in 1..=5 {
-            status = run_in_container(instance, &["/bin/bash", "-ec", UPDATE_SCRIPT]).unwrap_or(-1);
+            let options = RunOptions {
+                log_name: Some("update-os".to_string()),
+                ..Default::default()
+            };
+            status = run_in_container_with_options(
+                instance,
+                &["/bin/bash", "-ec", &update_script],
+                &options,
+            )
+            .unwrap_or(-1);
             if status == 0 {
                 break;
             } else {
@@ -158,11 +353,55 @@ fn package_build_inner<P: AsRef<Path>>(
             error!("Failed to update the OS before building packages");
             return Ok((status, index));
         }
-        let status = run_in_container(instance, &["/bin/acbs-build", "--", package])?;
+        let log_file = new_build_log_path(package, instance)?;
+        let options = RunOptions {
+            log_file: Some(log_file.clone()),
+            ..build_options.clone()
+        };
+        events::emit(
+            events::BUILD_STARTED,
+            instance,
+            json!({ "packages": [package], "index": index, "total": total }),
+        );
+        phase(events::PHASE_BUILD);
+        let build_start = Instant::now();
+        let cpu_start = get_cpu_time(instance);
+        let status =
+            run_in_container_with_options(instance, &["/bin/acbs-build", "--", package], &options)?;
+        let duration = build_start.elapsed().as_secs();
+        let usage = accounting.add(
+            package,
+            get_cpu_time(instance).saturating_sub(cpu_start) / 1_000_000,
+        );
+        events::emit(
+            events::BUILD_FINISHED,
+            instance,
+            json!({
+                "packages": [package],
+                "index": index,
+                "total": total,
+                "success": status == 0,
+                "exit_code": status,
+                "duration": duration,
+                "cpu_time": usage.cpu_time,
+                "log": log_file,
+            }),
+        );
+        if let Err(e) = record_build_log(package, instance, &log_file, status, duration, &usage) {
+            warn!("Unable to record the build log: {}", e);
+        }
         if status != 0 {
             error!("Build failed with status: {}", status);
             return Ok((status, index));
         }
+        info!(
+            "[{}/{}] {} built in {}.",
+            index + 1,
+            total,
+            package,
+            format_duration(start.elapsed().as_secs())
+        );
+        phase(events::PHASE_CLEANUP);
         rollback_container(instance)?;
     }
 
@@ -172,9 +411,9 @@ fn package_build_inner<P: AsRef<Path>>(
 pub fn packages_stage_select<'a, K: Clone + ExactSizeIterator<Item = &'a str>>(
     instance: &str,
     packages: K,
-    offline: bool,
+    settings: &BuildSettings,
     start_package: Option<&str>,
-) -> Result<i32> {
+) -> Result<BuildSummary> {
     let packages = expand_package_list(packages);
 
     let selection = if let Some(start_package) = start_package {
@@ -184,6 +423,10 @@ pub fn packages_stage_select<'a, K: Clone + ExactSizeIterator<Item = &'a str>>(
                 x == start_package || x.splitn(2, '/').next().unwrap_or("") == start_package
             })
             .ok_or_else(|| anyhow!("Can not find the specified package in the list!"))?
+    } else if !is_interactive() {
+        return Err(anyhow!(
+            "Please specify the package to start from in batch mode."
+        ));
     } else {
         eprintln!("-*-* S T A G E\t\tS E L E C T *-*-");
 
@@ -204,7 +447,7 @@ pub fn packages_stage_select<'a, K: Clone + ExactSizeIterator<Item = &'a str>>(
             time_elapsed: 0,
             attempts: 1,
         }),
-        offline,
+        settings,
     )
 }
 
@@ -219,12 +462,15 @@ pub fn package_fetch<S: AsRef<str>>(instance: &str, packages: &[S]) -> Result<i3
         warn!("Using this function without local sources caching is probably meaningless.");
     }
 
-    mount_fs(instance)?;
     rollback_container(instance)?;
 
     let mut cmd = vec!["/bin/acbs-build", "-g", "--"];
     cmd.extend(packages.iter().map(|p| p.as_ref()));
-    let status = run_in_container(instance, &cmd)?;
+    let options = RunOptions {
+        log_name: Some("fetch".to_string()),
+        ..Default::default()
+    };
+    let status = run_in_container_with_options(instance, &cmd, &options)?;
 
     Ok(status)
 }
@@ -234,13 +480,20 @@ pub fn package_build<'a, K: Clone + ExactSizeIterator<Item = &'a str>>(
     instance: &str,
     packages: K,
     state: Option<BuildCheckPoint>,
-    offline: bool,
-) -> Result<i32> {
+    settings: &BuildSettings,
+) -> Result<BuildSummary> {
     let conf = config::read_config();
     if conf.is_err() {
         return Err(anyhow!("Please configure this workspace first!"));
     }
     let conf = conf.unwrap();
+    let available = ensure_free_space(".", MIN_BUILD_SPACE, "building packages")?;
+    if available < RECOMMENDED_BUILD_SPACE {
+        warn!(
+            "Only {} of disk space is available, the build may run out of space.",
+            HumanBytes(available)
+        );
+    }
     let mut attempts = 1usize;
 
     let packages = if let Some(p) = state {
@@ -254,7 +507,12 @@ pub fn package_build<'a, K: Clone + ExactSizeIterator<Item = &'a str>>(
         expand_package_list(packages)
     };
 
-    if offline || std::env::var("CIEL_OFFLINE").is_ok() {
+    let policy = if settings.offline || std::env::var("CIEL_OFFLINE").is_ok() {
+        NetworkPolicy::Offline
+    } else {
+        settings.network_policy.unwrap_or(conf.build_network.policy)
+    };
+    if policy == NetworkPolicy::Offline {
         info!("Preparing offline mode. Fetching source packages first ...");
         package_fetch(instance, &packages)?;
         std::env::set_var("CIEL_OFFLINE", "ON");
@@ -262,21 +520,108 @@ pub fn package_build<'a, K: Clone + ExactSizeIterator<Item = &'a str>>(
         info!("Running in offline mode. Network access disabled.");
     }
 
-    mount_fs(instance)?;
     rollback_container(instance)?;
+    let mut build_env = get_build_env(&conf, settings, instance);
+    // lifted when the build is finished
+    let _network = if policy == NetworkPolicy::Restricted {
+        mount_fs(instance)?;
+        let network = netpolicy::restrict_build_network(Path::new(instance), &conf)?;
+        build_env.extend(network.proxy_env());
+        Some(network)
+    } else {
+        None
+    };
+    let staging = BuildStaging::start(instance, &conf)?;
+    let output_root = match &staging {
+        Some(staging) => staging.output_root(),
+        None => std::env::current_dir()?.join(get_output_directory(conf.sep_mount, conf.sep_arch)),
+    };
+    // removed when the build is finished
+    let secrets = BuildSecrets::mount(instance, &settings.secrets, &conf)?;
+    let options = RunOptions {
+        env: build_env,
+        secrets: secrets.as_ref().map(|s| s.dir().to_owned()),
+        ..Default::default()
+    };
+    let summary = build_in_instance(instance, packages, attempts, &conf, options, output_root);
+    drop(secrets);
+    if let Some(staging) = staging {
+        // also the packages built before a failure
+        staging.finish()?;
+    }
 
+    summary
+}
+
+fn build_in_instance(
+    instance: &str,
+    packages: Vec<String>,
+    attempts: usize,
+    conf: &CielConfig,
+    build_options: RunOptions,
+    root: PathBuf,
+) -> Result<BuildSummary> {
     if !conf.local_repo {
         let mut cmd = vec!["/bin/acbs-build".to_string(), "--".to_string()];
-        cmd.extend(packages.into_iter());
-        let status = run_in_container(instance, &cmd)?;
-        return Ok(status);
+        cmd.extend(packages.iter().cloned());
+        let start = Instant::now();
+        let mut accounting = BuildAccounting::new(instance);
+        let cpu_start = get_cpu_time(instance);
+        let options = RunOptions {
+            log_name: Some("build".to_string()),
+            ..build_options
+        };
+        events::emit(
+            events::BUILD_STARTED,
+            instance,
+            json!({ "packages": &packages, "index": 0, "total": packages.len() }),
+        );
+        events::emit(
+            events::BUILD_PHASE,
+            instance,
+            json!({
+                "packages": &packages,
+                "index": 0,
+                "total": packages.len(),
+                "phase": events::PHASE_BUILD,
+            }),
+        );
+        let status = run_in_container_with_options(instance, &cmd, &options)?;
+        // the packages are built in one go, so there is nothing to compare with
+        accounting.cpu_time = get_cpu_time(instance).saturating_sub(cpu_start) / 1_000_000;
+        events::emit(
+            events::BUILD_FINISHED,
+            instance,
+            json!({
+                "packages": &packages,
+                "index": 0,
+                "total": packages.len(),
+                "success": status == 0,
+                "exit_code": status,
+                "duration": start.elapsed().as_secs(),
+                "cpu_time": accounting.cpu_time,
+            }),
+        );
+        accounting.report(start.elapsed().as_secs());
+        return Ok(BuildSummary {
+            success: status == 0,
+            exit_code: status,
+            built: if status == 0 { packages.len() } else { 0 },
+            packages,
+            failed_package: None,
+            checkpoint: None,
+            duration: start.elapsed().as_secs(),
+            cpu_time: accounting.cpu_time,
+            emulated: accounting.emulated,
+            emulation_overhead: None,
+        });
     }
 
-    let output_dir = get_output_directory(conf.sep_mount);
-    let root = std::env::current_dir()?.join(output_dir);
     let total = packages.len();
     let start = Instant::now();
-    let (exit_status, progress) = package_build_inner(&packages, instance, root)?;
+    let mut accounting = BuildAccounting::new(instance);
+    let (exit_status, progress) =
+        package_build_inner(&packages, instance, root, &build_options, &mut accounting)?;
     if exit_status != 0 {
         let checkpoint = BuildCheckPoint {
             packages,
@@ -284,10 +629,22 @@ pub fn package_build<'a, K: Clone + ExactSizeIterator<Item = &'a str>>(
             attempts,
             time_elapsed: 0,
         };
-        dump_build_checkpoint(&checkpoint)?;
-        return Ok(exit_status);
+        let path = dump_build_checkpoint(&checkpoint)?;
+        return Ok(BuildSummary {
+            success: false,
+            exit_code: exit_status,
+            built: progress,
+            failed_package: checkpoint.packages.get(progress).cloned(),
+            packages: checkpoint.packages,
+            checkpoint: Some(path),
+            duration: start.elapsed().as_secs(),
+            cpu_time: accounting.cpu_time,
+            emulated: accounting.emulated,
+            emulation_overhead: accounting.overhead(),
+        });
     }
     let duration = start.elapsed().as_secs();
+    accounting.report(duration);
     eprintln!(
         "{} - {} packages in {}",
         style("BUILD SUCCESSFUL").bold().green(),
@@ -295,29 +652,16 @@ pub fn package_build<'a, K: Clone + ExactSizeIterator<Item = &'a str>>(
         format_duration(duration)
     );
 
-    Ok(0)
-}
-
-/// Clean up output directories
-pub fn cleanup_outputs() -> Result<()> {
-    let spinner = create_spinner("Removing output directories ...", 200);
-    for entry in WalkDir::new(".").max_depth(1) {
-        let entry = entry?;
-        if entry.file_type().is_dir() && entry.file_name().to_string_lossy().starts_with("OUTPUT-")
-        {
-            fs::remove_dir_all(entry.path())?;
-        }
-    }
-    if Path::new("./SRCS").is_dir() {
-        fs::remove_dir_all("./SRCS")?;
-    }
-    spinner.finish_with_message("Done.");
-
-    Ok(())
-}
-
-#[test]
-fn test_time_format() {
-    let test_dur = 3661;
-    assert_eq!(format_duration(test_dur), "01:01:01");
+    Ok(BuildSummary {
+        success: true,
+        exit_code: 0,
+        packages,
+        built: total,
+        failed_package: None,
+        checkpoint: None,
+        duration,
+        cpu_time: accounting.cpu_time,
+        emulated: accounting.emulated,
+        emulation_overhead: accounting.overhead(),
+    })
 }
@@ -0,0 +1,280 @@
+//! Building on other machines over SSH (`ciel remote` and `ciel build --on`)
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    fs,
+    process::{Command, Stdio},
+};
+
+use crate::{
+    common::is_interactive,
+    config::{self, RemoteBuilder, DEFAULT_CONFIG_LOCATION},
+    info,
+    repo::refresh_repo,
+};
+
+use super::{get_dist_arch, get_output_directory, BuildSettings};
+
+/// Quote the argument for the remote shell (if needed)
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"_-./:@%+=,".contains(&c));
+    if safe {
+        return arg.to_string();
+    }
+
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+fn remote_dir(remote: &RemoteBuilder) -> &str {
+    remote.path.as_deref().unwrap_or(".")
+}
+
+/// Run the shell script on the remote machine, a terminal is allocated if `tty` is set
+fn ssh(remote: &RemoteBuilder, script: &str, tty: bool) -> Command {
+    let mut command = Command::new("ssh");
+    if tty {
+        command.arg("-t");
+    } else {
+        command.args(&["-o", "BatchMode=yes"]);
+    }
+    command.arg("--").arg(&remote.host).arg(script);
+
+    command
+}
+
+/// The script running ciel with `args` in the remote workspace
+fn ciel_script(remote: &RemoteBuilder, args: &[String]) -> String {
+    let mut script = format!(
+        "cd {} && {} -b",
+        shell_quote(remote_dir(remote)),
+        remote.command.as_deref().unwrap_or("ciel")
+    );
+    for arg in args {
+        script.push(' ');
+        script.push_str(&shell_quote(arg));
+    }
+
+    script
+}
+
+fn rsync(source: &str, destination: &str, extra: &[&str]) -> Result<()> {
+    if which::which("rsync").is_err() {
+        return Err(anyhow!(
+            "rsync is required to copy the files to/from the remote machine."
+        ));
+    }
+    let status = Command::new("rsync")
+        .arg("-az")
+        .args(extra)
+        .arg(source)
+        .arg(destination)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("rsync exited with {}", status));
+    }
+
+    Ok(())
+}
+
+fn load_remote(name: &str) -> Result<RemoteBuilder> {
+    config::read_config()?
+        .remotes
+        .remove(name)
+        .ok_or_else(|| anyhow!("No such remote: {} (see `ciel remote list`)", name))
+}
+
+/// Register the workspace at `path` on `host` as the remote `name` (`host` if not specified)
+pub fn remote_add(name: Option<&str>, remote: RemoteBuilder) -> Result<()> {
+    let name = name.map_or_else(
+        || {
+            remote
+                .host
+                .rsplit('@')
+                .next()
+                .unwrap_or(&remote.host)
+                .to_string()
+        },
+        String::from,
+    );
+    let mut config = config::read_config()?;
+    if config.remotes.contains_key(&name) {
+        return Err(anyhow!(
+            "Remote {} already exists, remove it first with `ciel remote remove {}`.",
+            name,
+            name
+        ));
+    }
+    if which::which("ssh").is_err() {
+        return Err(anyhow!("ssh is required to build on other machines."));
+    }
+    info!("Checking the workspace on {} ...", remote.host);
+    let status = ssh(
+        &remote,
+        &format!("test -d {}/.ciel", shell_quote(remote_dir(&remote))),
+        false,
+    )
+    .status()?;
+    if !status.success() {
+        return Err(anyhow!(
+            "There is no ciel workspace at {} on {} (or it is unreachable).",
+            remote_dir(&remote),
+            remote.host
+        ));
+    }
+    config.remotes.insert(name.clone(), remote);
+    fs::write(DEFAULT_CONFIG_LOCATION, config.save_config()?)?;
+    info!(
+        "Remote {} added, use `ciel build --on {} PACKAGES`.",
+        name, name
+    );
+
+    Ok(())
+}
+
+pub fn remote_remove(name: &str) -> Result<()> {
+    let mut config = config::read_config()?;
+    if config.remotes.remove(name).is_none() {
+        return Err(anyhow!("No such remote: {}", name));
+    }
+    fs::write(DEFAULT_CONFIG_LOCATION, config.save_config()?)?;
+    info!("Remote {} removed.", name);
+
+    Ok(())
+}
+
+pub fn list_remotes() -> Result<()> {
+    let remotes = config::read_config()?.remotes;
+    if remotes.is_empty() {
+        info!("No remotes, add one with `ciel remote add HOST`.");
+        return Ok(());
+    }
+    for (name, remote) in remotes.iter() {
+        eprintln!(
+            "{}\t{}:{}\t{}{}",
+            style(name).cyan().bold(),
+            remote.host,
+            remote_dir(remote),
+            remote.instance.as_deref().unwrap_or("-"),
+            if remote.sync_tree { "\tsync-tree" } else { "" }
+        );
+    }
+
+    Ok(())
+}
+
+/// Copy the packages in the remote OUTPUT directory into the local one
+fn fetch_output(remote: &RemoteBuilder) -> Result<()> {
+    let output = ssh(
+        remote,
+        &ciel_script(remote, &["status".into(), "--json".into()]),
+        false,
+    )
+    .stderr(Stdio::inherit())
+    .output()?;
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| anyhow!("Unable to get the status of the remote workspace: {}", e))?;
+    let remote_output = status["output"]["path"]
+        .as_str()
+        .ok_or_else(|| anyhow!("The remote workspace has no output directory."))?;
+    let conf = config::read_config()?;
+    let local_output = get_output_directory(conf.sep_mount, conf.sep_arch);
+    fs::create_dir_all(&local_output)?;
+    info!(
+        "Fetching the packages from {} into {} ...",
+        remote.host, local_output
+    );
+    let source = format!("{}:{}/{}/", remote.host, remote_dir(remote), remote_output);
+    // the local packages are kept, only the remote ones are added or updated
+    rsync(&source, &local_output, &[])?;
+    if conf.local_repo {
+        info!("Refreshing the local repository ...");
+        let root = std::env::current_dir()?.join(&local_output);
+        refresh_repo(&root, get_dist_arch().as_deref())?;
+    }
+
+    Ok(())
+}
+
+/// Build the packages in the remote workspace, streaming its output, then fetch the packages.
+/// Returns the exit code of the remote build.
+pub fn remote_build(
+    name: &str,
+    instance: Option<&str>,
+    arch: Option<&str>,
+    packages: &[&str],
+    settings: &BuildSettings,
+) -> Result<i32> {
+    let remote = load_remote(name)?;
+    let mut args = vec!["build".to_string()];
+    match (instance.or(remote.instance.as_deref()), arch) {
+        (instance, Some(arch)) => {
+            args.extend_from_slice(&["--arch".to_string(), arch.to_string()]);
+            if let Some(instance) = instance {
+                args.extend_from_slice(&["-i".to_string(), instance.to_string()]);
+            }
+        }
+        (Some(instance), None) => args.extend_from_slice(&["-i".to_string(), instance.to_string()]),
+        (None, None) => {
+            return Err(anyhow!(
+                "No instance specified, use -i or `ciel remote add --instance` for {}.",
+                name
+            ))
+        }
+    }
+    if settings.offline {
+        args.push("-x".to_string());
+    }
+    if let Some(policy) = settings.network_policy {
+        args.extend_from_slice(&["--network-policy".to_string(), policy.to_string()]);
+    }
+    if let Some(jobs) = settings.jobs {
+        args.extend_from_slice(&["--jobs-per-build".to_string(), jobs.to_string()]);
+    }
+    // the events are forwarded through the stdout of ssh
+    if settings.progress_json {
+        args.extend_from_slice(&["--progress".to_string(), "json".to_string()]);
+    }
+    args.extend(packages.iter().map(|p| p.to_string()));
+    if remote.sync_tree {
+        info!("Copying TREE to {} ...", remote.host);
+        let destination = format!("{}:{}/TREE/", remote.host, remote_dir(&remote));
+        rsync("TREE/", &destination, &["--delete", "--exclude=.git"])?;
+    }
+    info!(
+        "Building {} on {} ...",
+        packages.join(" "),
+        style(&remote.host).cyan()
+    );
+    let status = ssh(&remote, &ciel_script(&remote, &args), is_interactive()).status()?;
+    let code = status
+        .code()
+        .ok_or_else(|| anyhow!("ssh was killed: {}", status))?;
+    // 255 is used by ssh itself
+    if code == 255 {
+        return Err(anyhow!("Unable to run the build on {}.", remote.host));
+    }
+    // the packages built before a failure are fetched too
+    fetch_output(&remote)?;
+
+    Ok(code)
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(shell_quote("linux+kernel"), "linux+kernel");
+    assert_eq!(shell_quote("a b"), "'a b'");
+    assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    assert_eq!(shell_quote(""), "''");
+    let remote = RemoteBuilder {
+        host: "builder".to_string(),
+        path: Some("/srv/ciel ws".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        ciel_script(&remote, &["build".into(), "-i".into(), "main".into()]),
+        "cd '/srv/ciel ws' && ciel -b build -i main"
+    );
+}
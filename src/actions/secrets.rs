@@ -0,0 +1,194 @@
+//! Secrets for the builds (`ciel build --secret NAME=@FILE`), e.g. the tokens for the private
+//! source mirrors: they are kept in a tmpfs in the instance directory (outside of the layers of the
+//! instance), which is bind-mounted read-only to `/run/ciel/secrets` in the container during the
+//! build, so they are never written into the layers or the output directory by ciel.
+use anyhow::{anyhow, Result};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use std::{
+    ffi::OsStr,
+    fmt, fs,
+    io::Write,
+    os::unix::{
+        ffi::OsStringExt,
+        fs::{DirBuilderExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{common::CIEL_INST_DIR, config::CielConfig, debug, info, mac, overlayfs, warn};
+
+use super::container::container_down;
+
+/// Where the secrets are in the container
+pub(crate) const FORWARDED_SECRETS_DIR: &str = "/run/ciel/secrets";
+// relative to the instance directory
+const SECRETS_DIR: &str = "secrets";
+
+/// A secret exposed to the builds as `/run/ciel/secrets/NAME`
+#[derive(Clone)]
+pub struct BuildSecret {
+    pub name: String,
+    value: Vec<u8>,
+}
+
+// keep the secrets out of the debug logs
+impl fmt::Debug for BuildSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BuildSecret({}, {} bytes)", self.name, self.value.len())
+    }
+}
+
+impl BuildSecret {
+    /// Parse `NAME=@FILE` (read from the file) or `NAME` (read from the environment variable)
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (name, value) = match spec.split_once('=') {
+            Some((name, source)) => {
+                let path = source.strip_prefix('@').ok_or_else(|| {
+                    anyhow!(
+                        "Secrets can not be given on the command line, use {}=@FILE instead.",
+                        name
+                    )
+                })?;
+                let value = fs::read(path)
+                    .map_err(|e| anyhow!("Unable to read the secret {}: {}", name, e))?;
+                (name, value)
+            }
+            None => {
+                let value = std::env::var_os(spec)
+                    .ok_or_else(|| anyhow!("The secret {} is not in the environment.", spec))?;
+                (spec, value.into_vec())
+            }
+        };
+        if name.is_empty()
+            || name.starts_with('.')
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        {
+            return Err(anyhow!("Invalid name of the secret: {}", name));
+        }
+
+        Ok(BuildSecret {
+            name: name.to_string(),
+            value,
+        })
+    }
+}
+
+fn get_secrets_dir(instance: &str) -> Result<PathBuf> {
+    Ok(std::env::current_dir()?
+        .join(CIEL_INST_DIR)
+        .join(instance)
+        .join(SECRETS_DIR))
+}
+
+/// The secrets of a build, removed (with the container stopped) when dropped
+pub struct BuildSecrets {
+    instance: String,
+    dir: PathBuf,
+}
+
+impl BuildSecrets {
+    /// Put the secrets into the tmpfs of the instance, `None` if there is no secret
+    pub fn mount(
+        instance: &str,
+        secrets: &[BuildSecret],
+        config: &CielConfig,
+    ) -> Result<Option<Self>> {
+        if secrets.is_empty() {
+            return Ok(None);
+        }
+        let dir = get_secrets_dir(instance)?;
+        // the left-overs of an interrupted build
+        unmount_secrets(instance)?;
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+        // labeled for the container
+        let options = match mac::get_mount_options(&config.mac) {
+            Some(context) => format!("mode=0700,{}", context),
+            None => "mode=0700".to_string(),
+        };
+        mount(
+            Some("tmpfs"),
+            &dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            Some(options.as_str()),
+        )?;
+        // unmounted from now on
+        let guard = BuildSecrets {
+            instance: instance.to_string(),
+            dir,
+        };
+        for secret in secrets.iter() {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o400)
+                .open(guard.dir.join(&secret.name))?
+                .write_all(&secret.value)?;
+        }
+        info!(
+            "{}: {} secret(s) available in {}.",
+            instance,
+            secrets.len(),
+            FORWARDED_SECRETS_DIR
+        );
+
+        Ok(Some(guard))
+    }
+
+    /// The directory to be bind-mounted to `/run/ciel/secrets`
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for BuildSecrets {
+    fn drop(&mut self) {
+        // the running container keeps the secrets mounted
+        if let Err(e) = container_down(&self.instance) {
+            warn!("{}: unable to shut down the instance: {}", self.instance, e);
+        }
+        if let Err(e) = unmount_secrets(&self.instance) {
+            warn!(
+                "{}: unable to remove the secrets in {}: {}",
+                self.instance,
+                self.dir.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Remove the secrets of the instance (if any)
+pub fn unmount_secrets(instance: &str) -> Result<()> {
+    let dir = get_secrets_dir(instance)?;
+    while overlayfs::is_mounted(&dir, OsStr::new("tmpfs"))? {
+        debug!("Un-mounting {}", dir.display());
+        umount2(&dir, MntFlags::MNT_DETACH)?;
+    }
+    if dir.is_dir() {
+        // only the mount point, which is empty
+        fs::remove_dir(&dir)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_secret() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("token");
+    fs::write(&path, "hunter2").unwrap();
+    let secret = BuildSecret::parse(&format!("mirror-token={}", path.display()));
+    assert!(secret.is_err());
+    let secret = BuildSecret::parse(&format!("mirror-token=@{}", path.display())).unwrap();
+    assert_eq!(secret.name, "mirror-token");
+    assert_eq!(secret.value, b"hunter2");
+    assert!(!format!("{:?}", secret).contains("hunter2"));
+    assert!(BuildSecret::parse(&format!("../token=@{}", path.display())).is_err());
+    assert!(BuildSecret::parse("CIEL_TEST_NO_SUCH_SECRET").is_err());
+}
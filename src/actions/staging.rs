@@ -0,0 +1,318 @@
+//! Protecting TREE and the output directory from the builds (`tree-read-only` and
+//! `output-staging` in the config): during the builds, the containers see them through overlays
+//! in the instance directory. The changes to TREE are discarded, and only the new packages are
+//! moved into the output directory after the build (the existing packages are not replaced by
+//! different ones unless `output-replace` is set).
+use anyhow::Result;
+use nix::mount::{umount2, MntFlags};
+use std::{
+    ffi::OsStr,
+    fs,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    common::{self, CIEL_INST_DIR},
+    config::CielConfig,
+    debug, info, mac, overlayfs, repo, warn,
+};
+
+use super::container::{container_down, get_dist_arch, get_output_directory};
+
+// relative to the instance directory
+const STAGING_DIR: &str = "staging";
+
+/// An overlay protecting a directory of the workspace (the lower layer)
+struct StagingOverlay {
+    lower: PathBuf,
+    upper: PathBuf,
+    work: PathBuf,
+    merged: PathBuf,
+}
+
+/// TREE and/or the output directory, as seen by the builds in the instance
+pub struct BuildStaging {
+    instance: String,
+    tree: Option<StagingOverlay>,
+    output: Option<StagingOverlay>,
+    /// The output directory of the workspace
+    output_root: PathBuf,
+    /// Replace the existing packages of different content
+    replace: bool,
+}
+
+impl StagingOverlay {
+    fn new(instance: &str, name: &str, lower: PathBuf) -> Result<Self> {
+        let dir = std::env::current_dir()?
+            .join(CIEL_INST_DIR)
+            .join(instance)
+            .join(STAGING_DIR);
+
+        Ok(StagingOverlay {
+            lower,
+            upper: dir.join(format!("{}.upper", name)),
+            work: dir.join(format!("{}.work", name)),
+            merged: dir.join(name),
+        })
+    }
+
+    /// TREE of the workspace
+    fn tree(instance: &str) -> Result<Self> {
+        Self::new(instance, "tree", std::env::current_dir()?.join("TREE"))
+    }
+
+    /// The packages in the output directory (`debs`), the overlay is mounted on `output/debs`
+    /// so that `output` can be used as the output directory
+    fn output(instance: &str, output_root: &Path) -> Result<Self> {
+        let mut overlay = Self::new(instance, "output", output_root.join("debs"))?;
+        overlay.merged = overlay.merged.join("debs");
+
+        Ok(overlay)
+    }
+
+    fn is_mounted(&self) -> Result<bool> {
+        overlayfs::is_mounted(&self.merged, OsStr::new("overlay"))
+    }
+
+    fn mount(&self, options: Option<String>) -> Result<()> {
+        if self.is_mounted()? {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.lower)?;
+        // TREE may be a symlink
+        let lower = fs::canonicalize(&self.lower)?;
+        overlayfs::mount_overlay(&lower, &self.upper, &self.work, &self.merged, options)
+    }
+
+    fn unmount(&self) -> Result<()> {
+        while self.is_mounted()? {
+            debug!("Un-mounting {}", self.merged.display());
+            umount2(&self.merged, MntFlags::MNT_DETACH)?;
+        }
+
+        Ok(())
+    }
+
+    /// Throw away the changes (the overlay must not be mounted)
+    fn discard(&self) -> Result<()> {
+        for dir in [&self.upper, &self.work] {
+            if dir.exists() {
+                common::remove_workspace_dir(dir)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check if the files have the same content
+fn is_same_content(a: &Path, b: &Path) -> Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    Ok(common::sha256sum(fs::File::open(a)?)? == common::sha256sum(fs::File::open(b)?)?)
+}
+
+/// Move the packages in `upper` into `lower`, returns how many were moved.
+/// The existing packages are only replaced by different ones if `replace` is set.
+/// Everything else (including the removal of the existing packages) is ignored.
+fn promote_packages(upper: &Path, lower: &Path, replace: bool) -> Result<usize> {
+    let mut moved = 0;
+    for entry in WalkDir::new(upper).min_depth(1) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(upper)?;
+        let file_type = entry.file_type();
+        // whiteouts are character devices
+        if file_type.is_char_device() {
+            warn!(
+                "Ignoring the removal of {} by the build",
+                relative.display()
+            );
+            continue;
+        }
+        if !file_type.is_file() || entry.path().extension() != Some(OsStr::new("deb")) {
+            continue;
+        }
+        let dest = lower.join(relative);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if dest.exists() {
+            if is_same_content(entry.path(), &dest)? {
+                debug!("{} is unchanged", relative.display());
+                continue;
+            }
+            if !replace {
+                warn!(
+                    "Not replacing {} with the different package built, set `output-replace` to allow it",
+                    relative.display()
+                );
+                continue;
+            }
+            info!("Replacing {}", relative.display());
+        }
+        if fs::rename(entry.path(), &dest).is_err() {
+            // the output directory may be on another filesystem
+            fs::copy(entry.path(), &dest)?;
+            fs::remove_file(entry.path())?;
+        }
+        moved += 1;
+    }
+
+    Ok(moved)
+}
+
+impl BuildStaging {
+    /// Set up the overlays for building in the instance (as configured), `None` if disabled
+    pub fn start(instance: &str, config: &CielConfig) -> Result<Option<Self>> {
+        if !config.tree_read_only && !config.output_staging {
+            return Ok(None);
+        }
+        let output_root =
+            std::env::current_dir()?.join(get_output_directory(config.sep_mount, config.sep_arch));
+        let staging = BuildStaging {
+            instance: instance.to_string(),
+            tree: if config.tree_read_only {
+                Some(StagingOverlay::tree(instance)?)
+            } else {
+                None
+            },
+            output: if config.output_staging {
+                Some(StagingOverlay::output(instance, &output_root)?)
+            } else {
+                None
+            },
+            output_root,
+            replace: config.output_replace,
+        };
+        // labeled for the container
+        let options = mac::get_mount_options(&config.mac);
+        if let Some(tree) = &staging.tree {
+            tree.unmount()?;
+            // the scratch of the previous builds
+            tree.discard()?;
+            tree.mount(options.clone())?;
+            info!("{}: TREE is read-only during the build.", instance);
+        }
+        if let Some(output) = &staging.output {
+            // the packages left by an interrupted build are kept
+            output.mount(options)?;
+            info!("{}: the packages are staged during the build.", instance);
+        }
+
+        Ok(Some(staging))
+    }
+
+    /// The output directory used for the build (as seen in the container)
+    pub fn output_root(&self) -> PathBuf {
+        match &self.output {
+            Some(output) => output.merged.parent().unwrap().to_owned(),
+            None => self.output_root.clone(),
+        }
+    }
+
+    /// Stop the instance and move the new packages into the output directory
+    pub fn finish(self) -> Result<()> {
+        // the container keeps the overlays busy
+        container_down(&self.instance)?;
+        if let Some(tree) = &self.tree {
+            tree.discard()?;
+        }
+        let output = match &self.output {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        let moved = if output.upper.is_dir() {
+            promote_packages(&output.upper, &output.lower, self.replace)?
+        } else {
+            0
+        };
+        output.discard()?;
+        if moved > 0 {
+            info!(
+                "{}: moved {} package(s) into the output directory.",
+                self.instance, moved
+            );
+            repo::refresh_repo(&self.output_root, get_dist_arch().as_deref())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Un-mount the overlays of the instance (the changes are kept)
+pub fn unmount_staging(instance: &str) -> Result<()> {
+    let output_root = Path::new("OUTPUT");
+    for overlay in [
+        StagingOverlay::tree(instance)?,
+        StagingOverlay::output(instance, output_root)?,
+    ] {
+        overlay.unmount()?;
+    }
+
+    Ok(())
+}
+
+/// Replace TREE and the output directory in the mounts of the container with the overlays
+/// of the instance, if mounted (during a build)
+pub fn apply_staging_mounts(instance: &str, mounts: &mut [(String, &str)]) -> Result<()> {
+    let tree = StagingOverlay::tree(instance)?;
+    let output = StagingOverlay::output(instance, Path::new("OUTPUT"))?;
+    for (source, dest) in mounts.iter_mut() {
+        let overlay = match *dest {
+            "/tree" => &tree,
+            "/debs/" => &output,
+            _ => continue,
+        };
+        if overlay.is_mounted()? {
+            *source = overlay.merged.to_string_lossy().to_string();
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_promote_packages() {
+    let dir = tempfile::tempdir().unwrap();
+    let upper = dir.path().join("upper");
+    let lower = dir.path().join("lower");
+    fs::create_dir_all(upper.join("b")).unwrap();
+    fs::create_dir_all(lower.join("b")).unwrap();
+    fs::write(upper.join("b/bash_5.2_amd64.deb"), "new").unwrap();
+    fs::write(upper.join("Packages"), "").unwrap();
+    fs::write(lower.join("b/bash_5.1_amd64.deb"), "old").unwrap();
+    assert_eq!(promote_packages(&upper, &lower, false).unwrap(), 1);
+    assert_eq!(
+        fs::read_to_string(lower.join("b/bash_5.2_amd64.deb")).unwrap(),
+        "new"
+    );
+    assert!(lower.join("b/bash_5.1_amd64.deb").is_file());
+    assert!(!lower.join("Packages").exists());
+}
+
+#[test]
+fn test_promote_existing_packages() {
+    let dir = tempfile::tempdir().unwrap();
+    let upper = dir.path().join("upper");
+    let lower = dir.path().join("lower");
+    fs::create_dir_all(&upper).unwrap();
+    fs::create_dir_all(&lower).unwrap();
+    fs::write(upper.join("bash_5.2_amd64.deb"), "rebuilt").unwrap();
+    fs::write(upper.join("zsh_5.9_amd64.deb"), "same").unwrap();
+    fs::write(lower.join("bash_5.2_amd64.deb"), "released").unwrap();
+    fs::write(lower.join("zsh_5.9_amd64.deb"), "same").unwrap();
+    assert_eq!(promote_packages(&upper, &lower, false).unwrap(), 0);
+    assert_eq!(
+        fs::read_to_string(lower.join("bash_5.2_amd64.deb")).unwrap(),
+        "released"
+    );
+    assert_eq!(promote_packages(&upper, &lower, true).unwrap(), 1);
+    assert_eq!(
+        fs::read_to_string(lower.join("bash_5.2_amd64.deb")).unwrap(),
+        "rebuilt"
+    );
+}
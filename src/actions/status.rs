@@ -0,0 +1,195 @@
+use anyhow::Result;
+use console::style;
+use git2::Repository;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::{fs, path::Path};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::{
+    common::*,
+    config,
+    machine::{self, CielInstance},
+};
+
+use super::{get_output_directory, read_dist_info, DistInfo, LAST_UPDATE_FILE};
+
+/// The checked out commit of the tree
+#[derive(Debug, Serialize)]
+pub struct TreeStatus {
+    pub branch: String,
+    pub commit: String,
+    pub summary: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputStatus {
+    pub path: String,
+    pub size: u64,
+    /// Number of packages in the local repository (None if not refreshed yet)
+    pub packages: Option<usize>,
+}
+
+/// An overview of the workspace (`ciel status`)
+#[derive(Serialize)]
+pub struct WorkspaceStatus {
+    pub tree: Option<TreeStatus>,
+    /// Pretty name of the base system
+    pub os: Option<String>,
+    pub base_system: Option<DistInfo>,
+    /// Time of the last successful `update-os` (UNIX timestamp)
+    pub last_update: Option<i64>,
+    pub local_repo: bool,
+    pub output: OutputStatus,
+    pub checkpoints: Vec<String>,
+    pub instances: Vec<CielInstance>,
+}
+
+fn get_tree_status() -> Option<TreeStatus> {
+    let repo = Repository::open("TREE").ok()?;
+    let head = repo.head().ok()?;
+    let commit = head.peel_to_commit().ok()?;
+
+    Some(TreeStatus {
+        branch: head.shorthand().unwrap_or("HEAD").to_owned(),
+        commit: commit.id().to_string(),
+        summary: commit.summary().unwrap_or_default().to_owned(),
+    })
+}
+
+/// Get the pretty name of the OS in the base layer
+fn get_os_version() -> Option<String> {
+    let os_release = fs::read_to_string(Path::new(CIEL_DIST_DIR).join("etc/os-release")).ok()?;
+    os_release.lines().find_map(|line| {
+        line.strip_prefix("PRETTY_NAME=")
+            .map(|name| name.trim_matches('"').to_owned())
+    })
+}
+
+fn get_last_update() -> Option<i64> {
+    fs::read_to_string(LAST_UPDATE_FILE)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn get_output_status(path: &str) -> OutputStatus {
+    let size = get_dir_size(path);
+    let packages = fs::read_to_string(Path::new(path).join("debs/Packages"))
+        .ok()
+        .map(|p| p.lines().filter(|l| l.starts_with("Package:")).count());
+
+    OutputStatus {
+        path: path.to_owned(),
+        size,
+        packages,
+    }
+}
+
+fn list_checkpoints() -> Vec<String> {
+    let mut checkpoints: Vec<String> = fs::read_dir("STATES")
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .filter(|name| name.ends_with(".ciel-ckpt"))
+                .collect()
+        })
+        .unwrap_or_default();
+    checkpoints.sort();
+
+    checkpoints
+}
+
+#[inline]
+fn format_timestamp(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()
+        .and_then(|t| {
+            t.format(format_description!(
+                "[year]-[month]-[day] [hour]:[minute]:[second] UTC"
+            ))
+            .ok()
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Get an overview of the workspace
+pub fn get_workspace_status() -> Result<WorkspaceStatus> {
+    let (sep_mount, sep_arch, local_repo) = config::read_config()
+        .map(|c| (c.sep_mount, c.sep_arch, c.local_repo))
+        .unwrap_or((false, false, false));
+    let output_dir = get_output_directory(sep_mount, sep_arch);
+
+    Ok(WorkspaceStatus {
+        tree: get_tree_status(),
+        os: get_os_version(),
+        base_system: read_dist_info(Path::new(CIEL_DIST_DIR)),
+        last_update: get_last_update(),
+        local_repo,
+        output: get_output_status(&output_dir),
+        checkpoints: list_checkpoints(),
+        instances: machine::list_instances()?,
+    })
+}
+
+/// Print an overview of the workspace
+pub fn print_status() -> Result<()> {
+    let status = get_workspace_status()?;
+    let unknown = || style("unknown").dim().to_string();
+    let label = |name: &str| style(format!("{:<14}", name)).bold();
+    eprintln!(
+        "{}{}",
+        label("Workspace:"),
+        std::env::current_dir()?.display()
+    );
+    eprintln!(
+        "{}{}",
+        label("Tree:"),
+        status.tree.map_or_else(unknown, |t| format!(
+            "{} @ {} ({})",
+            style(t.branch).cyan(),
+            &t.commit[..8],
+            t.summary
+        ))
+    );
+    eprintln!("{}{}", label("Base OS:"), status.os.unwrap_or_else(unknown));
+    eprintln!(
+        "{}{}",
+        label("Loaded from:"),
+        status
+            .base_system
+            .map_or_else(unknown, |info| info.describe())
+    );
+    eprintln!(
+        "{}{}",
+        label("Last update:"),
+        status.last_update.map_or_else(unknown, format_timestamp)
+    );
+    let repo_state = if !status.local_repo {
+        "local repository disabled".to_string()
+    } else if let Some(packages) = status.output.packages {
+        format!("{} packages in local repository", packages)
+    } else {
+        "local repository not refreshed".to_string()
+    };
+    eprintln!(
+        "{}{} ({}, {})",
+        label("Output:"),
+        status.output.path,
+        HumanBytes(status.output.size),
+        repo_state
+    );
+    if status.checkpoints.is_empty() {
+        eprintln!("{}none", label("Checkpoints:"));
+    } else {
+        eprintln!("{}", label("Checkpoints:"));
+        for checkpoint in status.checkpoints.iter() {
+            eprintln!("{:<14}{}", "", style(checkpoint).yellow());
+        }
+    }
+    eprintln!();
+
+    machine::print_instances(false)
+}
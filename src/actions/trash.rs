@@ -0,0 +1,156 @@
+//! Recoverable instance deletion (`ciel del` / `ciel restore`)
+use anyhow::{anyhow, Result};
+use console::style;
+use indicatif::HumanBytes;
+use std::{fs, path::Path};
+use time::{
+    format_description::FormatItem, macros::format_description, Duration, OffsetDateTime,
+    PrimitiveDateTime,
+};
+
+use crate::{
+    audit::audited,
+    common::{get_dir_size, remove_workspace_dir, CIEL_INST_DIR},
+    info,
+};
+
+use super::{container_down, detach_stale_mounts};
+
+/// Where the deleted instances are kept (relative to the workspace)
+pub const TRASH_DIR: &str = ".ciel/trash";
+/// Days to keep the deleted instances if not configured
+pub const DEFAULT_TRASH_RETENTION: u64 = 7;
+// the entries are named `<instance>-<timestamp>` (in UTC)
+const TIMESTAMP_FORMAT: &[FormatItem] =
+    format_description!("[year][month][day]-[hour][minute][second]");
+
+/// An instance in the trash
+#[derive(Debug)]
+pub struct TrashEntry {
+    /// Name of the directory in the trash
+    pub name: String,
+    /// Name of the deleted instance
+    pub instance: String,
+    pub deleted: OffsetDateTime,
+}
+
+fn parse_entry_name(name: &str) -> Option<(&str, OffsetDateTime)> {
+    let mut parts = name.rsplitn(3, '-');
+    let time = parts.next()?;
+    let date = parts.next()?;
+    let instance = parts.next()?;
+    let deleted = PrimitiveDateTime::parse(&format!("{}-{}", date, time), TIMESTAMP_FORMAT).ok()?;
+
+    Some((instance, deleted.assume_utc()))
+}
+
+/// List the instances in the trash, oldest first
+pub fn list_trash() -> Result<Vec<TrashEntry>> {
+    let entries = match fs::read_dir(TRASH_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut trash = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if let Some((instance, deleted)) = parse_entry_name(&name) {
+            trash.push(TrashEntry {
+                instance: instance.to_string(),
+                name,
+                deleted,
+            });
+        }
+    }
+    trash.sort_unstable_by_key(|e| e.deleted);
+
+    Ok(trash)
+}
+
+/// Permanently remove the instances deleted more than `retention` days ago
+pub fn purge_expired_trash(retention: u64) -> Result<()> {
+    let deadline = OffsetDateTime::now_utc() - Duration::days(retention as i64);
+    for entry in list_trash()?.into_iter().filter(|e| e.deleted < deadline) {
+        info!("Purging {} from the trash ...", entry.name);
+        remove_workspace_dir(Path::new(TRASH_DIR).join(&entry.name))?;
+    }
+
+    Ok(())
+}
+
+/// Move the instance (with its layers and configuration) into the trash
+pub fn trash_instance(instance: &str) -> Result<()> {
+    audited("del", Some(instance), || {
+        detach_stale_mounts(Some(instance))?;
+        container_down(instance)?;
+        fs::create_dir_all(TRASH_DIR)?;
+        let timestamp = OffsetDateTime::now_utc().format(TIMESTAMP_FORMAT)?;
+        let name = format!("{}-{}", instance, timestamp);
+        let dest = Path::new(TRASH_DIR).join(&name);
+        if dest.exists() {
+            return Err(anyhow!("{} already exists in the trash", name));
+        }
+        fs::rename(Path::new(CIEL_INST_DIR).join(instance), dest)?;
+        info!(
+            "{}: instance moved to the trash, use `ciel restore {}` to recover it.",
+            instance, instance
+        );
+
+        Ok(())
+    })
+}
+
+/// Restore `name` (either an instance name, which restores the latest deletion of it,
+/// or an entry in the trash) from the trash, optionally under a different name
+pub fn restore_instance(name: &str, rename: Option<&str>) -> Result<()> {
+    let entry = list_trash()?
+        .into_iter()
+        .rev()
+        .find(|e| e.name == name || e.instance == name)
+        .ok_or_else(|| anyhow!("{} is not found in the trash", name))?;
+    let instance = rename.unwrap_or(&entry.instance);
+    audited("restore", Some(instance), || {
+        let dest = Path::new(CIEL_INST_DIR).join(instance);
+        if dest.exists() {
+            return Err(anyhow!(
+                "Instance `{}` already exists, use `--as` to restore it under another name.",
+                instance
+            ));
+        }
+        fs::rename(Path::new(TRASH_DIR).join(&entry.name), dest)?;
+        info!("{}: instance restored from {}.", instance, entry.name);
+
+        Ok(())
+    })
+}
+
+/// Show the instances in the trash
+pub fn print_trash() -> Result<()> {
+    let trash = list_trash()?;
+    if trash.is_empty() {
+        info!("The trash is empty.");
+        return Ok(());
+    }
+    for entry in trash.iter().rev() {
+        eprintln!(
+            "{}\tdeleted {} UTC\t{}\t{}",
+            style(&entry.instance).cyan().bold(),
+            entry.deleted.format(format_description!(
+                "[year]-[month]-[day] [hour]:[minute]:[second]"
+            ))?,
+            HumanBytes(get_dir_size(Path::new(TRASH_DIR).join(&entry.name))),
+            entry.name
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_entry_name() {
+    let (instance, deleted) = parse_entry_name("my-instance-20240102-030405").unwrap();
+    assert_eq!(instance, "my-instance");
+    assert_eq!(deleted.hour(), 3);
+    assert!(parse_entry_name("main").is_none());
+    assert!(parse_entry_name("main-2024-01").is_none());
+}
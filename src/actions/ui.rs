@@ -0,0 +1,261 @@
+//! Interactive terminal dashboard (`ciel ui`)
+use anyhow::{anyhow, Result};
+use console::{style, Key, Term};
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, OutputFlags, SetArg, Termios},
+    unistd::read,
+};
+use std::{
+    fs,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::machine::{self, CielInstance};
+
+use super::{container_down, mount_fs, rollback_container, run_in_container};
+
+// where ACBS writes the build logs inside the container
+const ACBS_LOG_DIR: &str = "var/log/acbs";
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const HELP_TEXT: &str = "[↑/↓] select  [m] mount  [u] unmount  [s] shell  [r] rollback  [q] quit";
+
+/// Puts the terminal into raw mode (and the alternate screen), restores it on drop
+struct RawTerminal {
+    original: Termios,
+}
+
+impl RawTerminal {
+    fn enable(term: &Term) -> Result<Self> {
+        let terminal = RawTerminal {
+            original: tcgetattr(std::io::stdin().as_raw_fd())?,
+        };
+        terminal.enter(term)?;
+
+        Ok(terminal)
+    }
+
+    fn enter(&self, term: &Term) -> Result<()> {
+        let mut raw = self.original.clone();
+        cfmakeraw(&mut raw);
+        // keep the newline translation, so that the log messages are printed normally
+        raw.output_flags.insert(OutputFlags::OPOST);
+        tcsetattr(std::io::stdin().as_raw_fd(), SetArg::TCSANOW, &raw)?;
+        term.write_str("\x1b[?1049h")?;
+        term.hide_cursor()?;
+
+        Ok(())
+    }
+
+    fn leave(&self, term: &Term) -> Result<()> {
+        term.show_cursor()?;
+        term.write_str("\x1b[?1049l")?;
+        tcsetattr(
+            std::io::stdin().as_raw_fd(),
+            SetArg::TCSANOW,
+            &self.original,
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        self.leave(&Term::stdout()).ok();
+    }
+}
+
+/// Wait for a key press, returns None if nothing is pressed before the timeout
+fn read_key(timeout: Duration) -> Result<Option<Key>> {
+    let fd = std::io::stdin().as_raw_fd();
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+    if poll(&mut fds, timeout.as_millis() as i32)? < 1 {
+        return Ok(None);
+    }
+    let mut buf = [0u8; 8];
+    let len = read(fd, &mut buf)?;
+    let key = match &buf[..len] {
+        b"\x1b[A" | b"k" => Key::ArrowUp,
+        b"\x1b[B" | b"j" => Key::ArrowDown,
+        b"\x1b" => Key::Escape,
+        b"\r" | b"\n" => Key::Enter,
+        [c] if c.is_ascii() => Key::Char(*c as char),
+        _ => Key::Unknown,
+    };
+
+    Ok(Some(key))
+}
+
+/// Find the most recent build log of the (mounted) instance
+fn find_latest_log(instance: &str) -> Option<PathBuf> {
+    fs::read_dir(Path::new(instance).join(ACBS_LOG_DIR))
+        .ok()?
+        .flatten()
+        .filter_map(|e| {
+            let modified = e.metadata().ok()?.modified().ok()?;
+            Some((modified, e.path()))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+}
+
+#[inline]
+fn yes_no(value: bool) -> console::StyledObject<&'static str> {
+    if value {
+        style("Yes").green()
+    } else {
+        style("No").dim()
+    }
+}
+
+fn render(term: &Term, instances: &[CielInstance], selected: usize, message: &str) -> Result<()> {
+    let (rows, cols) = term.size();
+    let (rows, cols) = (rows as usize, cols as usize);
+    let mut lines = vec![
+        format!(
+            "{} {}",
+            style("CIEL!").bold().cyan(),
+            std::env::current_dir()?.display()
+        ),
+        style(format!(
+            "  {:<24}{:<10}{:<10}{:<10}",
+            "NAME", "MOUNTED", "RUNNING", "BOOTED"
+        ))
+        .bold()
+        .to_string(),
+    ];
+    if instances.is_empty() {
+        lines.push(
+            style("  No instances found. Add one with `ciel add`.")
+                .dim()
+                .to_string(),
+        );
+    }
+    for (i, instance) in instances.iter().enumerate() {
+        let booted = match instance.booted {
+            Some(booted) => yes_no(booted),
+            None => style("-").dim(),
+        };
+        let name = format!("{:<24}", instance.name);
+        let name = if i == selected {
+            style(name).reverse().to_string()
+        } else {
+            name
+        };
+        lines.push(format!(
+            "{} {}{:<10}{:<10}{:<10}",
+            if i == selected { ">" } else { " " },
+            name,
+            yes_no(instance.mounted),
+            yes_no(instance.running),
+            booted
+        ));
+    }
+    lines.push("─".repeat(cols));
+
+    // the rest of the screen (except the help and message lines) is used by the build log
+    let log_rows = rows.saturating_sub(lines.len() + 4);
+    let log = instances
+        .get(selected)
+        .filter(|i| i.mounted)
+        .and_then(|i| find_latest_log(&i.name));
+    match log {
+        Some(log) => {
+            lines.push(
+                style(format!("Build log: {}", log.display()))
+                    .bold()
+                    .to_string(),
+            );
+            let content = fs::read_to_string(&log).unwrap_or_default();
+            let content: Vec<&str> = content.lines().collect();
+            for line in &content[content.len().saturating_sub(log_rows)..] {
+                lines.push(console::truncate_str(line, cols, "…").to_string());
+            }
+        }
+        None => lines.push(style("No build log available.").dim().to_string()),
+    }
+
+    term.clear_screen()?;
+    for line in lines.iter().take(rows.saturating_sub(2)) {
+        term.write_line(line)?;
+    }
+    term.move_cursor_to(0, rows.saturating_sub(2))?;
+    term.write_line(&style(HELP_TEXT).dim().to_string())?;
+    term.write_str(message)?;
+
+    Ok(())
+}
+
+/// Perform the action on the selected instance, returns the message to be shown
+fn perform_action(term: &Term, key: char, instance: &str) -> Result<String> {
+    match key {
+        'm' => {
+            mount_fs(instance)?;
+            Ok(format!("{}: filesystem mounted.", instance))
+        }
+        'u' => {
+            container_down(instance)?;
+            Ok(format!("{}: instance is down.", instance))
+        }
+        'r' => {
+            term.clear_line()?;
+            term.write_str(&format!("Roll back {}? [y/N] ", instance))?;
+            if read_key(Duration::from_secs(30))? != Some(Key::Char('y')) {
+                return Ok("Not confirmed.".to_string());
+            }
+            rollback_container(instance)?;
+            Ok(format!("{}: instance has been rolled back.", instance))
+        }
+        _ => Err(anyhow!("Unknown action: {}", key)),
+    }
+}
+
+/// Show the interactive dashboard
+pub fn run_ui() -> Result<()> {
+    let term = Term::stdout();
+    if !term.is_term() {
+        return Err(anyhow!("`ciel ui` requires an interactive terminal."));
+    }
+    let raw = RawTerminal::enable(&term)?;
+    let mut selected = 0;
+    let mut message = String::new();
+    loop {
+        let instances = machine::list_instances()?;
+        selected = selected.min(instances.len().saturating_sub(1));
+        render(&term, &instances, selected, &message)?;
+        let key = match read_key(REFRESH_INTERVAL)? {
+            Some(key) => key,
+            None => continue,
+        };
+        message.clear();
+        let instance = instances.get(selected).map(|i| i.name.clone());
+        match (key, instance) {
+            (Key::Char('q'), _) | (Key::Escape, _) => break,
+            (Key::ArrowUp, _) => selected = selected.saturating_sub(1),
+            (Key::ArrowDown, _) => selected += 1,
+            (Key::Char('s'), Some(instance)) | (Key::Enter, Some(instance)) => {
+                // leave the dashboard while the shell is running
+                raw.leave(&term)?;
+                let result = run_in_container(&instance, &["/bin/bash"]);
+                raw.enter(&term)?;
+                message = match result {
+                    Ok(status) => format!("{}: shell exited with status {}.", instance, status),
+                    Err(e) => style(format!("{}: {}", instance, e)).red().to_string(),
+                };
+            }
+            (Key::Char(c @ ('m' | 'u' | 'r')), Some(instance)) => {
+                message = match perform_action(&term, c, &instance) {
+                    Ok(msg) => style(msg).green().to_string(),
+                    Err(e) => style(format!("{}: {}", instance, e)).red().to_string(),
+                };
+            }
+            _ => (),
+        }
+        term.flush()?;
+    }
+
+    Ok(())
+}
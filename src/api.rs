@@ -0,0 +1,354 @@
+//! HTTP+JSON API of the workspace (`ciel serve-api`)
+//!
+//! - `GET /api/v1/status`: same as `ciel status --json`
+//! - `GET /api/v1/instances`, `GET /api/v1/instances/NAME`: same as `ciel list --json`
+//! - `GET /api/v1/builds[?package=NAME]`: the recorded builds
+//! - `GET /api/v1/builds/PACKAGE/log[?previous=N]`: the build log (as text)
+//! - `GET /api/v1/repo`: the packages in the output directory
+//! - `GET /api/v1/jobs`, `GET /api/v1/jobs/ID`: the builds triggered with the API
+//! - `POST /api/v1/builds` with `{"instance": "main", "packages": ["bash"]}`: trigger a build,
+//!   returns `{"job": ID}` (the builds run one at a time)
+//! - `POST /api/v1/instances/NAME/rollback`: roll back the instance
+//!
+//! The `POST` endpoints require `Authorization: Bearer TOKEN`, they are disabled if no token is
+//! configured. The errors are returned as `{"error": "..."}`.
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    info, machine,
+    rpc::{self, Daemon, Handler, RpcError, INVALID_PARAMS, METHOD_NOT_FOUND},
+    warn,
+};
+
+const API_PREFIX: &str = "/api/v1/";
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct HttpRequest {
+    method: String,
+    url: Url,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// What the endpoint does: the daemon method, its parameters and whether it changes anything
+#[derive(Debug, PartialEq)]
+struct Route {
+    method: &'static str,
+    params: Value,
+    write: bool,
+}
+
+impl Route {
+    fn read(method: &'static str, params: Value) -> Option<Self> {
+        Some(Route {
+            method,
+            params,
+            write: false,
+        })
+    }
+
+    fn write(method: &'static str, params: Value) -> Option<Self> {
+        Some(Route {
+            method,
+            params,
+            write: true,
+        })
+    }
+}
+
+fn route(method: &str, url: &Url, body: &[u8]) -> Result<Option<Route>, RpcError> {
+    let path = match url.path().strip_prefix(API_PREFIX) {
+        Some(path) => path.trim_end_matches('/'),
+        None => return Ok(None),
+    };
+    let segments = path
+        .split('/')
+        .map(|s| {
+            percent_decode(s).ok_or_else(|| RpcError::new(INVALID_PARAMS, "Invalid URL encoding"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.to_string())
+    };
+    let segments = segments.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    let route = match (method, segments.as_slice()) {
+        ("GET", ["status"]) => Route::read("status", Value::Null),
+        ("GET", ["instances"]) => Route::read("list_instances", Value::Null),
+        ("GET", ["instances", name]) => Route::read("instance", json!({ "name": name })),
+        ("POST", ["instances", name, "rollback"]) => {
+            Route::write("rollback", json!({ "name": name }))
+        }
+        ("GET", ["builds"]) => Route::read("builds", json!({ "package": query("package") })),
+        ("GET", ["builds", package, "log"]) => {
+            let previous = match query("previous") {
+                Some(n) => n
+                    .parse::<usize>()
+                    .map_err(|e| RpcError::new(INVALID_PARAMS, format!("previous: {}", e)))?,
+                None => 0,
+            };
+            Route::read(
+                "build_log",
+                json!({ "package": package, "previous": previous }),
+            )
+        }
+        ("POST", ["builds"]) => {
+            let params = serde_json::from_slice(body)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid body: {}", e)))?;
+            Route::write("build", params)
+        }
+        ("GET", ["jobs"]) => Route::read("jobs", Value::Null),
+        ("GET", ["jobs", id]) => {
+            let id = id
+                .parse::<u32>()
+                .map_err(|_| RpcError::new(INVALID_PARAMS, format!("Invalid job: {}", id)))?;
+            Route::read("job", json!({ "job": id }))
+        }
+        ("GET", ["repo"]) => Route::read("repo", Value::Null),
+        _ => None,
+    };
+
+    Ok(route)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(c) = iter.next() {
+        if c != b'%' {
+            bytes.push(c);
+            continue;
+        }
+        let hex = [iter.next()?, iter.next()?];
+        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+// compare the whole token regardless of where it differs
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Result<HttpRequest> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(anyhow!("Malformed request line")),
+    };
+    let url = Url::parse(&format!("http://localhost{}", target))?;
+    let mut authorization = None;
+    let mut length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.parse()?;
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = value.strip_prefix("Bearer ").map(String::from);
+            }
+        }
+    }
+    if length > MAX_BODY_SIZE {
+        return Err(anyhow!("Request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    Ok(HttpRequest {
+        method,
+        url,
+        authorization,
+        body,
+    })
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+
+    Ok(())
+}
+
+/// Answer the request, returning the status and the body
+fn respond(daemon: &Daemon, token: Option<&str>, request: &HttpRequest) -> (u16, Value) {
+    let error = |status, message: String| (status, json!({ "error": message }));
+    let route = match route(&request.method, &request.url, &request.body) {
+        Ok(Some(route)) => route,
+        Ok(None) => return error(404, format!("No such endpoint: {}", request.url.path())),
+        Err(e) => return error(400, e.message),
+    };
+    if route.write {
+        match (token, request.authorization.as_deref()) {
+            (None, _) => {
+                return error(403, "Write endpoints are disabled (no token is set)".into())
+            }
+            (Some(token), Some(given)) if token_matches(given, token) => (),
+            _ => return error(401, "Invalid or missing token".into()),
+        }
+    }
+    // the instance names come from the URL, only the existing instances are accepted
+    if let Some(name) = route.params.get("name").and_then(Value::as_str) {
+        if let Err(e) = machine::check_instance_name(name) {
+            return error(400, e.to_string());
+        }
+    }
+    match daemon.call(route.method, route.params) {
+        Ok(result) => (200, result),
+        Err(e) if e.code == INVALID_PARAMS => error(400, e.message),
+        Err(e) if e.code == METHOD_NOT_FOUND => error(404, e.message),
+        Err(e) => error(500, e.message),
+    }
+}
+
+fn handle_client(daemon: &Daemon, token: Option<&str>, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = match read_request(&mut reader) {
+        Ok(request) => request,
+        Err(e) => {
+            let body = json!({ "error": e.to_string() }).to_string();
+            return write_response(&mut stream, 400, "application/json", body.as_bytes());
+        }
+    };
+    let (status, result) = respond(daemon, token, &request);
+    info!("{} {} {}", request.method, request.url.path(), status);
+    match result {
+        // the build logs are sent as-is
+        Value::String(text) if status == 200 => write_response(
+            &mut stream,
+            status,
+            "text/plain; charset=utf-8",
+            text.as_bytes(),
+        ),
+        result => write_response(
+            &mut stream,
+            status,
+            "application/json",
+            result.to_string().as_bytes(),
+        ),
+    }
+}
+
+/// Serve the API on `listen` until killed, the write endpoints require the token in
+/// `token_file` (or `CIEL_API_TOKEN`)
+pub fn serve_api(listen: &str, token_file: Option<&Path>) -> Result<()> {
+    let token = match token_file {
+        Some(path) => Some(fs::read_to_string(path)?.trim().to_string()),
+        None => std::env::var("CIEL_API_TOKEN").ok(),
+    };
+    let token = token.filter(|t| !t.is_empty());
+    if token.is_none() {
+        warn!("No API token is set, the write endpoints are disabled.");
+    }
+    let listener = TcpListener::bind(listen)?;
+    if !listener.local_addr()?.ip().is_loopback() {
+        warn!("The API is served without TLS, consider putting it behind a reverse proxy.");
+    }
+    // nobody is there to answer the prompts
+    std::env::set_var("CIEL_BATCH", "1");
+    let daemon = rpc::start_daemon();
+    info!(
+        "Serving {} on http://{}{} ...",
+        std::env::current_dir()?.display(),
+        listener.local_addr()?,
+        API_PREFIX
+    );
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Unable to accept the connection: {}", e);
+                continue;
+            }
+        };
+        let daemon = daemon.clone();
+        let token = token.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_client(&daemon, token.as_deref(), stream) {
+                warn!("Connection closed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_route() {
+    let url = |path: &str| Url::parse(&format!("http://localhost{}", path)).unwrap();
+    let log = route(
+        "GET",
+        &url("/api/v1/builds/linux%2Bkernel/log?previous=2"),
+        b"",
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(log.method, "build_log");
+    assert_eq!(
+        log.params,
+        json!({ "package": "linux+kernel", "previous": 2 })
+    );
+    assert!(!log.write);
+    let build = route(
+        "POST",
+        &url("/api/v1/builds"),
+        br#"{"instance": "main", "packages": ["bash"]}"#,
+    )
+    .unwrap()
+    .unwrap();
+    assert!(build.write);
+    assert!(route("POST", &url("/api/v1/builds"), b"{").is_err());
+    assert_eq!(route("GET", &url("/api/v1/nope"), b"").unwrap(), None);
+    assert_eq!(route("DELETE", &url("/api/v1/status"), b"").unwrap(), None);
+    assert!(token_matches("secret", "secret"));
+    assert!(!token_matches("secreT", "secret"));
+}
@@ -0,0 +1,83 @@
+//! Audit trail of the destructive operations (commit, rollback, del, update-os, farewell)
+use anyhow::Result;
+use nix::unistd::{getuid, User};
+use serde::Serialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::warn;
+
+/// Where the audit log is kept (relative to the workspace), outside of `.ciel`
+/// so that it survives `ciel farewell`
+pub const AUDIT_LOG: &str = "LOGS/audit.jsonl";
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: String,
+    /// The user who invoked ciel (through sudo if possible)
+    user: String,
+    uid: u32,
+    pid: u32,
+    operation: &'a str,
+    instance: Option<&'a str>,
+    success: bool,
+    error: Option<String>,
+}
+
+/// The name of the user who invoked ciel, looking through sudo
+fn invoking_user() -> String {
+    if let Ok(user) = std::env::var("SUDO_USER") {
+        return user;
+    }
+    User::from_uid(getuid())
+        .ok()
+        .flatten()
+        .map_or_else(|| getuid().to_string(), |u| u.name)
+}
+
+fn append_entry(entry: &AuditEntry) -> Result<()> {
+    let path = Path::new(AUDIT_LOG);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o640)
+        .open(path)?;
+    // a single write, the instances may be operated on in parallel
+    file.write_all(format!("{}\n", serde_json::to_string(entry)?).as_bytes())?;
+
+    Ok(())
+}
+
+/// Run the operation and record it (and whether it succeeded) in the audit log
+pub fn audited<T, F: FnOnce() -> Result<T>>(
+    operation: &str,
+    instance: Option<&str>,
+    f: F,
+) -> Result<T> {
+    let result = f();
+    let entry = AuditEntry {
+        timestamp: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        user: invoking_user(),
+        uid: getuid().as_raw(),
+        pid: std::process::id(),
+        operation,
+        instance,
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    if let Err(e) = append_entry(&entry) {
+        warn!("Unable to write the audit log: {}", e);
+    }
+
+    result
+}
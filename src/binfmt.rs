@@ -0,0 +1,293 @@
+//! Running foreign-architecture instances with qemu-user (through binfmt_misc)
+use anyhow::{anyhow, Result};
+use nix::mount::{mount, MsFlags};
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{debug, info, network::get_arch_name};
+
+const BINFMT_MISC_DIR: &str = "/proc/sys/fs/binfmt_misc";
+// where the emulator is placed in the container
+const QEMU_INSTALL_DIR: &str = "usr/bin";
+const ELF_MASK: &str =
+    r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff";
+
+/// The emulator and the ELF header pattern of an architecture (from qemu-binfmt-conf.sh)
+#[derive(Debug)]
+pub struct QemuTarget {
+    /// AOSC OS architecture name
+    pub arch: &'static str,
+    /// QEMU architecture name (as in `qemu-<name>-static`)
+    pub qemu: &'static str,
+    magic: &'static str,
+    mask: &'static str,
+}
+
+const QEMU_TARGETS: &[QemuTarget] = &[
+    QemuTarget {
+        arch: "amd64",
+        qemu: "x86_64",
+        magic: r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x3e\x00",
+        mask: r"\xff\xff\xff\xff\xff\xfe\xfe\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+    },
+    QemuTarget {
+        arch: "i486",
+        qemu: "i386",
+        magic: r"\x7fELF\x01\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x03\x00",
+        mask: r"\xff\xff\xff\xff\xff\xfe\xfe\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+    },
+    QemuTarget {
+        arch: "arm64",
+        qemu: "aarch64",
+        magic: r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xb7\x00",
+        mask: ELF_MASK,
+    },
+    QemuTarget {
+        arch: "armv7hf",
+        qemu: "arm",
+        magic: r"\x7fELF\x01\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x28\x00",
+        mask: ELF_MASK,
+    },
+    QemuTarget {
+        arch: "loongarch64",
+        qemu: "loongarch64",
+        magic: r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x02\x01",
+        mask: ELF_MASK,
+    },
+    QemuTarget {
+        arch: "loongson3",
+        qemu: "mips64el",
+        magic: r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x08\x00",
+        mask: ELF_MASK,
+    },
+    QemuTarget {
+        arch: "ppc64el",
+        qemu: "ppc64le",
+        magic: r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x15\x00",
+        mask: r"\xff\xff\xff\xff\xff\xff\xff\xfc\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\x00",
+    },
+    QemuTarget {
+        arch: "riscv64",
+        qemu: "riscv64",
+        magic: r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xf3\x00",
+        mask: ELF_MASK,
+    },
+];
+
+/// Find the emulation target of the (AOSC OS) architecture
+pub fn qemu_target(arch: &str) -> Result<&'static QemuTarget> {
+    QEMU_TARGETS.iter().find(|t| t.arch == arch).ok_or_else(|| {
+        anyhow!(
+            "Architecture {} can not be emulated, supported: {}",
+            arch,
+            QEMU_TARGETS
+                .iter()
+                .map(|t| t.arch)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })
+}
+
+/// Check if the (AOSC OS) architecture needs to be emulated on this host
+#[inline]
+pub fn is_foreign_arch(arch: &str) -> bool {
+    get_arch_name() != Some(arch)
+}
+
+/// A registered binfmt_misc handler
+#[derive(Debug)]
+pub struct BinfmtHandler {
+    pub name: String,
+    pub enabled: bool,
+    pub interpreter: PathBuf,
+    pub flags: String,
+}
+
+impl BinfmtHandler {
+    /// Whether the interpreter is opened at registration (and thus usable inside the containers)
+    #[inline]
+    pub fn fix_binary(&self) -> bool {
+        self.flags.contains('F')
+    }
+}
+
+fn parse_handler(name: &str, status: &str) -> BinfmtHandler {
+    BinfmtHandler {
+        name: name.to_string(),
+        enabled: status.lines().next() == Some("enabled"),
+        interpreter: status
+            .lines()
+            .find_map(|line| line.strip_prefix("interpreter "))
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+        flags: status
+            .lines()
+            .find_map(|line| line.strip_prefix("flags:"))
+            .map(|flags| flags.trim().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Read the registered handler called `name`
+pub fn read_handler(name: &str) -> Option<BinfmtHandler> {
+    let status = fs::read_to_string(Path::new(BINFMT_MISC_DIR).join(name)).ok()?;
+
+    Some(parse_handler(name, &status))
+}
+
+/// List the registered handlers of qemu-user
+pub fn list_qemu_handlers() -> Vec<BinfmtHandler> {
+    let mut handlers = fs::read_dir(BINFMT_MISC_DIR)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .filter(|name| name.starts_with("qemu-"))
+                .filter_map(|name| read_handler(&name))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    handlers.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    handlers
+}
+
+impl QemuTarget {
+    #[inline]
+    pub fn handler_name(&self) -> String {
+        format!("qemu-{}", self.qemu)
+    }
+
+    #[inline]
+    fn binary_name(&self) -> String {
+        format!("qemu-{}-static", self.qemu)
+    }
+
+    /// The statically linked emulator on the host (found in PATH)
+    pub fn find_binary(&self) -> Result<PathBuf> {
+        let name = self.binary_name();
+        std::env::var_os("PATH")
+            .and_then(|paths| {
+                std::env::split_paths(&paths)
+                    .map(|dir| dir.join(&name))
+                    .find(|path| path.is_file())
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} is not found, please install qemu-user-static (or an equivalent package).",
+                    name
+                )
+            })
+    }
+
+    fn registration(&self, interpreter: &Path) -> String {
+        // `F`: the emulator is opened now, so that it also works inside the containers
+        format!(
+            ":{}:M::{}:{}:{}:F",
+            self.handler_name(),
+            self.magic,
+            self.mask,
+            interpreter.display()
+        )
+    }
+}
+
+/// Enable (`true`) or remove (`false`) the registered handler of the target
+pub fn set_handler_state(target: &QemuTarget, enabled: bool) -> Result<()> {
+    let state = if enabled { "1" } else { "-1" };
+    fs::write(
+        Path::new(BINFMT_MISC_DIR).join(target.handler_name()),
+        state,
+    )
+    .map_err(|e| anyhow!("Unable to update {}: {}", target.handler_name(), e))?;
+
+    Ok(())
+}
+
+/// Register the binfmt_misc handler for the target if there is none yet
+pub fn ensure_binfmt(target: &QemuTarget) -> Result<()> {
+    let binfmt = Path::new(BINFMT_MISC_DIR);
+    if !binfmt.join("register").exists() {
+        debug!("Mounting binfmt_misc ...");
+        mount(
+            Some("binfmt_misc"),
+            binfmt,
+            Some("binfmt_misc"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .map_err(|e| anyhow!("Unable to mount binfmt_misc: {}", e))?;
+    }
+    let handler = binfmt.join(target.handler_name());
+    if let Ok(status) = fs::read_to_string(&handler) {
+        if status.lines().next() == Some("enabled") {
+            return Ok(());
+        }
+        return Err(anyhow!(
+            "The binfmt_misc handler {} is disabled, enable it with `echo 1 > {}`.",
+            target.handler_name(),
+            handler.display()
+        ));
+    }
+    let binary = target.find_binary()?;
+    info!(
+        "Registering {} for {} binaries ...",
+        binary.display(),
+        target.arch
+    );
+    fs::write(binfmt.join("register"), target.registration(&binary))
+        .map_err(|e| anyhow!("Unable to register the binfmt_misc handler: {}", e))?;
+
+    Ok(())
+}
+
+/// Where the emulator is placed inside `root`
+#[inline]
+pub fn emulator_path(target: &QemuTarget, root: &Path) -> PathBuf {
+    root.join(QEMU_INSTALL_DIR).join(target.binary_name())
+}
+
+/// Put the emulator into `root` (the handlers registered without `F` look for it there)
+pub fn install_emulator(target: &QemuTarget, root: &Path) -> Result<()> {
+    let binary = target.find_binary()?;
+    fs::create_dir_all(root.join(QEMU_INSTALL_DIR))?;
+    let dest = emulator_path(target, root);
+    fs::copy(&binary, &dest)?;
+    fs::set_permissions(&dest, fs::Permissions::from_mode(0o755))?;
+
+    Ok(())
+}
+
+#[test]
+fn test_qemu_target() {
+    let target = qemu_target("arm64").unwrap();
+    assert_eq!(target.binary_name(), "qemu-aarch64-static");
+    assert!(target
+        .registration(Path::new("/usr/bin/qemu-aarch64-static"))
+        .starts_with(r":qemu-aarch64:M::\x7fELF\x02"));
+    assert!(qemu_target("sparc").is_err());
+    // every pattern is 20 bytes
+    for target in QEMU_TARGETS {
+        assert_eq!(target.magic.matches(r"\x").count(), 17, "{}", target.arch);
+        assert_eq!(target.mask.matches(r"\x").count(), 20, "{}", target.arch);
+    }
+}
+
+#[test]
+fn test_parse_handler() {
+    let handler = parse_handler(
+        "qemu-aarch64",
+        "enabled\ninterpreter /usr/bin/qemu-aarch64-static\nflags: OCF\noffset 0\nmagic 7f454c46\n",
+    );
+    assert!(handler.enabled);
+    assert!(handler.fix_binary());
+    assert_eq!(
+        handler.interpreter,
+        Path::new("/usr/bin/qemu-aarch64-static")
+    );
+    assert!(!parse_handler("qemu-arm", "disabled\nflags: \n").fix_binary());
+}
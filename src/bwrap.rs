@@ -0,0 +1,170 @@
+//! Fallback container backend using bubblewrap (bwrap), for hosts without systemd.
+//! Only the lightweight (non-boot) containers are supported.
+use crate::capture;
+use crate::common::CIEL_INST_DIR;
+use crate::config::{self, ContainerBackend};
+use crate::{debug, info, mac, netpolicy};
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    thread::sleep,
+    time::Duration,
+};
+
+const BWRAP_PID_FILE: &str = "bwrap.pid";
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+lazy_static! {
+    // the backend is decided once per process, as this is checked for every container operation
+    static ref ENABLED: bool = {
+        let backend = config::read_config().map(|c| c.backend).unwrap_or_default();
+        match backend {
+            ContainerBackend::Nspawn => false,
+            ContainerBackend::Bwrap => true,
+            // same check as sd_booted(3)
+            ContainerBackend::Auto => !Path::new("/run/systemd/system").is_dir(),
+        }
+    };
+}
+
+/// Check if the bwrap executable can be found in PATH
+pub fn is_available() -> bool {
+    let paths = match std::env::var_os("PATH") {
+        Some(paths) => paths,
+        None => return false,
+    };
+
+    std::env::split_paths(&paths).any(|dir| dir.join("bwrap").is_file())
+}
+
+/// Check if the fallback backend should be used (decided by the workspace config or the host)
+#[inline]
+pub fn is_enabled() -> bool {
+    *ENABLED
+}
+
+#[inline]
+fn get_pid_file(instance: &str) -> PathBuf {
+    Path::new(CIEL_INST_DIR).join(instance).join(BWRAP_PID_FILE)
+}
+
+/// Get the PID of the bwrap process running in the instance (if any)
+pub fn get_running_pid(instance: &str) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(get_pid_file(instance))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    if comm.trim() != "bwrap" {
+        // stale PID file
+        return None;
+    }
+
+    Some(pid)
+}
+
+/// Remove the PID file if the process is gone, returns whether it was stale
+pub fn remove_stale_pid_file(instance: &str) -> Result<bool> {
+    let pid_file = get_pid_file(instance);
+    if !pid_file.exists() || get_running_pid(instance).is_some() {
+        return Ok(false);
+    }
+    fs::remove_file(pid_file)?;
+
+    Ok(true)
+}
+
+/// Execute a command in a bwrap container, which exits together with the command
+pub fn execute_container_command<P: AsRef<Path>, S: AsRef<OsStr>>(
+    instance: &str,
+    path: P,
+    args: &[S],
+    extra_options: &[String],
+    mounts: &[(String, &str)],
+    env: &[(String, String)],
+    user: Option<&str>,
+) -> Result<i32> {
+    if !is_available() {
+        return Err(anyhow!(
+            "bwrap is required to run containers without systemd, please install bubblewrap."
+        ));
+    }
+    let mut binds = Vec::new();
+    for mount in mounts {
+        fs::create_dir_all(&mount.0)?;
+        let source_path = fs::canonicalize(&mount.0)?;
+        binds.push("--bind".to_string());
+        binds.push(source_path.to_string_lossy().to_string());
+        binds.push(mount.1.to_string());
+    }
+    let home = user.map_or_else(|| "/root".to_string(), |u| format!("/home/{}", u));
+    let mut command = Command::new("bwrap");
+    command
+        .arg("--bind")
+        .arg(path.as_ref())
+        .args(&["/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/run"])
+        .args(&["--ro-bind", "/sys", "/sys"])
+        .args(&[
+            "--unshare-pid",
+            "--unshare-ipc",
+            "--unshare-uts",
+            "--die-with-parent",
+        ])
+        .args(&["--hostname", instance])
+        .args(&["--clearenv", "--setenv", "PATH", DEFAULT_PATH])
+        .args(&["--setenv", "HOME", &home])
+        .args(&["--chdir", "/"]);
+    if let Ok(term) = std::env::var("TERM") {
+        command.args(&["--setenv", "TERM", &term]);
+    }
+    if !extra_options.iter().any(|o| o == "--unshare-net") && Path::new("/etc/resolv.conf").exists()
+    {
+        command.args(&["--ro-bind", "/etc/resolv.conf", "/etc/resolv.conf"]);
+    }
+    for (key, value) in env {
+        command.args(&["--setenv", key, value]);
+    }
+    command.args(extra_options).args(binds).arg("--");
+    if let Some(user) = user {
+        command.args(&["runuser", "-u", user, "--"]);
+    }
+    command.args(args);
+    netpolicy::enter_build_netns(&mut command);
+    mac::confine_command(&mut command);
+    debug!("Running {:?}", command);
+    let pid_file = get_pid_file(instance);
+    let status = capture::spawn_and_wait(&mut command, |pid| {
+        Ok(fs::write(&pid_file, pid.to_string())?)
+    });
+    fs::remove_file(&pid_file).ok();
+
+    Ok(status?.code().unwrap_or(127))
+}
+
+/// Terminate the bwrap container (kill it if it does not exit in time)
+pub fn terminate_container(instance: &str) -> Result<()> {
+    let pid = match get_running_pid(instance) {
+        Some(pid) => Pid::from_raw(pid as i32),
+        None => return Ok(()),
+    };
+    kill(pid, Signal::SIGTERM)?;
+    for _ in 0..10 {
+        sleep(Duration::from_secs(1));
+        if get_running_pid(instance).is_none() {
+            return Ok(());
+        }
+    }
+    info!("{}: container did not exit in time, killing...", instance);
+    // killing bwrap also tears down the PID namespace and everything inside
+    kill(pid, Signal::SIGKILL)?;
+    fs::remove_file(get_pid_file(instance)).ok();
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Copy the output of the commands run in the containers into log files
+use anyhow::Result;
+use lazy_static::lazy_static;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::events;
+
+/// Where the captured output is saved (relative to the workspace)
+pub const CAPTURE_DIR: &str = ".ciel/log/runs";
+
+lazy_static! {
+    static ref CAPTURE_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Captures the output of the container commands into a log file until dropped
+pub struct Capture {
+    pub path: PathBuf,
+}
+
+impl Capture {
+    /// Start capturing into a new log file, named after the time, the instance and `name`
+    pub fn start(instance: &str, name: &str) -> Result<Capture> {
+        fs::create_dir_all(CAPTURE_DIR)?;
+        let timestamp = OffsetDateTime::now_utc().format(format_description!(
+            "[year][month][day]-[hour][minute][second]"
+        ))?;
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || "-_.+".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = Path::new(CAPTURE_DIR).join(format!("{}-{}-{}.log", timestamp, instance, name));
+
+        Ok(Capture::start_at(path))
+    }
+
+    /// Start capturing into the specified file
+    pub fn start_at(path: PathBuf) -> Capture {
+        *CAPTURE_FILE.lock().unwrap() = Some(path.clone());
+
+        Capture { path }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        *CAPTURE_FILE.lock().unwrap() = None;
+    }
+}
+
+/// Copy everything from `source` to both `terminal` and `log`
+fn tee<R, W>(mut source: R, mut terminal: W, log: Arc<Mutex<File>>) -> thread::JoinHandle<()>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        while let Ok(len) = source.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+            terminal.write_all(&buf[..len]).ok();
+            terminal.flush().ok();
+            log.lock().unwrap().write_all(&buf[..len]).ok();
+        }
+    })
+}
+
+/// Describe the command for the log without the values of the environment variables, which
+/// may be tokens (the logs are served by the API)
+fn describe_command(command: &Command) -> String {
+    let mut words = vec![command.get_program().to_string_lossy().to_string()];
+    let mut args = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().to_string());
+    while let Some(arg) = args.next() {
+        if let Some(variable) = arg.strip_prefix("--setenv=") {
+            let name = variable.split_once('=').map_or(variable, |(name, _)| name);
+            words.push(format!("--setenv={}=...", name));
+        } else if arg == "--setenv" {
+            // `--setenv NAME VALUE` of bwrap
+            words.push(arg);
+            words.extend(args.next());
+            if args.next().is_some() {
+                words.push("...".to_string());
+            }
+        } else {
+            words.push(arg);
+        }
+    }
+
+    words.join(" ")
+}
+
+/// Spawn the command and wait for it to exit, its output is also copied into the capture file
+/// (if capturing). `on_spawn` is called with the PID of the child process.
+pub fn spawn_and_wait<F: FnOnce(u32) -> Result<()>>(
+    command: &mut Command,
+    on_spawn: F,
+) -> Result<ExitStatus> {
+    let path = CAPTURE_FILE.lock().unwrap().clone();
+    let stream = events::is_progress_stream();
+    if stream && path.is_none() {
+        command.stdout(io::stderr());
+    }
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let mut child = command.spawn()?;
+            on_spawn(child.id())?;
+            return Ok(child.wait()?);
+        }
+    };
+    let mut log = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(log, "==> {}", describe_command(command))?;
+    let log = Arc::new(Mutex::new(log));
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    on_spawn(child.id())?;
+    let threads = vec![
+        child.stdout.take().map(|stdout| {
+            // stdout is reserved for the events
+            let terminal: Box<dyn Write + Send> = if stream {
+                Box::new(io::stderr())
+            } else {
+                Box::new(io::stdout())
+            };
+            tee(stdout, terminal, log.clone())
+        }),
+        child
+            .stderr
+            .take()
+            .map(|stderr| tee(stderr, io::stderr(), log.clone())),
+    ];
+    let status = child.wait()?;
+    for thread in threads.into_iter().flatten() {
+        thread.join().ok();
+    }
+    writeln!(log.lock().unwrap(), "==> {}", status)?;
+
+    Ok(status)
+}
+
+#[test]
+fn test_describe_command() {
+    let mut command = Command::new("systemd-run");
+    command.args(&["-M", "test", "--setenv=TOKEN=hunter2", "--", "env"]);
+    assert_eq!(
+        describe_command(&command),
+        "systemd-run -M test --setenv=TOKEN=... -- env"
+    );
+    let mut command = Command::new("bwrap");
+    command.args(&["--setenv", "TOKEN", "hunter2", "env"]);
+    assert_eq!(describe_command(&command), "bwrap --setenv TOKEN ... env");
+}
@@ -1,13 +1,171 @@
 use anyhow::{anyhow, Result};
 use clap::{App, AppSettings, Arg};
-use std::ffi::OsStr;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-/// List all the available plugins/helper scripts
-fn list_helpers() -> Result<Vec<String>> {
+// the plugins are listed again after this long, or when the plugin directory is modified
+const PLUGIN_CACHE_TTL: Duration = Duration::from_secs(3600);
+const PLUGIN_CACHE_NAME: &str = "plugins.toml";
+
+/// Describes the plugin `ciel-<name>`, read from `ciel-<name>.toml` next to it
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PluginManifest {
+    /// Shown in `ciel --help`
+    #[serde(default)]
+    pub about: Option<String>,
+    /// Shown in `ciel <name> --help`
+    #[serde(rename = "long-about", default)]
+    pub long_about: Option<String>,
+    /// The plugin queries ciel with JSON-RPC over its stdout (and reads the responses from stdin)
+    #[serde(rename = "json-rpc", default)]
+    pub json_rpc: bool,
+    /// The arguments of the plugin (the command line is passed as-is if not declared)
+    #[serde(default)]
+    pub args: Option<Vec<PluginArg>>,
+}
+
+/// An argument declared in the plugin manifest
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PluginArg {
+    pub name: String,
+    #[serde(default)]
+    pub help: Option<String>,
+    /// The argument is positional unless `long` or `short` is set
+    #[serde(default)]
+    pub long: Option<String>,
+    #[serde(default)]
+    pub short: Option<char>,
+    /// Whether the option takes a value (the positional arguments always do)
+    #[serde(rename = "takes-value", default)]
+    pub takes_value: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub multiple: bool,
+    #[serde(rename = "possible-values", default)]
+    pub possible_values: Vec<String>,
+}
+
+impl PluginArg {
+    pub fn is_positional(&self) -> bool {
+        self.long.is_none() && self.short.is_none()
+    }
+}
+
+/// Where the plugins (and their manifests) are installed
+pub fn get_plugins_dir() -> Result<PathBuf> {
     let exe_dir = std::env::current_exe()?;
     let exe_dir = exe_dir.parent().ok_or_else(|| anyhow!("Where am I?"))?;
-    let plugins_dir = exe_dir.join("../libexec/ciel-plugin/").read_dir()?;
-    let plugins = plugins_dir
+
+    Ok(exe_dir.join("../libexec/ciel-plugin/"))
+}
+
+/// Read the manifest of the plugin (e.g. `ciel-release`), `None` if it does not have one
+pub fn read_plugin_manifest(plugin: &str) -> Result<Option<PluginManifest>> {
+    let path = get_plugins_dir()?.join(format!("{}.toml", plugin));
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let manifest = toml::from_str(&std::fs::read_to_string(&path)?)
+        .map_err(|e| anyhow!("Invalid manifest {}: {}", path.display(), e))?;
+
+    Ok(Some(manifest))
+}
+
+/// Where the caches of the user are kept (`$XDG_CACHE_HOME/ciel`, usually `~/.cache/ciel`)
+pub fn get_user_cache_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("ciel"))
+}
+
+/// The plugins found in the plugin directory, with their manifests
+#[derive(Debug, Deserialize, Serialize)]
+struct PluginCache {
+    dir: PathBuf,
+    /// Modification time of the plugin directory (seconds and nanoseconds since the epoch)
+    modified: (u64, u32),
+    /// When the plugin directory was listed (seconds since the epoch)
+    listed: u64,
+    plugins: BTreeMap<String, PluginManifest>,
+}
+
+impl PluginCache {
+    fn path() -> Option<PathBuf> {
+        get_user_cache_dir().map(|dir| dir.join(PLUGIN_CACHE_NAME))
+    }
+
+    /// Load the cache if it is still valid for the plugin directory
+    fn load(dir: &Path, modified: (u64, u32), now: u64) -> Option<PluginCache> {
+        let cache: PluginCache =
+            toml::from_str(&std::fs::read_to_string(Self::path()?).ok()?).ok()?;
+        let age = now.checked_sub(cache.listed)?;
+        if cache.dir != dir || cache.modified != modified || age > PLUGIN_CACHE_TTL.as_secs() {
+            return None;
+        }
+
+        Some(cache)
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow!("No cache directory"))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // replaced atomically, ciel may be run concurrently
+        let temp = path.with_extension(format!("tmp.{}", std::process::id()));
+        std::fs::write(&temp, toml::to_string(self)?)?;
+        std::fs::rename(&temp, &path)?;
+
+        Ok(())
+    }
+}
+
+/// List all the available plugins with their manifests (the default one if the manifest
+/// is missing or invalid), the listing is cached
+fn list_plugins() -> Result<BTreeMap<String, PluginManifest>> {
+    let dir = get_plugins_dir()?;
+    let modified = dir.metadata()?.modified()?.duration_since(UNIX_EPOCH)?;
+    let modified = (modified.as_secs(), modified.subsec_nanos());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    if let Some(cache) = PluginCache::load(&dir, modified, now) {
+        return Ok(cache.plugins);
+    }
+    let plugins = list_helpers_uncached(&dir)?
+        .into_iter()
+        .map(|plugin| {
+            let manifest = read_plugin_manifest(&plugin)
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            (plugin, manifest)
+        })
+        .collect();
+    let cache = PluginCache {
+        dir,
+        modified,
+        listed: now,
+        plugins,
+    };
+    // only makes the next run faster
+    cache.save().ok();
+
+    Ok(cache.plugins)
+}
+
+/// List all the available plugins/helper scripts
+fn list_helpers() -> Result<Vec<String>> {
+    Ok(list_plugins()?.into_keys().collect())
+}
+
+/// List the plugins/helper scripts in the plugin directory (bypassing the cache)
+fn list_helpers_uncached(dir: &Path) -> Result<Vec<String>> {
+    let plugins = dir
+        .read_dir()?
         .filter_map(|x| {
             if let Ok(x) = x {
                 let path = x.path();
@@ -15,7 +173,8 @@ fn list_helpers() -> Result<Vec<String>> {
                     .file_name()
                     .unwrap_or_else(|| OsStr::new(""))
                     .to_string_lossy();
-                if path.is_file() && filename.starts_with("ciel-") {
+                let is_manifest = path.extension() == Some(OsStr::new("toml"));
+                if path.is_file() && filename.starts_with("ciel-") && !is_manifest {
                     return Some(filename.to_string());
                 }
             }
@@ -26,50 +185,177 @@ fn list_helpers() -> Result<Vec<String>> {
     Ok(plugins)
 }
 
+// clap only takes the borrowed strings, the CLI lives as long as the process anyway
+fn leak(s: &str) -> &'static str {
+    Box::leak(s.to_owned().into_boxed_str())
+}
+
+/// The subcommand of the plugin (e.g. `ciel-release`), as declared in its manifest
+pub fn plugin_command(plugin: &str, manifest: &PluginManifest) -> App<'static> {
+    let name = plugin.strip_prefix("ciel-").unwrap_or("???");
+    let mut app = App::new(name).about(manifest.about.as_deref().map_or("", leak));
+    if let Some(long_about) = &manifest.long_about {
+        app = app.long_about(leak(long_about));
+    }
+    let args = match &manifest.args {
+        Some(args) => args,
+        None => {
+            return app.arg(
+                Arg::new("COMMANDS")
+                    .required(false)
+                    .min_values(1)
+                    .help("Applet specific commands"),
+            );
+        }
+    };
+    for declared in args {
+        let mut arg = Arg::new(leak(&declared.name)).required(declared.required);
+        if let Some(help) = &declared.help {
+            arg = arg.help(leak(help));
+        }
+        if let Some(long) = &declared.long {
+            arg = arg.long(leak(long));
+        }
+        if let Some(short) = declared.short {
+            arg = arg.short(short);
+        }
+        if declared.is_positional() {
+            arg = arg.multiple_values(declared.multiple);
+        } else {
+            arg = arg
+                .takes_value(declared.takes_value)
+                .multiple_occurrences(declared.multiple);
+        }
+        if !declared.possible_values.is_empty() {
+            arg = arg.possible_values(
+                declared
+                    .possible_values
+                    .iter()
+                    .map(|v| leak(v))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        app = app.arg(arg);
+    }
+
+    app
+}
+
 /// Build the CLI instance
 pub fn build_cli() -> App<'static> {
     App::new("CIEL!")
         .version(env!("CARGO_PKG_VERSION"))
         .about("CIEL! is a nspawn container manager")
         .setting(AppSettings::AllowExternalSubcommands)
-        .subcommand(App::new("version").about("Display the version of CIEL!"))
+        .arg(Arg::new("verbose").short('v').long("verbose").multiple_occurrences(true).global(true).help("Show more details (-v: commands and mounts, -vv: everything), use CIEL_LOG=overlayfs=debug,... for per-module levels"))
+        .arg(Arg::new("quiet").short('q').long("quiet").global(true).conflicts_with("verbose").help("Only show warnings and errors"))
+        .arg(Arg::new("log-format").long("log-format").global(true).takes_value(true).possible_values(["text", "json"]).help("Format of the log messages on stderr and in the workspace log (default: `log-format` in the config, or text)"))
+        .arg(Arg::new("color").long("color").global(true).takes_value(true).value_name("WHEN").possible_values(["auto", "always", "never"]).default_value("auto").help("When to use colors and other escape sequences (auto honors NO_COLOR and CLICOLOR_FORCE)"))
+        .arg(Arg::new("timestamps").long("timestamps").global(true).help("Prefix the log messages with timestamps (default: `log-timestamps` in the config)"))
+        .arg(Arg::new("no-wait").long("no-wait").global(true).help("Fail instead of waiting when the workspace is being used by another command"))
+        .arg(Arg::new("json").long("json").global(true).help("Print machine-readable JSON output to stdout (list, doctor, build and repo)"))
+        .subcommand(App::new("version")
+            .arg(Arg::new("os").long("os").help("Show where the base system was loaded from instead"))
+            .about("Display the version of CIEL!"))
         .subcommand(App::new("init")
             .arg(Arg::new("upgrade").long("upgrade").help("Upgrade Ciel workspace from an older version"))
             .about("Initialize the work directory"))
         .subcommand(
             App::new("load-os")
                 .arg(Arg::new("url").help("URL or path to the tarball"))
+                .arg(Arg::new("replace").long("replace").help("Remove the current base system instead of archiving it"))
+                .arg(Arg::new("list-archived").long("list-archived").conflicts_with_all(&["url", "from-dir", "arch", "variant", "replace", "switch", "remove-archived"]).help("List the archived base systems"))
+                .arg(Arg::new("switch").long("switch").takes_value(true).value_name("NAME").conflicts_with_all(&["url", "from-dir", "arch", "variant", "replace", "remove-archived"]).help("Switch to an archived base system (the current one is archived)"))
+                .arg(Arg::new("remove-archived").long("remove-archived").takes_value(true).value_name("NAME").conflicts_with_all(&["url", "from-dir", "arch", "variant", "replace"]).help("Permanently remove an archived base system"))
+                .arg(Arg::new("from-dir").long("from-dir").takes_value(true).value_name("DIR").conflicts_with_all(&["url", "arch", "variant"]).help("Copy the base system from an existing root filesystem directory"))
+                .arg(Arg::new("arch").long("arch").takes_value(true).conflicts_with("url").help("Fetch the tarball for this architecture instead of the host one (e.g. riscv64)"))
+                .arg(Arg::new("variant").long("variant").takes_value(true).conflicts_with("url").help("Fetch this variant instead of BuildKit (e.g. base)"))
                 .about("Unpack OS tarball or fetch the latest BuildKit from the repository"),
         )
-        .subcommand(App::new("update-os").about("Update the OS in the container"))
+        .subcommand(
+            App::new("update-os")
+                .arg(Arg::new("dry-run").short('n').long("dry-run").help("Only show the packages that would be changed, without updating the base system"))
+                .arg(Arg::new("undo").long("undo").conflicts_with("dry-run").help("Revert the base system to the snapshot taken before the last update"))
+                .about("Update the OS in the container"),
+        )
         .subcommand(
             App::new("load-tree")
                 .arg(Arg::new("url").help("URL to the git repository"))
                 .about("Clone package tree from the link provided or AOSC OS ABBS main repository"),
         )
         .subcommand(
-            App::new("new").about("Create a new CIEL workspace")
+            App::new("new")
+                .arg(Arg::new("instance").long("instance").takes_value(true).help("Create an instance with this name after initialization"))
+                .arg(Arg::new("tarball").long("tarball").takes_value(true).value_name("URL").help("URL to the OS tarball (the latest BuildKit is used if not specified)"))
+                .about("Create a new CIEL workspace")
         )
         .subcommand(
             App::new("list")
                 .alias("ls")
+                .arg(Arg::new("state").short('s').long("state").help("Also query the system state of the booted instances (slower)"))
                 .about("List all the instances under the specified working directory"),
         )
+        .subcommand(
+            App::new("status")
+                .about("Show an overview of the workspace"),
+        )
+        .subcommand(
+            App::new("ui")
+                .about("Interactive dashboard for managing the instances"),
+        )
+        .subcommand(
+            App::new("top")
+                .arg(Arg::new("delay").short('d').long("delay").takes_value(true).default_value("2").help("Refresh interval in seconds"))
+                .about("Show the resource usage of the running instances"),
+        )
+        .subcommand(
+            App::new("daemon")
+                .arg(Arg::new("dbus").long("dbus").required_unless_present("socket").conflicts_with("socket").help("Serve on D-Bus (as io.aosc.Ciel1)"))
+                .arg(Arg::new("session").long("session").requires("dbus").help("Use the session bus instead of the system bus"))
+                .arg(Arg::new("socket").long("socket").takes_value(true).value_name("PATH").min_values(0).max_values(1).default_missing_value(".ciel/ciel.sock").help("Serve JSON-RPC on a Unix socket (.ciel/ciel.sock by default), only accessible by the owner"))
+                .arg(Arg::new("group").long("group").takes_value(true).requires("socket").help("Also allow the members of the group to use the socket"))
+                .about("Serve the workspace operations to other programs"),
+        )
+        .subcommand(
+            App::new("serve-api")
+                .arg(Arg::new("listen").long("listen").takes_value(true).value_name("ADDR").default_value("127.0.0.1:8080").help("Address to listen on"))
+                .arg(Arg::new("token-file").long("token-file").takes_value(true).value_name("FILE").help("File containing the token required by the write endpoints (CIEL_API_TOKEN by default)"))
+                .about("Serve the workspace over HTTP (JSON API under /api/v1/)"),
+        )
         .subcommand(
             App::new("add")
                 .arg(Arg::new("INSTANCE").required(true))
+                .arg(Arg::new("no-boot").long("no-boot").help("Run commands in a lightweight container without booting systemd"))
+                .arg(Arg::new("network").long("network").takes_value(true).possible_values(["host", "private", "none"]).help("Network mode of the instance"))
+                .arg(Arg::new("publish").short('p').long("publish").takes_value(true).multiple_occurrences(true).value_name("[PROTO:]HOSTPORT[:PORT]").help("Forward a host port to the instance (private network only)"))
+                .arg(Arg::new("arch").long("arch").takes_value(true).help("Architecture of the instance (must match the base system, foreign ones are emulated with qemu-user)"))
+                .arg(Arg::new("description").short('d').long("description").takes_value(true).help("What the instance is used for (shown in `ciel list`)"))
+                .arg(Arg::new("from").long("from").takes_value(true).value_name("INSTANCE").conflicts_with_all(&["no-boot", "network", "publish", "arch"]).help("Start with a copy of the changes and the configuration of another instance (cloned if the filesystem supports it)"))
                 .about("Add a new instance"),
         )
         .subcommand(
             App::new("del")
                 .alias("rm")
                 .arg(Arg::new("INSTANCE").required(true))
+                .arg(Arg::new("force").short('f').long("force").help("Do not ask for confirmation (required in batch mode)"))
+                .arg(Arg::new("purge").long("purge").help("Remove the instance permanently instead of moving it to the trash"))
                 .about("Remove an instance"),
         )
+        .subcommand(
+            App::new("restore")
+                .arg(Arg::new("INSTANCE").required_unless_present("list").help("Instance (or trash entry) to be restored"))
+                .arg(Arg::new("as").long("as").takes_value(true).help("Restore under another name"))
+                .arg(Arg::new("list").short('l').long("list").conflicts_with("INSTANCE").help("List the instances in the trash"))
+                .about("Restore a deleted instance from the trash"),
+        )
         .subcommand(
             App::new("shell")
                 .alias("sh")
                 .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to be used"))
+                .arg(Arg::new("boot").long("boot").help("Boot the container with systemd (overrides the instance setting)"))
+                .arg(Arg::new("no-boot").long("no-boot").conflicts_with("boot").help("Use a lightweight container without booting systemd (overrides the instance setting)"))
+                .arg(Arg::new("user").short('u').long("user").takes_value(true).help("Run as the specified user (created if not exists)"))
+                .arg(Arg::new("forward-agent").short('A').long("forward-agent").help("Forward the host SSH agent and git config (~/.gitconfig) into the container"))
                 .arg(Arg::new("COMMANDS").required(false).min_values(1))
                 .about("Start an interactive shell"),
         )
@@ -77,34 +363,114 @@ pub fn build_cli() -> App<'static> {
             App::new("run")
                 .alias("exec")
                 .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to run command in"))
+                .arg(Arg::new("boot").long("boot").help("Boot the container with systemd (overrides the instance setting)"))
+                .arg(Arg::new("no-boot").long("no-boot").conflicts_with("boot").help("Use a lightweight container without booting systemd (overrides the instance setting)"))
+                .arg(Arg::new("user").short('u').long("user").takes_value(true).help("Run as the specified user (created if not exists)"))
                 .arg(Arg::new("COMMANDS").required(true).min_values(1))
                 .about("Lower-level version of 'shell', without login environment, without sourcing ~/.bash_profile"),
         )
+        .subcommand(
+            App::new("attach")
+                .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to attach to"))
+                .about("Attach to the console of a running instance"),
+        )
         .subcommand(
             App::new("config")
                 .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to be configured"))
                 .arg(Arg::new("g").short('g').required(false).conflicts_with("INSTANCE").help("Configure base system instead of an instance"))
+                .arg(Arg::new("check").long("check").conflicts_with_all(&["INSTANCE", "g"]).help("Check the workspace configuration for problems"))
+                .arg(Arg::new("boot").long("boot").conflicts_with("g").help("Boot the instance with systemd"))
+                .arg(Arg::new("no-boot").long("no-boot").conflicts_with_all(&["g", "boot"]).help("Run commands in the instance in a lightweight container without booting systemd"))
+                .arg(Arg::new("network").long("network").takes_value(true).conflicts_with("g").possible_values(["host", "private", "none"]).help("Set the network mode of the instance"))
+                .arg(Arg::new("publish").short('p').long("publish").takes_value(true).multiple_occurrences(true).conflicts_with("g").value_name("[PROTO:]HOSTPORT[:PORT]").help("Set the port forwarding rules of the instance (private network only)"))
+                .arg(Arg::new("cap-add").long("cap-add").takes_value(true).multiple_occurrences(true).conflicts_with("g").value_name("CAP").help("Set the extra capabilities granted to the instance"))
+                .arg(Arg::new("cap-drop").long("cap-drop").takes_value(true).multiple_occurrences(true).conflicts_with("g").value_name("CAP").help("Set the capabilities dropped from the instance"))
+                .arg(Arg::new("syscall-filter").long("syscall-filter").takes_value(true).multiple_occurrences(true).conflicts_with("g").value_name("[~]SYSCALL").help("Set the system call filter of the instance"))
+                .arg(Arg::new("seccomp-profile").long("seccomp-profile").takes_value(true).conflicts_with("g").value_name("PROFILE").help("Set the seccomp profile of the instance (`default` or one in the workspace config, empty to remove it)"))
+                .arg(Arg::new("description").short('d').long("description").takes_value(true).conflicts_with("g").help("Set the description of the instance (empty to remove it)"))
+                .subcommand(
+                    App::new("repo")
+                        .setting(AppSettings::ArgRequiredElseHelp)
+                        .subcommands(vec![
+                            App::new("add")
+                                .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to be configured (base system if not specified)"))
+                                .arg(Arg::new("key").long("key").takes_value(true).help("Path or URL to the signing key of the repository"))
+                                .arg(Arg::new("SOURCE").required(true).min_values(1).help("sources.list entry of the repository"))
+                                .about("Add an extra APT repository"),
+                            App::new("remove")
+                                .alias("rm")
+                                .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to be configured (base system if not specified)"))
+                                .arg(Arg::new("SOURCE").required(true).min_values(1).help("sources.list entry of the repository"))
+                                .about("Remove an extra APT repository"),
+                        ])
+                        .about("Manage extra APT repositories"),
+                )
                 .about("Configure system and toolchain for building interactively"),
         )
         .subcommand(
             App::new("commit")
                 .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to be committed"))
+                .arg(Arg::new("keep-upper").long("keep-upper").help("Keep the changes in the instance (they are copied, or cloned if the filesystem supports it)"))
                 .about("Commit changes onto the shared underlying OS"),
         )
         .subcommand(
             App::new("doctor")
-                .about("Diagnose problems (hopefully)"),
+                .arg(Arg::new("fix").long("fix").help("Fix the problems found in the workspace (after confirmation)"))
+                .arg(Arg::new("force").short('f').long("force").requires("fix").help("Do not ask for confirmation (required in batch mode)"))
+                .arg(Arg::new("bundle").long("bundle").takes_value(true).value_name("FILE").help("Save a diagnostic bundle (logs, redacted configs, mounts, etc.) for bug reports, e.g. report.tar.gz"))
+                .about("Diagnose problems (hopefully)")
+                .after_help("Exit codes: 0 if all the checks passed, 1 if any check failed, 2 if there are only warnings."),
         )
         .subcommand(
             App::new("build")
                 .arg(Arg::new("FETCH").short('g').takes_value(false).help("Fetch source packages only"))
                 .arg(Arg::new("OFFLINE").short('x').long("offline").takes_value(false).help("Disable network in the container during the build"))
+                .arg(Arg::new("network-policy").long("network-policy").takes_value(true).possible_values(["open", "offline", "restricted"]).conflicts_with("OFFLINE").help("Network access during the build (overrides [build-network] in the config), restricted only allows the configured hosts through a proxy"))
+                .arg(Arg::new("progress").long("progress").takes_value(true).possible_values(["text", "json"]).default_value("text").help("How to report the progress, json writes the build events to stdout (one per line) and the build output to stderr"))
+                .arg(Arg::new("JOBS").long("jobs-per-build").takes_value(true).value_name("N").help("Number of parallel jobs used by each package build"))
+                .arg(Arg::new("secret").long("secret").takes_value(true).multiple_occurrences(true).value_name("NAME=@FILE").conflicts_with("on").help("Make the content of FILE (or the environment variable NAME if only NAME is given) available to the build as /run/ciel/secrets/NAME, never stored in the instance or the output"))
                 .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to build in"))
+                .arg(Arg::new("arch").long("arch").takes_value(true).help("Build for the architecture, in an instance picked (or created) automatically"))
+                .arg(Arg::new("matrix").long("matrix").takes_value(true).value_name("ARCHS").conflicts_with_all(&["INSTANCE", "arch", "CONTINUE", "SELECT", "FETCH"]).requires("PACKAGES").help("Build for each of the comma-separated architectures (switching the base systems as needed)"))
+                .arg(Arg::new("on").long("on").takes_value(true).value_name("REMOTE").conflicts_with_all(&["matrix", "CONTINUE", "SELECT", "FETCH"]).requires("PACKAGES").help("Build on the remote workspace (see `ciel remote`) and fetch the packages, -i and --arch select the instance there"))
                 .arg(Arg::new("CONTINUE").conflicts_with("SELECT").short('c').long("resume").alias("continue").takes_value(true).help("Continue from a Ciel checkpoint"))
                 .arg(Arg::new("SELECT").max_values(1).min_values(0).long("stage-select").help("Select the starting point for a build"))
                 .arg(Arg::new("PACKAGES").conflicts_with("CONTINUE").min_values(1))
                 .about("Build the packages using the specified instance"),
         )
+        .subcommand(
+            App::new("remote")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .subcommands(vec![
+                    App::new("add")
+                        .arg(Arg::new("HOST").required(true).help("SSH destination, e.g. root@builder"))
+                        .arg(Arg::new("name").long("name").takes_value(true).help("Name of the remote (the host name by default)"))
+                        .arg(Arg::new("path").long("path").takes_value(true).value_name("DIR").help("Path to the workspace on the remote machine (the login directory by default)"))
+                        .arg(Arg::new("INSTANCE").short('i').long("instance").takes_value(true).help("Instance to build in by default"))
+                        .arg(Arg::new("sync-tree").long("sync-tree").help("Copy the local TREE to the remote workspace before building"))
+                        .arg(Arg::new("command").long("command").takes_value(true).help("How to run ciel on the remote machine (ciel by default), e.g. \"sudo ciel\""))
+                        .about("Add a remote workspace to build on"),
+                    App::new("remove")
+                        .alias("rm")
+                        .arg(Arg::new("NAME").required(true))
+                        .about("Remove a remote workspace"),
+                    App::new("list").about("List the remote workspaces"),
+                ])
+                .about("Manage the remote workspaces to build on (with `ciel build --on`)"),
+        )
+        .subcommand(
+            App::new("cross-setup")
+                .arg(Arg::new("ARCH").required(true).help("Target architecture of the toolchain"))
+                .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to set up (cross-<ARCH> by default, created if missing)"))
+                .about("Set up a cross toolchain for building packages of another architecture"),
+        )
+        .subcommand(
+            App::new("logs")
+                .arg(Arg::new("list").long("list").help("List the recorded builds (of the package if specified)"))
+                .arg(Arg::new("previous").long("previous").takes_value(true).value_name("N").default_value("0").help("Show the log of the N-th previous build (0 is the latest one)"))
+                .arg(Arg::new("PACKAGE").required_unless_present("list").help("Package to show the build log of"))
+                .about("Browse the history of the package build logs"),
+        )
         .subcommand(
             App::new("rollback")
                 .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to be rolled back"))
@@ -114,6 +480,8 @@ pub fn build_cli() -> App<'static> {
             App::new("down")
                 .alias("umount")
                 .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to be un-mounted"))
+                .arg(Arg::new("all").long("all").conflicts_with("INSTANCE").help("Shutdown and unmount all the instances"))
+                .arg(Arg::new("force").short('f').long("force").requires("all").help("Ignore errors and detach the stale mounts left behind by crashed runs"))
                 .about("Shutdown and unmount all or one instance"),
         )
         .subcommand(
@@ -126,30 +494,49 @@ pub fn build_cli() -> App<'static> {
                 .arg(Arg::new("INSTANCE").short('i').takes_value(true).help("Instance to be mounted"))
                 .about("Mount all or specified instance"),
         )
+        .subcommand(
+            App::new("export-workspace")
+                .arg(Arg::new("FILE").required(true).help("Archive to create, compressed according to the extension (e.g. workspace.tar.zst)"))
+                .about("Archive the workspace (base system, instances, configuration, tree and outputs) for moving to another machine"),
+        )
+        .subcommand(
+            App::new("import-workspace")
+                .arg(Arg::new("FILE").required(true).help("Archive created by `ciel export-workspace`"))
+                .about("Restore an exported workspace into the current (empty) directory"),
+        )
         .subcommand(
             App::new("farewell")
                 .alias("harakiri")
-                .about("Remove everything related to CIEL!"),
+                .arg(Arg::new("force").short('f').long("force").help("Do not ask for confirmation (required in batch mode)"))
+                .arg(Arg::new("instances-only").long("instances-only").conflicts_with("purge").help("Only remove the instances, keeping the base system and the configuration"))
+                .arg(Arg::new("purge").long("purge").help("Also remove the tree, outputs and sources (kept by default)"))
+                .arg(Arg::new("keep-tree").long("keep-tree").requires("purge").help("Keep the tree when purging"))
+                .arg(Arg::new("keep-output").long("keep-output").requires("purge").help("Keep the outputs when purging"))
+                .about("Remove the workspace (base system, instances and configuration)"),
         )
         .subcommand(
             App::new("repo")
                 .setting(AppSettings::ArgRequiredElseHelp)
-                .subcommands(vec![App::new("refresh").about("Refresh the repository"), App::new("init").arg(Arg::new("INSTANCE").required(true)).about("Initialize the repository"), App::new("deinit").about("Uninitialize the repository")])
+                .subcommands(vec![App::new("refresh").about("Refresh the repository"), App::new("init").arg(Arg::new("INSTANCE").required(true)).about("Initialize the repository"), App::new("deinit").about("Uninitialize the repository"), App::new("dedupe").arg(Arg::new("dry-run").short('n').long("dry-run").help("Only show how much space would be freed")).about("Hard link the identical packages across the output directories (e.g. of the different branches)")])
                 .alias("localrepo")
                 .about("Local repository operations")
         )
         .subcommand(
             App::new("clean")
+                .arg(Arg::new("all").short('a').long("all").help("Also remove the tarball caches, stale checkpoints and logs, and orphaned work directories"))
+                .arg(Arg::new("dry-run").short('n').long("dry-run").help("Only show what would be removed (and how much space would be freed)"))
+                .arg(Arg::new("max-age").long("max-age").takes_value(true).value_name("DAYS").default_value("14").requires("all").help("Checkpoints and logs older than this are considered stale"))
                 .about("Clean all the output directories and source cache directories")
         )
+        .subcommand(
+            App::new("gen-manpages")
+                .arg(Arg::new("DIR").required(true).help("Directory to write the man pages to"))
+                .about("Generate man pages for ciel and all the subcommands")
+        )
         .subcommands({
-            let plugins = list_helpers();
+            let plugins = list_plugins();
             if let Ok(plugins) = plugins {
-                plugins.iter().map(|plugin| {
-                    App::new(plugin.strip_prefix("ciel-").unwrap_or("???"))
-                    .arg(Arg::new("COMMANDS").required(false).min_values(1).help("Applet specific commands"))
-                    .about("")
-                }).collect()
+                plugins.iter().map(|(plugin, manifest)| plugin_command(plugin, manifest)).collect()
             } else {
                 vec![]
             }
@@ -160,10 +547,124 @@ pub fn build_cli() -> App<'static> {
                     .short('C')
                     .value_name("DIR")
                     .help("set the CIEL! working directory"),
+                Arg::new("workspace")
+                    .short('w')
+                    .value_name("NAME")
+                    .conflicts_with("C")
+                    .help("Use the workspace registered as NAME in ~/.config/ciel/workspaces.toml"),
                 Arg::new("batch")
                     .short('b')
                     .long("batch")
-                    .help("Batch mode, no input required"),
+                    .global(true)
+                    .help("Batch mode, no input required (fails instead of prompting)"),
             ]
         )
 }
+
+/// Check if the subcommand is provided by ciel itself (instead of a plugin)
+#[allow(dead_code)]
+pub fn is_builtin_command(name: &str) -> bool {
+    let plugin = format!("ciel-{}", name);
+    build_cli().find_subcommand(name).is_some()
+        && !list_helpers().unwrap_or_default().contains(&plugin)
+}
+
+/// The options of ciel itself taking a value (e.g. `--color always`), in their short and long forms
+fn get_value_options(app: &App) -> Vec<String> {
+    app.get_arguments()
+        .filter(|arg| arg.is_takes_value_set() && !arg.is_positional())
+        .flat_map(|arg| {
+            arg.get_short()
+                .map(|short| format!("-{}", short))
+                .into_iter()
+                .chain(arg.get_long().map(|long| format!("--{}", long)))
+        })
+        .collect()
+}
+
+/// Expand the user-defined alias (`[alias]` section in the config) in the command line.
+/// Aliases are resolved before the plugins, but never override the built-in commands.
+/// `load_aliases` is only called when the subcommand is not built-in, with the directory given by `-C`
+/// and the workspace name given by `-w`.
+#[allow(dead_code)]
+pub fn expand_alias<F>(args: Vec<OsString>, load_aliases: F) -> Vec<OsString>
+where
+    F: FnOnce(Option<&OsStr>, Option<&OsStr>) -> BTreeMap<String, String>,
+{
+    let value_options = get_value_options(&build_cli());
+    let mut directory = None;
+    let mut workspace = None;
+    let mut position = None;
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].to_string_lossy();
+        if arg == "-C" {
+            directory = args.get(i + 1).map(|d| d.as_os_str());
+            i += 2;
+            continue;
+        }
+        if arg == "-w" {
+            workspace = args.get(i + 1).map(|w| w.as_os_str());
+            i += 2;
+            continue;
+        }
+        if value_options.iter().any(|option| *option == arg) {
+            i += 2;
+            continue;
+        }
+        if arg == "--" {
+            break;
+        }
+        if !arg.starts_with('-') {
+            position = Some(i);
+            break;
+        }
+        i += 1;
+    }
+    let (position, name) = match position.and_then(|i| Some((i, args[i].to_str()?))) {
+        Some((position, name)) if !is_builtin_command(name) => (position, name),
+        _ => return args,
+    };
+    let expansion = match load_aliases(directory, workspace).remove(name) {
+        Some(expansion) => expansion,
+        None => return args,
+    };
+    let mut expanded = args[..position].to_vec();
+    expanded.extend(expansion.split_whitespace().map(OsString::from));
+    expanded.extend_from_slice(&args[position + 1..]);
+
+    expanded
+}
+
+#[test]
+fn test_expand_alias() {
+    let args = |line: &str| -> Vec<OsString> { line.split(' ').map(OsString::from).collect() };
+    let aliases = |_: Option<&OsStr>, _: Option<&OsStr>| {
+        let mut aliases = BTreeMap::new();
+        aliases.insert("rebuild".to_string(), "build --resume last".to_string());
+        aliases.insert("list".to_string(), "down".to_string());
+        aliases
+    };
+    assert_eq!(
+        expand_alias(args("ciel -C /tmp rebuild -i main"), aliases),
+        args("ciel -C /tmp build --resume last -i main")
+    );
+    assert_eq!(
+        expand_alias(args("ciel -w main rebuild"), aliases),
+        args("ciel -w main build --resume last")
+    );
+    assert_eq!(
+        expand_alias(args("ciel --color always -v rebuild"), aliases),
+        args("ciel --color always -v build --resume last")
+    );
+    assert_eq!(
+        expand_alias(args("ciel --log-format=json rebuild"), aliases),
+        args("ciel --log-format=json build --resume last")
+    );
+    // built-in commands can not be overridden
+    assert_eq!(expand_alias(args("ciel list"), aliases), args("ciel list"));
+    assert_eq!(
+        expand_alias(args("ciel unknown"), aliases),
+        args("ciel unknown")
+    );
+}
@@ -1,46 +1,175 @@
+use crate::{debug, info, privsep, progress, warn};
 use anyhow::{anyhow, Result};
-use lazy_static::lazy_static;
+use fs3::statvfs;
+use indicatif::{HumanBytes, ProgressBar};
+use libmount::mountinfo::Parser;
+use nix::{
+    fcntl::{posix_fadvise, OFlag, PosixFadviseAdvice},
+    sys::{
+        resource::{getrlimit, Resource},
+        statfs::statfs,
+    },
+    unistd::{fchownat, FchownatFlags, Gid, Uid},
+};
 use progress_streams::ProgressReader;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fs::{self, File};
-use std::os::unix::prelude::MetadataExt;
+use std::os::unix::prelude::{
+    AsRawFd, MetadataExt, OpenOptionsExt, OsStrExt, OsStringExt, PermissionsExt,
+};
 use std::{
-    io::{Read, Write},
-    path::{Path, PathBuf},
+    ffi::OsStr,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc::{sync_channel, Receiver},
+    thread,
+    time::Instant,
 };
+use walkdir::WalkDir;
 
 pub const CURRENT_CIEL_VERSION: usize = 3;
-const CURRENT_CIEL_VERSION_STR: &str = "3";
+/// Records the version of the workspace layout
+pub const CIEL_VERSION_FILE: &str = ".ciel/version";
 pub const CIEL_DIST_DIR: &str = ".ciel/container/dist";
 pub const CIEL_INST_DIR: &str = ".ciel/container/instances";
 pub const CIEL_DATA_DIR: &str = ".ciel/data";
-const SKELETON_DIRS: &[&str] = &[CIEL_DIST_DIR, CIEL_INST_DIR, CIEL_DATA_DIR];
-
-lazy_static! {
-    static ref SPINNER_STYLE: indicatif::ProgressStyle =
-        indicatif::ProgressStyle::default_spinner()
-            .tick_chars("⠋⠙⠸⠴⠦⠇ ")
-            .template("{spinner:.green} {wide_msg}");
-}
-
-#[macro_export]
-macro_rules! make_progress_bar {
-    ($msg:expr) => {
-        concat!(
-            "{spinner} [{bar:25.cyan/blue}] ",
-            $msg,
-            " ({bytes_per_sec}, eta {eta})"
-        )
-    };
+/// Data that can be regenerated at any time, e.g. the scan results of the local repository
+pub const CIEL_CACHE_DIR: &str = ".ciel/cache";
+pub const SKELETON_DIRS: &[&str] = &[CIEL_DIST_DIR, CIEL_INST_DIR, CIEL_DATA_DIR];
+/// Builds are refused below this amount of free space
+pub const MIN_BUILD_SPACE: u64 = 1024 * 1024 * 1024;
+/// Free space needed for doing something meaningful (e.g. building larger packages)
+pub const RECOMMENDED_BUILD_SPACE: u64 = 10 * 1024 * 1024 * 1024;
+// estimated size of the extracted system, relative to the compressed tarball
+const TARBALL_EXPANSION_RATIO: u64 = 4;
+// chunks (and the number of them in flight) passed between the stages of the unpacking pipeline
+const PIPELINE_CHUNK_SIZE: usize = 1024 * 1024;
+const PIPELINE_DEPTH: usize = 16;
+// the tarball is dropped from the page cache every this many bytes read
+const DROP_BEHIND_SIZE: u64 = 16 * 1024 * 1024;
+// the unpacked files are flushed in batches of this many files (or bytes)
+const WRITE_BEHIND_FILES: usize = 128;
+const WRITE_BEHIND_BYTES: u64 = 64 * 1024 * 1024;
+// two batches are kept open, each one has at most this fraction of RLIMIT_NOFILE
+const WRITE_BEHIND_FD_SHARE: u64 = 8;
+// (magic, name) of the network filesystems, which can not hold the overlay upper layers
+// (no support for the trusted.* xattrs and whiteouts), see statfs(2)
+const NETWORK_FILESYSTEMS: &[(u32, &str)] = &[
+    (0x6969, "NFS"),
+    (0x517b, "SMB"),
+    (0xff53_4d42, "CIFS"),
+    (0xfe53_4d42, "SMB2"),
+    (0x564c, "NCP"),
+    (0x7375_7245, "Coda"),
+    (0x6b41_4653, "AFS"),
+    (0x0102_1997, "9P"),
+    (0x00c3_6400, "CephFS"),
+    (0x0bd0_0bd0, "Lustre"),
+];
+
+/// Check if it is okay to prompt the user (attended and not in batch mode)
+pub fn is_interactive() -> bool {
+    console::user_attended() && std::env::var("CIEL_BATCH").is_err()
+}
+
+/// Recursively remove the directory `path` inside `root`, refusing to do so if it is outside
+/// of `root` (e.g. through a symlink) or if anything is mounted in it
+pub(crate) fn remove_dir_within(root: &Path, path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        return Err(anyhow!(
+            "Refusing to remove {}: it is a symlink",
+            path.display()
+        ));
+    }
+    // any of the parents may be a symlink as well
+    let target = path.canonicalize()?;
+    let root = root.canonicalize()?;
+    if target == root || !target.starts_with(&root) {
+        return Err(anyhow!(
+            "Refusing to remove {}: it resolves to {}, which is outside of the workspace",
+            path.display(),
+            target.display()
+        ));
+    }
+    let mountinfo = fs::read("/proc/self/mountinfo")?;
+    for mount in Parser::new(&mountinfo) {
+        let mount = mount?;
+        if Path::new(&*mount.mount_point).starts_with(&target) {
+            return Err(anyhow!(
+                "Refusing to remove {}: {} is still mounted",
+                path.display(),
+                mount.mount_point.display()
+            ));
+        }
+    }
+
+    Ok(fs::remove_dir_all(&target)?)
+}
+
+/// Recursively remove a directory of the workspace (the current directory), refusing to do so
+/// if it is outside of the workspace (e.g. through a symlink) or if anything is mounted in it
+pub fn remove_workspace_dir<P: AsRef<Path>>(path: P) -> Result<()> {
+    remove_dir_within(&std::env::current_dir()?, path.as_ref())
+}
+
+/// Calculate the total size of the files under the given directory (without crossing filesystems)
+pub fn get_dir_size<P: AsRef<Path>>(path: P) -> u64 {
+    WalkDir::new(path)
+        .same_file_system(true)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Make sure the filesystem containing `path` has at least `required` bytes available
+/// before starting `operation`, returns the available space
+pub fn ensure_free_space<P: AsRef<Path>>(path: P, required: u64, operation: &str) -> Result<u64> {
+    let available = statvfs(path.as_ref())?.available_space();
+    if available < required {
+        return Err(anyhow!(
+            "Not enough disk space for {}: {} is needed on {}, but only {} is available.",
+            operation,
+            HumanBytes(required),
+            fs::canonicalize(path.as_ref())?.display(),
+            HumanBytes(available)
+        ));
+    }
+
+    Ok(available)
 }
 
 #[inline]
-pub fn create_spinner(msg: &'static str, tick_rate: u64) -> indicatif::ProgressBar {
-    let spinner = indicatif::ProgressBar::new_spinner().with_style(SPINNER_STYLE.clone());
-    spinner.set_message(msg);
-    spinner.enable_steady_tick(tick_rate);
+fn network_filesystem_name(magic: u32) -> Option<&'static str> {
+    NETWORK_FILESYSTEMS
+        .iter()
+        .find(|(m, _)| *m == magic)
+        .map(|(_, name)| *name)
+}
 
-    spinner
+/// Get the name of the network filesystem containing `path`, `None` if it is a local one
+pub fn network_filesystem<P: AsRef<Path>>(path: P) -> Result<Option<&'static str>> {
+    let fs = statfs(path.as_ref())?;
+    // the magic numbers are 32-bit, while the width of f_type varies between architectures
+    Ok(network_filesystem_name(fs.filesystem_type().0 as u32))
+}
+
+/// Refuse to work on network filesystems (where the overlay mounts would fail in obscure ways)
+pub fn ensure_local_filesystem<P: AsRef<Path>>(path: P) -> Result<()> {
+    if let Some(name) = network_filesystem(path.as_ref())? {
+        return Err(anyhow!(
+            "{} is on {}, which can not be used for the container layers. \
+            Please move the workspace to a local filesystem (e.g. ext4, XFS or Btrfs).",
+            fs::canonicalize(path.as_ref())?.display(),
+            name
+        ));
+    }
+
+    Ok(())
 }
 
 /// Calculate the Sha256 checksum of the given stream
@@ -51,49 +180,714 @@ pub fn sha256sum<R: Read>(mut reader: R) -> Result<String> {
     Ok(format!("{:x}", hasher.finalize()))
 }
 
-/// Extract the given .tar.xz stream and preserve all the file attributes
-pub fn extract_tar_xz<R: Read>(reader: R, path: &Path) -> Result<()> {
-    let decompress = xz2::read::XzDecoder::new(reader);
-    let mut tar_processor = tar::Archive::new(decompress);
-    tar_processor.set_unpack_xattrs(true);
-    tar_processor.set_preserve_permissions(true);
-    tar_processor.unpack(path)?;
+/// Calculate the Sha256 checksum of the given file, with progress indicator
+pub fn sha256sum_progress(path: &Path) -> Result<String> {
+    let mut f = File::open(path)?;
+    let progress_bar = progress::bytes_bar(f.metadata()?.len(), "Verifying checksum...");
+    let reader = ProgressReader::new(&mut f, |progress: usize| {
+        progress_bar.inc(progress as u64);
+    });
+    let checksum = sha256sum(reader)?;
+    progress_bar.finish_and_clear();
+
+    Ok(checksum)
+}
+
+/// Formats of the base system tarballs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarballFormat {
+    Tar,
+    Xz,
+    Zstd,
+    Gzip,
+    /// A SquashFS image instead of a tarball
+    Squashfs,
+}
+
+/// Detect the format from the first bytes of the file (the extension is not trusted)
+pub fn detect_tarball_format(header: &[u8]) -> Option<TarballFormat> {
+    if header.starts_with(b"\xfd7zXZ\x00") {
+        Some(TarballFormat::Xz)
+    } else if header.starts_with(b"\x28\xb5\x2f\xfd") {
+        Some(TarballFormat::Zstd)
+    } else if header.starts_with(b"\x1f\x8b") {
+        Some(TarballFormat::Gzip)
+    } else if header.starts_with(b"hsqs") {
+        Some(TarballFormat::Squashfs)
+    } else if header.get(257..262) == Some(b"ustar") {
+        Some(TarballFormat::Tar)
+    } else {
+        None
+    }
+}
+
+/// Reads the tarball sequentially, dropping what has been read from the page cache
+/// (the tarball is only read once, caching it would only evict the pages of the others)
+struct DropBehindReader {
+    file: File,
+    position: u64,
+    dropped: u64,
+}
+
+impl DropBehindReader {
+    fn new(file: File) -> Self {
+        // only a hint, the errors are ignored
+        posix_fadvise(
+            file.as_raw_fd(),
+            0,
+            0,
+            PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+        )
+        .ok();
+
+        DropBehindReader {
+            file,
+            position: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl Read for DropBehindReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.file.read(buf)?;
+        self.position += len as u64;
+        if self.position - self.dropped >= DROP_BEHIND_SIZE || len == 0 {
+            posix_fadvise(
+                self.file.as_raw_fd(),
+                self.dropped as i64,
+                (self.position - self.dropped) as i64,
+                PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+            )
+            .ok();
+            self.dropped = self.position;
+        }
+
+        Ok(len)
+    }
+}
+
+/// Writes back the unpacked files in batches and drops them from the page cache, so that
+/// unpacking does not fill the memory with dirty pages (and stall everything else waiting
+/// for them to be written back).
+/// A batch is waited for when the next one is full, so the disk is kept busy in the meantime.
+struct WriteBehind {
+    writing: Vec<File>,
+    batch: Vec<File>,
+    batch_size: u64,
+    max_files: usize,
+}
+
+impl WriteBehind {
+    fn new() -> Self {
+        // the soft limit is 1024 by default
+        let max_files = match getrlimit(Resource::RLIMIT_NOFILE) {
+            Ok((Some(soft), _)) => (soft / WRITE_BEHIND_FD_SHARE) as usize,
+            _ => WRITE_BEHIND_FILES,
+        };
+
+        Self {
+            writing: Vec::new(),
+            batch: Vec::new(),
+            batch_size: 0,
+            max_files: max_files.clamp(1, WRITE_BEHIND_FILES),
+        }
+    }
+
+    /// Start writing back the file just unpacked to `path`, errors are ignored (only hints)
+    fn add(&mut self, path: &Path, size: u64) {
+        // the entry has just been unpacked as a regular file, not following what replaced it
+        let file = match fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_NOFOLLOW.bits())
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        unsafe { libc::sync_file_range(file.as_raw_fd(), 0, 0, libc::SYNC_FILE_RANGE_WRITE) };
+        self.batch.push(file);
+        self.batch_size += size;
+        if self.batch.len() >= self.max_files || self.batch_size >= WRITE_BEHIND_BYTES {
+            self.wait();
+            self.writing = std::mem::take(&mut self.batch);
+            self.batch_size = 0;
+        }
+    }
+
+    /// Wait for the previous batch to be written and drop it from the page cache
+    fn wait(&mut self) {
+        for file in self.writing.drain(..) {
+            unsafe {
+                libc::sync_file_range(
+                    file.as_raw_fd(),
+                    0,
+                    0,
+                    libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                        | libc::SYNC_FILE_RANGE_WRITE
+                        | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+                )
+            };
+            posix_fadvise(
+                file.as_raw_fd(),
+                0,
+                0,
+                PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+            )
+            .ok();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.wait();
+        self.writing = std::mem::take(&mut self.batch);
+        self.wait();
+    }
+}
+
+/// Return the path of the tar entry relative to the destination, like `Entry::unpack_in` does
+/// (the root and the `.` components are dropped)
+fn sanitize_entry_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// What is left for root after unpacking an entry as a regular user, who can not set the
+/// owners and the privileged xattrs (e.g. security.capability) nor keep the setuid and setgid
+/// bits when the files are handed over to root
+#[derive(Serialize, Deserialize)]
+struct UnpackFixup {
+    path: Vec<u8>,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Return what is left for root after unpacking the entry as a regular user, if anything
+fn get_unpack_fixup<R: Read>(
+    entry: &mut tar::Entry<'_, R>,
+    path: &Path,
+) -> Result<Option<UnpackFixup>> {
+    let mode = entry.header().mode()?;
+    let uid = entry.header().uid()? as u32;
+    let gid = entry.header().gid()? as u32;
+    let xattrs: Vec<_> = match entry.pax_extensions()? {
+        Some(extensions) => extensions
+            .filter_map(|extension| extension.ok())
+            .filter_map(|extension| {
+                let name = extension.key_bytes().strip_prefix(b"SCHILY.xattr.")?;
+                Some((name.to_vec(), extension.value_bytes().to_vec()))
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    if uid == 0 && gid == 0 && mode & 0o6000 == 0 && xattrs.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(UnpackFixup {
+        path: sanitize_entry_path(path).into_os_string().into_vec(),
+        uid,
+        gid,
+        mode,
+        xattrs,
+    }))
+}
+
+/// Unpack the tar stream into `dest`, showing the file being extracted
+/// (and the bytes unpacked if `count_unpacked` is set).
+/// If `unprivileged` is set, the owners, the xattrs and the setuid and setgid bits are returned
+/// instead,
+/// see `adopt_unprivileged_tree`.
+fn unpack_tar<R: Read>(
+    reader: R,
+    dest: &Path,
+    progress_bar: &ProgressBar,
+    count_unpacked: bool,
+    unprivileged: bool,
+) -> Result<Vec<UnpackFixup>> {
+    let reader = ProgressReader::new(reader, |progress: usize| {
+        if count_unpacked {
+            progress_bar.inc(progress as u64);
+        }
+    });
+    let mut archive = tar::Archive::new(reader);
+    archive.set_unpack_xattrs(!unprivileged);
+    archive.set_preserve_permissions(true);
+    // same as `Archive::unpack`: directories are unpacked last,
+    // so that read-only directories do not block the files inside from being extracted
+    let mut directories = Vec::new();
+    let mut write_behind = WriteBehind::new();
+    let mut fixups = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        let path = entry.path()?.to_path_buf();
+        if unprivileged {
+            fixups.extend(get_unpack_fixup(&mut entry, &path)?);
+        }
+        if entry_type == tar::EntryType::Directory {
+            directories.push(entry);
+            continue;
+        }
+        progress_bar.set_message(path.display().to_string());
+        let size = entry.header().size()?;
+        // false if the path is outside of `dest` (and the entry is skipped)
+        if entry.unpack_in(dest)? && entry_type.is_file() && size > 0 {
+            write_behind.add(&dest.join(sanitize_entry_path(&path)), size);
+        }
+    }
+    progress_bar.set_message("Writing to the disk...");
+    write_behind.finish();
+    progress_bar.set_message("Setting up directories...");
+    for mut dir in directories {
+        dir.unpack_in(dest)?;
+    }
+
+    Ok(fixups)
+}
+
+/// Hand the tree unpacked by the invoking user in `staging` over to root, doing what the user
+/// could not (see `UnpackFixup`), and move it into `dest`
+fn adopt_unprivileged_tree(staging: &Path, fixups: &[UnpackFixup], dest: &Path) -> Result<()> {
+    privsep::chown_tree(staging, Uid::from_raw(0), Gid::from_raw(0))?;
+    for fixup in fixups {
+        let path = staging.join(OsStr::from_bytes(&fixup.path));
+        // skipped by tar (outside of the tree), or replaced by a later symlink
+        match path.parent().map(fs::canonicalize) {
+            Some(Ok(parent)) if parent.starts_with(staging) => (),
+            _ => continue,
+        }
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if fixup.uid != 0 || fixup.gid != 0 {
+            fchownat(
+                None,
+                &path,
+                Some(Uid::from_raw(fixup.uid)),
+                Some(Gid::from_raw(fixup.gid)),
+                FchownatFlags::NoFollowSymlink,
+            )?;
+        }
+        if meta.file_type().is_symlink() {
+            continue;
+        }
+        for (name, value) in &fixup.xattrs {
+            xattr::set(&path, OsStr::from_bytes(name), value)?;
+        }
+        // changing the owner cleared the setuid and setgid bits
+        fs::set_permissions(&path, fs::Permissions::from_mode(fixup.mode & 0o7777))?;
+    }
+    for entry in fs::read_dir(staging)? {
+        let entry = entry?;
+        fs::rename(entry.path(), dest.join(entry.file_name()))?;
+    }
 
     Ok(())
 }
 
-pub fn extract_system_tarball(path: &Path, total: u64) -> Result<()> {
-    let mut f = File::open(path)?;
-    let progress_bar = indicatif::ProgressBar::new(total);
-    progress_bar.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template(make_progress_bar!("Extracting tarball...")),
-    );
-    progress_bar.enable_steady_tick(500);
+/// Reads the chunks produced by another thread
+struct ChannelReader {
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position >= self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                // the producer has finished
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+/// Run `reader` in another thread, which passes what is read through a bounded channel,
+/// so that the stages of the unpacking pipeline run concurrently
+fn spawn_stage<'scope, R: Read + Send + 'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    reader: R,
+) -> ChannelReader {
+    let (sender, receiver) = sync_channel(PIPELINE_DEPTH);
+    scope.spawn(move || {
+        let mut reader = reader;
+        loop {
+            let mut chunk = vec![0u8; PIPELINE_CHUNK_SIZE];
+            let chunk = match reader.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(len) => {
+                    chunk.truncate(len);
+                    Ok(chunk)
+                }
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            // the receiver is gone if the next stage failed
+            if sender.send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    ChannelReader {
+        receiver,
+        chunk: Vec::new(),
+        position: 0,
+    }
+}
+
+/// Unpack the tarball read from `reader` in a pipeline: reading, decompressing (with the
+/// decoder created by `decoder`) and unpacking are done by different threads
+fn unpack_tar_threaded<R, D, F>(
+    reader: R,
+    decoder: F,
+    dest: &Path,
+    progress_bar: &ProgressBar,
+    count_unpacked: bool,
+    unprivileged: bool,
+) -> Result<Vec<UnpackFixup>>
+where
+    R: Read + Send,
+    D: Read + Send,
+    F: FnOnce(ChannelReader) -> D,
+{
+    thread::scope(|s| {
+        let compressed = spawn_stage(s, reader);
+        let decompressed = spawn_stage(s, decoder(compressed));
+
+        unpack_tar(
+            decompressed,
+            dest,
+            progress_bar,
+            count_unpacked,
+            unprivileged,
+        )
+    })
+}
+
+/// Decompress the stream with an external (multi-threaded) decompressor and unpack the output
+fn unpack_tar_with<R: Read + Send>(
+    reader: R,
+    decompressor: &[&str],
+    dest: &Path,
+    progress_bar: &ProgressBar,
+    count_unpacked: bool,
+    unprivileged: bool,
+) -> Result<Vec<UnpackFixup>> {
+    let mut child = Command::new(decompressor[0])
+        .args(&decompressor[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Unable to run {}: {}", decompressor[0], e))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let result = thread::scope(|s| {
+        let feeder = s.spawn(move || {
+            let mut reader = reader;
+            std::io::copy(&mut reader, &mut stdin)
+        });
+        // drain the padding after the end of the archive, or the decompressor gets SIGPIPE
+        let result = unpack_tar(
+            &mut stdout,
+            dest,
+            progress_bar,
+            count_unpacked,
+            unprivileged,
+        )
+        .and_then(|fixups| {
+            std::io::copy(&mut stdout, &mut std::io::sink())?;
+            Ok(fixups)
+        });
+        drop(stdout);
+        // the error of the feeder (e.g. broken pipe) is only interesting if unpacking succeeded
+        match feeder.join() {
+            Ok(Err(e)) if result.is_ok() => Err(e.into()),
+            _ => result,
+        }
+    });
+    let status = child.wait()?;
+    let fixups = result?;
+    if !status.success() {
+        return Err(anyhow!("{} exited with {}", decompressor[0], status));
+    }
+
+    Ok(fixups)
+}
+
+/// Get the size of the tar stream inside the compressed file, if it is recorded
+fn get_uncompressed_size(path: &Path, format: TarballFormat) -> Option<u64> {
+    match format {
+        TarballFormat::Tar => fs::metadata(path).ok().map(|m| m.len()),
+        // totals <streams> <blocks> <compressed> <uncompressed> ...
+        TarballFormat::Xz => {
+            let output = Command::new("xz")
+                .args(&["--robot", "--list"])
+                .arg(path)
+                .output()
+                .ok()?;
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find(|line| line.starts_with("totals\t"))?
+                .split('\t')
+                .nth(4)?
+                .parse()
+                .ok()
+        }
+        // the gzip trailer only records the size modulo 2^32, which a base system exceeds
+        _ => None,
+    }
+}
+
+/// Extract the SquashFS image with `unsquashfs` (which preserves the xattrs by default)
+fn extract_squashfs(path: &Path, dest: &Path) -> Result<()> {
+    let spinner = progress::spinner("Extracting SquashFS image...");
+    let status = Command::new("unsquashfs")
+        .args(&["-no-progress", "-force", "-dest"])
+        .arg(dest)
+        .arg(path)
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| anyhow!("Unable to run unsquashfs: {}", e));
+    spinner.finish_and_clear();
+    let status = status?;
+    if !status.success() {
+        return Err(anyhow!("unsquashfs exited with {}", status));
+    }
+
+    Ok(())
+}
+
+/// Unpack the (compressed) tarball `f` into `dest`, see `unpack_tar`.
+/// `uncompressed` is the size of the tar stream if known, `total` the size of the file
+fn unpack_tarball(
+    f: File,
+    format: TarballFormat,
+    uncompressed: Option<u64>,
+    total: u64,
+    dest: &Path,
+    unprivileged: bool,
+) -> Result<Vec<UnpackFixup>> {
+    let mut f = DropBehindReader::new(f);
+    // count the bytes unpacked if the total is known, or the compressed bytes read otherwise
+    let count_unpacked = uncompressed.is_some();
+    let progress_bar = progress::bytes_bar(uncompressed.unwrap_or(total), "Extracting tarball...");
     let reader = ProgressReader::new(&mut f, |progress: usize| {
-        progress_bar.inc(progress as u64);
+        if !count_unpacked {
+            progress_bar.inc(progress as u64);
+        }
     });
-    extract_tar_xz(reader, &PathBuf::from(CIEL_DIST_DIR))?;
+    let bar = &progress_bar;
+    let result = match format {
+        TarballFormat::Xz if which::which("xz").is_ok() => unpack_tar_with(
+            reader,
+            &["xz", "-dcq", "-T0"],
+            dest,
+            bar,
+            count_unpacked,
+            unprivileged,
+        ),
+        TarballFormat::Xz => unpack_tar_threaded(
+            reader,
+            xz2::read::XzDecoder::new,
+            dest,
+            bar,
+            count_unpacked,
+            unprivileged,
+        ),
+        TarballFormat::Zstd => unpack_tar_with(
+            reader,
+            &["zstd", "-dcq", "-T0"],
+            dest,
+            bar,
+            count_unpacked,
+            unprivileged,
+        ),
+        TarballFormat::Gzip if which::which("pigz").is_ok() => unpack_tar_with(
+            reader,
+            &["pigz", "-dc"],
+            dest,
+            bar,
+            count_unpacked,
+            unprivileged,
+        ),
+        TarballFormat::Gzip => unpack_tar_threaded(
+            reader,
+            flate2::read::MultiGzDecoder::new,
+            dest,
+            bar,
+            count_unpacked,
+            unprivileged,
+        ),
+        _ => thread::scope(|s| {
+            unpack_tar(
+                spawn_stage(s, reader),
+                dest,
+                bar,
+                count_unpacked,
+                unprivileged,
+            )
+        }),
+    };
     progress_bar.finish_and_clear();
 
+    result
+}
+
+/// Extract the base system tarball, showing the file being extracted.
+/// The format (xz, zstd, gzip, uncompressed or SquashFS) is detected automatically.
+pub fn extract_system_tarball(path: &Path, total: u64) -> Result<()> {
+    let mut f = File::open(path)?;
+    let mut header = [0u8; 512];
+    let len = f.read(&mut header)?;
+    f.seek(SeekFrom::Start(0))?;
+    let format = detect_tarball_format(&header[..len])
+        .ok_or_else(|| anyhow!("{} is not a supported tarball", path.display()))?;
+    debug!("Detected tarball format: {:?}", format);
+    let uncompressed = get_uncompressed_size(path, format);
+    fs::create_dir_all(CIEL_DIST_DIR)?;
+    ensure_free_space(
+        CIEL_DIST_DIR,
+        uncompressed.unwrap_or(total * TARBALL_EXPANSION_RATIO),
+        "extracting the tarball",
+    )?;
+    let dest = fs::canonicalize(CIEL_DIST_DIR)?;
+    let start = Instant::now();
+    if format == TarballFormat::Squashfs {
+        extract_squashfs(path, &dest)?;
+    } else {
+        match privsep::invoking_user_ids() {
+            // parsed by the invoking user, the unpacked tree is handed over to root afterwards
+            Some((uid, gid)) => {
+                let staging = privsep::private_temp_dir(dest.parent().unwrap_or(&dest), uid, gid)?;
+                let fixups = privsep::run_unprivileged("tarball extraction", || {
+                    unpack_tarball(f, format, uncompressed, total, staging.path(), true)
+                })?;
+                adopt_unprivileged_tree(staging.path(), &fixups, &dest)?;
+            }
+            None => {
+                unpack_tarball(f, format, uncompressed, total, &dest, false)?;
+            }
+        }
+    }
+    info!(
+        "Tarball extracted in {}.",
+        format_duration(start.elapsed().as_secs())
+    );
+
+    Ok(())
+}
+
+/// Populate the base system from an existing root filesystem directory,
+/// with rsync if available (or `cp -a` otherwise), preserving hardlinks, ACLs and xattrs
+pub fn copy_system_rootfs(source: &Path) -> Result<()> {
+    if !source.is_dir() {
+        return Err(anyhow!("{} is not a directory", source.display()));
+    }
+    if !source.join("etc/os-release").is_file() {
+        warn!(
+            "{} does not look like a root filesystem (missing etc/os-release).",
+            source.display()
+        );
+    }
+    fs::create_dir_all(CIEL_DIST_DIR)?;
+    ensure_free_space(
+        CIEL_DIST_DIR,
+        get_dir_size(source),
+        "copying the root filesystem",
+    )?;
+    let start = Instant::now();
+    let spinner = progress::spinner("Copying root filesystem...");
+    // the trailing slash makes rsync copy the contents instead of the directory itself
+    let status = if which::which("rsync").is_ok() {
+        Command::new("rsync")
+            .args(&["-aHAX", "--numeric-ids", "--delete"])
+            .arg(format!("{}/", source.display()))
+            .arg(CIEL_DIST_DIR)
+            .status()
+    } else {
+        Command::new("cp")
+            .args(&["-a", "--no-target-directory"])
+            .arg(source)
+            .arg(CIEL_DIST_DIR)
+            .status()
+    }
+    .map_err(|e| anyhow!("Unable to copy the root filesystem: {}", e));
+    spinner.finish_and_clear();
+    let status = status?;
+    if !status.success() {
+        return Err(anyhow!(
+            "Unable to copy the root filesystem: exited with {}",
+            status
+        ));
+    }
+    info!(
+        "Root filesystem copied in {}.",
+        format_duration(start.elapsed().as_secs())
+    );
+
     Ok(())
 }
 
+/// Format the duration as `HH:MM:SS`
+#[inline]
+pub fn format_duration(seconds: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
 pub fn ciel_init() -> Result<()> {
+    ensure_local_filesystem(".")?;
     for dir in SKELETON_DIRS {
         fs::create_dir_all(dir)?;
     }
-    let mut f = File::create(".ciel/version")?;
-    f.write_all(CURRENT_CIEL_VERSION_STR.as_bytes())?;
+    write_workspace_version(CURRENT_CIEL_VERSION)
+}
+
+/// Read the version of the workspace layout
+pub fn read_workspace_version() -> Result<usize> {
+    let version = fs::read_to_string(CIEL_VERSION_FILE)?;
+
+    version
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("Invalid workspace version: {:?}", version.trim()))
+}
+
+#[inline]
+pub fn write_workspace_version(version: usize) -> Result<()> {
+    let mut f = File::create(CIEL_VERSION_FILE)?;
+    f.write_all(version.to_string().as_bytes())?;
 
     Ok(())
 }
 
-/// Find the ciel directory
-pub fn find_ciel_dir<P: AsRef<Path>>(start: P) -> Result<PathBuf> {
+/// Find the ciel directory, searching upwards at most `max_depth` levels
+/// (until the filesystem boundary if not specified)
+pub fn find_ciel_dir<P: AsRef<Path>>(start: P, max_depth: Option<usize>) -> Result<PathBuf> {
     let start_path = fs::metadata(start.as_ref())?;
     let start_dev = start_path.dev();
     let mut current_dir = start.as_ref().to_path_buf();
+    let mut depth = 0;
     loop {
         if !current_dir.exists() {
             return Err(anyhow!("Hit filesystem ceiling!"));
@@ -105,19 +899,132 @@ pub fn find_ciel_dir<P: AsRef<Path>>(start: P) -> Result<PathBuf> {
         if current_dir.join(".ciel").is_dir() {
             return Ok(current_dir);
         }
+        if current_dir.canonicalize()? == Path::new("/") {
+            return Err(anyhow!("Hit filesystem ceiling!"));
+        }
+        if let Some(max_depth) = max_depth {
+            if depth >= max_depth {
+                return Err(anyhow!(
+                    "No Ciel workspace found within {} levels up",
+                    max_depth
+                ));
+            }
+        }
+        depth += 1;
         current_dir = current_dir.join("..");
     }
 }
 
 pub fn is_instance_exists(instance: &str) -> bool {
-    Path::new(CIEL_INST_DIR).join(instance).is_dir()
+    // absolute paths and `..` would point outside of the instance directory
+    !instance.is_empty()
+        && !instance.contains('/')
+        && !instance.contains("..")
+        && Path::new(CIEL_INST_DIR).join(instance).is_dir()
 }
 
 pub fn is_legacy_workspace() -> Result<bool> {
-    let mut f = fs::File::open(".ciel/version")?;
-    // TODO: use a more robust check
-    let mut buf = [0u8; 1];
-    f.read_exact(&mut buf)?;
+    Ok(read_workspace_version()? < CURRENT_CIEL_VERSION)
+}
+
+#[test]
+fn test_time_format() {
+    let test_dur = 3661;
+    assert_eq!(format_duration(test_dur), "01:01:01");
+}
+
+#[test]
+fn test_network_filesystem_name() {
+    assert_eq!(network_filesystem_name(0x6969), Some("NFS"));
+    assert_eq!(network_filesystem_name(0xff53_4d42), Some("CIFS"));
+    // ext4
+    assert_eq!(network_filesystem_name(0xef53), None);
+}
+
+#[test]
+fn test_detect_tarball_format() {
+    assert_eq!(
+        detect_tarball_format(b"\xfd7zXZ\x00\x00\x04"),
+        Some(TarballFormat::Xz)
+    );
+    assert_eq!(
+        detect_tarball_format(b"\x28\xb5\x2f\xfd\x04"),
+        Some(TarballFormat::Zstd)
+    );
+    assert_eq!(
+        detect_tarball_format(b"hsqs\x00"),
+        Some(TarballFormat::Squashfs)
+    );
+    assert_eq!(detect_tarball_format(b"PK\x03\x04"), None);
+}
+
+#[test]
+fn test_remove_dir_within() {
+    let root = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    fs::create_dir_all(outside.path().join("diff/usr")).unwrap();
+    fs::create_dir_all(root.path().join("inst/layers")).unwrap();
+    std::os::unix::fs::symlink(outside.path(), root.path().join("inst/escape")).unwrap();
+    assert!(remove_dir_within(root.path(), &root.path().join("inst/escape")).is_err());
+    assert!(remove_dir_within(root.path(), &root.path().join("inst/escape/diff")).is_err());
+    assert!(remove_dir_within(root.path(), root.path()).is_err());
+    assert!(outside.path().join("diff/usr").is_dir());
+    remove_dir_within(root.path(), &root.path().join("inst")).unwrap();
+    assert!(!root.path().join("inst").exists());
+    assert!(outside.path().join("diff/usr").is_dir());
+}
+
+#[test]
+fn test_is_instance_exists() {
+    // existing directories outside of the workspace are not instances
+    assert!(!is_instance_exists("/tmp"));
+    assert!(!is_instance_exists("../../../tmp"));
+    assert!(!is_instance_exists(".."));
+    assert!(!is_instance_exists(""));
+}
 
-    Ok(buf[0] < CURRENT_CIEL_VERSION_STR.as_bytes()[0])
+#[test]
+fn test_sanitize_entry_path() {
+    assert_eq!(
+        sanitize_entry_path(Path::new("/usr/bin/bash")),
+        Path::new("usr/bin/bash")
+    );
+    assert_eq!(
+        sanitize_entry_path(Path::new("./usr/./lib")),
+        Path::new("usr/lib")
+    );
+    assert_eq!(sanitize_entry_path(Path::new("etc")), Path::new("etc"));
+}
+
+#[test]
+fn test_adopt_unprivileged_tree() {
+    // handing the tree over to other users needs root
+    if !Uid::effective().is_root() {
+        return;
+    }
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(4);
+    header.set_uid(0);
+    header.set_gid(42);
+    header.set_mode(0o2755);
+    builder
+        .append_data(&mut header, "usr/bin/chage", &b"test"[..])
+        .unwrap();
+    let tarball = builder.into_inner().unwrap();
+    let staging = tempfile::tempdir().unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let fixups = unpack_tar(
+        &tarball[..],
+        staging.path(),
+        &ProgressBar::hidden(),
+        false,
+        true,
+    )
+    .unwrap();
+    adopt_unprivileged_tree(staging.path(), &fixups, dest.path()).unwrap();
+    let meta = fs::metadata(dest.path().join("usr/bin/chage")).unwrap();
+    assert_eq!((meta.uid(), meta.gid()), (0, 42));
+    assert_eq!(meta.mode() & 0o7777, 0o2755);
 }
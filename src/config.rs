@@ -1,23 +1,37 @@
 //! This module contains configuration files related APIs
 
-use crate::common::CURRENT_CIEL_VERSION;
+use crate::cli::is_builtin_command;
+use crate::common::{find_ciel_dir, is_interactive, CIEL_INST_DIR, CURRENT_CIEL_VERSION};
 use crate::info;
+use crate::network::normalize_arch_name;
+use crate::webhook;
 use anyhow::{anyhow, Result};
-use console::{style, user_attended};
 use dialoguer::{theme::ColorfulTheme, Confirm, Editor, Input};
 use serde::{Deserialize, Serialize};
-use std::{ffi::OsString, path::Path};
 use std::{
+    collections::BTreeMap,
     fs,
-    io::{Read, Write},
+    io::{self, Read, Write},
+};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-const DEFAULT_CONFIG_LOCATION: &str = ".ciel/data/config.toml";
+pub const DEFAULT_CONFIG_LOCATION: &str = ".ciel/data/config.toml";
+pub const INSTANCE_CONFIG_NAME: &str = "config.toml";
+pub const SYSTEM_CONFIG_LOCATION: &str = "/etc/ciel/config.toml";
+// relative to the XDG configuration directory (usually `~/.config`)
+const USER_CONFIG_LOCATION: &str = "ciel/workspaces.toml";
 const DEFAULT_APT_SOURCE: &str = "deb https://repo.aosc.io/debs/ stable main";
 const DEFAULT_AB3_CONFIG_LOCATION: &str = "usr/lib/autobuild3/etc/autobuild/ab3cfg.sh";
 const DEFAULT_APT_LIST_LOCATION: &str = "etc/apt/sources.list";
 const DEFAULT_RESOLV_LOCATION: &str = "etc/systemd/resolved.conf";
 const DEFAULT_ACBS_CONFIG: &str = "etc/acbs/forest.conf";
+const DEFAULT_APT_PROXY_LOCATION: &str = "etc/apt/apt.conf.d/99ciel-proxy";
+const EXTRA_APT_LIST_LOCATION: &str = "etc/apt/sources.list.d/ciel-extra.list";
+const APT_KEYRING_DIR: &str = "etc/apt/trusted.gpg.d";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CielConfig {
@@ -31,17 +45,447 @@ pub struct CielConfig {
     pub extra_options: Vec<String>,
     #[serde(rename = "branch-exclusive-output")]
     pub sep_mount: bool,
+    /// Also use different output directories for different architectures
+    #[serde(rename = "arch-exclusive-output", default)]
+    pub sep_arch: bool,
     #[serde(rename = "volatile-mount", default)]
     pub volatile_mount: bool,
+    #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(rename = "sources-cache", default)]
+    pub sources_cache: Option<String>,
+    #[serde(rename = "env-passthrough", default)]
+    pub env_passthrough: Vec<String>,
+    #[serde(rename = "build-nocheck", default)]
+    pub build_nocheck: bool,
+    #[serde(rename = "build-debug", default)]
+    pub build_debug: bool,
+    #[serde(rename = "build-jobs", default)]
+    pub build_jobs: Option<usize>,
+    /// Keep TREE unchanged by the builds (the changes go to a scratch overlay, which is discarded)
+    #[serde(rename = "tree-read-only", default)]
+    pub tree_read_only: bool,
+    /// Only move the new packages into the output directory after the builds,
+    /// so that the builds can not remove or modify the others
+    #[serde(rename = "output-staging", default)]
+    pub output_staging: bool,
+    /// Let the staged builds replace the existing packages of different content
+    #[serde(rename = "output-replace", default)]
+    pub output_replace: bool,
+    #[serde(default)]
+    pub backend: ContainerBackend,
+    #[serde(rename = "log-format", default)]
+    pub log_format: LogFormat,
+    /// Prefix the log messages with timestamps
+    #[serde(rename = "log-timestamps", default)]
+    pub log_timestamps: bool,
+    /// Per-module log levels, e.g. `overlayfs=debug` (overridden by `CIEL_LOG`)
+    #[serde(rename = "log-filter", default)]
+    pub log_filter: Option<String>,
+    /// Forward the log messages to `syslog://host[:port]`, `tcp://host:port` or `http(s)://...`
+    #[serde(rename = "log-forward", default)]
+    pub log_forward: Option<String>,
+    /// Days to keep the deleted instances in the trash (0 to delete them immediately)
+    #[serde(rename = "trash-retention", default = "default_trash_retention")]
+    pub trash_retention: u64,
+    /// The commands run (in order) by `update-os` and before building,
+    /// e.g. to use oma or opt into topics (the apt-get procedure is used if empty)
+    #[serde(rename = "update-commands", default)]
+    pub update_commands: Vec<String>,
+    /// Packages of the cross toolchain installed by `cross-setup` (`{arch}` is replaced
+    /// by the target architecture, the AOSC OS toolchain packages are used if empty)
+    #[serde(rename = "cross-packages", default)]
+    pub cross_packages: Vec<String>,
+    /// Build settings for the instances of the architecture, e.g. `[arch-profiles.riscv64]`
+    #[serde(rename = "arch-profiles", default)]
+    pub arch_profiles: BTreeMap<String, ArchProfile>,
+    /// Named system call filters for the instances (`seccomp-profile` of the instance),
+    /// e.g. `strict = ["~@debug", "~personality"]`
+    #[serde(rename = "seccomp-profiles", default)]
+    pub seccomp_profiles: BTreeMap<String, Vec<String>>,
+    /// How to tell that a build is finished, e.g. `[notify]`
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    /// Network access of the builds, e.g. `[build-network]`
+    #[serde(rename = "build-network", default)]
+    pub build_network: BuildNetworkConfig,
+    /// SELinux/AppArmor labels of the instances, e.g. `[mac]`
+    #[serde(default)]
+    pub mac: MacConfig,
+    /// HTTP endpoints notified of the builds and commits, e.g. `[[webhooks]]`
+    // an empty array would be emitted after the tables, which TOML does not allow
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<Webhook>,
+    /// Other machines to build on (`ciel build --on NAME`), managed by `ciel remote`
+    #[serde(default)]
+    pub remotes: BTreeMap<String, RemoteBuilder>,
+    /// User-defined subcommands, e.g. `rebuild = "build --resume last"`
+    #[serde(default)]
+    pub alias: BTreeMap<String, String>,
+}
+
+/// Build settings applied when building in an instance of the architecture
+/// (the global settings are used for what is not set)
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ArchProfile {
+    #[serde(rename = "build-jobs", default)]
+    pub build_jobs: Option<usize>,
+    #[serde(rename = "build-nocheck", default)]
+    pub build_nocheck: Option<bool>,
+    /// Skip the tests only if the instance is emulated with qemu-user
+    #[serde(rename = "nocheck-emulated", default)]
+    pub nocheck_emulated: bool,
+    /// Extra environment variables for the builds
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// Notifications sent when a build is finished
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotifyConfig {
+    /// Ring the terminal bell
+    #[serde(default = "default_true")]
+    pub bell: bool,
+    /// Show a desktop notification (through `org.freedesktop.Notifications` on the session bus)
+    #[serde(default)]
+    pub desktop: bool,
+    /// Shell command run with the result in the `CIEL_BUILD_*` variables,
+    /// e.g. `notify-send ciel "$CIEL_BUILD_MESSAGE"`
+    #[serde(default)]
+    pub command: Option<String>,
+    /// Only notify of the failed builds
+    #[serde(rename = "failure-only", default)]
+    pub failure_only: bool,
+}
+
+/// Network access of the builds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildNetworkConfig {
+    #[serde(default)]
+    pub policy: NetworkPolicy,
+    /// Hosts reachable with the `restricted` policy (`*.example.org` for the subdomains),
+    /// the mirrors in the APT sources are always allowed
+    #[serde(rename = "allowed-hosts", default)]
+    pub allowed_hosts: Vec<String>,
+    /// Ports reachable on the allowed hosts, the ports of the mirrors are always allowed
+    #[serde(rename = "allowed-ports", default = "default_allowed_ports")]
+    pub allowed_ports: Vec<u16>,
+}
+
+/// Mandatory access control labels applied to the instances (for the hosts enforcing
+/// SELinux or AppArmor), nothing is labeled if not set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MacConfig {
+    /// SELinux context of the container processes, e.g. `system_u:system_r:container_t:s0`
+    #[serde(rename = "selinux-context", default)]
+    pub selinux_context: Option<String>,
+    /// SELinux context of the instance filesystems (and the API filesystems in the containers),
+    /// e.g. `system_u:object_r:container_file_t:s0`
+    #[serde(rename = "selinux-file-context", default)]
+    pub selinux_file_context: Option<String>,
+    /// AppArmor profile the containers are started under (it must be loaded, and allow running
+    /// systemd-nspawn or bwrap)
+    #[serde(rename = "apparmor-profile", default)]
+    pub apparmor_profile: Option<String>,
+}
+
+/// What the builds are allowed to reach on the network
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkPolicy {
+    /// As configured for the instance
+    #[default]
+    Open,
+    /// Nothing, the sources are fetched before the build (same as `--offline`)
+    Offline,
+    /// Only the allowed hosts, through a forwarding proxy (the `proxy` is used upstream)
+    Restricted,
+}
+
+impl FromStr for NetworkPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "open" => Ok(NetworkPolicy::Open),
+            "offline" => Ok(NetworkPolicy::Offline),
+            "restricted" => Ok(NetworkPolicy::Restricted),
+            _ => Err(anyhow!("Unknown network policy: {}", s)),
+        }
+    }
 }
 
+impl std::fmt::Display for NetworkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            NetworkPolicy::Open => "open",
+            NetworkPolicy::Offline => "offline",
+            NetworkPolicy::Restricted => "restricted",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// An HTTP endpoint notified (with a POST request) of the lifecycle events
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// The events to notify of (see [`WEBHOOK_EVENTS`]), all of them if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Body of the request, `{field}` is replaced by the field of the event
+    /// (e.g. `{packages}` or `{log_excerpt}`), the event is posted as JSON if not set
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(rename = "content-type", default)]
+    pub content_type: Option<String>,
+}
+
+/// A ciel workspace on another machine, reached with SSH
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RemoteBuilder {
+    /// SSH destination, e.g. `root@builder` or a host in `~/.ssh/config`
+    pub host: String,
+    /// Path to the workspace on the remote machine (the login directory if not set)
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Instance to build in (unless specified with `-i`)
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Copy the local TREE to the remote workspace before building
+    #[serde(rename = "sync-tree", default)]
+    pub sync_tree: bool,
+    /// How to run ciel on the remote machine, e.g. `sudo ciel`
+    #[serde(default)]
+    pub command: Option<String>,
+}
+
+/// The events that can be sent to the webhooks
+pub const WEBHOOK_EVENTS: &[&str] = &[
+    "build-started",
+    "build-succeeded",
+    "build-failed",
+    "instance-committed",
+];
+
 impl CielConfig {
     pub fn save_config(&self) -> Result<String> {
         Ok(toml::to_string(self)?)
     }
 
+    /// Return the (HTTP/HTTPS) mirrors used in the APT sources
+    pub fn get_mirror_urls(&self) -> Vec<String> {
+        let mut urls: Vec<String> = Vec::new();
+        for line in self.apt_sources.lines().map(str::trim) {
+            if !line.starts_with("deb") {
+                continue;
+            }
+            let uri = line
+                .split_whitespace()
+                .find(|part| part.starts_with("http://") || part.starts_with("https://"));
+            if let Some(uri) = uri {
+                if !urls.iter().any(|u| u == uri) {
+                    urls.push(uri.to_string());
+                }
+            }
+        }
+
+        urls
+    }
+
+    /// Load the configuration, missing values are inherited from the system-wide defaults
     pub fn load_config(data: &[u8]) -> Result<CielConfig> {
-        Ok(toml::from_slice(data)?)
+        let mut config = toml::Value::try_from(CielConfig::default())?;
+        if let Ok(data) = fs::read(SYSTEM_CONFIG_LOCATION) {
+            let mut defaults: toml::Value = toml::from_slice(&data)
+                .map_err(|e| anyhow!("Invalid {}: {}", SYSTEM_CONFIG_LOCATION, e))?;
+            if let Some(defaults) = defaults.as_table_mut() {
+                // the version number is always decided by the workspace
+                defaults.remove("version");
+            }
+            merge_config(&mut config, defaults);
+        }
+        merge_config(&mut config, toml::from_slice(data)?);
+
+        Ok(config.try_into()?)
+    }
+
+    /// Return the system-wide defaults (from /etc/ciel/config.toml)
+    pub fn system_defaults() -> Result<CielConfig> {
+        Self::load_config(b"")
+    }
+}
+
+/// The container backend used for running the instances
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerBackend {
+    /// Use systemd-nspawn if the host is running systemd, bwrap otherwise
+    #[default]
+    Auto,
+    Nspawn,
+    /// Lightweight chroot-like containers using bubblewrap, does not require systemd
+    Bwrap,
+}
+
+/// Format of the log messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Network mode of the container
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// Share the network with the host
+    #[default]
+    Host,
+    /// Private network, connected to the host with a veth link
+    Private,
+    /// No network access at all
+    None,
+}
+
+impl FromStr for NetworkMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "host" => Ok(NetworkMode::Host),
+            "private" => Ok(NetworkMode::Private),
+            "none" => Ok(NetworkMode::None),
+            _ => Err(anyhow!("Unknown network mode: {}", s)),
+        }
+    }
+}
+
+/// Per-instance configuration, stored inside the instance directory
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstanceConfig {
+    /// Boot the container with systemd (otherwise commands are run in a lightweight container)
+    #[serde(default = "default_true")]
+    pub boot: bool,
+    #[serde(default)]
+    pub network: NetworkMode,
+    /// Port forwarding rules (`[tcp|udp:]HOSTPORT[:CONTAINERPORT]`), private network only
+    #[serde(default)]
+    pub publish: Vec<String>,
+    /// Extra capabilities granted to the container (e.g. `CAP_SYS_ADMIN`)
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Capabilities removed from the container
+    #[serde(rename = "drop-capabilities", default)]
+    pub drop_capabilities: Vec<String>,
+    /// System call filter entries (`[~]SYSCALL` or `[~]@GROUP`), see systemd-nspawn(1)
+    #[serde(rename = "system-call-filter", default)]
+    pub system_call_filter: Vec<String>,
+    /// Name of the system call filter profile applied before `system-call-filter`
+    /// (`default`, shipped by ciel, or one of `seccomp-profiles` in the workspace config)
+    #[serde(rename = "seccomp-profile", default)]
+    pub seccomp_profile: Option<String>,
+    /// Architecture of the instance, recorded when it is created
+    /// (its binaries are run with qemu-user if it is foreign)
+    #[serde(default)]
+    pub arch: Option<String>,
+    /// What the instance is used for, shown by `ciel list`
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Target architecture of the cross toolchain set up in the instance
+    #[serde(default)]
+    pub cross: Option<String>,
+}
+
+impl Default for InstanceConfig {
+    fn default() -> Self {
+        InstanceConfig {
+            boot: true,
+            network: NetworkMode::default(),
+            publish: Vec::new(),
+            capabilities: Vec::new(),
+            drop_capabilities: Vec::new(),
+            system_call_filter: Vec::new(),
+            seccomp_profile: None,
+            arch: None,
+            description: None,
+            cross: None,
+        }
+    }
+}
+
+impl InstanceConfig {
+    /// Load the configuration of the instance, returns the defaults if the instance is not configured
+    pub fn load(instance: &str) -> Result<InstanceConfig> {
+        let path = Path::new(CIEL_INST_DIR)
+            .join(instance)
+            .join(INSTANCE_CONFIG_NAME);
+        match fs::read(&path) {
+            Ok(data) => Ok(toml::from_slice(&data)
+                .map_err(|e| anyhow!("Invalid {}: {}", path.display(), e))?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(InstanceConfig::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the configuration of the instance
+    pub fn save(&self, instance: &str) -> Result<()> {
+        fs::write(
+            Path::new(CIEL_INST_DIR)
+                .join(instance)
+                .join(INSTANCE_CONFIG_NAME),
+            toml::to_string(self)?,
+        )?;
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn default_true() -> bool {
+    true
+}
+
+#[inline]
+fn default_trash_retention() -> u64 {
+    7
+}
+
+#[inline]
+fn default_allowed_ports() -> Vec<u16> {
+    vec![80, 443]
+}
+
+/// Merge the TOML value `overlay` into `base`, tables are merged recursively
+fn merge_config(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                if let Some(base_value) = base.get_mut(&key) {
+                    merge_config(base_value, value);
+                } else {
+                    base.insert(key, value);
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
     }
 }
 
@@ -56,7 +500,54 @@ impl Default for CielConfig {
             local_sources: true,
             extra_options: Vec::new(),
             sep_mount: true,
+            sep_arch: false,
             volatile_mount: false,
+            proxy: None,
+            sources_cache: None,
+            env_passthrough: Vec::new(),
+            build_nocheck: false,
+            build_debug: false,
+            build_jobs: None,
+            tree_read_only: false,
+            output_staging: false,
+            output_replace: false,
+            backend: ContainerBackend::default(),
+            log_format: LogFormat::default(),
+            log_timestamps: false,
+            log_filter: None,
+            log_forward: None,
+            trash_retention: default_trash_retention(),
+            update_commands: Vec::new(),
+            cross_packages: Vec::new(),
+            arch_profiles: BTreeMap::new(),
+            seccomp_profiles: BTreeMap::new(),
+            notify: NotifyConfig::default(),
+            build_network: BuildNetworkConfig::default(),
+            mac: MacConfig::default(),
+            webhooks: Vec::new(),
+            remotes: BTreeMap::new(),
+            alias: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            bell: true,
+            desktop: false,
+            command: None,
+            failure_only: false,
+        }
+    }
+}
+
+impl Default for BuildNetworkConfig {
+    fn default() -> Self {
+        BuildNetworkConfig {
+            policy: NetworkPolicy::default(),
+            allowed_hosts: Vec::new(),
+            allowed_ports: default_allowed_ports(),
         }
     }
 }
@@ -111,6 +602,314 @@ fn validate_maintainer(maintainer: &String) -> Result<(), String> {
     Err("Invalid format.".to_owned())
 }
 
+/// Check the syntax of the (one-line-style) sources.list
+fn validate_apt_sources(sources: &str) -> Result<(), String> {
+    for (lineno, line) in sources.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let lineno = lineno + 1;
+        let (kind, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if kind != "deb" && kind != "deb-src" {
+            return Err(format!(
+                "sources.list line {}: expected `deb` or `deb-src`, found `{}`.",
+                lineno, kind
+            ));
+        }
+        let mut rest = rest.trim_start();
+        if rest.starts_with('[') {
+            let end = rest.find(']').ok_or_else(|| {
+                format!("sources.list line {}: unterminated option list.", lineno)
+            })?;
+            rest = &rest[end + 1..];
+        }
+        let mut parts = rest.split_whitespace();
+        let uri = parts.next().unwrap_or("");
+        let scheme = uri.split_once(':').map(|(scheme, _)| scheme).unwrap_or("");
+        if scheme.is_empty()
+            || !scheme
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'+' || c == b'-')
+        {
+            return Err(format!(
+                "sources.list line {}: `{}` is not a valid URI.",
+                lineno, uri
+            ));
+        }
+        let suite = parts
+            .next()
+            .ok_or_else(|| format!("sources.list line {}: missing suite.", lineno))?;
+        let has_components = parts.next().is_some();
+        if suite.ends_with('/') && has_components {
+            return Err(format!(
+                "sources.list line {}: components are not allowed with a flat repository.",
+                lineno
+            ));
+        } else if !suite.ends_with('/') && !has_components {
+            return Err(format!(
+                "sources.list line {}: missing components (e.g. `main`).",
+                lineno
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check if the repositories in the sources.list have their signing keys installed
+fn validate_apt_keys(rootfs: &Path, sources: &str) -> Result<(), String> {
+    let has_keyring = rootfs.join("etc/apt/trusted.gpg").is_file()
+        || fs::read_dir(rootfs.join("etc/apt/trusted.gpg.d"))
+            .map(|mut d| d.next().is_some())
+            .unwrap_or(false);
+    for line in sources.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let options = line
+            .split_once('[')
+            .and_then(|(_, rest)| rest.split_once(']'))
+            .map(|(options, _)| options)
+            .unwrap_or("");
+        if options.split_whitespace().any(|o| o == "trusted=yes") {
+            continue;
+        }
+        if let Some(key) = options
+            .split_whitespace()
+            .find_map(|o| o.strip_prefix("signed-by="))
+        {
+            if !rootfs.join(key.trim_start_matches('/')).is_file() {
+                return Err(format!(
+                    "Signing key `{}` is not installed in the base system.",
+                    key
+                ));
+            }
+            continue;
+        }
+        if !has_keyring {
+            return Err(format!(
+                "No APT signing keys are installed in the base system for `{}`.",
+                line
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the configuration (and the paths it references) for problems,
+/// returns a list of human-readable problem descriptions
+pub fn validate_config(config: &CielConfig, rootfs: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+    if config.version != CURRENT_CIEL_VERSION {
+        problems.push(format!(
+            "Configuration version is {}, but {} is expected. Try `ciel init --upgrade`.",
+            config.version, CURRENT_CIEL_VERSION
+        ));
+    }
+    if let Err(e) = validate_maintainer(&config.maintainer) {
+        problems.push(format!(
+            "Maintainer `{}` is invalid: {} (Expected `Name <email>`)",
+            config.maintainer, e
+        ));
+    }
+    if let Err(e) = validate_apt_sources(&config.apt_sources) {
+        problems.push(e);
+    }
+    for option in config.extra_options.iter() {
+        if !option.starts_with('-') {
+            problems.push(format!(
+                "nspawn option `{}` does not look like an option (missing leading `-`).",
+                option
+            ));
+        }
+    }
+    for name in config.env_passthrough.iter() {
+        let name = name.strip_suffix('*').unwrap_or(name);
+        if name.is_empty() || !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_') {
+            problems.push(format!(
+                "`{}` is not a valid environment variable name (or prefix).",
+                name
+            ));
+        }
+    }
+    if config.build_jobs == Some(0) {
+        problems.push("`build-jobs` must be at least 1.".to_owned());
+    }
+    for (arch, profile) in config.arch_profiles.iter() {
+        let canonical = normalize_arch_name(arch);
+        if canonical != arch {
+            problems.push(format!(
+                "Build profile `{}` will never be used, name it `{}` instead.",
+                arch, canonical
+            ));
+        }
+        if profile.build_jobs == Some(0) {
+            problems.push(format!("`build-jobs` of `{}` must be at least 1.", arch));
+        }
+        for name in profile.env.keys() {
+            if name.is_empty() || !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_') {
+                problems.push(format!(
+                    "`{}` (in the build profile of `{}`) is not a valid environment variable name.",
+                    name, arch
+                ));
+            }
+        }
+    }
+    for webhook in config.webhooks.iter() {
+        if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+            problems.push(format!(
+                "Webhook at `{}` is not an HTTP(S) URL.",
+                webhook::url_host(&webhook.url)
+            ));
+        }
+        for event in webhook.events.iter() {
+            if !WEBHOOK_EVENTS.contains(&event.as_str()) {
+                problems.push(format!(
+                    "Webhook at `{}` subscribes to an unknown event `{}` (expected one of {}).",
+                    webhook::url_host(&webhook.url),
+                    event,
+                    WEBHOOK_EVENTS.join(", ")
+                ));
+            }
+        }
+    }
+    if config.update_commands.iter().any(|c| c.trim().is_empty()) {
+        problems.push("`update-commands` contains an empty command.".to_owned());
+    }
+    for (name, command) in config.alias.iter() {
+        if is_builtin_command(name) {
+            problems.push(format!(
+                "Alias `{}` has the same name as a built-in command and will be ignored.",
+                name
+            ));
+        } else if command.trim().is_empty() {
+            problems.push(format!("Alias `{}` has an empty command.", name));
+        }
+    }
+    if !rootfs.join("etc/os-release").is_file() {
+        problems.push(format!(
+            "Base system at {} is missing or incomplete. Try `ciel load-os`.",
+            rootfs.display()
+        ));
+    } else {
+        if let Err(e) = validate_apt_keys(rootfs, &config.apt_sources) {
+            problems.push(e);
+        }
+        match read_extra_apt_sources(&rootfs.join(EXTRA_APT_LIST_LOCATION)) {
+            Ok(extra) => {
+                let extra = extra.join("\n");
+                if let Err(e) =
+                    validate_apt_sources(&extra).and_then(|_| validate_apt_keys(rootfs, &extra))
+                {
+                    problems.push(format!("Extra repositories: {}", e));
+                }
+            }
+            Err(e) => problems.push(format!("Unable to read extra repositories: {}", e)),
+        }
+        if !rootfs.join(DEFAULT_AB3_CONFIG_LOCATION).is_file() {
+            problems.push(
+                "Configuration has not been applied to the base system. Try `ciel config -g`."
+                    .to_owned(),
+            );
+        }
+    }
+    if !Path::new("TREE/.git").exists() {
+        problems.push("TREE is not a Git repository. Try `ciel load-tree`.".to_owned());
+    }
+
+    problems
+}
+
+/// Normalize the whitespace in a sources.list entry for comparison
+#[inline]
+fn normalize_apt_source(source: &str) -> String {
+    source.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Name of the signing key file installed alongside the extra repository (without extension)
+#[inline]
+fn apt_key_name(source: &str) -> String {
+    format!(
+        "ciel-extra-{:08x}",
+        adler32::adler32(source.as_bytes()).unwrap_or(0)
+    )
+}
+
+fn read_extra_apt_sources(path: &Path) -> Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(data) => Ok(data
+            .lines()
+            .map(normalize_apt_source)
+            .filter(|l| !l.is_empty())
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Add an extra APT repository (and its signing key) to the given root,
+/// returns false if the repository already exists
+pub fn add_apt_source<P: AsRef<Path>>(root: P, source: &str, key: Option<&[u8]>) -> Result<bool> {
+    let root = root.as_ref();
+    let source = normalize_apt_source(source);
+    validate_apt_sources(&source).map_err(|e| anyhow!("{}", e))?;
+    let list_path = root.join(EXTRA_APT_LIST_LOCATION);
+    let mut sources = read_extra_apt_sources(&list_path)?;
+    if sources.contains(&source) {
+        return Ok(false);
+    }
+    if let Some(key) = key {
+        // apt only accepts ASCII-armored keys with the .asc extension
+        let ext = if key.starts_with(b"-----BEGIN PGP") {
+            "asc"
+        } else {
+            "gpg"
+        };
+        let key_path =
+            root.join(APT_KEYRING_DIR)
+                .join(format!("{}.{}", apt_key_name(&source), ext));
+        create_parent_dir(&key_path)?;
+        fs::write(key_path, key)?;
+    }
+    sources.push(source);
+    create_parent_dir(&list_path)?;
+    fs::write(list_path, sources.join("\n") + "\n")?;
+
+    Ok(true)
+}
+
+/// Remove an extra APT repository (and its signing key) from the given root,
+/// returns false if the repository does not exist
+pub fn remove_apt_source<P: AsRef<Path>>(root: P, source: &str) -> Result<bool> {
+    let root = root.as_ref();
+    let source = normalize_apt_source(source);
+    let list_path = root.join(EXTRA_APT_LIST_LOCATION);
+    let mut sources = read_extra_apt_sources(&list_path)?;
+    let count = sources.len();
+    sources.retain(|s| s != &source);
+    if sources.len() == count {
+        return Ok(false);
+    }
+    for ext in ["asc", "gpg"] {
+        let key_path =
+            root.join(APT_KEYRING_DIR)
+                .join(format!("{}.{}", apt_key_name(&source), ext));
+        if key_path.is_file() {
+            fs::remove_file(key_path)?;
+        }
+    }
+    if sources.is_empty() {
+        fs::remove_file(list_path)?;
+    } else {
+        fs::write(list_path, sources.join("\n") + "\n")?;
+    }
+
+    Ok(true)
+}
+
 #[inline]
 fn create_parent_dir(path: &Path) -> Result<()> {
     let path = path
@@ -138,8 +937,12 @@ fn get_default_editor() -> OsString {
 
 /// Shows a series of prompts to let the user select the configurations
 pub fn ask_for_config(config: Option<CielConfig>) -> Result<CielConfig> {
-    let mut config = config.unwrap_or_default();
-    if !user_attended() {
+    let mut config = if let Some(config) = config {
+        config
+    } else {
+        CielConfig::system_defaults()?
+    };
+    if !is_interactive() {
         info!("Not controlled by an user. Default values are used.");
         return Ok(config);
     }
@@ -180,6 +983,10 @@ pub fn ask_for_config(config: Option<CielConfig>) -> Result<CielConfig> {
         .with_prompt("Use different OUTPUT dir for different branches")
         .default(config.sep_mount)
         .interact()?;
+    config.sep_arch = Confirm::with_theme(&theme)
+        .with_prompt("Use different OUTPUT dir for different architectures")
+        .default(config.sep_arch)
+        .interact()?;
     config.volatile_mount = Confirm::with_theme(&theme)
         .with_prompt("Use volatile mode for filesystem operations")
         .default(config.volatile_mount)
@@ -197,6 +1004,67 @@ pub fn read_config() -> Result<CielConfig> {
     CielConfig::load_config(data.as_slice())
 }
 
+/// Per-user configuration: the known workspaces and how to discover the workspace
+#[derive(Debug, Default, Deserialize)]
+pub struct UserConfig {
+    /// How many levels to search upwards for the workspace (until the filesystem boundary if unset)
+    #[serde(rename = "search-depth", default)]
+    pub search_depth: Option<usize>,
+    /// Known workspaces (name -> path), selected by `ciel -w NAME`
+    #[serde(default)]
+    pub workspaces: BTreeMap<String, PathBuf>,
+}
+
+impl UserConfig {
+    /// Reads `$XDG_CONFIG_HOME/ciel/workspaces.toml` (empty if it does not exist)
+    pub fn load() -> Result<UserConfig> {
+        let path = match std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        {
+            Some(dir) => dir.join(USER_CONFIG_LOCATION),
+            None => return Ok(UserConfig::default()),
+        };
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(UserConfig::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        toml::from_slice(&data).map_err(|e| anyhow!("Invalid {}: {}", path.display(), e))
+    }
+
+    /// Get the path of the known workspace
+    pub fn get_workspace(&self, name: &str) -> Result<&Path> {
+        self.workspaces
+            .get(name)
+            .map(PathBuf::as_path)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown workspace `{}` (known workspaces: {})",
+                    name,
+                    self.workspaces
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Reads the user-defined command aliases of the workspace containing `start`
+/// (falls back to the system-wide aliases outside of a workspace)
+pub fn read_aliases(start: &Path, search_depth: Option<usize>) -> BTreeMap<String, String> {
+    let data = find_ciel_dir(start, search_depth)
+        .and_then(|dir| Ok(fs::read(dir.join(DEFAULT_CONFIG_LOCATION))?))
+        .unwrap_or_default();
+
+    CielConfig::load_config(&data)
+        .map(|c| c.alias)
+        .unwrap_or_default()
+}
+
 /// Applies the given configuration (th configuration itself will not be saved to the disk)
 pub fn apply_config<P: AsRef<Path>>(root: P, config: &CielConfig) -> Result<()> {
     // write maintainer information
@@ -228,6 +1096,20 @@ pub fn apply_config<P: AsRef<Path>>(root: P, config: &CielConfig) -> Result<()>
         let mut f = std::fs::File::create(resolv_path)?;
         f.write_all(b"[Resolve]\nDNSSEC=no\n")?;
     }
+    // write APT proxy configuration
+    let proxy_path = rootfs.join(DEFAULT_APT_PROXY_LOCATION);
+    if let Some(proxy) = &config.proxy {
+        create_parent_dir(&proxy_path)?;
+        fs::write(
+            proxy_path,
+            format!(
+                "Acquire::http::Proxy \"{0}\";\nAcquire::https::Proxy \"{0}\";\n",
+                proxy
+            ),
+        )?;
+    } else if proxy_path.is_file() {
+        fs::remove_file(proxy_path)?;
+    }
     // write acbs configuration
     let mut acbs_path = rootfs.to_owned();
     acbs_path.push(DEFAULT_ACBS_CONFIG);
@@ -249,3 +1131,64 @@ fn test_validate_maintainer() {
         Err("Invalid format.".to_owned())
     );
 }
+
+#[test]
+fn test_merge_config() {
+    let mut base: toml::Value = toml::from_str("maintainer = \"A <a@a>\"\ndnssec = true").unwrap();
+    merge_config(&mut base, toml::from_str("dnssec = false").unwrap());
+    assert_eq!(
+        base,
+        toml::from_str("maintainer = \"A <a@a>\"\ndnssec = false").unwrap()
+    );
+}
+
+#[test]
+fn test_validate_apt_sources() {
+    assert_eq!(
+        validate_apt_sources("deb https://repo.aosc.io/debs/ stable main\n# comment\n"),
+        Ok(())
+    );
+    assert_eq!(
+        validate_apt_sources("deb [trusted=yes] file:///debs/ /"),
+        Ok(())
+    );
+    assert!(validate_apt_sources("deb https://repo.aosc.io/debs/ stable").is_err());
+    assert!(validate_apt_sources("dbe https://repo.aosc.io/debs/ stable main").is_err());
+    assert!(validate_apt_sources("deb repo.aosc.io/debs/ stable main").is_err());
+}
+
+#[test]
+fn test_arch_profiles() {
+    let mut config: toml::Value = toml::Value::try_from(CielConfig::default()).unwrap();
+    merge_config(
+        &mut config,
+        toml::from_str("[arch-profiles.riscv64]\nbuild-jobs = 2\nnocheck-emulated = true\nenv = { QEMU_CPU = \"max\" }").unwrap(),
+    );
+    let config: CielConfig = config.try_into().unwrap();
+    let profile = &config.arch_profiles["riscv64"];
+    assert_eq!(profile.build_jobs, Some(2));
+    assert_eq!(profile.build_nocheck, None);
+    assert!(profile.nocheck_emulated);
+    assert_eq!(profile.env["QEMU_CPU"], "max");
+}
+
+#[test]
+fn test_save_config() {
+    let mut config = CielConfig::default();
+    config
+        .arch_profiles
+        .insert("riscv64".to_string(), ArchProfile::default());
+    let saved = config.save_config().unwrap();
+    let loaded = CielConfig::load_config(saved.as_bytes()).unwrap();
+    assert!(loaded.arch_profiles.contains_key("riscv64"));
+    assert!(loaded.webhooks.is_empty());
+}
+
+#[test]
+fn test_default_trash_retention() {
+    // the workspaces configured before the trash was introduced
+    let mut config: toml::Value = toml::Value::try_from(CielConfig::default()).unwrap();
+    config.as_table_mut().unwrap().remove("trash-retention");
+    let config: CielConfig = config.try_into().unwrap();
+    assert_eq!(config.trash_retention, 7);
+}
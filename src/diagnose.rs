@@ -2,10 +2,23 @@ use anyhow::{anyhow, Result};
 use console::style;
 use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
 use dbus::blocking::Connection;
+use dialoguer::{theme::ColorfulTheme, Confirm};
 use fs3::statvfs;
+use git2::Repository;
 use indicatif::HumanBytes;
+use nix::mount::{umount2, MntFlags};
+use nix::unistd::{chown, Gid, Uid};
+use reqwest::Url;
+use serde::Serialize;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::sync::mpsc::channel;
-use std::{fs::File, io::BufRead, time::Duration};
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::BufRead,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use std::{
     io::{BufReader, Write},
     thread,
@@ -13,24 +26,112 @@ use std::{
 use tempfile::tempfile_in;
 use which::which;
 
-use crate::error;
+use crate::{
+    actions::{get_dist_arch, get_output_directory},
+    binfmt, bundle, bwrap,
+    common::{
+        is_interactive, is_legacy_workspace, network_filesystem, CIEL_DATA_DIR, CIEL_DIST_DIR,
+        CIEL_INST_DIR, RECOMMENDED_BUILD_SPACE,
+    },
+    config, error, info,
+    mac::{self, SelinuxMode},
+    machine, network,
+    overlayfs::{find_stale_mounts, get_missing_layer_dirs, is_mounted, LOWER_DIR},
+};
 
+/// Exit code of `ciel doctor` when all the checks passed
+pub const EXIT_OK: i32 = 0;
+/// Exit code of `ciel doctor` when any of the checks failed (or the diagnosis itself failed)
+pub const EXIT_ERROR: i32 = 1;
+/// Exit code of `ciel doctor` when there are only warnings
+pub const EXIT_WARNING: i32 = 2;
 const SYSTEMD1_PATH: &str = "/org/freedesktop/systemd1";
 const SYSTEMD1_DEST: &str = "org.freedesktop.systemd1";
 const SYSTEMD1_OBJ: &str = "org.freedesktop.systemd1.Manager";
 const TEST_TEXT: &[u8] = b"An-An was born a rabbit, but found herself a girl with bunny ears and tails when she woke up one day. She couldn't seem to remember why.";
 const TEST_PROGRAMS: &[&str] = &["systemd-nspawn", "systemd-run"];
-const TEST_CASES: &[&dyn Fn() -> Result<String>] = &[
-    &test_sd_bus,
-    &test_io_simple,
-    &test_required_binaries,
-    &test_fs_support,
-    &test_vm_container,
-    &test_disk_io,
-    &test_disk_space,
+const TEST_PROGRAMS_FALLBACK: &[&str] = &["bwrap", "runuser"];
+type TestCase = dyn Fn() -> Result<String>;
+type WorkspaceCheck = dyn Fn() -> Result<Vec<Fixable>>;
+
+// (id, test) pairs, the ids are part of the JSON output and should not be changed
+const TEST_CASES: &[(&str, &TestCase)] = &[
+    ("sd-bus", &test_sd_bus),
+    ("machined", &test_machined),
+    ("systemd-version", &test_systemd_version),
+    ("nested-container", &test_nested_container),
+    ("io-simple", &test_io_simple),
+    ("required-binaries", &test_required_binaries),
+    ("fs-support", &test_fs_support),
+    ("network-fs", &test_network_fs),
+    ("overlay-features", &test_overlay_features),
+    ("user-namespaces", &test_user_namespaces),
+    ("cgroup-v2", &test_cgroup_v2),
+    ("binfmt-misc", &test_binfmt_misc),
+    ("qemu-user", &test_qemu_user),
+    ("loop-devices", &test_loop_devices),
+    ("vm-container", &test_vm_container),
+    ("disk-io", &test_disk_io),
+    ("disk-space", &test_disk_space),
+    ("conflicting-processes", &test_conflicting_processes),
+    ("mac", &test_mac),
+];
+// these are independent of each other and skipped in offline mode (`CIEL_OFFLINE`)
+const NETWORK_CHECKS: &[(&str, &TestCase)] = &[
+    ("proxy", &test_proxy),
+    ("releases-mirror", &test_releases_mirror),
+    ("apt-mirrors", &test_apt_mirrors),
+    ("tree-remote", &test_tree_remote),
+];
+const WORKSPACE_CHECKS: &[(&str, &WorkspaceCheck)] = &[
+    ("layer-dirs", &check_layer_dirs),
+    ("stale-mounts", &check_stale_mounts),
+    ("stale-machines", &check_stale_machines),
+    ("permissions", &check_permissions),
+    ("emulation", &check_emulation),
+];
+// the oldest systemd supporting all the nspawn options used by ciel (`--system-call-filter`)
+const MIN_SYSTEMD_VERSION: u32 = 235;
+// virtualization types (as reported by systemd) of the containers that can not run nspawn inside
+const UNSUPPORTED_CONTAINERS: &[&str] = &[
+    "docker",
+    "podman",
+    "lxc",
+    "lxc-libvirt",
+    "openvz",
+    "rkt",
+    "proot",
+    "pouch",
 ];
+const PROXY_VARIABLES: &[&str] = &[
+    "https_proxy",
+    "HTTPS_PROXY",
+    "http_proxy",
+    "HTTP_PROXY",
+    "all_proxy",
+    "ALL_PROXY",
+];
+// responses slower than this are reported as warnings
+const SLOW_RESPONSE: Duration = Duration::from_secs(2);
+const OVERLAY_PARAMETERS_DIR: &str = "/sys/module/overlay/parameters";
+const BINFMT_MISC_STATUS: &str = "/proc/sys/fs/binfmt_misc/status";
+// expected mode of the workspace directories
+const WORKSPACE_DIR_MODE: u32 = 0o755;
+
+/// A problem in the workspace that can be fixed automatically (by `ciel doctor --fix`)
+struct Fixable {
+    problem: String,
+    // description of the fix
+    fix: String,
+    apply: Box<dyn Fn() -> Result<()>>,
+}
 
 fn test_sd_bus() -> Result<String> {
+    if bwrap::is_enabled() {
+        return Ok(
+            "!Systemd is not used, containers will be run with bwrap (no boot support)".to_string(),
+        );
+    }
     let conn = Connection::new_system()?;
     let proxy = conn.with_proxy(SYSTEMD1_DEST, SYSTEMD1_PATH, Duration::from_secs(10));
     let version: String = proxy.get(SYSTEMD1_OBJ, "Version")?;
@@ -40,13 +141,93 @@ fn test_sd_bus() -> Result<String> {
     ))
 }
 
+fn test_machined() -> Result<String> {
+    if bwrap::is_enabled() {
+        return Ok("Systemd-machined is not used".to_string());
+    }
+    let machines = machine::list_registered_machines().map_err(|e| {
+        anyhow!(
+            "Systemd-machined is not responding: {} (try `systemctl start systemd-machined`)",
+            e
+        )
+    })?;
+
+    Ok(format!(
+        "Systemd-machined is running ({} machine(s) registered)",
+        machines.len()
+    ))
+}
+
+/// Parse the major version from the systemd version string (e.g. "252.4-1" or "252 (252.4-1)")
+#[inline]
+fn parse_systemd_version(version: &str) -> Option<u32> {
+    let digits: String = version
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+fn test_systemd_version() -> Result<String> {
+    if bwrap::is_enabled() {
+        return Ok("Systemd version check is skipped (without systemd)".to_string());
+    }
+    let conn = Connection::new_system()?;
+    let proxy = conn.with_proxy(SYSTEMD1_DEST, SYSTEMD1_PATH, Duration::from_secs(10));
+    let version: String = proxy.get(SYSTEMD1_OBJ, "Version")?;
+    match parse_systemd_version(&version) {
+        Some(major) if major >= MIN_SYSTEMD_VERSION => Ok(format!(
+            "Systemd {} supports all the required nspawn options",
+            major
+        )),
+        Some(major) => Err(anyhow!(
+            "Systemd {} is too old, at least systemd {} is required by systemd-nspawn options used by ciel",
+            major,
+            MIN_SYSTEMD_VERSION
+        )),
+        None => Ok(format!(
+            "!Unable to determine the systemd version from `{}`",
+            version
+        )),
+    }
+}
+
+fn test_nested_container() -> Result<String> {
+    if bwrap::is_enabled() {
+        return Ok("Nested container check is skipped (without systemd)".to_string());
+    }
+    let conn = Connection::new_system()?;
+    let proxy = conn.with_proxy(SYSTEMD1_DEST, SYSTEMD1_PATH, Duration::from_secs(10));
+    let virt: String = proxy.get(SYSTEMD1_OBJ, "Virtualization")?;
+    if UNSUPPORTED_CONTAINERS.contains(&virt.as_str()) {
+        return Err(anyhow!(
+            "Running inside a {} container is not supported by systemd-nspawn, run ciel on the host or in a VM",
+            virt
+        ));
+    }
+    if virt == "systemd-nspawn" {
+        return Ok(
+            "!Running inside a systemd-nspawn container, nested containers may not work"
+                .to_string(),
+        );
+    }
+
+    Ok("Not running inside an unsupported container".to_string())
+}
+
 fn test_io_simple() -> Result<String> {
     File::open("/proc/1/cmdline")?;
     Ok("Basic I/O operations seem to be working".to_string())
 }
 
 fn test_required_binaries() -> Result<String> {
-    for binary in TEST_PROGRAMS {
+    let programs = if bwrap::is_enabled() {
+        TEST_PROGRAMS_FALLBACK
+    } else {
+        TEST_PROGRAMS
+    };
+    for binary in programs {
         if which(binary).is_err() {
             return Err(anyhow!("Required program `{}` is not found", binary));
         }
@@ -54,6 +235,19 @@ fn test_required_binaries() -> Result<String> {
     Ok("Required binaries are correctly installed".to_string())
 }
 
+fn test_network_fs() -> Result<String> {
+    if !Path::new(".ciel").is_dir() {
+        return Ok("Not in a workspace, skipped".to_string());
+    }
+    if let Some(name) = network_filesystem(".ciel")? {
+        return Err(anyhow!(
+            "The workspace is on {}, which can not hold the container layers, please move it to a local filesystem",
+            name
+        ));
+    }
+    Ok("The workspace is on a local filesystem".to_string())
+}
+
 fn test_fs_support() -> Result<String> {
     let f = File::open("/proc/filesystems")?;
     let reader = BufReader::new(f);
@@ -72,7 +266,118 @@ fn test_fs_support() -> Result<String> {
     ))
 }
 
+/// Read a boolean (Y/N) kernel module parameter
+#[inline]
+fn read_module_flag(name: &str) -> Option<bool> {
+    fs::read_to_string(Path::new(OVERLAY_PARAMETERS_DIR).join(name))
+        .ok()
+        .map(|v| v.trim() == "Y")
+}
+
+fn test_overlay_features() -> Result<String> {
+    if !Path::new(OVERLAY_PARAMETERS_DIR).is_dir() {
+        return Ok(
+            "!Overlay module is not loaded, its features can not be checked (try `modprobe overlay`)"
+                .to_string(),
+        );
+    }
+    if read_module_flag("metacopy") == Some(true) {
+        // with metacopy, the upper layer may only contain the metadata of the files
+        return Ok("!Overlayfs metacopy is enabled, committing instances may lose file contents (boot with `overlay.metacopy=N`)".to_string());
+    }
+    let redirect_dir = match read_module_flag("redirect_dir") {
+        Some(true) => "on",
+        _ => "off",
+    };
+    Ok(format!(
+        "Overlayfs features seem to be compatible (redirect_dir: {}, metacopy: off)",
+        redirect_dir
+    ))
+}
+
+fn test_user_namespaces() -> Result<String> {
+    let max_namespaces = fs::read_to_string("/proc/sys/user/max_user_namespaces")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    Ok(match max_namespaces {
+        None => "!Kernel does not support user namespaces (CONFIG_USER_NS is not set)".to_string(),
+        Some(0) => "!User namespaces are disabled (try `sysctl user.max_user_namespaces=15000`)"
+            .to_string(),
+        Some(_) => "User namespaces are supported".to_string(),
+    })
+}
+
+fn test_cgroup_v2() -> Result<String> {
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").is_file() {
+        return Ok("Unified cgroup hierarchy (cgroup v2) is in use".to_string());
+    }
+
+    Ok("!Unified cgroup hierarchy (cgroup v2) is not in use, `ciel top` will not work and newer systemd in containers may fail to boot (boot with `systemd.unified_cgroup_hierarchy=1`)".to_string())
+}
+
+fn test_binfmt_misc() -> Result<String> {
+    match fs::read_to_string(BINFMT_MISC_STATUS) {
+        Ok(status) if status.trim() == "enabled" => {
+            Ok("binfmt_misc is available for foreign architecture containers".to_string())
+        }
+        Ok(_) => Ok("!binfmt_misc is disabled, foreign architecture containers will not work (try `echo 1 > /proc/sys/fs/binfmt_misc/status`)".to_string()),
+        Err(_) => Ok("!binfmt_misc is not mounted, foreign architecture containers will not work (try `mount -t binfmt_misc binfmt_misc /proc/sys/fs/binfmt_misc`)".to_string()),
+    }
+}
+
+fn test_qemu_user() -> Result<String> {
+    let handlers = binfmt::list_qemu_handlers();
+    if handlers.is_empty() {
+        return Ok(
+            "No qemu-user handlers are registered (only needed for foreign architecture containers)"
+                .to_string(),
+        );
+    }
+    let mut problems = Vec::new();
+    for handler in handlers.iter() {
+        if !handler.enabled {
+            problems.push(format!("{} is disabled", handler.name));
+        } else if !handler.fix_binary() {
+            // the interpreter is looked up inside the container then
+            problems.push(format!(
+                "{} lacks the F (fix-binary) flag (so {} must exist in the containers)",
+                handler.name,
+                handler.interpreter.display()
+            ));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(format!(
+            "qemu-user is registered for {}",
+            handlers
+                .iter()
+                .map(|h| h.name.trim_start_matches("qemu-"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    Ok(format!(
+        "!{}, emulated programs may fail with \"Exec format error\" in the containers (run `ciel doctor --fix` in the workspace)",
+        problems.join(", ")
+    ))
+}
+
+fn test_loop_devices() -> Result<String> {
+    if Path::new("/dev/loop-control").exists() {
+        return Ok("Loop devices are available".to_string());
+    }
+
+    Ok(
+        "!Loop devices are not available, building disk images will not work (try `modprobe loop`)"
+            .to_string(),
+    )
+}
+
 fn test_vm_container() -> Result<String> {
+    if bwrap::is_enabled() {
+        return Ok("Environment seems sane (without systemd)".to_string());
+    }
     let conn = Connection::new_system()?;
     let proxy = conn.with_proxy(SYSTEMD1_DEST, SYSTEMD1_PATH, Duration::from_secs(10));
     let virt: String = proxy.get(SYSTEMD1_OBJ, "Virtualization")?;
@@ -110,9 +415,8 @@ fn test_disk_io() -> Result<String> {
 
 fn test_disk_space() -> Result<String> {
     let stats = statvfs(std::fs::canonicalize(".")?)?;
-    if stats.available_space() < (10 * 1024 * 1024 * 1024) {
-        // 10 GB
-        Err(anyhow!("Disk space insufficient. Need at least 10 GB of free space to do something meaningful (You have {}).", HumanBytes(stats.available_space())))
+    if stats.available_space() < RECOMMENDED_BUILD_SPACE {
+        Err(anyhow!("Disk space insufficient. Need at least {} of free space to do something meaningful (You have {}).", HumanBytes(RECOMMENDED_BUILD_SPACE), HumanBytes(stats.available_space())))
     } else {
         Ok(format!(
             "Disk space is sufficient ({} free of {}).",
@@ -122,41 +426,721 @@ fn test_disk_space() -> Result<String> {
     }
 }
 
-/// Carry out the diagnostic tests
-pub fn run_diagnose() -> Result<()> {
-    let mut lines = vec![];
-    let mut has_error = false;
-    for test in TEST_CASES {
-        match test() {
-            Ok(msg) => {
-                if msg.starts_with('!') {
-                    lines.push(format!(
-                        "{} {}",
-                        style("!").yellow(),
-                        style(msg.strip_prefix('!').unwrap()).yellow().bold()
-                    ));
-                    continue;
+/// Describe the response time, slow responses are marked as warnings
+#[inline]
+fn describe_response(what: &str, elapsed: Duration) -> String {
+    if elapsed > SLOW_RESPONSE {
+        return format!(
+            "!{} is slow to respond ({} ms), downloads may take a long time",
+            what,
+            elapsed.as_millis()
+        );
+    }
+
+    format!("{} is reachable ({} ms)", what, elapsed.as_millis())
+}
+
+fn test_proxy() -> Result<String> {
+    let proxy = PROXY_VARIABLES.iter().find_map(|var| {
+        std::env::var(var)
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(|p| (var, p))
+    });
+    let (var, proxy) = match proxy {
+        Some(proxy) => proxy,
+        None => return Ok("No proxy is configured".to_string()),
+    };
+    // the credentials in the URL must not end up in the report
+    let url =
+        Url::parse(&proxy).map_err(|e| anyhow!("Proxy in ${} is not a valid URL: {}", var, e))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("Proxy in ${} has no host", var))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let proxy = format!("{}://{}:{}", url.scheme(), host, port);
+    let elapsed = network::probe_tcp(host, port)
+        .map_err(|e| anyhow!("Proxy {} is not reachable: {}", proxy, e))?;
+
+    Ok(describe_response(&format!("Proxy {}", proxy), elapsed))
+}
+
+fn test_releases_mirror() -> Result<String> {
+    let elapsed = network::probe_url(network::MANIFEST_URL).map_err(|e| {
+        anyhow!(
+            "Unable to reach the release mirror ({}): {}, `ciel load-os` will not work",
+            network::MANIFEST_URL,
+            e
+        )
+    })?;
+
+    Ok(describe_response("Release mirror", elapsed))
+}
+
+fn test_apt_mirrors() -> Result<String> {
+    let config = config::read_config().unwrap_or_default();
+    let mut messages = Vec::new();
+    let mut slowest = Duration::default();
+    for url in config.get_mirror_urls() {
+        let elapsed = network::probe_url(&url)
+            .map_err(|e| anyhow!("Unable to reach APT mirror {}: {}", url, e))?;
+        slowest = slowest.max(elapsed);
+        messages.push(format!("{} ({} ms)", url, elapsed.as_millis()));
+    }
+    if messages.is_empty() {
+        return Ok("!No HTTP(S) APT mirrors are configured".to_string());
+    }
+    if slowest > SLOW_RESPONSE {
+        return Ok(format!(
+            "!APT mirrors are slow to respond: {}",
+            messages.join(", ")
+        ));
+    }
+
+    Ok(format!(
+        "APT mirrors are reachable: {}",
+        messages.join(", ")
+    ))
+}
+
+fn test_tree_remote() -> Result<String> {
+    // if the tree is not cloned yet, check the one `ciel load-tree` would use
+    let url = Repository::open("TREE")
+        .ok()
+        .and_then(|repo| {
+            repo.find_remote("origin")
+                .ok()
+                .and_then(|r| r.url().map(String::from))
+        })
+        .unwrap_or_else(|| network::GIT_TREE_URL.to_string());
+    let elapsed = if url.starts_with("http://") || url.starts_with("https://") {
+        network::probe_url(&format!(
+            "{}/info/refs?service=git-upload-pack",
+            url.trim_end_matches('/')
+        ))
+    } else if let Some((host, port)) = network::parse_git_remote(&url) {
+        network::probe_tcp(&host, port)
+    } else {
+        return Ok(format!("Tree remote {} is local", url));
+    }
+    .map_err(|e| anyhow!("Unable to reach the tree remote {}: {}", url, e))?;
+
+    Ok(describe_response(&format!("Tree remote {}", url), elapsed))
+}
+
+/// A process (other than this one) using the workspace
+struct WorkspaceProcess {
+    pid: u32,
+    command: String,
+    is_ciel: bool,
+    // the instance the process is operating on (if known)
+    instance: Option<String>,
+}
+
+/// Find the instance specified in the ciel command line (`-i`) or environment (`CIEL_INST`)
+fn get_ciel_instance(pid: u32, args: &[String]) -> Option<String> {
+    if let Some(pos) = args.iter().position(|a| a == "-i") {
+        return args.get(pos + 1).cloned();
+    }
+    let environ = fs::read(format!("/proc/{}/environ", pid)).ok()?;
+    environ
+        .split(|c| *c == 0)
+        .find_map(|var| var.strip_prefix(b"CIEL_INST="))
+        .map(|inst| String::from_utf8_lossy(inst).to_string())
+}
+
+/// Scan /proc for the ciel processes running in the workspace `root`,
+/// and the host processes working inside the instances (which keep the filesystems busy)
+fn find_workspace_processes(root: &Path, instances: &[String]) -> Result<Vec<WorkspaceProcess>> {
+    let this_pid = std::process::id();
+    let mut processes = Vec::new();
+    for entry in fs::read_dir("/proc")?.flatten() {
+        let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(pid) if pid != this_pid => pid,
+            _ => continue,
+        };
+        let proc_dir = entry.path();
+        // the process may exit at any time, or we may not have the permission to inspect it
+        let cwd = match fs::read_link(proc_dir.join("cwd")) {
+            Ok(cwd) => cwd,
+            Err(_) => continue,
+        };
+        // processes inside the containers have their root set to the instance
+        if fs::read_link(proc_dir.join("root")).ok() != Some(PathBuf::from("/")) {
+            continue;
+        }
+        let args: Vec<String> = fs::read(proc_dir.join("cmdline"))
+            .unwrap_or_default()
+            .split(|c| *c == 0)
+            .filter(|a| !a.is_empty())
+            .map(|a| String::from_utf8_lossy(a).to_string())
+            .collect();
+        let is_ciel = fs::read_link(proc_dir.join("exe"))
+            .ok()
+            .and_then(|exe| {
+                exe.file_name()
+                    .map(|n| n.to_string_lossy().starts_with("ciel"))
+            })
+            .unwrap_or(false);
+        let relative = match cwd.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        let cwd_instance = relative
+            .components()
+            .next()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .filter(|name| instances.contains(name));
+        let instance = if is_ciel {
+            get_ciel_instance(pid, &args).or(cwd_instance)
+        } else if cwd_instance.is_some() {
+            cwd_instance
+        } else {
+            continue;
+        };
+        processes.push(WorkspaceProcess {
+            pid,
+            command: args.join(" "),
+            is_ciel,
+            instance,
+        });
+    }
+
+    Ok(processes)
+}
+
+fn test_conflicting_processes() -> Result<String> {
+    let root = std::env::current_dir()?;
+    let processes = find_workspace_processes(&root, &list_instance_names()?)?;
+    if processes.is_empty() {
+        return Ok("No other processes are using the workspace".to_string());
+    }
+    let descriptions: Vec<String> = processes
+        .iter()
+        .map(|p| {
+            let kind = if p.is_ciel { "ciel" } else { "process" };
+            match &p.instance {
+                Some(instance) => format!(
+                    "{} {} (`{}`) holds instance {}",
+                    kind, p.pid, p.command, instance
+                ),
+                None => format!("{} {} (`{}`)", kind, p.pid, p.command),
+            }
+        })
+        .collect();
+
+    Ok(format!(
+        "!Other processes are using the workspace, operations may fail with \"resource busy\": {}",
+        descriptions.join("; ")
+    ))
+}
+
+/// The directories of the workspace bind-mounted into the containers
+fn list_bind_mount_sources(config: &config::CielConfig) -> Vec<PathBuf> {
+    let mut sources = vec![
+        PathBuf::from("TREE"),
+        PathBuf::from(get_output_directory(config.sep_mount, config.sep_arch)),
+    ];
+    if config.local_sources {
+        sources.push(PathBuf::from(
+            config.sources_cache.as_deref().unwrap_or("SRCS"),
+        ));
+    }
+
+    sources
+}
+
+fn test_mac() -> Result<String> {
+    let config = config::read_config().unwrap_or_default();
+    let selinux = mac::selinux_mode();
+    let apparmor = mac::apparmor_enabled();
+    mac::check_config(&config.mac)?;
+    if selinux == SelinuxMode::Disabled && !apparmor {
+        return Ok("No mandatory access control (SELinux or AppArmor) is in use".to_string());
+    }
+    let mut problems = Vec::new();
+    if selinux == SelinuxMode::Enforcing && config.mac.selinux_file_context.is_none() {
+        problems.push("SELinux is enforcing, but no contexts are configured for the instances (set `selinux-file-context` and `selinux-context` in `[mac]`)".to_string());
+    }
+    if let Some(expected) = config
+        .mac
+        .selinux_file_context
+        .as_deref()
+        .and_then(mac::get_selinux_type)
+    {
+        for source in list_bind_mount_sources(&config) {
+            let label = match mac::get_selinux_label(&source) {
+                Some(label) => label,
+                None => continue,
+            };
+            if mac::get_selinux_type(&label) != Some(expected) {
+                problems.push(format!(
+                    "{} is labeled {}, the containers may not be able to use it (try `chcon -R -t {} {}`)",
+                    source.display(),
+                    label,
+                    expected,
+                    source.display()
+                ));
+            }
+        }
+    }
+    let denials = mac::find_recent_denials(&config.mac, &std::env::current_dir()?);
+    if let Some(last) = denials.last() {
+        problems.push(format!(
+            "{} access denial(s) involving the workspace in the last day, the latest: {}",
+            denials.len(),
+            last
+        ));
+    }
+    let state = match selinux {
+        SelinuxMode::Enforcing => "SELinux is enforcing",
+        SelinuxMode::Permissive => "SELinux is permissive",
+        SelinuxMode::Disabled => "AppArmor is enabled",
+    };
+    if !problems.is_empty() {
+        return Ok(format!("!{}: {}", state, problems.join("; ")));
+    }
+
+    Ok(format!(
+        "{}, no access denials involving the workspace were found",
+        state
+    ))
+}
+
+fn list_instance_names() -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(CIEL_INST_DIR)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+
+    Ok(names)
+}
+
+/// The binfmt_misc handler and the emulator in the instances, if the base system is of
+/// a foreign architecture
+fn check_emulation() -> Result<Vec<Fixable>> {
+    let arch = match get_dist_arch() {
+        Some(arch) if binfmt::is_foreign_arch(&arch) => arch,
+        _ => return Ok(Vec::new()),
+    };
+    let target = binfmt::qemu_target(&arch)?;
+    let mut problems = Vec::new();
+    match binfmt::read_handler(&target.handler_name()) {
+        None => {
+            target.find_binary()?;
+            problems.push(Fixable {
+                problem: format!(
+                    "The base system is for {}, but there is no binfmt_misc handler for it",
+                    arch
+                ),
+                fix: format!("Register {} with the F flag", target.handler_name()),
+                apply: Box::new(move || binfmt::ensure_binfmt(target)),
+            });
+        }
+        Some(handler) if !handler.enabled => problems.push(Fixable {
+            problem: format!("{} is disabled", handler.name),
+            fix: format!("Enable {}", handler.name),
+            apply: Box::new(move || binfmt::set_handler_state(target, true)),
+        }),
+        Some(handler) if !handler.fix_binary() => {
+            target.find_binary()?;
+            problems.push(Fixable {
+                problem: format!(
+                    "{} is registered without the F (fix-binary) flag, programs may fail with \"Exec format error\" in the containers",
+                    handler.name
+                ),
+                fix: format!("Register {} again with the F flag", handler.name),
+                apply: Box::new(move || {
+                    binfmt::set_handler_state(target, false)?;
+                    binfmt::ensure_binfmt(target)
+                }),
+            });
+        }
+        Some(_) => (),
+    }
+    for name in list_instance_names()? {
+        let local = Path::new(CIEL_INST_DIR).join(&name).join(LOWER_DIR);
+        if binfmt::emulator_path(target, &local).is_file() {
+            continue;
+        }
+        let arch = arch.clone();
+        problems.push(Fixable {
+            problem: format!("{}: qemu-user is not set up in the instance", name),
+            fix: format!("Copy the {} emulator into the instance", arch),
+            apply: Box::new(move || {
+                binfmt::install_emulator(target, &local)?;
+                let mut config = config::InstanceConfig::load(&name)?;
+                config.arch = Some(arch.clone());
+                config.save(&name)
+            }),
+        });
+    }
+
+    Ok(problems)
+}
+
+fn check_layer_dirs() -> Result<Vec<Fixable>> {
+    let mut problems = Vec::new();
+    for name in list_instance_names()? {
+        let missing = get_missing_layer_dirs(&Path::new(CIEL_INST_DIR).join(&name));
+        if missing.is_empty() {
+            continue;
+        }
+        problems.push(Fixable {
+            problem: format!("{}: {} layer directories are missing", name, missing.len()),
+            fix: "Recreate the missing layer directories".to_string(),
+            apply: Box::new(move || {
+                for dir in missing.iter() {
+                    fs::create_dir_all(dir)?;
                 }
+                Ok(())
+            }),
+        });
+    }
+
+    Ok(problems)
+}
+
+/// Find the mounts in the workspace left behind by crashed runs or removed instances
+/// (including the mounts nested inside the instance mount points)
+fn check_stale_mounts() -> Result<Vec<Fixable>> {
+    let root = std::env::current_dir()?;
+    let mut problems = Vec::new();
+    // the mounts are sorted so that the nested ones are detached first
+    for mount in find_stale_mounts(&root, &list_instance_names()?)? {
+        let problem = if mount.orphaned {
+            format!(
+                "{} is mounted, but there is no such instance",
+                mount.mount_point.display()
+            )
+        } else {
+            format!(
+                "{} is mounted inside instance {}",
+                mount.mount_point.display(),
+                mount.instance
+            )
+        };
+        let mount_point = mount.mount_point;
+        problems.push(Fixable {
+            problem,
+            fix: "Un-mount the stale filesystem".to_string(),
+            apply: Box::new(move || Ok(umount2(&mount_point, MntFlags::MNT_DETACH)?)),
+        });
+    }
+
+    Ok(problems)
+}
+
+/// Find the containers registered with machined while their filesystems are not mounted
+/// (e.g. the workspace was un-mounted behind ciel's back)
+fn check_stale_machines() -> Result<Vec<Fixable>> {
+    if bwrap::is_enabled() {
+        return Ok(Vec::new());
+    }
+    let root = std::env::current_dir()?;
+    let legacy = is_legacy_workspace()?;
+    let registered = machine::list_registered_machines()?;
+    let mut problems = Vec::new();
+    for name in list_instance_names()? {
+        let ns_name = machine::get_container_ns_name(&name, legacy)?;
+        if !registered.contains(&ns_name) || is_mounted(&root.join(&name), OsStr::new("overlay"))? {
+            continue;
+        }
+        problems.push(Fixable {
+            problem: format!(
+                "{}: container is registered, but its filesystem is not mounted",
+                name
+            ),
+            fix: "Terminate the stale container, so that it can be registered again".to_string(),
+            apply: Box::new(move || machine::terminate_machine(&ns_name)),
+        });
+    }
+
+    Ok(problems)
+}
+
+/// Check that the workspace directories are owned by root and accessible by everyone in the container
+fn check_permissions() -> Result<Vec<Fixable>> {
+    let mut problems = Vec::new();
+    for path in [".ciel", CIEL_DIST_DIR, CIEL_INST_DIR, CIEL_DATA_DIR] {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let mode = metadata.mode() & 0o7777;
+        if metadata.uid() == 0 && metadata.gid() == 0 && mode == WORKSPACE_DIR_MODE {
+            continue;
+        }
+        problems.push(Fixable {
+            problem: format!(
+                "{} has owner {}:{} and mode {:o}",
+                path,
+                metadata.uid(),
+                metadata.gid(),
+                mode
+            ),
+            fix: format!(
+                "Change the owner to root and mode to {:o}",
+                WORKSPACE_DIR_MODE
+            ),
+            apply: Box::new(move || {
+                chown(path, Some(Uid::from_raw(0)), Some(Gid::from_raw(0)))?;
+                fs::set_permissions(path, fs::Permissions::from_mode(WORKSPACE_DIR_MODE))?;
+                Ok(())
+            }),
+        });
+    }
+
+    Ok(problems)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    // the check was not run because of an earlier error
+    Skipped,
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Serialize)]
+struct DiagnoseResult {
+    id: &'static str,
+    severity: Severity,
+    message: String,
+    // the automated fix available for the problem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fix: Option<String>,
+    // whether the fix has been applied successfully (only when `--fix` is used)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fixed: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct DiagnoseReport {
+    // the most severe result of all the checks
+    status: Severity,
+    exit_code: i32,
+    checks: Vec<DiagnoseResult>,
+}
+
+impl DiagnoseResult {
+    fn new(id: &'static str, severity: Severity, message: String) -> Self {
+        DiagnoseResult {
+            id,
+            severity,
+            message,
+            fix: None,
+            fixed: None,
+        }
+    }
+
+    /// Whether the result counts towards the overall status (fixed problems do not)
+    fn effective_severity(&self) -> Severity {
+        if self.fixed == Some(true) {
+            Severity::Ok
+        } else {
+            self.severity
+        }
+    }
+}
+
+/// Ask for confirmation before applying the fixes (unless `force` is set)
+fn confirm_fixes(count: usize, force: bool) -> Result<bool> {
+    if force {
+        return Ok(true);
+    }
+    if !is_interactive() {
+        return Err(anyhow!(
+            "Refusing to apply the fixes without confirmation, use `--force` in batch mode."
+        ));
+    }
+
+    Ok(Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("Apply {} fix(es)?", count))
+        .default(false)
+        .interact()?)
+}
+
+/// Record the result of the test for printing, returns false if the test failed
+fn record_test_result(
+    id: &'static str,
+    result: Result<String>,
+    lines: &mut Vec<String>,
+    results: &mut Vec<DiagnoseResult>,
+) -> bool {
+    match result {
+        Ok(msg) => {
+            if let Some(msg) = msg.strip_prefix('!') {
                 lines.push(format!(
                     "{} {}",
-                    style("✓").green(),
-                    style(msg).green().bold()
-                ))
+                    style("!").yellow(),
+                    style(msg).yellow().bold()
+                ));
+                results.push(DiagnoseResult::new(id, Severity::Warning, msg.to_string()));
+                return true;
             }
+            lines.push(format!(
+                "{} {}",
+                style("✓").green(),
+                style(&msg).green().bold()
+            ));
+            results.push(DiagnoseResult::new(id, Severity::Ok, msg));
+            true
+        }
+        Err(err) => {
+            lines.push(format!("{} {}", style("x").red(), style(&err).red().bold()));
+            results.push(DiagnoseResult::new(id, Severity::Error, err.to_string()));
+            false
+        }
+    }
+}
+
+/// Carry out the diagnostic tests and write the results to `output` (as JSON if `json` is set).
+/// If `fix` is set, the problems found in the workspace are fixed after confirmation (or with `force`).
+/// If `bundle` is set, a diagnostic bundle is saved there (see `bundle::create_bundle`).
+/// Returns the exit code (`EXIT_OK`, `EXIT_WARNING` or `EXIT_ERROR`).
+pub fn run_diagnose(
+    json: bool,
+    fix: bool,
+    force: bool,
+    bundle: Option<&Path>,
+    output: &mut dyn Write,
+) -> Result<i32> {
+    let mut lines = vec![];
+    let mut results = vec![];
+    let mut failed = false;
+    for (id, test) in TEST_CASES {
+        if failed {
+            results.push(DiagnoseResult::new(
+                id,
+                Severity::Skipped,
+                "Skipped because of an earlier error".to_string(),
+            ));
+            continue;
+        }
+        failed = !record_test_result(id, test(), &mut lines, &mut results);
+    }
+    let offline = std::env::var("CIEL_OFFLINE").is_ok();
+    for (id, test) in NETWORK_CHECKS {
+        if offline {
+            results.push(DiagnoseResult::new(
+                id,
+                Severity::Skipped,
+                "Skipped in offline mode".to_string(),
+            ));
+            continue;
+        }
+        record_test_result(id, test(), &mut lines, &mut results);
+    }
+
+    let mut problems = Vec::new();
+    for (id, check) in WORKSPACE_CHECKS {
+        match check() {
+            Ok(found) if found.is_empty() => results.push(DiagnoseResult::new(
+                id,
+                Severity::Ok,
+                "No problems found".to_string(),
+            )),
+            Ok(found) => problems.extend(found.into_iter().map(|problem| (*id, problem))),
             Err(err) => {
-                has_error = true;
-                lines.push(format!("{} {}", style("x").red(), style(err).red().bold()));
-                break;
+                lines.push(format!("{} {}", style("x").red(), style(&err).red().bold()));
+                results.push(DiagnoseResult::new(id, Severity::Error, err.to_string()));
             }
         }
     }
+    if problems.is_empty() && results.iter().all(|r| r.severity != Severity::Error) {
+        lines.push(format!(
+            "{} {}",
+            style("✓").green(),
+            style("Workspace seems to be in good shape").green().bold()
+        ));
+    }
+    for (_, problem) in problems.iter() {
+        lines.push(format!(
+            "{} {}\n  {} {}",
+            style("x").red(),
+            style(&problem.problem).red().bold(),
+            style("fix:").dim(),
+            problem.fix
+        ));
+    }
 
-    for line in lines {
-        println!("{}", line);
+    if !json {
+        for line in lines {
+            writeln!(output, "{}", line)?;
+        }
+    }
+    let apply = fix && !problems.is_empty() && confirm_fixes(problems.len(), force)?;
+    for (id, problem) in problems.iter() {
+        let fixed = if apply {
+            let result = (problem.apply)();
+            if let Err(err) = &result {
+                error!("Unable to fix `{}`: {}", problem.problem, err);
+            }
+            Some(result.is_ok())
+        } else {
+            None
+        };
+        results.push(DiagnoseResult {
+            id,
+            severity: Severity::Error,
+            message: problem.problem.clone(),
+            fix: Some(problem.fix.clone()),
+            fixed,
+        });
     }
-    if has_error {
-        return Err(anyhow!("Test error detected"));
+    let status = results
+        .iter()
+        .map(|r| r.effective_severity())
+        .max()
+        .unwrap_or(Severity::Ok);
+    let exit_code = match status {
+        Severity::Error => EXIT_ERROR,
+        Severity::Warning => EXIT_WARNING,
+        Severity::Ok | Severity::Skipped => EXIT_OK,
+    };
+    let report = DiagnoseReport {
+        status,
+        exit_code,
+        checks: results,
+    };
+    if let Some(bundle) = bundle {
+        bundle::create_bundle(bundle, &report)?;
+        info!("Diagnostic bundle saved to {}.", bundle.display());
     }
+    if json {
+        serde_json::to_writer_pretty(&mut *output, &report)?;
+        writeln!(output)?;
+    } else {
+        if apply {
+            let fixed = report
+                .checks
+                .iter()
+                .filter(|r| r.fixed == Some(true))
+                .count();
+            writeln!(output, "{} {} problem(s) fixed", style("✓").green(), fixed)?;
+        } else if !problems.is_empty() {
+            writeln!(
+                output,
+                "Run `ciel doctor --fix` to fix the problems automatically."
+            )?;
+        }
+        if status == Severity::Error {
+            error!("Test error detected");
+        }
+    }
+
+    Ok(exit_code)
+}
 
-    Ok(())
+#[test]
+fn test_parse_systemd_version() {
+    assert_eq!(parse_systemd_version("252.4-1"), Some(252));
+    assert_eq!(parse_systemd_version("249 (249.11-0ubuntu3)"), Some(249));
+    assert_eq!(parse_systemd_version("unknown"), None);
 }
@@ -0,0 +1,79 @@
+//! Lifecycle events for external tools (IDE plugins, bots, etc.)
+//!
+//! To receive the events, bind a Unix datagram socket at `.ciel/events.sock` in the workspace.
+//! Each datagram is a JSON object like
+//! `{"event": "build-finished", "instance": "main", "timestamp": "...", "pid": 42, ...}`.
+//! Nothing is sent if no one is listening.
+//!
+//! The build and commit events are also posted to the webhooks configured, see [`crate::webhook`].
+//! With `ciel build --progress json`, the events are also written to stdout, one per line
+//! (see [`set_progress_stream`]).
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::{io::Write, os::unix::net::UnixDatagram, path::Path, sync::Mutex};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{trace, webhook};
+
+/// Where the listener binds its socket (relative to the workspace)
+pub const EVENT_SOCKET: &str = ".ciel/events.sock";
+
+pub const INSTANCE_MOUNTED: &str = "instance-mounted";
+pub const INSTANCE_UNMOUNTED: &str = "instance-unmounted";
+pub const INSTANCE_COMMITTED: &str = "instance-committed";
+pub const INSTANCE_ROLLED_BACK: &str = "instance-rolled-back";
+pub const BUILD_STARTED: &str = "build-started";
+pub const BUILD_FINISHED: &str = "build-finished";
+/// The build of a package moved to another phase (see the `PHASE_*` constants)
+pub const BUILD_PHASE: &str = "build-phase";
+
+pub const PHASE_PREPARE: &str = "prepare";
+pub const PHASE_UPDATE: &str = "update-os";
+pub const PHASE_BUILD: &str = "build";
+pub const PHASE_CLEANUP: &str = "cleanup";
+
+lazy_static! {
+    static ref PROGRESS_STREAM: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+}
+
+/// Also write the events to `output` (one JSON object per line) from now on. If the events
+/// go to stdout, the output of the commands run in the containers goes to stderr instead.
+pub fn set_progress_stream(output: Box<dyn Write + Send>) {
+    *PROGRESS_STREAM.lock().unwrap() = Some(output);
+}
+
+/// Whether the events are written to a progress stream (stdout is then reserved for them)
+pub fn is_progress_stream() -> bool {
+    PROGRESS_STREAM.lock().unwrap().is_some()
+}
+
+/// Send the event to the listener (if any) and the webhooks, `details` (a JSON object) is merged
+/// into the event. Errors are ignored since the listener may come and go at any time.
+pub fn emit(event: &str, instance: &str, details: Value) {
+    let mut message = json!({
+        "event": event,
+        "instance": instance,
+        "timestamp": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+        "pid": std::process::id(),
+    });
+    if let (Some(message), Value::Object(details)) = (message.as_object_mut(), details) {
+        message.extend(details);
+    }
+    if let Some(stream) = PROGRESS_STREAM.lock().unwrap().as_mut() {
+        writeln!(stream, "{}", message)
+            .and_then(|_| stream.flush())
+            .ok();
+    }
+    webhook::notify(&message);
+    let path = Path::new(EVENT_SOCKET);
+    if !path.exists() {
+        return;
+    }
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        socket.send_to(message.to_string().as_bytes(), path)
+    });
+    if let Err(e) = result {
+        trace!("Unable to send the event {}: {}", event, e);
+    }
+}
@@ -0,0 +1,133 @@
+//! Forward the log messages to a remote endpoint (syslog, TCP or HTTP),
+//! so that the builders can be followed from a central place
+use anyhow::{anyhow, bail, Result};
+use lazy_static::lazy_static;
+use nix::unistd::gethostname;
+use reqwest::{blocking::Client, Url};
+use std::{
+    io::Write,
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{logging::Level, warn};
+
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_SYSLOG_PORT: u16 = 514;
+// facility "user" (1) as defined in RFC 5424
+const SYSLOG_FACILITY: u8 = 1;
+
+enum Target {
+    /// RFC 5424 messages over UDP
+    Syslog(UdpSocket),
+    /// JSON lines over a TCP connection
+    Tcp(TcpStream),
+    /// One JSON object per POST request
+    Http(Client, Url),
+}
+
+struct Forwarder {
+    target: Target,
+    hostname: String,
+}
+
+lazy_static! {
+    static ref FORWARDER: Mutex<Option<Forwarder>> = Mutex::new(None);
+}
+
+impl Forwarder {
+    fn send(&mut self, level: Level, record: &serde_json::Value) -> Result<()> {
+        match &mut self.target {
+            Target::Syslog(socket) => {
+                let severity = match level {
+                    Level::Error => 3,
+                    Level::Warning => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                let message = format!(
+                    "<{}>1 {} {} ciel {} - - {}",
+                    SYSLOG_FACILITY * 8 + severity,
+                    record["timestamp"].as_str().unwrap_or("-"),
+                    self.hostname,
+                    std::process::id(),
+                    record
+                );
+                socket.send(message.as_bytes())?;
+            }
+            Target::Tcp(stream) => writeln!(stream, "{}", record)?,
+            Target::Http(client, url) => {
+                client
+                    .post(url.clone())
+                    .json(record)
+                    .send()?
+                    .error_for_status()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn resolve(url: &Url, default_port: Option<u16>) -> Result<std::net::SocketAddr> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("No host specified in {}", url))?;
+    let port = url
+        .port()
+        .or(default_port)
+        .ok_or_else(|| anyhow!("No port specified in {}", url))?;
+    (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Unable to resolve {}", host))
+}
+
+/// Start forwarding the log messages to `url`, which is one of
+/// `syslog://host[:port]`, `tcp://host:port` or `http(s)://...`
+pub fn set_forward_target(url: &str) -> Result<()> {
+    let url = Url::parse(url)?;
+    let target = match url.scheme() {
+        "syslog" => {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(resolve(&url, Some(DEFAULT_SYSLOG_PORT))?)?;
+            Target::Syslog(socket)
+        }
+        "tcp" => {
+            let stream = TcpStream::connect_timeout(&resolve(&url, None)?, FORWARD_TIMEOUT)?;
+            stream.set_write_timeout(Some(FORWARD_TIMEOUT))?;
+            Target::Tcp(stream)
+        }
+        "http" | "https" => Target::Http(Client::builder().timeout(FORWARD_TIMEOUT).build()?, url),
+        scheme => bail!("Unsupported log forwarding protocol: {}", scheme),
+    };
+    let mut buf = [0u8; 64];
+    let hostname = gethostname(&mut buf)
+        .ok()
+        .and_then(|s| s.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string();
+    *FORWARDER.lock().unwrap() = Some(Forwarder { target, hostname });
+
+    Ok(())
+}
+
+/// Forward the log record (if enabled). Forwarding is disabled after the first failure
+/// so that an unreachable endpoint does not slow down every message.
+pub fn forward(level: Level, mut record: serde_json::Value) {
+    let mut forwarder = FORWARDER.lock().unwrap();
+    let result = match forwarder.as_mut() {
+        Some(f) => {
+            record["host"] = serde_json::Value::from(f.hostname.as_str());
+            f.send(level, &record)
+        }
+        None => return,
+    };
+    if let Err(e) = result {
+        *forwarder = None;
+        drop(forwarder);
+        warn!("Log forwarding disabled: {}", e);
+    }
+}
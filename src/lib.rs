@@ -0,0 +1,39 @@
+//! Ciel, an nspawn container manager for building AOSC OS packages
+//!
+//! The workspace, instance, build and repository operations are available here for the tools
+//! embedding Ciel, the `ciel` command is a thin frontend of them. The operations in [`actions`]
+//! report their progress through [`logging`] (on stderr) and return their results, printing them
+//! is left to the caller.
+pub mod actions;
+pub mod api;
+mod audit;
+mod binfmt;
+mod bundle;
+mod bwrap;
+mod capture;
+pub mod cli;
+pub mod common;
+pub mod config;
+mod dbus_machine1;
+mod dbus_machine1_machine;
+pub mod diagnose;
+pub mod events;
+pub mod forward;
+pub mod lock;
+pub mod logging;
+mod mac;
+pub mod machine;
+pub mod manpage;
+pub mod migrate;
+pub mod netpolicy;
+pub mod network;
+pub mod notify;
+mod overlayfs;
+pub mod plugin;
+mod polkit;
+mod privsep;
+mod progress;
+pub mod repo;
+pub mod rpc;
+pub mod service;
+mod webhook;
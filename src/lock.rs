@@ -0,0 +1,138 @@
+//! Workspace-wide lock, so that concurrent invocations do not step on each other
+use anyhow::{anyhow, Result};
+use fs3::FileExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    os::unix::fs::MetadataExt,
+};
+
+use crate::info;
+
+/// The lock file (relative to the workspace)
+pub const LOCK_FILE: &str = ".ciel/lock";
+
+/// How the command uses the workspace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Works on the instances, can run alongside other shared commands
+    Shared,
+    /// Changes the base system or the whole workspace
+    Exclusive,
+}
+
+/// Decide how the (sub)command locks the workspace, `None` if no lock is needed
+pub fn lock_mode_for(command: &str, subcommand: Option<&str>) -> Option<LockMode> {
+    match (command, subcommand) {
+        (
+            "init" | "new" | "load-os" | "update-os" | "commit" | "del" | "restore" | "farewell"
+            | "config",
+            _,
+        )
+        | ("export-workspace" | "import-workspace" | "clean", _)
+        | ("repo", Some("init" | "deinit"))
+        | ("remote", Some("add" | "remove")) => Some(LockMode::Exclusive),
+        (
+            "add" | "shell" | "run" | "attach" | "build" | "rollback" | "down" | "stop"
+            | "cross-setup",
+            _,
+        )
+        | ("mount" | "load-tree" | "repo", _) => Some(LockMode::Shared),
+        _ => None,
+    }
+}
+
+/// Held as long as this is alive (or until the process exits)
+pub struct WorkspaceLock {
+    _file: File,
+}
+
+/// Find the processes holding (or waiting for) the lock, from `/proc/locks`
+fn lock_holders(file: &File) -> Vec<u32> {
+    let inode = match file.metadata() {
+        Ok(m) => format!(":{}", m.ino()),
+        Err(_) => return Vec::new(),
+    };
+    let locks = fs::read_to_string("/proc/locks").unwrap_or_default();
+    let mut pids = locks
+        .lines()
+        .filter(|line| !line.contains("->"))
+        .filter_map(|line| {
+            // 1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.get(5)?.ends_with(&inode) {
+                fields.get(4)?.parse().ok()
+            } else {
+                None
+            }
+        })
+        .filter(|pid| *pid != std::process::id())
+        .collect::<Vec<u32>>();
+    pids.dedup();
+
+    pids
+}
+
+#[inline]
+fn describe_process(pid: u32) -> String {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let cmdline = String::from_utf8_lossy(&cmdline).replace('\0', " ");
+    if cmdline.trim().is_empty() {
+        return format!("PID {}", pid);
+    }
+
+    format!("PID {}: {}", pid, cmdline.trim())
+}
+
+/// Lock the workspace, waiting for the other invocations to finish unless `wait` is false
+pub fn lock_workspace(mode: LockMode, wait: bool) -> Result<WorkspaceLock> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(LOCK_FILE)?;
+    let result = match mode {
+        LockMode::Shared => FileExt::try_lock_shared(&file),
+        LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+    };
+    match result {
+        Ok(()) => return Ok(WorkspaceLock { _file: file }),
+        Err(e) if e.raw_os_error() == fs3::lock_contended_error().raw_os_error() => (),
+        Err(e) => return Err(e.into()),
+    }
+    let holders = lock_holders(&file)
+        .into_iter()
+        .map(describe_process)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let holders = if holders.is_empty() {
+        "another process".to_string()
+    } else {
+        holders
+    };
+    if !wait {
+        return Err(anyhow!("Workspace busy (held by {})", holders));
+    }
+    info!("Workspace busy (held by {}), waiting ...", holders);
+    match mode {
+        LockMode::Shared => FileExt::lock_shared(&file),
+        LockMode::Exclusive => FileExt::lock_exclusive(&file),
+    }
+    .map_err(|e| anyhow!("Unable to lock the workspace: {}", e))?;
+
+    Ok(WorkspaceLock { _file: file })
+}
+
+#[test]
+fn test_lock_mode() {
+    assert_eq!(lock_mode_for("commit", None), Some(LockMode::Exclusive));
+    assert_eq!(lock_mode_for("build", None), Some(LockMode::Shared));
+    assert_eq!(
+        lock_mode_for("repo", Some("init")),
+        Some(LockMode::Exclusive)
+    );
+    assert_eq!(
+        lock_mode_for("repo", Some("refresh")),
+        Some(LockMode::Shared)
+    );
+    assert_eq!(lock_mode_for("list", None), None);
+}
@@ -1,24 +1,334 @@
+use anyhow::{anyhow, Result};
+use console::style;
+use lazy_static::lazy_static;
+use std::{
+    cell::RefCell,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+
+use crate::{forward, progress};
+
+/// Only warnings and errors are shown
+pub const LEVEL_QUIET: usize = 0;
+pub const LEVEL_INFO: usize = 1;
+/// Also show the executed commands and the mounts performed
+pub const LEVEL_DEBUG: usize = 2;
+/// Also show the full details (environment variables, D-Bus calls, etc.)
+pub const LEVEL_TRACE: usize = 3;
+
+/// Where the log of the workspace is kept (relative to the workspace)
+pub const WORKSPACE_LOG_FILE: &str = ".ciel/log/ciel.log";
+// the log file is rotated when it grows larger than this
+const MAX_LOG_SIZE: u64 = 4 * 1024 * 1024;
+// how many rotated log files (ciel.log.1, ciel.log.2, ...) are kept
+const LOG_ROTATIONS: usize = 3;
+
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LEVEL_INFO);
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+static INSTANCE_PREFIX: AtomicBool = AtomicBool::new(false);
+
+// (module path without the crate name, log level), e.g. ("overlayfs", LEVEL_DEBUG)
+type ModuleLevels = Vec<(String, usize)>;
+
+/// Environment variable for the per-module log levels (e.g. `info,overlayfs=debug`)
+pub const LOG_FILTER_ENV: &str = "CIEL_LOG";
+
+lazy_static! {
+    static ref LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+    static ref MODULE_LEVELS: Mutex<ModuleLevels> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    // instances may be operated on in parallel, see `actions::for_each_instance`
+    static CURRENT_INSTANCE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Set the verbosity of the log messages (one of the `LEVEL_*` constants)
+pub fn set_log_level(level: usize) {
+    LOG_LEVEL.store(level, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn log_level() -> usize {
+    LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+fn parse_level(name: &str) -> Result<usize> {
+    match name.to_lowercase().as_str() {
+        "quiet" | "error" | "warn" | "warning" => Ok(LEVEL_QUIET),
+        "info" => Ok(LEVEL_INFO),
+        "debug" => Ok(LEVEL_DEBUG),
+        "trace" => Ok(LEVEL_TRACE),
+        _ => Err(anyhow!("Invalid log level: {}", name)),
+    }
+}
+
+/// Return the module path relative to the crate, e.g. `overlayfs` for `ciel::overlayfs`
+/// (`ciel_rs::`, the name of the crate before, is accepted too)
+fn strip_crate_name(module: &str) -> &str {
+    module
+        .strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::"))
+        .or_else(|| module.strip_prefix("ciel_rs::"))
+        .unwrap_or(module)
+}
+
+/// Parse the log filter (`level,module=level,...`) into the default level and
+/// the levels of the modules
+fn parse_log_filter(filter: &str) -> Result<(Option<usize>, ModuleLevels)> {
+    let mut default = None;
+    let mut modules = Vec::new();
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((module, level)) => {
+                let module = strip_crate_name(module.trim());
+                modules.push((module.to_string(), parse_level(level.trim())?));
+            }
+            None => default = Some(parse_level(directive)?),
+        }
+    }
+
+    Ok((default, modules))
+}
+
+/// Set the per-module log levels, e.g. `overlayfs=debug,machine=trace`.
+/// A bare level (e.g. `debug`) sets the default level unless `override_default` is false.
+pub fn set_log_filter(filter: &str, override_default: bool) -> Result<()> {
+    let (default, modules) = parse_log_filter(filter)?;
+    if let Some(level) = default.filter(|_| override_default) {
+        set_log_level(level);
+    }
+    *MODULE_LEVELS.lock().unwrap() = modules;
+
+    Ok(())
+}
+
+/// The log level of the module (the most specific directive wins)
+fn module_log_level(module: &str) -> usize {
+    let module = strip_crate_name(module);
+    MODULE_LEVELS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(prefix, _)| {
+            module == prefix
+                || (module.starts_with(prefix.as_str()) && module[prefix.len()..].starts_with("::"))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or_else(log_level, |(_, level)| *level)
+}
+
+#[inline]
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Rotate the log file if it is too large: ciel.log -> ciel.log.1 -> ciel.log.2 ...
+fn rotate_log_file(path: &Path) -> std::io::Result<()> {
+    if fs::metadata(path).map(|m| m.len()).unwrap_or(0) < MAX_LOG_SIZE {
+        return Ok(());
+    }
+    for index in (1..LOG_ROTATIONS).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, index + 1))?;
+        }
+    }
+
+    fs::rename(path, rotated_path(path, 1))
+}
+
+#[inline]
+fn format_now() -> String {
+    OffsetDateTime::now_utc()
+        .format(format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second]Z"
+        ))
+        .unwrap_or_default()
+}
+
+/// Start copying the info, warning and error messages (regardless of the log level)
+/// into the log file, `command` is recorded as the header of this run
+pub fn open_log_file(path: &Path, command: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    rotate_log_file(path)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "[{}] ==> {} (pid {})",
+        format_now(),
+        command,
+        std::process::id()
+    )?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+
+    Ok(())
+}
+
+/// Use JSON lines for the log messages (or human-readable text if unset)
+pub fn set_json_format(json: bool) {
+    JSON_FORMAT.store(json, Ordering::Relaxed);
+}
+
+/// Prefix the messages printed to the terminal with the current time
+pub fn set_timestamps(enabled: bool) {
+    TIMESTAMPS.store(enabled, Ordering::Relaxed);
+}
+
+/// Set the instance being operated on (by the current thread), which is recorded in the
+/// JSON log messages
+pub fn set_current_instance(instance: Option<&str>) {
+    CURRENT_INSTANCE.with(|current| *current.borrow_mut() = instance.map(String::from));
+}
+
+/// Return the instance being operated on by the current thread
+pub fn current_instance() -> Option<String> {
+    CURRENT_INSTANCE.with(|current| current.borrow().clone())
+}
+
+/// Prefix the messages printed to the terminal with the current instance, for telling apart
+/// the instances operated on in parallel
+pub fn set_instance_prefix(enabled: bool) {
+    INSTANCE_PREFIX.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+fn json_record(level: &str, module: &str, message: &str) -> serde_json::Value {
+    serde_json::json!({
+        "level": level,
+        "timestamp": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+        "module": module,
+        "message": console::strip_ansi_codes(message),
+        "instance": current_instance(),
+    })
+}
+
+#[inline]
+fn format_json(level: &str, module: &str, message: &str) -> String {
+    json_record(level, module, message).to_string()
+}
+
+/// Write the message to the log file (if opened), errors are ignored
+fn write_log(level: &str, module: &str, message: &str) {
+    if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+        if JSON_FORMAT.load(Ordering::Relaxed) {
+            writeln!(file, "{}", format_json(level, module, message)).ok();
+            return;
+        }
+        for line in console::strip_ansi_codes(message).lines() {
+            writeln!(file, "[{}] {}: {}", format_now(), level, line).ok();
+        }
+    }
+}
+
+/// Severity of the log messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// The minimal log level (`LEVEL_*`) to show the messages of this severity
+    fn min_log_level(self) -> usize {
+        match self {
+            Level::Error | Level::Warning => LEVEL_QUIET,
+            Level::Info => LEVEL_INFO,
+            Level::Debug => LEVEL_DEBUG,
+            Level::Trace => LEVEL_TRACE,
+        }
+    }
+}
+
+/// Print the message (if allowed by the log level) and record it in the log file.
+/// Use the macros (`info!`, `warn!`, etc.) instead of calling this directly.
+pub fn log(level: Level, module: &str, message: &str) {
+    if module_log_level(module) >= level.min_log_level() {
+        if JSON_FORMAT.load(Ordering::Relaxed) {
+            progress::eprintln(&format_json(level.name(), module, message));
+        } else {
+            let prefix = match level {
+                Level::Error => style("error:").red().bold(),
+                Level::Warning => style("warning:").yellow().bold(),
+                Level::Info => style("info:").cyan().bold(),
+                Level::Debug => style("debug:").magenta().bold(),
+                Level::Trace => style("trace:").dim().bold(),
+            };
+            let mut line = format!("{} {}", prefix, message);
+            if INSTANCE_PREFIX.load(Ordering::Relaxed) {
+                if let Some(instance) = current_instance() {
+                    line = format!("{} {}", style(instance).cyan().bold(), line);
+                }
+            }
+            if TIMESTAMPS.load(Ordering::Relaxed) {
+                line = format!("{} {}", style(format!("[{}]", format_now())).dim(), line);
+            }
+            progress::eprintln(&line);
+        }
+    }
+    // only the messages shown by default are recorded
+    if level.min_log_level() <= LEVEL_INFO {
+        write_log(level.name(), module, message);
+        forward::forward(level, json_record(level.name(), module, message));
+    }
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Trace, module_path!(), &format!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Debug, module_path!(), &format!($($arg)+))
+    };
+}
+
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)+) => {
-        eprint!("{} ", style("info:").cyan().bold());
-        eprintln!($($arg)+);
+        $crate::logging::log($crate::logging::Level::Info, module_path!(), &format!($($arg)+))
     };
 }
 
 #[macro_export]
 macro_rules! warn {
     ($($arg:tt)+) => {
-        eprint!("{} ", style("warning:").yellow().bold());
-        eprintln!($($arg)+);
+        $crate::logging::log($crate::logging::Level::Warning, module_path!(), &format!($($arg)+))
     };
 }
 
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)+) => {
-        eprint!("{} ", style("error:").red().bold());
-        eprintln!($($arg)+);
+        $crate::logging::log($crate::logging::Level::Error, module_path!(), &format!($($arg)+))
     };
 }
 
@@ -32,3 +342,27 @@ macro_rules! color_bool {
         }
     };
 }
+
+#[test]
+fn test_log_filter() {
+    let (default, modules) =
+        parse_log_filter("info, overlayfs=debug,ciel::repo::scan=trace,ciel_rs::machine=info")
+            .unwrap();
+    assert_eq!(default, Some(LEVEL_INFO));
+    assert_eq!(
+        modules,
+        vec![
+            ("overlayfs".to_string(), LEVEL_DEBUG),
+            ("repo::scan".to_string(), LEVEL_TRACE),
+            ("machine".to_string(), LEVEL_INFO)
+        ]
+    );
+    assert!(parse_log_filter("overlayfs=loud").is_err());
+}
+
+#[test]
+fn test_module_log_level() {
+    set_log_filter("logging=trace", false).unwrap();
+    assert_eq!(module_log_level(module_path!()), LEVEL_TRACE);
+    set_log_filter("", false).unwrap();
+}
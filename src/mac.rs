@@ -0,0 +1,300 @@
+//! Mandatory access control: labeling the filesystems and the processes of the instances with
+//! the SELinux contexts and the AppArmor profile in `[mac]` of the config, so that the instances
+//! work on the hosts enforcing the policies without relabeling the workspace by hand
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use nix::{
+    fcntl::{open, OFlag},
+    sys::stat::Mode,
+    unistd::{close, write},
+};
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::process::CommandExt,
+    path::Path,
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config, config::MacConfig, machine::get_output_with_timeout};
+
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+const APPARMOR_ENABLED: &str = "/sys/module/apparmor/parameters/enabled";
+const APPARMOR_PROFILES: &str = "/sys/kernel/security/apparmor/profiles";
+// the AppArmor interface of the kernels stacking the LSMs (5.8+), and the legacy one
+const APPARMOR_EXEC_ATTR: &str = "/proc/self/attr/apparmor/exec";
+const LEGACY_EXEC_ATTR: &str = "/proc/self/attr/exec";
+const SELINUX_LABEL_XATTR: &str = "security.selinux";
+const AUDIT_LOG: &str = "/var/log/audit/audit.log";
+// how far back to look for the denials
+const DENIAL_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+lazy_static! {
+    // read once per process, as every container (and command in it) is started under the profile
+    static ref APPARMOR_PROFILE: Option<String> = config::read_config()
+        .ok()
+        .and_then(|c| c.mac.apparmor_profile);
+}
+
+/// The state of SELinux on the host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelinuxMode {
+    Disabled,
+    /// The denials are only logged
+    Permissive,
+    Enforcing,
+}
+
+pub fn selinux_mode() -> SelinuxMode {
+    match fs::read_to_string(SELINUX_ENFORCE) {
+        Ok(value) if value.trim() == "1" => SelinuxMode::Enforcing,
+        Ok(_) => SelinuxMode::Permissive,
+        Err(_) => SelinuxMode::Disabled,
+    }
+}
+
+pub fn apparmor_enabled() -> bool {
+    fs::read_to_string(APPARMOR_ENABLED)
+        .map(|value| value.trim() == "Y")
+        .unwrap_or(false)
+}
+
+/// Check if the AppArmor profile is loaded (listed as `NAME (MODE)`)
+fn is_apparmor_profile_loaded(name: &str) -> Result<bool> {
+    let profiles = fs::read_to_string(APPARMOR_PROFILES)
+        .map_err(|e| anyhow!("Unable to list the AppArmor profiles: {}", e))?;
+
+    Ok(profiles
+        .lines()
+        .any(|line| line.rsplit_once(" (").map(|(profile, _)| profile) == Some(name)))
+}
+
+/// The type of the SELinux context (`user:role:type:level`)
+pub fn get_selinux_type(context: &str) -> Option<&str> {
+    context.split(':').nth(2).filter(|t| !t.is_empty())
+}
+
+/// The SELinux label of the file, if labeled
+pub fn get_selinux_label(path: &Path) -> Option<String> {
+    let label = xattr::get(path, SELINUX_LABEL_XATTR).ok()??;
+
+    Some(
+        String::from_utf8_lossy(&label)
+            .trim_end_matches('\0')
+            .to_string(),
+    )
+}
+
+/// Check if the labels in the config can be applied on this host
+pub fn check_config(config: &MacConfig) -> Result<()> {
+    let contexts = config
+        .selinux_context
+        .iter()
+        .chain(config.selinux_file_context.iter());
+    for context in contexts {
+        if get_selinux_type(context).is_none()
+            || context.contains(|c: char| c.is_whitespace() || c == '"')
+        {
+            return Err(anyhow!(
+                "Invalid SELinux context: {} (expected `user:role:type:level`)",
+                context
+            ));
+        }
+        if selinux_mode() == SelinuxMode::Disabled {
+            return Err(anyhow!(
+                "SELinux context {} is configured, but SELinux is not enabled on this host.",
+                context
+            ));
+        }
+    }
+    if let Some(profile) = &config.apparmor_profile {
+        if !apparmor_enabled() {
+            return Err(anyhow!(
+                "AppArmor profile {} is configured, but AppArmor is not enabled on this host.",
+                profile
+            ));
+        }
+        if !is_apparmor_profile_loaded(profile)? {
+            return Err(anyhow!(
+                "AppArmor profile {} is not loaded (try `apparmor_parser -r`).",
+                profile
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The option labeling the filesystems mounted for the instances, if configured
+pub fn get_mount_options(config: &MacConfig) -> Option<String> {
+    // the level may contain commas (`s0:c1,c2`)
+    config
+        .selinux_file_context
+        .as_ref()
+        .map(|context| format!("context=\"{}\"", context))
+}
+
+/// Generate the nspawn options for the SELinux contexts of the container
+pub fn get_nspawn_options(config: &MacConfig) -> Vec<String> {
+    let mut options = Vec::new();
+    if let Some(context) = &config.selinux_context {
+        options.push(format!("--selinux-context={}", context));
+    }
+    if let Some(context) = &config.selinux_file_context {
+        options.push(format!("--selinux-apifs-context={}", context));
+    }
+
+    options
+}
+
+/// Generate the bwrap options for the SELinux contexts of the container
+pub fn get_bwrap_options(config: &MacConfig) -> Vec<String> {
+    let mut options = Vec::new();
+    if let Some(context) = &config.selinux_context {
+        options.push("--exec-label".to_string());
+        options.push(context.to_string());
+    }
+    if let Some(context) = &config.selinux_file_context {
+        options.push("--file-label".to_string());
+        options.push(context.to_string());
+    }
+
+    options
+}
+
+/// Start the command (systemd-nspawn or bwrap) under the AppArmor profile of the workspace,
+/// if configured
+pub fn confine_command(command: &mut Command) {
+    let profile = match APPARMOR_PROFILE.as_deref() {
+        Some(profile) => profile,
+        None => return,
+    };
+    let attr = if Path::new(APPARMOR_EXEC_ATTR).exists() {
+        APPARMOR_EXEC_ATTR
+    } else {
+        LEGACY_EXEC_ATTR
+    };
+    // nothing is allocated after forking
+    let attr = CString::new(attr).unwrap();
+    let request = format!("exec {}", profile).into_bytes();
+    unsafe {
+        command.pre_exec(move || {
+            let fd = open(attr.as_c_str(), OFlag::O_WRONLY, Mode::empty())
+                .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+            let result = write(fd, &request);
+            close(fd).ok();
+            result
+                .map(|_| ())
+                .map_err(|e| io::Error::from_raw_os_error(e as i32))
+        });
+    }
+}
+
+/// The time (in seconds) of the audit record, from `msg=audit(1697458400.123:456)`
+fn get_audit_time(line: &str) -> Option<u64> {
+    let (_, time) = line.split_once("msg=audit(")?;
+
+    time.split('.').next()?.parse().ok()
+}
+
+/// Check if the line of the kernel or audit log is a denial involving the workspace
+/// or the labels of the instances
+fn is_instance_denial(line: &str, config: &MacConfig, workspace: &str) -> bool {
+    let selinux = line.contains("avc:") && line.contains(" denied ");
+    let apparmor = line.contains("apparmor=\"DENIED\"");
+    if !selinux && !apparmor {
+        return false;
+    }
+    if line.contains(workspace)
+        || line.contains("comm=\"systemd-nspawn\"")
+        || line.contains("comm=\"bwrap\"")
+    {
+        return true;
+    }
+    if selinux {
+        let contexts = config
+            .selinux_context
+            .iter()
+            .chain(config.selinux_file_context.iter());
+        for selinux_type in contexts.filter_map(|c| get_selinux_type(c)) {
+            if line.contains(&format!(":{}:", selinux_type)) {
+                return true;
+            }
+        }
+    }
+
+    apparmor
+        && matches!(&config.apparmor_profile, Some(profile) if line.contains(&format!("profile=\"{}\"", profile)))
+}
+
+/// Read the audit log (written by auditd) of the last day
+fn read_audit_log() -> Option<String> {
+    let log = fs::read(AUDIT_LOG).ok()?;
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .saturating_sub(DENIAL_WINDOW)
+        .as_secs();
+    let lines: Vec<&str> = std::str::from_utf8(&log)
+        .ok()?
+        .lines()
+        .filter(|line| !matches!(get_audit_time(line), Some(time) if time < since))
+        .collect();
+
+    Some(lines.join("\n"))
+}
+
+/// Find the SELinux/AppArmor denials involving the instances in the workspace during the last
+/// day (in the journal, or the audit log if the journal is not available), the oldest first
+pub fn find_recent_denials(config: &MacConfig, workspace: &Path) -> Vec<String> {
+    let workspace = workspace.to_string_lossy();
+    let since = format!("--since=-{}s", DENIAL_WINDOW.as_secs());
+    let log = get_output_with_timeout(
+        Command::new("journalctl").args(&[
+            "-q",
+            "--no-pager",
+            "-o",
+            "cat",
+            &since,
+            "_TRANSPORT=kernel",
+            "_TRANSPORT=audit",
+        ]),
+        Duration::from_secs(10),
+    )
+    .filter(|log| !log.trim().is_empty())
+    .or_else(read_audit_log)
+    .unwrap_or_default();
+
+    log.lines()
+        .filter(|line| is_instance_denial(line, config, &workspace))
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+#[test]
+fn test_is_instance_denial() {
+    let config = MacConfig {
+        selinux_file_context: Some("system_u:object_r:container_file_t:s0".to_string()),
+        apparmor_profile: Some("ciel-container".to_string()),
+        ..Default::default()
+    };
+    let avc = r#"type=AVC msg=audit(1697458400.123:456): avc:  denied  { write } for  pid=1234 comm="make" name="debs" dev="dm-0" ino=42 scontext=system_u:system_r:spc_t:s0 tcontext=unconfined_u:object_r:container_file_t:s0 tclass=dir permissive=0"#;
+    assert!(is_instance_denial(avc, &config, "/buildroots/ciel"));
+    assert_eq!(get_audit_time(avc), Some(1697458400));
+    let apparmor = r#"audit: type=1400 audit(1697458400.123:457): apparmor="DENIED" operation="mount" profile="ciel-container" name="/proc/" pid=1234 comm="systemd""#;
+    assert!(is_instance_denial(apparmor, &config, "/buildroots/ciel"));
+    let other = r#"apparmor="DENIED" operation="open" profile="snap.firefox" name="/etc/passwd" pid=1 comm="firefox""#;
+    assert!(!is_instance_denial(other, &config, "/buildroots/ciel"));
+    let in_workspace = r#"apparmor="DENIED" operation="open" profile="snap.firefox" name="/buildroots/ciel/TREE/" pid=1 comm="firefox""#;
+    assert!(is_instance_denial(
+        in_workspace,
+        &config,
+        "/buildroots/ciel"
+    ));
+    assert!(!is_instance_denial(
+        "audit: type=1130 audit(1697458400.123:458): pid=1 comm=\"bwrap\"",
+        &config,
+        "/buildroots/ciel"
+    ));
+}
@@ -1,46 +1,150 @@
 //! This module contains systemd machined related APIs
 
+use crate::actions::get_dist_arch;
+use crate::bwrap;
+use crate::capture;
 use crate::common::{is_legacy_workspace, CIEL_INST_DIR};
+use crate::config::{InstanceConfig, NetworkMode};
 use crate::dbus_machine1::OrgFreedesktopMachine1Manager;
 use crate::dbus_machine1_machine::OrgFreedesktopMachine1Machine;
+use crate::mac;
+use crate::netpolicy;
 use crate::overlayfs::is_mounted;
-use crate::{color_bool, info, overlayfs::LayerManager, warn};
+use crate::{
+    color_bool, debug, error, info,
+    overlayfs::{LayerManager, MountState},
+    trace, warn,
+};
 use adler32::adler32;
 use anyhow::{anyhow, Result};
-use console::style;
+use console::{style, Term};
 use dbus::blocking::{Connection, Proxy};
+use indicatif::HumanBytes;
 use libc::{c_char, ftok, waitpid, WNOHANG};
 use libsystemd_sys::bus::{sd_bus_flush_close_unref, sd_bus_open_system_machine};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::{close, isatty, read, write};
+use serde::Serialize;
 use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
     ffi::{CString, OsStr},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
     mem::MaybeUninit,
+    net::IpAddr,
+    os::unix::io::RawFd,
     process::Command,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use std::{fs, time::Duration};
 use std::{os::unix::ffi::OsStrExt, process::Child};
-use std::{path::Path, process::Stdio, thread::sleep};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    thread::sleep,
+};
 
 const MACHINE1_PATH: &str = "/org/freedesktop/machine1";
+// Ctrl-], pressing it three times within a second detaches from the console
+const ESCAPE_CHAR: u8 = 0x1d;
 const MACHINE1_DEST: &str = "org.freedesktop.machine1";
+// machined keeps a state file for each registered machine here
+const MACHINED_STATE_DIR: &str = "/run/systemd/machines";
+// how many lines of logs to show when the container fails to boot
+const BOOT_LOG_LINES: usize = 20;
+const KNOWN_CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
 const DEFAULT_NSPAWN_OPTIONS: &[&str] = &[
-    "-qb",
+    "-q",
     "--capability=CAP_IPC_LOCK",
     "--system-call-filter=swapcontext",
 ];
+/// The `default` seccomp profile: system calls a build has no business with
+/// (in addition to those already blocked by nspawn)
+const DEFAULT_SECCOMP_PROFILE: &[&str] = &[
+    "~@clock",
+    "~@cpu-emulation",
+    "~@module",
+    "~@obsolete",
+    "~@raw-io",
+    "~@swap",
+];
 
 extern "C" {
     fn SYS_SIGRTMIN() -> libc::c_int;
 }
 /// Instance status information
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct CielInstance {
-    name: String,
+    pub name: String,
     // namespace name (in the form of `$name-$id`)
     ns_name: String,
     pub mounted: bool,
-    running: bool,
+    pub running: bool,
     pub started: bool,
-    booted: Option<bool>,
+    pub booted: Option<bool>,
+    // system state reported by the systemd in the container (e.g. `running`, `degraded`)
+    system_state: Option<String>,
+    // PID of the leader process (init) on the host
+    leader: Option<u32>,
+    addresses: Vec<IpAddr>,
+    // from the instance configuration (the architecture of the base system if not emulated)
+    pub arch: Option<String>,
+    pub description: Option<String>,
+}
+
+impl CielInstance {
+    /// Query the system state from the systemd in the container (only for the booted ones),
+    /// this spawns `systemctl` so it is not done by `inspect_instance`
+    pub fn query_system_state(&mut self) {
+        if self.booted == Some(true) {
+            self.system_state = get_system_state(&self.ns_name);
+        }
+    }
 }
 
 /// Used for getting the instance name from Ciel 1/2
@@ -131,6 +235,12 @@ fn setup_bind_mounts(ns_name: &str, mounts: &[(String, &str)]) -> Result<()> {
     for mount in mounts {
         fs::create_dir_all(&mount.0)?;
         let source_path = fs::canonicalize(&mount.0)?;
+        debug!(
+            "{}: bind-mounting {} to {}",
+            ns_name,
+            source_path.display(),
+            mount.1
+        );
         proxy.bind_mount_machine(
             ns_name,
             &source_path.to_string_lossy(),
@@ -143,6 +253,175 @@ fn setup_bind_mounts(ns_name: &str, mounts: &[(String, &str)]) -> Result<()> {
     Ok(())
 }
 
+/// Bind-mount host files (host path, container path, read-only) into the running container
+pub fn bind_mount_files(ns_name: &str, files: &[(PathBuf, &str, bool)]) -> Result<()> {
+    let conn = Connection::new_system()?;
+    let proxy = conn.with_proxy(MACHINE1_DEST, MACHINE1_PATH, Duration::from_secs(10));
+    for (source, dest, read_only) in files {
+        debug!(
+            "{}: bind-mounting {} to {}",
+            ns_name,
+            source.display(),
+            dest
+        );
+        proxy.bind_mount_machine(ns_name, &source.to_string_lossy(), dest, *read_only, true)?;
+    }
+
+    Ok(())
+}
+
+/// Un-mount the files bind-mounted by `bind_mount_files` from the running container
+pub fn unbind_mount_files(ns_name: &str, files: &[(PathBuf, &str, bool)]) -> Result<()> {
+    let mut command = Command::new("systemd-run");
+    command
+        .args(&["-M", ns_name, "-q", "--wait", "--", "umount", "-l"])
+        .args(files.iter().map(|(_, dest, _)| dest));
+    debug!("Running {:?}", command);
+    if !command.status()?.success() {
+        return Err(anyhow!(
+            "{}: unable to un-mount the forwarded files",
+            ns_name
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validate and normalize the port forwarding rule (`[tcp|udp:]HOSTPORT[:CONTAINERPORT]`)
+fn parse_port_rule(rule: &str) -> Result<String> {
+    let (protocol, ports) = match rule.split_once(':') {
+        Some((protocol, ports)) if protocol == "tcp" || protocol == "udp" => (protocol, ports),
+        _ => ("tcp", rule),
+    };
+    let (host, container) = ports.split_once(':').unwrap_or((ports, ports));
+    let host: u16 = host
+        .parse()
+        .map_err(|_| anyhow!("Invalid host port in `{}`", rule))?;
+    let container: u16 = container
+        .parse()
+        .map_err(|_| anyhow!("Invalid container port in `{}`", rule))?;
+    if host == 0 || container == 0 {
+        return Err(anyhow!("Port 0 is not allowed in `{}`", rule));
+    }
+
+    Ok(format!("{}:{}:{}", protocol, host, container))
+}
+
+/// Generate the nspawn options for the network mode and the port forwarding rules
+pub fn get_network_options(mode: NetworkMode, publish: &[String]) -> Result<Vec<String>> {
+    let mut options = Vec::new();
+    match mode {
+        NetworkMode::Host => (),
+        NetworkMode::Private => options.push("--network-veth".to_string()),
+        NetworkMode::None => options.push("--private-network".to_string()),
+    }
+    if !publish.is_empty() && mode != NetworkMode::Private {
+        return Err(anyhow!(
+            "Port forwarding requires the private network mode."
+        ));
+    }
+    for rule in publish {
+        options.push(format!("--port={}", parse_port_rule(rule)?));
+    }
+
+    Ok(options)
+}
+
+/// Normalize the capability name (`sys_admin` -> `CAP_SYS_ADMIN`), `all` is also accepted
+fn parse_capability(cap: &str) -> Result<String> {
+    let cap = cap.trim().to_ascii_uppercase();
+    if cap == "ALL" {
+        return Ok("all".to_string());
+    }
+    let cap = if cap.starts_with("CAP_") {
+        cap
+    } else {
+        format!("CAP_{}", cap)
+    };
+    if !KNOWN_CAPABILITIES.contains(&cap.as_str()) {
+        return Err(anyhow!("Unknown capability: {}", cap));
+    }
+
+    Ok(cap)
+}
+
+/// Check if the entry is a valid system call filter entry (`[~]SYSCALL` or `[~]@GROUP`)
+fn is_valid_syscall_filter(entry: &str) -> bool {
+    let entry = entry.strip_prefix('~').unwrap_or(entry);
+    let (name, allowed): (&str, fn(char) -> bool) = if let Some(group) = entry.strip_prefix('@') {
+        (group, |c| c.is_ascii_lowercase() || c == '-')
+    } else {
+        (entry, |c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'
+        })
+    };
+
+    !name.is_empty() && name.chars().all(allowed)
+}
+
+/// Find the system call filter entries of the seccomp profile, the profiles defined in the
+/// workspace config take precedence over the one shipped by ciel
+fn get_seccomp_profile(
+    name: &str,
+    profiles: &BTreeMap<String, Vec<String>>,
+) -> Result<Vec<String>> {
+    if let Some(profile) = profiles.get(name) {
+        return Ok(profile.clone());
+    }
+    if name == "default" {
+        return Ok(DEFAULT_SECCOMP_PROFILE
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect());
+    }
+
+    Err(anyhow!(
+        "Unknown seccomp profile: {} (define it in `seccomp-profiles` of the workspace config)",
+        name
+    ))
+}
+
+/// Generate the nspawn options for the capabilities and the system call filter of the instance,
+/// `profiles` are the seccomp profiles defined in the workspace config
+pub fn get_security_options(
+    config: &InstanceConfig,
+    profiles: &BTreeMap<String, Vec<String>>,
+) -> Result<Vec<String>> {
+    let mut options = Vec::new();
+    let capabilities = config
+        .capabilities
+        .iter()
+        .map(|c| parse_capability(c))
+        .collect::<Result<Vec<_>>>()?;
+    let drop_capabilities = config
+        .drop_capabilities
+        .iter()
+        .map(|c| parse_capability(c))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(cap) = capabilities.iter().find(|c| drop_capabilities.contains(c)) {
+        return Err(anyhow!("Capability {} is both granted and dropped.", cap));
+    }
+    if !capabilities.is_empty() {
+        options.push(format!("--capability={}", capabilities.join(",")));
+    }
+    if !drop_capabilities.is_empty() {
+        options.push(format!("--drop-capability={}", drop_capabilities.join(",")));
+    }
+    let mut filter = match &config.seccomp_profile {
+        Some(name) => get_seccomp_profile(name, profiles)?,
+        None => Vec::new(),
+    };
+    filter.extend(config.system_call_filter.iter().cloned());
+    for entry in filter.iter() {
+        if !is_valid_syscall_filter(entry) {
+            return Err(anyhow!("Invalid system call filter: {}", entry));
+        }
+        options.push(format!("--system-call-filter={}", entry));
+    }
+
+    Ok(options)
+}
+
 /// Get the container name (ns_name) of the instance
 pub fn get_container_ns_name<P: AsRef<Path>>(path: P, legacy: bool) -> Result<String> {
     let current_dir = std::env::current_dir()?;
@@ -156,6 +435,106 @@ pub fn get_container_ns_name<P: AsRef<Path>>(path: P, legacy: bool) -> Result<St
     new_container_name(&path)
 }
 
+/// Run the command and collect its output, gives up if the command does not finish in time
+pub fn get_output_with_timeout(command: &mut Command, timeout: Duration) -> Option<String> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let start = Instant::now();
+    while child.try_wait().ok()?.is_none() {
+        if start.elapsed() > timeout {
+            child.kill().ok();
+            child.wait().ok();
+            return None;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    let mut output = String::new();
+    child.stdout.take()?.read_to_string(&mut output).ok()?;
+
+    Some(output)
+}
+
+/// Print the last lines of the text with the title, does nothing if the text is empty
+fn print_log_tail(title: &str, text: &str) {
+    let lines = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        return;
+    }
+    error!("{}", title);
+    for line in lines
+        .iter()
+        .skip(lines.len().saturating_sub(BOOT_LOG_LINES))
+    {
+        eprintln!("  {}", style(line).dim());
+    }
+}
+
+/// Show the nspawn error output and the relevant journal entries to help diagnosing boot failures
+fn print_boot_diagnostics(ns_name: &str, stderr_log: &mut File, since: u64) {
+    let mut output = String::new();
+    if stderr_log.seek(SeekFrom::Start(0)).is_ok() {
+        stderr_log.read_to_string(&mut output).ok();
+    }
+    print_log_tail("Output from systemd-nspawn:", &output);
+    let lines = BOOT_LOG_LINES.to_string();
+    // journal inside the container (only available if it has started far enough)
+    let journal = get_output_with_timeout(
+        Command::new("journalctl")
+            .args(&["-M", ns_name, "-b", "-q", "--no-pager", "-p", "warning"])
+            .args(&["-n", &lines]),
+        Duration::from_secs(5),
+    );
+    if let Some(journal) = journal {
+        print_log_tail("Journal of the container:", &journal);
+    }
+    // messages from machined on the host (e.g. registration failures)
+    let journal = get_output_with_timeout(
+        Command::new("journalctl")
+            .args(&["-u", "systemd-machined", "-q", "--no-pager"])
+            .arg(format!("--since=@{}", since))
+            .args(&["-n", &lines]),
+        Duration::from_secs(5),
+    );
+    if let Some(journal) = journal {
+        print_log_tail("Journal of systemd-machined:", &journal);
+    }
+}
+
+/// Forcibly terminate the container if it is registered, errors are ignored
+fn discard_container(ns_name: &str) {
+    terminate_machine(ns_name).ok();
+}
+
+/// Ask machined to terminate the machine (and drop its registration)
+pub fn terminate_machine(ns_name: &str) -> Result<()> {
+    let conn = Connection::new_system()?;
+    let proxy = conn.with_proxy(MACHINE1_DEST, MACHINE1_PATH, Duration::from_secs(10));
+    proxy.terminate_machine(ns_name)?;
+
+    Ok(())
+}
+
+/// Check if the machine is registered with machined, from its state file (without D-Bus)
+pub fn is_machine_registered(ns_name: &str) -> bool {
+    Path::new(MACHINED_STATE_DIR).join(ns_name).exists()
+}
+
+/// List the names of all the machines registered with machined
+pub fn list_registered_machines() -> Result<Vec<String>> {
+    let conn = Connection::new_system()?;
+    let proxy = conn.with_proxy(MACHINE1_DEST, MACHINE1_PATH, Duration::from_secs(10));
+    trace!("Calling ListMachines() on machined");
+    let machines = proxy.list_machines()?;
+
+    Ok(machines.into_iter().map(|m| m.0).collect())
+}
+
 /// Spawn a new container using nspawn
 pub fn spawn_container<P: AsRef<Path>>(
     ns_name: &str,
@@ -167,17 +546,34 @@ pub fn spawn_container<P: AsRef<Path>>(
         .as_ref()
         .to_str()
         .ok_or_else(|| anyhow!("Path contains invalid Unicode characters."))?;
-    let mut child = Command::new("systemd-nspawn")
+    // keep the nspawn error output in a file for diagnosing boot failures
+    let mut stderr_log = tempfile::tempfile()?;
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut command = Command::new("systemd-nspawn");
+    command
         .args(DEFAULT_NSPAWN_OPTIONS)
+        .arg("-b")
         .args(extra_options)
         .args(&["-D", path, "-M", ns_name, "--"])
         .env("SYSTEMD_NSPAWN_TMPFS_TMP", "0")
         .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?;
+        .stderr(stderr_log.try_clone()?);
+    netpolicy::enter_build_netns(&mut command);
+    mac::confine_command(&mut command);
+    debug!("Running {:?}", command);
+    let mut child = command.spawn()?;
 
     info!("{}: waiting for container to start...", ns_name);
-    wait_for_container(&mut child, ns_name, 10)?;
+    if let Err(e) = wait_for_container(&mut child, ns_name, 10) {
+        print_boot_diagnostics(ns_name, &mut stderr_log, since);
+        // do not leave a half-booted container behind
+        discard_container(ns_name);
+        child.kill().ok();
+        child.wait().ok();
+        return Err(e);
+    }
     info!("{}: setting up mounts...", ns_name);
     if let Err(e) = setup_bind_mounts(ns_name, mounts) {
         warn!("Failed to setup bind mounts: {:?}", e);
@@ -186,20 +582,169 @@ pub fn spawn_container<P: AsRef<Path>>(
     Ok(())
 }
 
-/// Execute a command in the container
-pub fn execute_container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) -> Result<i32> {
+/// Execute a command in the container, with the specified environment variables set
+/// (as the specified user, or root if not specified)
+pub fn execute_container_command<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    env: &[(String, String)],
+    user: Option<&str>,
+) -> Result<i32> {
     // TODO: maybe replace with systemd API cross-namespace call?
-    let exit_code = Command::new("systemd-run")
-        .args(&["-M", ns_name, "-qt", "--"])
+    let mut command = Command::new("systemd-run");
+    command
+        .args(&["-M", ns_name, "-qt"])
+        .args(user.map(|u| format!("--uid={}", u)))
+        .args(env.iter().map(|(k, v)| format!("--setenv={}={}", k, v)))
+        .arg("--")
+        .args(args);
+    debug!("Running {:?}", command);
+    let exit_code = capture::spawn_and_wait(&mut command, |_| Ok(()))?
+        .code()
+        .unwrap_or(127);
+
+    Ok(exit_code)
+}
+
+/// Execute a command in a lightweight (non-boot) container, which exits together with the command
+pub fn execute_container_command_direct<P: AsRef<Path>, S: AsRef<OsStr>>(
+    ns_name: &str,
+    path: P,
+    args: &[S],
+    extra_options: &[String],
+    mounts: &[(String, &str)],
+    env: &[(String, String)],
+    user: Option<&str>,
+) -> Result<i32> {
+    let path = path
+        .as_ref()
+        .to_str()
+        .ok_or_else(|| anyhow!("Path contains invalid Unicode characters."))?;
+    let mut binds = Vec::new();
+    for mount in mounts {
+        fs::create_dir_all(&mount.0)?;
+        let source_path = fs::canonicalize(&mount.0)?;
+        binds.push(format!("--bind={}:{}", source_path.display(), mount.1));
+    }
+    let mut command = Command::new("systemd-nspawn");
+    command
+        .args(DEFAULT_NSPAWN_OPTIONS)
+        .args(extra_options)
+        .args(binds)
+        .args(user.map(|u| format!("--user={}", u)))
+        .args(env.iter().map(|(k, v)| format!("--setenv={}={}", k, v)))
+        .args(&["-D", path, "-M", ns_name, "--"])
         .args(args)
-        .spawn()?
-        .wait()?
+        .env("SYSTEMD_NSPAWN_TMPFS_TMP", "0");
+    netpolicy::enter_build_netns(&mut command);
+    mac::confine_command(&mut command);
+    debug!("Running {:?}", command);
+    let exit_code = capture::spawn_and_wait(&mut command, |_| Ok(()))?
         .code()
         .unwrap_or(127);
 
     Ok(exit_code)
 }
 
+#[inline]
+fn write_all_fd(fd: RawFd, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        let written = write(fd, buf)?;
+        buf = &buf[written..];
+    }
+
+    Ok(())
+}
+
+/// Forward the data between the current terminal and the pty master
+fn forward_pty(master: RawFd) -> Result<()> {
+    let mut buf = [0u8; 4096];
+    let mut escape_count = 0usize;
+    let mut escape_start = Instant::now();
+    loop {
+        let mut fds = [
+            PollFd::new(libc::STDIN_FILENO, PollFlags::POLLIN),
+            PollFd::new(master, PollFlags::POLLIN),
+        ];
+        match poll(&mut fds, -1) {
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+            Ok(_) => (),
+        }
+        let stdin_events = fds[0].revents().unwrap_or_else(PollFlags::empty);
+        let master_events = fds[1].revents().unwrap_or_else(PollFlags::empty);
+        if stdin_events.contains(PollFlags::POLLIN) {
+            let size = read(libc::STDIN_FILENO, &mut buf)?;
+            if size == 0 {
+                return Ok(());
+            }
+            for c in buf[..size].iter() {
+                if *c != ESCAPE_CHAR {
+                    escape_count = 0;
+                    continue;
+                }
+                if escape_count == 0 || escape_start.elapsed() > Duration::from_secs(1) {
+                    escape_count = 1;
+                    escape_start = Instant::now();
+                } else {
+                    escape_count += 1;
+                }
+                if escape_count >= 3 {
+                    return Ok(());
+                }
+            }
+            write_all_fd(master, &buf[..size])?;
+        }
+        if master_events.contains(PollFlags::POLLIN) {
+            let size = match read(master, &mut buf) {
+                // EIO means the other side of the pty has been closed
+                Err(Errno::EIO) => return Ok(()),
+                Err(e) => return Err(e.into()),
+                Ok(size) => size,
+            };
+            if size == 0 {
+                return Ok(());
+            }
+            write_all_fd(libc::STDOUT_FILENO, &buf[..size])?;
+        } else if master_events.intersects(PollFlags::POLLHUP | PollFlags::POLLERR) {
+            return Ok(());
+        }
+    }
+}
+
+/// Attach the current terminal to a login console of the container (like `machinectl login`)
+pub fn attach_container(ns_name: &str) -> Result<()> {
+    if !isatty(libc::STDIN_FILENO)? {
+        return Err(anyhow!("Attaching to the console requires a terminal."));
+    }
+    let conn = Connection::new_system()?;
+    let proxy = conn.with_proxy(MACHINE1_DEST, MACHINE1_PATH, Duration::from_secs(10));
+    let (master, pty_path) = proxy.open_machine_login(ns_name)?;
+    let master = master.into_fd();
+    // copy the window size of the current terminal to the pty
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut size) == 0 {
+            libc::ioctl(master, libc::TIOCSWINSZ, &size);
+        }
+    }
+    info!(
+        "{}: connected to {}. Press ^] three times within 1s to detach.",
+        ns_name, pty_path
+    );
+    let original = tcgetattr(libc::STDIN_FILENO)?;
+    let mut raw = original.clone();
+    cfmakeraw(&mut raw);
+    tcsetattr(libc::STDIN_FILENO, SetArg::TCSANOW, &raw)?;
+    let result = forward_pty(master);
+    tcsetattr(libc::STDIN_FILENO, SetArg::TCSANOW, &original)?;
+    close(master).ok();
+    eprintln!();
+    info!("{}: detached from the console.", ns_name);
+
+    result
+}
+
 /// Reap all the exited child processes
 pub(crate) fn clean_child_process() {
     let mut status = 0;
@@ -246,6 +791,37 @@ fn is_booted(proxy: &Proxy<&Connection>) -> Result<bool> {
     Ok(false)
 }
 
+/// Get the addresses of the container, excluding the loopback addresses
+fn get_addresses(proxy: &Proxy<&Connection>) -> Result<Vec<IpAddr>> {
+    let addresses = proxy
+        .get_addresses()?
+        .into_iter()
+        .filter_map(|(family, addr)| match family {
+            libc::AF_INET => <[u8; 4]>::try_from(addr).ok().map(IpAddr::from),
+            libc::AF_INET6 => <[u8; 16]>::try_from(addr).ok().map(IpAddr::from),
+            _ => None,
+        })
+        .filter(|addr| !addr.is_loopback())
+        .collect();
+
+    Ok(addresses)
+}
+
+/// Query the overall system state from the systemd in the container
+fn get_system_state(ns_name: &str) -> Option<String> {
+    let output = Command::new("systemctl")
+        .args(&["-M", ns_name, "is-system-running"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let state = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if state.is_empty() {
+        return None;
+    }
+
+    Some(state)
+}
+
 fn terminate_container(proxy: &Proxy<&Connection>) -> Result<()> {
     if !is_booted(proxy)? {
         // with normal container, just kill it
@@ -287,23 +863,55 @@ pub fn terminate_container_by_name(ns_name: &str) -> Result<()> {
     terminate_container(&proxy)
 }
 
-/// Mount the filesystem layers using the specified layer manager and the instance name
-pub fn mount_layers(manager: &mut dyn LayerManager, name: &str) -> Result<()> {
+/// Mount the filesystem layers using the specified layer manager and the instance name,
+/// nothing is done if they are already mounted. Returns whether they are mounted now.
+pub fn mount_layers(manager: &mut dyn LayerManager, name: &str) -> Result<bool> {
     let target = std::env::current_dir()?.join(name);
-    if !manager.is_mounted(&target)? {
-        fs::create_dir_all(&target)?;
-        manager.mount(&target)?;
+    match manager.mount_state(&target)? {
+        MountState::Mounted => Ok(false),
+        MountState::NotMounted => {
+            fs::create_dir_all(&target)?;
+            manager.mount(&target)?;
+            Ok(true)
+        }
+        MountState::Stacked(count) => Err(anyhow!(
+            "{} filesystems are stacked on {}, run `ciel down -i {}` to clean them up.",
+            count,
+            target.display(),
+            name
+        )),
+        MountState::Foreign(upper) => Err(anyhow!(
+            "{} is mounted with other layers (upper layer: {}), run `ciel down -i {}` first.",
+            target.display(),
+            upper.display(),
+            name
+        )),
     }
-
-    Ok(())
 }
 
 /// Get the information of the container specified
 pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
     let full_path = std::env::current_dir()?.join(name);
     let mounted = is_mounted(&full_path, OsStr::new("overlay"))?;
+    if bwrap::is_enabled() {
+        let leader = bwrap::get_running_pid(name);
+        return Ok(CielInstance {
+            name: name.to_owned(),
+            ns_name: ns_name.to_owned(),
+            started: leader.is_some(),
+            running: leader.is_some(),
+            mounted,
+            booted: leader.map(|_| false),
+            system_state: None,
+            leader,
+            addresses: Vec::new(),
+            arch: None,
+            description: None,
+        });
+    }
     let conn = Connection::new_system()?;
     let proxy = conn.with_proxy(MACHINE1_DEST, MACHINE1_PATH, Duration::from_secs(10));
+    trace!("Calling GetMachine({}) on machined", ns_name);
     let path = proxy.get_machine(ns_name);
     if let Err(e) = path {
         let err_name = e.name().ok_or_else(|| anyhow!("{}", e))?;
@@ -315,6 +923,11 @@ pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
                 running: false,
                 mounted,
                 booted: None,
+                system_state: None,
+                leader: None,
+                addresses: Vec::new(),
+                arch: None,
+                description: None,
             });
         }
         // For all other errors, just return the original error object
@@ -334,19 +947,28 @@ pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
         running,
         mounted,
         booted: Some(booted),
+        system_state: None,
+        leader: proxy.leader().ok(),
+        addresses: get_addresses(&proxy).unwrap_or_default(),
+        arch: None,
+        description: None,
     })
 }
 
 /// List all the instances under the current directory
 pub fn list_instances() -> Result<Vec<CielInstance>> {
     let legacy = is_legacy_workspace()?;
+    let dist_arch = get_dist_arch();
     let mut instances: Vec<CielInstance> = Vec::new();
     for entry in (fs::read_dir(CIEL_INST_DIR)?).flatten() {
         if entry.file_type().map(|e| e.is_dir())? {
-            instances.push(inspect_instance(
-                &entry.file_name().to_string_lossy(),
-                &get_container_ns_name(&entry.file_name(), legacy)?,
-            )?);
+            let name = entry.file_name().to_string_lossy().to_string();
+            let mut instance =
+                inspect_instance(&name, &get_container_ns_name(&entry.file_name(), legacy)?)?;
+            let config = InstanceConfig::load(&name).unwrap_or_default();
+            instance.arch = config.arch.or_else(|| dist_arch.clone());
+            instance.description = config.description;
+            instances.push(instance);
         }
     }
 
@@ -365,10 +987,31 @@ pub fn list_instances_simple() -> Result<Vec<String>> {
     Ok(instances)
 }
 
-/// Print all the instances under the current directory
-pub fn print_instances() -> Result<()> {
-    let instances = list_instances()?;
-    eprintln!("NAME\t\tMOUNTED\t\tRUNNING\t\tBOOTED");
+/// Check if the instance name given by a client (JSON-RPC, D-Bus or the HTTP API) is one of the
+/// instances, so that it can not point outside of the workspace
+pub fn check_instance_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('/') || name.contains("..") {
+        return Err(anyhow!("Invalid instance name: {:?}", name));
+    }
+    if !list_instances_simple()?.iter().any(|i| i == name) {
+        return Err(anyhow!("No such instance: {}", name));
+    }
+
+    Ok(())
+}
+
+/// Print all the instances under the current directory, with the system state of
+/// the booted ones if `detailed` is set
+pub fn print_instances(detailed: bool) -> Result<()> {
+    let mut instances = list_instances()?;
+    if detailed {
+        instances
+            .iter_mut()
+            .for_each(CielInstance::query_system_state);
+    }
+    eprintln!(
+        "NAME\t\tARCH\t\tMOUNTED\t\tRUNNING\t\tBOOTED\t\tSTATE\t\tLEADER\t\tADDRESS\t\tDESCRIPTION"
+    );
     for instance in instances {
         let mounted = color_bool!(instance.mounted);
         let running = color_bool!(instance.running);
@@ -379,15 +1022,232 @@ pub fn print_instances() -> Result<()> {
                 style("-").dim()
             }
         };
+        let state = match instance.system_state.as_deref() {
+            Some("running") => style("running").green(),
+            Some("degraded") => style("degraded").yellow().bold(),
+            Some(state) => style(state).cyan(),
+            None => style("-").dim(),
+        };
+        let leader = instance
+            .leader
+            .map_or_else(|| "-".to_string(), |pid| pid.to_string());
+        let address = instance
+            .addresses
+            .first()
+            .map_or_else(|| "-".to_string(), |addr| addr.to_string());
+        let arch = instance.arch.as_deref().unwrap_or("-");
+        let description = instance.description.as_deref().unwrap_or("");
         eprintln!(
-            "{}\t\t{}\t\t{}\t\t{}",
-            instance.name, mounted, running, booted
+            "{}\t\t{}\t\t{}\t\t{}\t\t{}\t\t{}\t\t{}\t\t{}\t\t{}",
+            instance.name, arch, mounted, running, booted, state, leader, address, description
         );
     }
 
     Ok(())
 }
 
+/// Resource usage counters of a container, from the cgroup accounting
+#[derive(Debug, Default, Clone, Copy)]
+struct ResourceUsage {
+    cpu_usec: u64,
+    memory: u64,
+    tasks: u64,
+    io_read: u64,
+    io_write: u64,
+}
+
+/// Find the cgroup (the machine scope) of the container from its leader process
+fn get_container_cgroup(leader: u32) -> Result<PathBuf> {
+    let content = fs::read_to_string(format!("/proc/{}/cgroup", leader))?;
+    let cgroup = content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| anyhow!("Resource monitoring requires cgroup v2."))?;
+    // the leader may be inside a sub-cgroup (e.g. `payload`) of the scope
+    let cgroup = Path::new(cgroup);
+    let path = cgroup
+        .ancestors()
+        .find(|p| p.to_string_lossy().ends_with(".scope"))
+        .ok_or_else(|| anyhow!("Container is not running in its own cgroup."))?;
+
+    Ok(Path::new("/sys/fs/cgroup").join(path.strip_prefix("/")?))
+}
+
+/// Read a counter from the cgroup (returns 0 if the controller is not enabled)
+#[inline]
+fn read_cgroup_value(cgroup: &Path, name: &str) -> u64 {
+    fs::read_to_string(cgroup.join(name))
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Get the value of `key` from the flat keyed files in the cgroup (e.g. `cpu.stat`)
+fn parse_keyed_value(content: &str, key: &str) -> u64 {
+    content
+        .lines()
+        .find_map(|line| {
+            let (k, v) = line.split_once(' ')?;
+            if k == key {
+                v.trim().parse().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(0)
+}
+
+/// Sum the read and written bytes of all the devices in `io.stat`
+fn parse_io_stat(content: &str) -> (u64, u64) {
+    let mut read = 0;
+    let mut written = 0;
+    for field in content.split_whitespace() {
+        if let Some((key, value)) = field.split_once('=') {
+            let value: u64 = value.parse().unwrap_or(0);
+            match key {
+                "rbytes" => read += value,
+                "wbytes" => written += value,
+                _ => (),
+            }
+        }
+    }
+
+    (read, written)
+}
+
+/// Read the resource usage counters of the cgroup
+fn read_resource_usage(cgroup: &Path) -> ResourceUsage {
+    let cpu_stat = fs::read_to_string(cgroup.join("cpu.stat")).unwrap_or_default();
+    let io_stat = fs::read_to_string(cgroup.join("io.stat")).unwrap_or_default();
+    let (io_read, io_write) = parse_io_stat(&io_stat);
+
+    ResourceUsage {
+        cpu_usec: parse_keyed_value(&cpu_stat, "usage_usec"),
+        memory: read_cgroup_value(cgroup, "memory.current"),
+        tasks: read_cgroup_value(cgroup, "pids.current"),
+        io_read,
+        io_write,
+    }
+}
+
+#[inline]
+fn timeval_usec(time: libc::timeval) -> u64 {
+    time.tv_sec as u64 * 1_000_000 + time.tv_usec as u64
+}
+
+/// CPU time (in microseconds) used so far by the container of the instance and the children
+/// of Ciel, the difference between two readings is the CPU time of what is run in between
+pub fn get_cpu_time(instance: &str) -> u64 {
+    let mut usage = MaybeUninit::<libc::rusage>::zeroed();
+    let children = unsafe {
+        if libc::getrusage(libc::RUSAGE_CHILDREN, usage.as_mut_ptr()) == 0 {
+            let usage = usage.assume_init();
+            timeval_usec(usage.ru_utime) + timeval_usec(usage.ru_stime)
+        } else {
+            0
+        }
+    };
+    // bwrap containers exit together with the commands, so they are counted as the children
+    if bwrap::is_enabled() {
+        return children;
+    }
+    let container = is_legacy_workspace()
+        .and_then(|legacy| get_container_ns_name(instance, legacy))
+        .and_then(|ns_name| inspect_instance(instance, &ns_name))
+        .ok()
+        .and_then(|inst| inst.leader)
+        .and_then(|leader| get_container_cgroup(leader).ok())
+        .map_or(0, |cgroup| read_resource_usage(&cgroup).cpu_usec);
+
+    children + container
+}
+
+/// Show the resource usage of the running instances, refreshing until interrupted
+pub fn monitor_instances(interval: Duration) -> Result<()> {
+    let term = Term::stderr();
+    let mut last: HashMap<String, (Instant, ResourceUsage)> = HashMap::new();
+    loop {
+        let mut lines = Vec::new();
+        let instances = list_instances()?;
+        let width = instances
+            .iter()
+            .map(|i| i.name.len() + 2)
+            .max()
+            .unwrap_or(0)
+            .max(16);
+        for instance in instances {
+            let leader = match instance.leader {
+                Some(leader) if instance.running => leader,
+                _ => continue,
+            };
+            let cgroup = match get_container_cgroup(leader) {
+                Ok(cgroup) => cgroup,
+                Err(e) => {
+                    lines.push(format!(
+                        "{:<width$}{}",
+                        instance.name,
+                        style(e).red(),
+                        width = width
+                    ));
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            let usage = read_resource_usage(&cgroup);
+            let (cpu, read, write) = if let Some((then, prev)) = last.get(&instance.name) {
+                let elapsed = now.duration_since(*then).as_secs_f64();
+                let cpu = usage.cpu_usec.saturating_sub(prev.cpu_usec) as f64 / 1e4 / elapsed;
+                let read = usage.io_read.saturating_sub(prev.io_read) as f64 / elapsed;
+                let write = usage.io_write.saturating_sub(prev.io_write) as f64 / elapsed;
+                (
+                    format!("{:.1}", cpu),
+                    format!("{}/s", HumanBytes(read as u64)),
+                    format!("{}/s", HumanBytes(write as u64)),
+                )
+            } else {
+                ("-".to_string(), "-".to_string(), "-".to_string())
+            };
+            lines.push(format!(
+                "{:<width$}{:<8}{:<12}{:<8}{:<14}{}",
+                instance.name,
+                cpu,
+                HumanBytes(usage.memory).to_string(),
+                usage.tasks,
+                read,
+                write,
+                width = width
+            ));
+            last.insert(instance.name, (now, usage));
+        }
+        term.clear_screen()?;
+        eprintln!(
+            "{:<width$}{:<8}{:<12}{:<8}{:<14}{}",
+            "NAME",
+            "CPU%",
+            "MEMORY",
+            "TASKS",
+            "READ",
+            "WRITE",
+            width = width
+        );
+        for line in lines {
+            eprintln!("{}", line);
+        }
+        eprintln!("{}", style("Press Ctrl-C to exit.").dim());
+        sleep(interval);
+    }
+}
+
+#[test]
+fn test_parse_cgroup_stat() {
+    let io_stat = "8:0 rbytes=4096 wbytes=1024 rios=1 wios=1 dbytes=0 dios=0\n\
+                   259:0 rbytes=1000 wbytes=24 rios=3 wios=2 dbytes=0 dios=0\n";
+    assert_eq!(parse_io_stat(io_stat), (5096, 1048));
+    let cpu_stat = "usage_usec 12345\nuser_usec 10000\nsystem_usec 2345\n";
+    assert_eq!(parse_keyed_value(cpu_stat, "usage_usec"), 12345);
+    assert_eq!(parse_keyed_value(cpu_stat, "nr_throttled"), 0);
+}
+
 #[test]
 fn test_inspect_instance() {
     println!("{:#?}", inspect_instance("alpine", "alpine"));
@@ -404,3 +1264,44 @@ fn test_container_name() {
         get_container_ns_name(Path::new("/tmp/"), true).unwrap()
     );
 }
+
+#[test]
+fn test_security_options() {
+    assert_eq!(parse_capability("sys_admin").unwrap(), "CAP_SYS_ADMIN");
+    assert_eq!(parse_capability("CAP_NET_RAW").unwrap(), "CAP_NET_RAW");
+    assert!(parse_capability("CAP_FOO").is_err());
+    assert!(is_valid_syscall_filter("~@clock"));
+    assert!(is_valid_syscall_filter("personality"));
+    assert!(!is_valid_syscall_filter("~"));
+    assert!(!is_valid_syscall_filter("add_key; rm"));
+    let config = InstanceConfig {
+        capabilities: vec!["sys_ptrace".to_string()],
+        drop_capabilities: vec!["CAP_SYS_PTRACE".to_string()],
+        ..Default::default()
+    };
+    assert!(get_security_options(&config, &BTreeMap::new()).is_err());
+    let mut config = InstanceConfig {
+        seccomp_profile: Some("default".to_string()),
+        system_call_filter: vec!["~personality".to_string()],
+        ..Default::default()
+    };
+    let options = get_security_options(&config, &BTreeMap::new()).unwrap();
+    assert_eq!(options.len(), DEFAULT_SECCOMP_PROFILE.len() + 1);
+    assert_eq!(options.last().unwrap(), "--system-call-filter=~personality");
+    let mut profiles = BTreeMap::new();
+    profiles.insert("strict".to_string(), vec!["~@debug".to_string()]);
+    config.seccomp_profile = Some("strict".to_string());
+    let options = get_security_options(&config, &profiles).unwrap();
+    assert_eq!(options[0], "--system-call-filter=~@debug");
+    config.seccomp_profile = Some("foo".to_string());
+    assert!(get_security_options(&config, &profiles).is_err());
+}
+
+#[test]
+fn test_parse_port_rule() {
+    assert_eq!(parse_port_rule("8080").unwrap(), "tcp:8080:8080");
+    assert_eq!(parse_port_rule("8080:80").unwrap(), "tcp:8080:80");
+    assert_eq!(parse_port_rule("udp:5353:53").unwrap(), "udp:5353:53");
+    assert!(parse_port_rule("sctp:1:2").is_err());
+    assert!(parse_port_rule("0:80").is_err());
+}
@@ -1,22 +1,22 @@
-mod actions;
-mod cli;
-mod common;
-mod config;
-mod dbus_machine1;
-mod dbus_machine1_machine;
-mod diagnose;
-mod logging;
-mod machine;
-mod network;
-mod overlayfs;
-mod repo;
-
+//! The command line frontend of Ciel
 use anyhow::{anyhow, Result};
+use ciel::{
+    actions, api, cli, common, config, diagnose, error, events, forward, info, lock, logging,
+    machine, manpage, migrate, network, notify, plugin, repo, rpc, service, warn,
+};
 use clap::ArgMatches;
 use console::style;
 use dotenv::dotenv;
-use std::process;
-use std::{path::Path, process::Command};
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+use std::{
+    process,
+    time::{Duration, Instant},
+};
 
 macro_rules! print_error {
     ($input:block) => {
@@ -27,6 +27,31 @@ macro_rules! print_error {
     };
 }
 
+/// Print the value as JSON to stdout (for `--json`)
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+
+    Ok(())
+}
+
+/// `ciel list`, with the base system it was loaded from
+fn print_instance_list(json: bool, detailed: bool) -> Result<()> {
+    if json {
+        let mut instances = machine::list_instances()?;
+        if detailed {
+            instances
+                .iter_mut()
+                .for_each(machine::CielInstance::query_system_state);
+        }
+        return print_json(&instances);
+    }
+    if let Some(info) = actions::read_dist_info(Path::new(common::CIEL_DIST_DIR)) {
+        eprintln!("Base system: {}\n", info.describe());
+    }
+
+    machine::print_instances(detailed)
+}
+
 macro_rules! one_or_all_instance {
     ($args:ident, $func:expr) => {{
         if let Ok(instance) = get_instance_option($args) {
@@ -39,9 +64,9 @@ macro_rules! one_or_all_instance {
 
 fn get_output_dir() -> String {
     if let Ok(c) = config::read_config() {
-        return actions::get_output_directory(c.sep_mount);
+        return actions::get_output_directory(c.sep_mount, c.sep_arch);
     }
-    "OUTPUT".to_string()
+    actions::get_output_directory(false, false)
 }
 
 #[inline]
@@ -55,31 +80,350 @@ fn get_instance_option(args: &ArgMatches) -> Result<String> {
     Ok(option_instance.map_or_else(|| default_instance.expect("Internal error"), String::from))
 }
 
+/// Get the command execution options (boot mode, user) from the command line
+#[inline]
+fn get_run_options(args: &ArgMatches) -> actions::RunOptions {
+    let boot = if args.is_present("boot") {
+        Some(true)
+    } else if args.is_present("no-boot") {
+        Some(false)
+    } else {
+        None
+    };
+
+    actions::RunOptions {
+        boot,
+        user: args.value_of("user").map(String::from),
+        ..Default::default()
+    }
+}
+
+/// Get the build settings from the command line
+fn get_build_settings(args: &ArgMatches) -> Result<actions::BuildSettings> {
+    Ok(actions::BuildSettings {
+        offline: args.is_present("OFFLINE"),
+        network_policy: args
+            .value_of("network-policy")
+            .map(str::parse)
+            .transpose()?,
+        progress_json: args.value_of("progress") == Some("json"),
+        jobs: if args.is_present("JOBS") {
+            Some(args.value_of_t("JOBS")?)
+        } else {
+            None
+        },
+        secrets: args
+            .values_of("secret")
+            .unwrap_or_default()
+            .map(actions::BuildSecret::parse)
+            .collect::<Result<_>>()?,
+    })
+}
+
+/// Write the build events to stdout for `--progress json` (the remote builders write their own)
+fn start_progress_stream(settings: &actions::BuildSettings) {
+    if settings.progress_json {
+        events::set_progress_stream(Box::new(std::io::stdout()));
+    }
+}
+
+/// Show the build log of the package (`previous` builds ago) with a header on stderr
+fn show_build_log(package: &str, previous: usize) -> Result<()> {
+    let entry = actions::find_build_log(package, previous)?;
+    eprintln!(
+        "{} {} in {} at {} ({})",
+        style("==>").bold(),
+        entry.package,
+        entry.instance,
+        entry.started,
+        entry.path.display()
+    );
+
+    actions::show_build_log(&entry, &mut std::io::stdout())
+}
+
+/// Update the configuration of the instance with the options from `ciel config`
+fn update_instance_config(instance: &str, args: &ArgMatches) -> Result<()> {
+    let mut config = config::InstanceConfig::load(instance)?;
+    if args.is_present("boot") {
+        config.boot = true;
+    } else if args.is_present("no-boot") {
+        config.boot = false;
+    }
+    if args.is_present("network") {
+        config.network = args.value_of_t("network")?;
+        // port forwarding rules only make sense for the private network
+        if config.network != config::NetworkMode::Private {
+            config.publish.clear();
+        }
+    }
+    if let Some(publish) = args.values_of("publish") {
+        config.publish = publish.map(String::from).collect();
+    }
+    if let Some(caps) = args.values_of("cap-add") {
+        config.capabilities = caps.map(String::from).collect();
+    }
+    if let Some(caps) = args.values_of("cap-drop") {
+        config.drop_capabilities = caps.map(String::from).collect();
+    }
+    if let Some(filter) = args.values_of("syscall-filter") {
+        config.system_call_filter = filter.map(String::from).collect();
+    }
+    if let Some(profile) = args.value_of("seccomp-profile") {
+        config.seccomp_profile = Some(profile.to_string()).filter(|p| !p.is_empty());
+    }
+    if let Some(description) = args.value_of("description") {
+        config.description = Some(description.to_string()).filter(|d| !d.is_empty());
+    }
+    actions::update_instance_config(instance, &config)
+}
+
+/// Get the configuration of the new instance from the options of `ciel add`
+fn get_new_instance_config(args: &ArgMatches) -> Result<config::InstanceConfig> {
+    Ok(config::InstanceConfig {
+        boot: !args.is_present("no-boot"),
+        network: if args.is_present("network") {
+            args.value_of_t("network")?
+        } else {
+            config::NetworkMode::default()
+        },
+        publish: args
+            .values_of("publish")
+            .map(|p| p.map(String::from).collect())
+            .unwrap_or_default(),
+        arch: args
+            .value_of("arch")
+            .map(|arch| network::normalize_arch_name(arch).to_string()),
+        description: args.value_of("description").map(String::from),
+        ..Default::default()
+    })
+}
+
+/// Print the build summary (as JSON if `json` is set), notify the user and exit with the status
+/// of the build
+fn exit_with_build_summary(instance: &str, summary: &actions::BuildSummary, json: bool) -> ! {
+    if json {
+        if let Err(e) = print_json(summary) {
+            error!("{}", e);
+        }
+    }
+    notify::build_finished(
+        &notify::BuildOutcome {
+            success: summary.success,
+            exit_code: summary.exit_code,
+            packages: &summary.packages,
+            target: Some(instance),
+            failed_package: summary.failed_package.as_deref(),
+            duration: summary.duration,
+        },
+        &mut std::io::stderr(),
+    );
+    process::exit(summary.exit_code);
+}
+
+/// Print the result of the local repository operation as JSON (if `json` is set)
+fn print_repo_result(json: bool, action: &str, path: &Path) -> Result<()> {
+    if !json {
+        return Ok(());
+    }
+    let packages = path.join("debs/Packages");
+    let count = std::fs::read_to_string(&packages)
+        .map(|p| p.lines().filter(|l| l.starts_with("Package:")).count())
+        .unwrap_or(0);
+
+    print_json(&serde_json::json!({
+        "action": action,
+        "success": true,
+        "path": path,
+        "packages": count,
+    }))
+}
+
 #[inline]
 fn is_root() -> bool {
     nix::unistd::geteuid().is_root()
 }
 
+/// Check if the command can be done by the daemon (`ciel daemon --dbus`) for a regular user
+fn is_brokered((name, args): (&str, &ArgMatches)) -> bool {
+    let has_instance = || get_instance_option(args).is_ok();
+    match name {
+        "mount" | "rollback" => has_instance(),
+        "down" => has_instance() && !args.is_present("all"),
+        // the daemon builds with the default settings
+        "build" => {
+            has_instance()
+                && args.is_present("PACKAGES")
+                && [
+                    "FETCH",
+                    "OFFLINE",
+                    "network-policy",
+                    "progress",
+                    "JOBS",
+                    "secret",
+                    "arch",
+                    "matrix",
+                    "on",
+                    "CONTINUE",
+                    "SELECT",
+                ]
+                .iter()
+                // `progress` has a default value
+                .all(|arg| args.occurrences_of(arg) == 0)
+        }
+        _ => false,
+    }
+}
+
+/// Ask the daemon to do the command (authorized by polkit), see `is_brokered`
+fn run_brokered((name, args): (&str, &ArgMatches), json: bool) -> Result<()> {
+    let instance = get_instance_option(args)?;
+    let client = service::Client::connect()?;
+    match name {
+        "mount" => client.call_on_instance("Mount", &instance),
+        "down" => client.call_on_instance("Down", &instance),
+        "rollback" => client.call_on_instance("Rollback", &instance),
+        "build" => {
+            let packages = args
+                .values_of("PACKAGES")
+                .unwrap()
+                .map(String::from)
+                .collect::<Vec<_>>();
+            let (success, summary) = client.build(&instance, &packages)?;
+            if json {
+                println!("{}", summary);
+            }
+            if !success {
+                // the summary is the error message if the build could not be done
+                match serde_json::from_str::<serde_json::Value>(&summary) {
+                    Ok(serde_json::Value::String(e)) => error!("{}", e),
+                    _ => error!("Build failed, see the build log for details."),
+                }
+                process::exit(1);
+            }
+            info!("Build finished.");
+            Ok(())
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Select the workspace: `-C DIR`, `-w NAME` or `$CIEL_DIR` (in that order),
+/// `None` if the workspace should be searched from the current directory
+fn select_workspace(
+    dir: Option<&OsStr>,
+    workspace: Option<&OsStr>,
+    user_config: &config::UserConfig,
+) -> Result<Option<PathBuf>> {
+    if let Some(dir) = dir {
+        return Ok(Some(PathBuf::from(dir)));
+    }
+    if let Some(workspace) = workspace {
+        let path = user_config.get_workspace(&workspace.to_string_lossy())?;
+        return Ok(Some(path.to_owned()));
+    }
+
+    Ok(std::env::var_os("CIEL_DIR").map(PathBuf::from))
+}
+
 fn main() -> Result<()> {
-    let args = cli::build_cli().get_matches();
-    if !is_root() {
+    let user_config = config::UserConfig::load().unwrap_or_else(|e| {
+        warn!("{}", e);
+        config::UserConfig::default()
+    });
+    let args = cli::expand_alias(std::env::args_os().collect(), |dir, workspace| {
+        let directory = select_workspace(dir, workspace, &user_config)
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| PathBuf::from("."));
+        config::read_aliases(&directory, user_config.search_depth)
+    });
+    let args = cli::build_cli().get_matches_from(args);
+    let colors = match args.value_of("color") {
+        Some("always") => Some(true),
+        Some("never") => Some(false),
+        _ => None,
+    };
+    if let Some(enabled) = colors {
+        console::set_colors_enabled(enabled);
+        console::set_colors_enabled_stderr(enabled);
+        // also let the host tools (git, etc.) know
+        if enabled {
+            std::env::set_var("CLICOLOR_FORCE", "1");
+            std::env::remove_var("NO_COLOR");
+        } else {
+            std::env::set_var("NO_COLOR", "1");
+            std::env::remove_var("CLICOLOR_FORCE");
+        }
+    }
+    if args.is_present("quiet") {
+        logging::set_log_level(logging::LEVEL_QUIET);
+    } else {
+        let level = logging::LEVEL_INFO + args.occurrences_of("verbose") as usize;
+        logging::set_log_level(level.min(logging::LEVEL_TRACE));
+    }
+    // explicit -v/-q takes precedence over the default level in the filter
+    let explicit_level = args.is_present("quiet") || args.is_present("verbose");
+    let filter_env = std::env::var(logging::LOG_FILTER_ENV).ok();
+    if let Some(filter) = &filter_env {
+        if let Err(e) = logging::set_log_filter(filter, !explicit_level) {
+            warn!("Ignoring {}: {}", logging::LOG_FILTER_ENV, e);
+        }
+    }
+    let log_format = args.value_of_t::<config::LogFormat>("log-format").ok();
+    if let Some(log_format) = log_format {
+        logging::set_json_format(log_format == config::LogFormat::Json);
+    }
+    let timestamps = args.is_present("timestamps");
+    logging::set_timestamps(timestamps);
+    // generating man pages requires neither root nor a workspace
+    if let Some(("gen-manpages", args)) = args.subcommand() {
+        let dir = Path::new(args.value_of("DIR").unwrap());
+        let pages = manpage::generate_manpages(&cli::build_cli(), "ciel", dir)?;
+        info!("Generated {} man pages in {}.", pages.len(), dir.display());
+        return Ok(());
+    }
+    // the regular users may ask the daemon to do some of the operations instead
+    let brokered = !is_root() && args.subcommand().filter(|c| is_brokered(*c)).is_some();
+    if !is_root() && !brokered {
         println!("Please run me as root!");
         process::exit(1);
     }
-    let mut directory = Path::new(args.value_of("C").unwrap_or(".")).to_path_buf();
+    let selected = match select_workspace(
+        args.value_of("C").map(OsStr::new),
+        args.value_of("workspace").map(OsStr::new),
+        &user_config,
+    ) {
+        Ok(selected) => selected,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let explicit = selected.is_some();
+    let mut directory = selected.unwrap_or_else(|| PathBuf::from("."));
     // Switch to the target directory
-    std::env::set_current_dir(&directory).unwrap();
+    if let Err(e) = std::env::set_current_dir(&directory) {
+        error!("Unable to enter {}: {}", directory.display(), e);
+        process::exit(1);
+    }
     // get subcommands from command line parser
+    let json = args.is_present("json");
     let subcmd = args.subcommand();
     if subcmd.is_none() {
-        machine::print_instances()?;
-        return Ok(());
+        return print_instance_list(json, false);
     }
     let subcmd = subcmd.unwrap();
     // check if the workspace exists, except when the command is `init` or `new`
-    if !["init", "new", "version"].contains(&subcmd.0) && !Path::new("./.ciel").is_dir() {
-        if directory == Path::new(".") {
-            directory = common::find_ciel_dir(".")?;
+    // (`version` only needs it with `--os`)
+    let needs_workspace = match subcmd {
+        ("init" | "new" | "import-workspace", _) => false,
+        ("version", args) => args.is_present("os"),
+        _ => true,
+    };
+    if needs_workspace && !Path::new("./.ciel").is_dir() {
+        if !explicit {
+            directory = common::find_ciel_dir(".", user_config.search_depth)?;
             info!(
                 "Selected Ciel directory: {}",
                 style(directory.canonicalize()?.display()).cyan()
@@ -90,22 +434,111 @@ fn main() -> Result<()> {
             process::exit(1);
         }
     }
+    if brokered {
+        print_error!({ run_brokered(subcmd, json) });
+        return Ok(());
+    }
+    if let Ok(config) = config::read_config() {
+        if log_format.is_none() {
+            logging::set_json_format(config.log_format == config::LogFormat::Json);
+        }
+        if !timestamps {
+            logging::set_timestamps(config.log_timestamps);
+        }
+        if let (None, Some(filter)) = (&filter_env, &config.log_filter) {
+            if let Err(e) = logging::set_log_filter(filter, !explicit_level) {
+                warn!("Ignoring log-filter in the config: {}", e);
+            }
+        }
+        if let Some(target) = &config.log_forward {
+            if let Err(e) = forward::set_forward_target(target) {
+                warn!("Unable to forward the logs to {}: {}", target, e);
+            }
+        }
+    }
+    // not every subcommand takes an instance
+    if subcmd.1.is_valid_arg("INSTANCE") {
+        logging::set_current_instance(get_instance_option(subcmd.1).ok().as_deref());
+    }
+    // keep a record of what has been done in the workspace
+    if Path::new("./.ciel").is_dir() {
+        let command = std::env::args().collect::<Vec<_>>().join(" ");
+        if let Err(e) = logging::open_log_file(Path::new(logging::WORKSPACE_LOG_FILE), &command) {
+            warn!("Unable to open the workspace log file: {}", e);
+        }
+    }
+    // keep concurrent invocations from corrupting the workspace
+    let lock_mode = match lock::lock_mode_for(subcmd.0, subcmd.1.subcommand_name()) {
+        // matrix builds switch the base systems
+        Some(_) if subcmd.0 == "build" && subcmd.1.is_present("matrix") => {
+            Some(lock::LockMode::Exclusive)
+        }
+        mode => mode,
+    };
+    let _lock = match lock_mode {
+        Some(mode) if Path::new("./.ciel").is_dir() => {
+            match lock::lock_workspace(mode, !args.is_present("no-wait")) {
+                Ok(lock) => Some(lock),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+    // the commands working on the workspace would trip over what the crashed runs left behind
+    if _lock.is_some() {
+        if let Err(e) = actions::reconcile_workspace() {
+            warn!("Unable to check the state of the instances: {}", e);
+        }
+    }
     // source .env file, ignore errors
     dotenv().ok();
+    if args.is_present("batch") {
+        std::env::set_var("CIEL_BATCH", "1");
+    }
+    // pass the configured proxy to the network operations
+    if let Ok(Some(proxy)) = config::read_config()
+        .or_else(|_| config::CielConfig::system_defaults())
+        .map(|c| c.proxy)
+    {
+        for var in ["http_proxy", "https_proxy"] {
+            if std::env::var_os(var).is_none() {
+                std::env::set_var(var, &proxy);
+            }
+        }
+    }
     // Switch table
     match subcmd {
-        ("farewell", _) => {
-            actions::farewell(&directory).unwrap();
+        ("export-workspace", args) => {
+            let path = Path::new(args.value_of("FILE").unwrap());
+            print_error!({ actions::export_workspace(path) });
+        }
+        ("import-workspace", args) => {
+            let path = Path::new(args.value_of("FILE").unwrap());
+            print_error!({ actions::import_workspace(path) });
+        }
+        ("farewell", args) => {
+            let options = actions::FarewellOptions {
+                force: args.is_present("force"),
+                instances_only: args.is_present("instances-only"),
+                purge: args.is_present("purge"),
+                keep_tree: args.is_present("keep-tree"),
+                keep_output: args.is_present("keep-output"),
+            };
+            print_error!({ actions::farewell(&directory, &options) });
         }
         ("init", args) => {
             if args.is_present("upgrade") {
                 info!("Upgrading workspace...");
                 info!("First, shutting down all the instances...");
                 print_error!({ actions::for_each_instance(&actions::container_down) });
-            } else {
-                warn!("Please do not use this command manually ...");
-                warn!("... try `ciel new` instead.");
+                print_error!({ migrate::migrate_workspace() });
+                return Ok(());
             }
+            warn!("Please do not use this command manually ...");
+            warn!("... try `ciel new` instead.");
             print_error!({ common::ciel_init() });
             info!("Initialized working directory at {}", directory.display());
         }
@@ -117,6 +550,26 @@ fn main() -> Result<()> {
             )?;
         }
         ("load-os", args) => {
+            if args.is_present("list-archived") {
+                print_error!({ actions::list_base_systems() });
+                return Ok(());
+            }
+            if let Some(name) = args.value_of("switch") {
+                print_error!({ actions::switch_base_system(name) });
+                return Ok(());
+            }
+            if let Some(name) = args.value_of("remove-archived") {
+                print_error!({ actions::remove_base_system(name) });
+                return Ok(());
+            }
+            print_error!({ actions::prepare_base_system(args.is_present("replace")) });
+            if let Some(dir) = args.value_of("from-dir") {
+                print_error!({
+                    common::copy_system_rootfs(Path::new(dir))
+                        .and_then(|_| actions::record_base_system(dir, None))
+                });
+                return Ok(());
+            }
             let url = args.value_of("url");
             if let Some(url) = url {
                 // load from network using specified url
@@ -135,18 +588,26 @@ fn main() -> Result<()> {
                         &tarball.to_path_buf(),
                         tarball.metadata()?.len(),
                     )
+                    .and_then(|_| actions::record_base_system(url, None))
                 });
 
                 return Ok(());
             }
             // load from network using auto picked url
             info!("No URL specified. Ciel will automatically pick one.");
-            let tarball = network::pick_latest_tarball();
+            let arch = args.value_of("arch").map(network::normalize_arch_name);
+            let tarball = network::pick_latest_tarball(arch, args.value_of("variant"));
             if let Err(e) = tarball {
                 error!("Unable to determine the latest tarball: {}", e);
                 process::exit(1);
             }
             let tarball = tarball.unwrap();
+            if arch.is_some() && arch != network::get_arch_name() {
+                warn!(
+                    "Loading a {} system, the instances will be run with qemu-user (make sure that qemu-user-static is installed).",
+                    tarball.arch
+                );
+            }
             print_error!({
                 actions::load_os(
                     &format!("https://releases.aosc.io/{}", tarball.path),
@@ -154,75 +615,239 @@ fn main() -> Result<()> {
                 )
             });
         }
-        ("update-os", _) => {
+        ("update-os", args) => {
+            if args.is_present("dry-run") {
+                print_error!({ actions::simulate_update_os() });
+                return Ok(());
+            }
+            if args.is_present("undo") {
+                print_error!({ actions::undo_update_os() });
+                return Ok(());
+            }
             print_error!({ actions::update_os() });
         }
         ("config", args) => {
+            if args.is_present("check") {
+                print_error!({ actions::check_config() });
+                return Ok(());
+            }
+            if let Some(("repo", args)) = args.subcommand() {
+                match args.subcommand() {
+                    Some(("add", args)) => {
+                        let source = args.values_of("SOURCE").unwrap().collect::<Vec<_>>();
+                        print_error!({
+                            actions::config_repo_add(
+                                args.value_of("INSTANCE"),
+                                &source.join(" "),
+                                args.value_of("key"),
+                            )
+                        });
+                    }
+                    Some(("remove", args)) => {
+                        let source = args.values_of("SOURCE").unwrap().collect::<Vec<_>>();
+                        print_error!({
+                            actions::config_repo_remove(
+                                args.value_of("INSTANCE"),
+                                &source.join(" "),
+                            )
+                        });
+                    }
+                    _ => unreachable!(),
+                }
+                return Ok(());
+            }
             if args.is_present("g") {
                 print_error!({ actions::config_os(None) });
                 return Ok(());
             }
             let instance = get_instance_option(args)?;
+            if [
+                "boot",
+                "no-boot",
+                "network",
+                "publish",
+                "cap-add",
+                "cap-drop",
+                "syscall-filter",
+                "seccomp-profile",
+                "description",
+            ]
+            .iter()
+            .any(|arg| args.is_present(arg))
+            {
+                print_error!({ update_instance_config(&instance, args) });
+                return Ok(());
+            }
             print_error!({ actions::config_os(Some(&instance)) });
         }
         ("mount", args) => {
             print_error!({ one_or_all_instance!(args, &actions::mount_fs) });
         }
-        ("new", _) => {
-            if let Err(e) = actions::onboarding() {
+        ("new", args) => {
+            if let Err(e) = actions::onboarding(args.value_of("instance"), args.value_of("tarball"))
+            {
                 error!("{}", e);
                 process::exit(1);
             }
         }
         ("run", args) => {
             let instance = get_instance_option(args)?;
+            let options = get_run_options(args);
             let cmd = args.values_of("COMMANDS").unwrap();
             let args: Vec<&str> = cmd.into_iter().collect();
-            let status = actions::run_in_container(&instance, &args)?;
+            let status = actions::run_in_container_with_options(&instance, &args, &options)?;
             process::exit(status);
         }
         ("shell", args) => {
             let instance = get_instance_option(args)?;
+            let mut options = get_run_options(args);
+            options.forward_identity = args.is_present("forward-agent");
             if let Some(cmd) = args.values_of("COMMANDS") {
                 let command = cmd.into_iter().collect::<Vec<&str>>().join(" ");
-                let status = actions::run_in_container(&instance, &["/bin/bash", "-ec", &command])?;
+                let status = actions::run_in_container_with_options(
+                    &instance,
+                    &["/bin/bash", "-ec", &command],
+                    &options,
+                )?;
                 process::exit(status);
             }
-            let status = actions::run_in_container(&instance, &["/bin/bash"])?;
+            let status =
+                actions::run_in_container_with_options(&instance, &["/bin/bash"], &options)?;
             process::exit(status);
         }
+        ("attach", args) => {
+            let instance = get_instance_option(args)?;
+            print_error!({ actions::attach_container(&instance) });
+        }
         ("stop", args) => {
             let instance = get_instance_option(args)?;
             print_error!({ actions::stop_container(&instance) });
         }
         ("down", args) => {
-            print_error!({ one_or_all_instance!(args, &actions::container_down) });
+            if args.is_present("force") {
+                print_error!({ actions::force_down_all() });
+            } else if args.is_present("all") {
+                print_error!({ actions::for_each_instance(&actions::container_down) });
+            } else {
+                print_error!({ one_or_all_instance!(args, &actions::container_down) });
+            }
         }
         ("commit", args) => {
             let instance = get_instance_option(args)?;
-            print_error!({ actions::commit_container(&instance) });
+            print_error!({ actions::commit_container(&instance, args.is_present("keep-upper")) });
         }
         ("rollback", args) => {
             print_error!({ one_or_all_instance!(args, &actions::rollback_container) });
         }
         ("del", args) => {
             let instance = args.value_of("INSTANCE").unwrap();
-            print_error!({ actions::remove_instance(instance) });
+            print_error!({
+                actions::delete_instance(
+                    instance,
+                    args.is_present("force"),
+                    args.is_present("purge"),
+                )
+            });
+        }
+        ("restore", args) => {
+            if args.is_present("list") {
+                print_error!({ actions::print_trash() });
+                return Ok(());
+            }
+            let instance = args.value_of("INSTANCE").unwrap();
+            print_error!({ actions::restore_instance(instance, args.value_of("as")) });
+        }
+        ("add", args) if args.is_present("from") => {
+            print_error!({
+                actions::clone_instance(
+                    args.value_of("INSTANCE").unwrap(),
+                    args.value_of("from").unwrap(),
+                    args.value_of("description").map(String::from),
+                )
+            });
         }
         ("add", args) => {
             let instance = args.value_of("INSTANCE").unwrap();
-            print_error!({ actions::add_instance(instance) });
+            print_error!({
+                get_new_instance_config(args)
+                    .and_then(|config| actions::add_instance_with_config(instance, config))
+            });
+        }
+        ("build", args) if args.is_present("matrix") => {
+            let arches = args
+                .value_of("matrix")
+                .unwrap()
+                .split(',')
+                .map(|arch| network::normalize_arch_name(arch.trim()))
+                .collect::<Vec<_>>();
+            let settings = get_build_settings(args)?;
+            start_progress_stream(&settings);
+            let packages = args.values_of("PACKAGES").unwrap().collect::<Vec<_>>();
+            let start = Instant::now();
+            let results = actions::matrix_build(&arches, &packages, &settings)?;
+            if json {
+                print_json(&results)?;
+            }
+            let failed = results.iter().find(|r| !r.summary.success);
+            let exit_code = failed.map_or(0, |r| r.summary.exit_code);
+            let failed_package = failed.and_then(|r| {
+                r.summary
+                    .failed_package
+                    .as_ref()
+                    .map(|p| format!("{} ({})", p, r.arch))
+            });
+            notify::build_finished(
+                &notify::BuildOutcome {
+                    success: failed.is_none(),
+                    exit_code,
+                    packages: &packages.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+                    target: Some(&arches.join(", ")),
+                    failed_package: failed_package.as_deref(),
+                    duration: start.elapsed().as_secs(),
+                },
+                &mut std::io::stderr(),
+            );
+            process::exit(exit_code);
+        }
+        ("build", args) if args.is_present("on") => {
+            let settings = get_build_settings(args)?;
+            let packages = args.values_of("PACKAGES").unwrap().collect::<Vec<_>>();
+            let remote = args.value_of("on").unwrap();
+            let start = Instant::now();
+            let status = actions::remote_build(
+                remote,
+                args.value_of("INSTANCE"),
+                args.value_of("arch").map(network::normalize_arch_name),
+                &packages,
+                &settings,
+            )?;
+            notify::build_finished(
+                &notify::BuildOutcome {
+                    success: status == 0,
+                    exit_code: status,
+                    packages: &packages.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+                    target: Some(remote),
+                    failed_package: None,
+                    duration: start.elapsed().as_secs(),
+                },
+                &mut std::io::stderr(),
+            );
+            process::exit(status);
         }
         ("build", args) => {
-            let instance = get_instance_option(args)?;
-            let offline = args.is_present("OFFLINE");
+            let instance = match args.value_of("arch").map(network::normalize_arch_name) {
+                Some(arch) => actions::pick_instance_for_arch(arch, args.value_of("INSTANCE"))?,
+                None => get_instance_option(args)?,
+            };
+            let settings = get_build_settings(args)?;
+            start_progress_stream(&settings);
             let mut state = None;
             if let Some(cont) = args.value_of("CONTINUE") {
                 state = Some(actions::load_build_checkpoint(cont)?);
                 let empty: Vec<&str> = Vec::new();
-                let status = actions::package_build(&instance, empty.into_iter(), state, offline)?;
-                println!("\x07"); // bell character
-                process::exit(status);
+                let summary =
+                    actions::package_build(&instance, empty.into_iter(), state, &settings)?;
+                exit_with_build_summary(&instance, &summary, json);
             }
             let packages = args.values_of("PACKAGES");
             if packages.is_none() {
@@ -232,42 +857,143 @@ fn main() -> Result<()> {
             let packages = packages.unwrap();
             if args.is_present("SELECT") {
                 let start_package = args.value_of("SELECT");
-                let status =
-                    actions::packages_stage_select(&instance, packages, offline, start_package)?;
-                process::exit(status);
+                let summary =
+                    actions::packages_stage_select(&instance, packages, &settings, start_package)?;
+                exit_with_build_summary(&instance, &summary, json);
             }
             if args.is_present("FETCH") {
                 let status = actions::package_fetch(&instance, &packages.collect::<Vec<&str>>())?;
                 process::exit(status);
             }
-            let status = actions::package_build(&instance, packages, state, offline)?;
-            println!("\x07"); // bell character
-            process::exit(status);
+            let summary = actions::package_build(&instance, packages, state, &settings)?;
+            exit_with_build_summary(&instance, &summary, json);
+        }
+        ("remote", args) => match args.subcommand() {
+            Some(("add", args)) => {
+                let remote = config::RemoteBuilder {
+                    host: args.value_of("HOST").unwrap().to_string(),
+                    path: args.value_of("path").map(String::from),
+                    instance: args.value_of("INSTANCE").map(String::from),
+                    sync_tree: args.is_present("sync-tree"),
+                    command: args.value_of("command").map(String::from),
+                };
+                print_error!({ actions::remote_add(args.value_of("name"), remote) });
+            }
+            Some(("remove", args)) => {
+                print_error!({ actions::remote_remove(args.value_of("NAME").unwrap()) });
+            }
+            Some(("list", _)) => {
+                print_error!({ actions::list_remotes() });
+            }
+            _ => unreachable!(),
+        },
+        ("cross-setup", args) => {
+            let arch = network::normalize_arch_name(args.value_of("ARCH").unwrap());
+            print_error!({ actions::cross_setup(arch, args.value_of("INSTANCE")) });
+        }
+        ("logs", args) => {
+            let package = args.value_of("PACKAGE");
+            if args.is_present("list") {
+                if json {
+                    print_error!({ actions::get_build_logs(package).and_then(|e| print_json(&e)) });
+                } else {
+                    print_error!({ actions::list_build_logs(package) });
+                }
+            } else {
+                let previous = args.value_of_t("previous")?;
+                print_error!({ show_build_log(package.unwrap(), previous) });
+            }
         }
         ("", _) => {
-            machine::print_instances()?;
+            print_instance_list(json, false)?;
+        }
+        ("list", args) => {
+            print_instance_list(json, args.is_present("state"))?;
         }
-        ("list", _) => {
-            machine::print_instances()?;
+        ("status", _) => {
+            if json {
+                print_error!({ actions::get_workspace_status().and_then(|s| print_json(&s)) });
+            } else {
+                print_error!({ actions::print_status() });
+            }
         }
-        ("doctor", _) => {
-            print_error!({ diagnose::run_diagnose() });
+        ("ui", _) => {
+            print_error!({ actions::run_ui() });
+        }
+        ("daemon", args) => {
+            if let Some(path) = args.value_of("socket") {
+                print_error!({ rpc::serve_socket(Path::new(path), args.value_of("group")) });
+            } else {
+                print_error!({ service::serve_dbus(args.is_present("session")) });
+            }
+        }
+        ("serve-api", args) => {
+            let token_file = args.value_of("token-file").map(Path::new);
+            print_error!({ api::serve_api(args.value_of("listen").unwrap(), token_file) });
+        }
+        ("top", args) => {
+            let delay: f64 = args.value_of_t("delay")?;
+            if !delay.is_finite() || delay <= 0.0 {
+                error!("Refresh interval must be a positive number.");
+                process::exit(1);
+            }
+            print_error!({ machine::monitor_instances(Duration::from_secs_f64(delay)) });
+        }
+        ("doctor", args) => {
+            let fix = args.is_present("fix");
+            let bundle = args.value_of("bundle").map(Path::new);
+            let force = args.is_present("force");
+            match diagnose::run_diagnose(json, fix, force, bundle, &mut std::io::stdout()) {
+                Ok(code) => process::exit(code),
+                Err(e) => {
+                    error!("{:?}", e);
+                    process::exit(diagnose::EXIT_ERROR);
+                }
+            }
         }
         ("repo", args) => match args.subcommand() {
             Some(("refresh", _)) => {
                 info!("Refreshing repository...");
-                print_error!({
-                    repo::refresh_repo(&std::env::current_dir().unwrap().join(get_output_dir()))
-                });
+                let path = std::env::current_dir().unwrap().join(get_output_dir());
+                print_error!({ repo::refresh_repo(&path, actions::get_dist_arch().as_deref()) });
                 info!("Repository has been refreshed.");
+                print_repo_result(json, "refresh", &path)?;
+            }
+            Some(("dedupe", args)) => {
+                let dry_run = args.is_present("dry-run");
+                let roots = repo::find_output_dirs(&std::env::current_dir()?)?;
+                let result = repo::dedupe_outputs(&roots, dry_run)?;
+                if json {
+                    print_json(&result)?;
+                } else if dry_run {
+                    info!(
+                        "{} packages would be hard linked, freeing {}.",
+                        result.linked,
+                        HumanBytes(result.freed)
+                    );
+                } else {
+                    info!(
+                        "{} packages hard linked, {} freed.",
+                        result.linked,
+                        HumanBytes(result.freed)
+                    );
+                }
             }
             Some(("init", args)) => {
                 info!("Initializing repository...");
                 let instance = get_instance_option(args)?;
                 let cwd = std::env::current_dir().unwrap();
                 print_error!({ actions::mount_fs(&instance) });
-                print_error!({ repo::init_repo(&cwd.join(get_output_dir()), &cwd.join(instance)) });
+                let path = cwd.join(get_output_dir());
+                print_error!({
+                    repo::init_repo(
+                        &path,
+                        &cwd.join(instance),
+                        actions::get_dist_arch().as_deref(),
+                    )
+                });
                 info!("Repository has been initialized and refreshed.");
+                print_repo_result(json, "init", &path)?;
             }
             Some(("deinit", args)) => {
                 info!("Disabling local repository...");
@@ -276,33 +1002,48 @@ fn main() -> Result<()> {
                 print_error!({ actions::mount_fs(&instance) });
                 print_error!({ repo::deinit_repo(&cwd.join(instance)) });
                 info!("Repository has been disabled.");
+                print_repo_result(json, "deinit", &cwd.join(get_output_dir()))?;
             }
             _ => unreachable!(),
         },
-        ("clean", _) => {
-            print_error!({ actions::cleanup_outputs() });
+        ("clean", args) => {
+            let options = actions::CleanOptions {
+                all: args.is_present("all"),
+                dry_run: args.is_present("dry-run"),
+                max_age: args.value_of_t("max-age")?,
+            };
+            print_error!({ actions::clean_workspace(&options) });
         }
-        ("version", _) => {
+        ("version", args) => {
+            if args.is_present("os") {
+                print_error!({ actions::print_base_system_info(&mut std::io::stdout()) });
+                return Ok(());
+            }
             println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
         }
         // catch all other conditions
         (_, options) => {
-            let exe_dir = std::env::current_exe()?;
-            let exe_dir = exe_dir.parent().expect("Where am I?");
             let cmd = args.subcommand().unwrap().0;
-            let plugin = exe_dir
-                .join("../libexec/ciel-plugin/")
-                .join(format!("ciel-{}", cmd));
+            let name = format!("ciel-{}", cmd);
+            let plugin = cli::get_plugins_dir()?.join(&name);
             if !plugin.is_file() {
                 error!("Unknown command: `{}`.", cmd);
                 process::exit(1);
             }
-            info!("Executing applet ciel-{}", cmd);
-            let mut process = &mut Command::new(plugin);
-            if let Some(args) = options.values_of("COMMANDS") {
-                process = process.args(args.collect::<Vec<&str>>());
-            }
-            let status = process.status().unwrap().code().unwrap();
+            let manifest = cli::read_plugin_manifest(&name)
+                .unwrap_or_else(|e| {
+                    warn!("{}", e);
+                    None
+                })
+                .unwrap_or_default();
+            let plugin_args = plugin::get_plugin_args(&manifest, options);
+            info!("Executing applet {}", name);
+            let status = plugin::run_plugin(
+                &plugin,
+                &plugin_args,
+                manifest.json_rpc,
+                &mut std::io::stdout(),
+            )?;
             if status != 0 {
                 error!("Applet exited with error {}", status);
             }
@@ -0,0 +1,193 @@
+//! Generate roff man pages from the clap definitions (`ciel gen-manpages`)
+use anyhow::Result;
+use clap::{App, Arg};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const MANUAL_NAME: &str = "CIEL! Manual";
+// arguments generated by clap itself
+const BUILTIN_ARGS: &[&str] = &["help", "version"];
+
+/// Escape the text for use in roff
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\e")
+        .replace('-', "\\-")
+        .lines()
+        .map(|line| {
+            // lines starting with a dot or an apostrophe are treated as requests
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[inline]
+fn value_name(arg: &Arg) -> String {
+    arg.get_value_names()
+        .map(|names| names.join(" "))
+        .unwrap_or_else(|| arg.get_id().to_uppercase())
+}
+
+/// Get the help text of the argument, formatted as a paragraph
+#[inline]
+fn arg_help(arg: &Arg) -> String {
+    match arg.get_long_help().or_else(|| arg.get_help()) {
+        Some(help) if !help.is_empty() => format!("{}\n", escape(help)),
+        _ => String::new(),
+    }
+}
+
+#[inline]
+fn is_option(arg: &Arg) -> bool {
+    !arg.is_positional() && !arg.is_hide_set() && !BUILTIN_ARGS.contains(&arg.get_id())
+}
+
+fn render_option(arg: &Arg) -> String {
+    let mut flags = Vec::new();
+    if let Some(short) = arg.get_short() {
+        flags.push(format!("\\fB\\-{}\\fR", short));
+    }
+    if let Some(long) = arg.get_long() {
+        flags.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+    }
+    let mut section = format!(".TP\n{}", flags.join(", "));
+    if arg.is_takes_value_set() {
+        section += &format!(" \\fI{}\\fR", escape(&value_name(arg)));
+    }
+    section.push('\n');
+    section += &arg_help(arg);
+    if let Some(values) = arg.get_possible_values() {
+        let values: Vec<&str> = values.iter().map(|v| v.get_name()).collect();
+        section += &format!(".br\nPossible values: {}\n", escape(&values.join(", ")));
+    }
+
+    section
+}
+
+fn render_synopsis(app: &App, command: &str) -> String {
+    let mut synopsis = format!(".B {}\n", escape(command));
+    if app.get_arguments().any(is_option) {
+        synopsis += "[\\fIOPTIONS\\fR]\n";
+    }
+    for arg in app.get_positionals().filter(|a| !a.is_hide_set()) {
+        let name = escape(&value_name(arg));
+        let name = if arg.is_required_set() {
+            format!("<\\fI{}\\fR>", name)
+        } else {
+            format!("[\\fI{}\\fR]", name)
+        };
+        if arg.is_multiple_values_set() || arg.is_multiple_occurrences_set() {
+            synopsis += &format!("{}...\n", name);
+        } else {
+            synopsis += &format!("{}\n", name);
+        }
+    }
+    if app.get_subcommands().any(|s| !s.is_hide_set()) {
+        synopsis += "<\\fISUBCOMMAND\\fR>\n";
+    }
+
+    synopsis
+}
+
+/// Render the man page of the (sub)command, `path` is the full command (e.g. ["ciel", "config", "repo"])
+fn render_page(app: &App, path: &[String]) -> String {
+    let name = path.join("-");
+    let about = app.get_about().unwrap_or_default();
+    let mut page = format!(
+        ".TH {} 1 \"\" \"{} {}\" \"{}\"\n",
+        escape(&name.to_uppercase()),
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        MANUAL_NAME
+    );
+    page += &format!(".SH NAME\n{} \\- {}\n", escape(&name), escape(about));
+    page += ".SH SYNOPSIS\n";
+    page += &render_synopsis(app, &path.join(" "));
+    page += ".SH DESCRIPTION\n";
+    page += &escape(app.get_long_about().unwrap_or(about));
+    page.push('\n');
+    let aliases: Vec<&str> = app.get_all_aliases().collect();
+    if !aliases.is_empty() {
+        page += &format!(".PP\nAliases: {}\n", escape(&aliases.join(", ")));
+    }
+
+    let options: Vec<&Arg> = app.get_arguments().filter(|a| is_option(a)).collect();
+    if !options.is_empty() {
+        page += ".SH OPTIONS\n";
+        for option in options {
+            page += &render_option(option);
+        }
+    }
+    let positionals: Vec<&Arg> = app.get_positionals().filter(|a| !a.is_hide_set()).collect();
+    if !positionals.is_empty() {
+        page += ".SH ARGUMENTS\n";
+        for arg in positionals {
+            page += &format!(
+                ".TP\n\\fI{}\\fR\n{}",
+                escape(&value_name(arg)),
+                arg_help(arg)
+            );
+        }
+    }
+    let subcommands: Vec<&App> = app.get_subcommands().filter(|s| !s.is_hide_set()).collect();
+    if !subcommands.is_empty() {
+        page += ".SH SUBCOMMANDS\n";
+        for subcommand in subcommands {
+            page += &format!(
+                ".TP\n\\fB{}\\-{}\\fR(1)\n{}\n",
+                escape(&name),
+                escape(subcommand.get_name()),
+                escape(subcommand.get_about().unwrap_or_default())
+            );
+        }
+    }
+    if path.len() > 1 {
+        page += &format!(
+            ".SH SEE ALSO\n\\fB{}\\fR(1)\n",
+            escape(&path[..path.len() - 1].join("-"))
+        );
+    }
+
+    page
+}
+
+fn write_pages(
+    app: &App,
+    path: &mut Vec<String>,
+    dir: &Path,
+    written: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let file = dir.join(format!("{}.1", path.join("-")));
+    fs::write(&file, render_page(app, path))?;
+    written.push(file);
+    for subcommand in app.get_subcommands().filter(|s| !s.is_hide_set()) {
+        path.push(subcommand.get_name().to_string());
+        write_pages(subcommand, path, dir, written)?;
+        path.pop();
+    }
+
+    Ok(())
+}
+
+/// Generate man pages for the command and all of its subcommands into `dir`,
+/// returns the paths of the generated pages
+pub fn generate_manpages(app: &App, name: &str, dir: &Path) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+    write_pages(app, &mut vec![name.to_string()], dir, &mut written)?;
+
+    Ok(written)
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(escape("--force"), "\\-\\-force");
+    assert_eq!(escape("C:\\path"), "C:\\epath");
+    assert_eq!(escape("first\n.second"), "first\n\\&.second");
+}
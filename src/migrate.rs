@@ -0,0 +1,150 @@
+//! Step-by-step migrations of the workspace layout (`ciel init --upgrade`)
+use anyhow::{anyhow, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::{
+    common::{
+        read_workspace_version, write_workspace_version, CIEL_DATA_DIR, CIEL_INST_DIR,
+        CIEL_VERSION_FILE, CURRENT_CIEL_VERSION, SKELETON_DIRS,
+    },
+    config::{DEFAULT_CONFIG_LOCATION, INSTANCE_CONFIG_NAME},
+    info,
+};
+
+/// Where the backups are placed before migrating (relative to the workspace)
+const BACKUP_DIR: &str = ".ciel/backup";
+
+/// A migration from `from` to `from + 1`
+struct Migration {
+    from: usize,
+    description: &'static str,
+    apply: fn() -> Result<()>,
+}
+
+// the oldest version that can be migrated
+const OLDEST_SUPPORTED_VERSION: usize = 2;
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 2,
+    description: "switch to the path-based container names and bump the configuration version",
+    apply: migrate_2_to_3,
+}];
+
+/// Version 3 names the containers after the workspace path (instead of `ftok`),
+/// the instances are shut down by the caller using the old names
+fn migrate_2_to_3() -> Result<()> {
+    for dir in SKELETON_DIRS {
+        fs::create_dir_all(dir)?;
+    }
+    set_config_version(3)
+}
+
+/// Update the version in the workspace configuration (keeping everything else intact)
+fn set_config_version(version: usize) -> Result<()> {
+    let data = match fs::read_to_string(DEFAULT_CONFIG_LOCATION) {
+        Ok(data) => data,
+        // not configured yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut config: toml::Value = toml::from_str(&data)?;
+    let table = config
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("Invalid configuration file"))?;
+    table.insert("version".to_string(), toml::Value::Integer(version as i64));
+    fs::write(DEFAULT_CONFIG_LOCATION, toml::to_string(&config)?)?;
+
+    Ok(())
+}
+
+/// Copy the version file and the configurations (not the filesystem layers) into a backup directory
+fn backup_workspace(version: usize) -> Result<PathBuf> {
+    let timestamp = OffsetDateTime::now_utc().format(format_description!(
+        "[year][month][day]-[hour][minute][second]"
+    ))?;
+    let backup = Path::new(BACKUP_DIR).join(format!("{}-v{}", timestamp, version));
+    fs::create_dir_all(backup.join("data"))?;
+    fs::copy(CIEL_VERSION_FILE, backup.join("version"))?;
+    for entry in fs::read_dir(CIEL_DATA_DIR)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), backup.join("data").join(entry.file_name()))?;
+        }
+    }
+    if let Ok(instances) = fs::read_dir(CIEL_INST_DIR) {
+        for entry in instances {
+            let entry = entry?;
+            let config = entry.path().join(INSTANCE_CONFIG_NAME);
+            if config.is_file() {
+                let dest = backup.join("instances").join(entry.file_name());
+                fs::create_dir_all(&dest)?;
+                fs::copy(config, dest.join(INSTANCE_CONFIG_NAME))?;
+            }
+        }
+    }
+
+    Ok(backup)
+}
+
+/// Migrate the workspace to the current version one step at a time, the version file is updated
+/// after each step so that an interrupted migration can be resumed
+pub fn migrate_workspace() -> Result<()> {
+    let version = read_workspace_version()?;
+    if version > CURRENT_CIEL_VERSION {
+        return Err(anyhow!(
+            "This workspace (version {}) was created by a newer version of Ciel, please upgrade Ciel instead.",
+            version
+        ));
+    }
+    if version == CURRENT_CIEL_VERSION {
+        info!(
+            "Workspace is already up to date (version {}).",
+            CURRENT_CIEL_VERSION
+        );
+        return Ok(());
+    }
+    if version < OLDEST_SUPPORTED_VERSION {
+        return Err(anyhow!(
+            "Workspace version {} is too old to be migrated, please create a new workspace.",
+            version
+        ));
+    }
+    let backup = backup_workspace(version)?;
+    info!("Backed up the workspace metadata to {}", backup.display());
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= version) {
+        info!(
+            "Migrating from version {} to {}: {} ...",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        );
+        (migration.apply)().map_err(|e| {
+            anyhow!(
+                "Migration to version {} failed: {} (the backup is in {})",
+                migration.from + 1,
+                e,
+                backup.display()
+            )
+        })?;
+        write_workspace_version(migration.from + 1)?;
+    }
+    info!(
+        "Workspace has been upgraded to version {}.",
+        CURRENT_CIEL_VERSION
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_migrations_are_complete() {
+    let mut version = OLDEST_SUPPORTED_VERSION;
+    for migration in MIGRATIONS {
+        assert_eq!(migration.from, version);
+        version += 1;
+    }
+    assert_eq!(version, CURRENT_CIEL_VERSION);
+}
@@ -0,0 +1,468 @@
+//! Restricting the network access of the builds (`policy = "restricted"` in `[build-network]`):
+//! the builds run in a network namespace of their own, where the only thing reachable is a
+//! forwarding proxy (on the loopback interface) which only connects to the allowed hosts
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use nix::{
+    sched::{setns, unshare, CloneFlags},
+    sys::socket::{self, AddressFamily, SockFlag, SockType},
+    unistd::close,
+};
+use reqwest::Url;
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::{io::AsRawFd, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{config::CielConfig, debug, info, warn};
+
+// in the network namespace of the builds, so that it does not conflict with anything
+const PROXY_ADDRESS: &str = "127.0.0.1:3128";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+// read after the proxy configuration of the workspace (`99ciel-proxy`), so that it takes precedence
+const APT_OVERRIDE_LOCATION: &str = "etc/apt/apt.conf.d/99ciel-restricted-network";
+
+lazy_static! {
+    // the network namespace of the builds, if restricted
+    static ref BUILD_NETNS: Mutex<Option<File>> = Mutex::new(None);
+}
+
+/// `struct ifreq` with the flags of the interface
+#[repr(C)]
+struct InterfaceFlagsRequest {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _padding: [u8; 22],
+}
+
+/// What the proxy lets the builds reach
+struct Policy {
+    allowed_hosts: Vec<String>,
+    allowed_ports: Vec<u16>,
+    /// The proxy of the workspace, used for all the connections if set
+    upstream: Option<(String, u16)>,
+}
+
+/// A request to the proxy
+#[derive(Debug, PartialEq)]
+struct ProxyRequest {
+    /// A tunnel (`CONNECT`), e.g. for HTTPS
+    tunnel: bool,
+    host: String,
+    port: u16,
+    /// The request line sent to the host if connected directly (without the proxy of the workspace)
+    origin_line: String,
+}
+
+/// The restricted network of the builds, which is lifted when dropped
+pub struct RestrictedNetwork {
+    listener: TcpListener,
+    stopped: Arc<AtomicBool>,
+    apt_override: PathBuf,
+}
+
+impl RestrictedNetwork {
+    /// The environment variables pointing the tools in the container to the proxy
+    pub fn proxy_env(&self) -> Vec<(String, String)> {
+        let url = format!("http://{}", PROXY_ADDRESS);
+        ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"]
+            .iter()
+            .map(|name| (name.to_string(), url.clone()))
+            .collect()
+    }
+}
+
+impl Drop for RestrictedNetwork {
+    fn drop(&mut self) {
+        *BUILD_NETNS.lock().unwrap() = None;
+        self.stopped.store(true, Ordering::SeqCst);
+        // wakes up the proxy waiting for connections
+        socket::shutdown(self.listener.as_raw_fd(), socket::Shutdown::Read).ok();
+        fs::remove_file(&self.apt_override).ok();
+    }
+}
+
+impl Policy {
+    fn is_allowed(&self, host: &str, port: u16) -> bool {
+        if !self.allowed_ports.contains(&port) {
+            return false;
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.allowed_hosts
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .filter(|sub| sub.ends_with('.') && sub.len() > 1)
+                    .is_some(),
+                None => host == *pattern,
+            })
+    }
+
+    fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let (host, port) = match &self.upstream {
+            Some((host, port)) => (host.as_str(), *port),
+            None => (host, port),
+        };
+        let mut last_error = None;
+        for address in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.map_or_else(|| anyhow!("Unable to resolve {}", host), |e| e.into()))
+    }
+}
+
+fn bring_up_loopback() -> Result<()> {
+    let fd = socket::socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let mut request = InterfaceFlagsRequest {
+        name: [0; libc::IFNAMSIZ],
+        flags: 0,
+        _padding: [0; 22],
+    };
+    request.name[..2].copy_from_slice(b"lo");
+    let mut result = unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut request) };
+    if result == 0 {
+        request.flags |= libc::IFF_UP as libc::c_short;
+        result = unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS, &mut request) };
+    }
+    let error = io::Error::last_os_error();
+    close(fd).ok();
+    if result != 0 {
+        return Err(anyhow!(
+            "Unable to bring up the loopback interface: {}",
+            error
+        ));
+    }
+
+    Ok(())
+}
+
+/// Create the network namespace of the builds, with the proxy listening in it
+fn create_build_netns() -> Result<(TcpListener, File)> {
+    thread::spawn(|| {
+        // only this thread is moved into the new namespace, the sockets stay in there
+        unshare(CloneFlags::CLONE_NEWNET)?;
+        bring_up_loopback()?;
+        let listener = TcpListener::bind(PROXY_ADDRESS)?;
+        let netns = File::open("/proc/thread-self/ns/net")?;
+
+        Ok((listener, netns))
+    })
+    .join()
+    .map_err(|_| anyhow!("Unable to create the network namespace"))?
+}
+
+fn parse_request_line(line: &str) -> Result<ProxyRequest> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(anyhow!("Malformed request line: {}", line)),
+    };
+    let tunnel = method == "CONNECT";
+    let url = if tunnel {
+        Url::parse(&format!("tunnel://{}", target))?
+    } else {
+        Url::parse(target)?
+    };
+    if !tunnel && url.scheme() != "http" {
+        return Err(anyhow!("Unsupported protocol: {}", url.scheme()));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("No host specified in {}", target))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("No port specified in {}", target))?;
+    let origin_line = if tunnel {
+        line.to_string()
+    } else {
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        format!("{} {} {}", method, path, version)
+    };
+
+    Ok(ProxyRequest {
+        tunnel,
+        host: host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        port,
+        origin_line,
+    })
+}
+
+/// Read the request line and the headers (until the empty line)
+fn read_head<R: BufRead>(reader: &mut R) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("Connection closed"));
+        }
+        size += line.len();
+        if size > MAX_HEADER_SIZE {
+            return Err(anyhow!("Request headers too large"));
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line.is_empty() {
+            return Ok(lines);
+        }
+        lines.push(line.to_string());
+    }
+}
+
+fn write_error(client: &mut TcpStream, status: &str, message: &str) -> Result<()> {
+    write!(
+        client,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        message.len() + 1,
+        message
+    )?;
+
+    Ok(())
+}
+
+/// Copy the data in both directions until either side is done
+fn relay<R: Read + Send + 'static>(
+    mut from_client: R,
+    mut client: TcpStream,
+    mut server: TcpStream,
+) -> Result<()> {
+    let mut to_server = server.try_clone()?;
+    let upload = thread::spawn(move || {
+        io::copy(&mut from_client, &mut to_server).ok();
+        to_server.shutdown(Shutdown::Write).ok();
+    });
+    io::copy(&mut server, &mut client).ok();
+    client.shutdown(Shutdown::Write).ok();
+    upload.join().ok();
+
+    Ok(())
+}
+
+fn handle_client(policy: &Policy, mut client: TcpStream) -> Result<()> {
+    client.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(client.try_clone()?);
+    let mut head = read_head(&mut reader)?;
+    let request = match head.first().map(|line| parse_request_line(line)) {
+        Some(Ok(request)) => request,
+        Some(Err(e)) => return write_error(&mut client, "400 Bad Request", &e.to_string()),
+        None => return write_error(&mut client, "400 Bad Request", "Empty request"),
+    };
+    if !policy.is_allowed(&request.host, request.port) {
+        warn!(
+            "Blocked the connection to {}:{} (not allowed by the network policy)",
+            request.host, request.port
+        );
+        let message = format!(
+            "{}:{} is not allowed by the network policy",
+            request.host, request.port
+        );
+        return write_error(&mut client, "403 Forbidden", &message);
+    }
+    debug!("Proxying {}:{}", request.host, request.port);
+    let mut server = match policy.connect(&request.host, request.port) {
+        Ok(server) => server,
+        Err(e) => return write_error(&mut client, "502 Bad Gateway", &e.to_string()),
+    };
+    if request.tunnel {
+        if policy.upstream.is_some() {
+            write!(
+                server,
+                "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n\r\n",
+                request.host, request.port
+            )?;
+            let mut upstream = BufReader::new(server.try_clone()?);
+            let response = read_head(&mut upstream)?;
+            let status = response.first().map(String::as_str).unwrap_or_default();
+            if status.split(' ').nth(1) != Some("200") {
+                return write_error(&mut client, "502 Bad Gateway", status);
+            }
+        }
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+    } else {
+        if policy.upstream.is_none() {
+            head[0] = request.origin_line;
+        }
+        // the following requests may be for other hosts
+        head.retain(|line| {
+            let name = line.split(':').next().unwrap_or_default();
+            !name.eq_ignore_ascii_case("connection")
+                && !name.eq_ignore_ascii_case("proxy-connection")
+        });
+        head.push("Connection: close".to_string());
+        server.write_all(format!("{}\r\n\r\n", head.join("\r\n")).as_bytes())?;
+    }
+    client.set_read_timeout(None)?;
+
+    relay(reader, client, server)
+}
+
+fn serve(listener: TcpListener, policy: Policy, stopped: Arc<AtomicBool>) {
+    let policy = Arc::new(policy);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) if stopped.load(Ordering::SeqCst) => break,
+            Err(e) => {
+                warn!("Unable to accept the connection: {}", e);
+                continue;
+            }
+        };
+        let policy = policy.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_client(&policy, stream) {
+                debug!("Proxy connection closed: {}", e);
+            }
+        });
+    }
+}
+
+/// Restrict the network of the following builds to the allowed hosts (until the returned value
+/// is dropped), `rootfs` is the mounted instance, where APT is pointed to the proxy
+pub fn restrict_build_network(rootfs: &Path, config: &CielConfig) -> Result<RestrictedNetwork> {
+    let mut allowed_hosts = config
+        .build_network
+        .allowed_hosts
+        .iter()
+        .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let mut allowed_ports = config.build_network.allowed_ports.clone();
+    for mirror in config.get_mirror_urls() {
+        if let Ok(url) = Url::parse(&mirror) {
+            if let Some(host) = url.host_str() {
+                allowed_hosts.push(host.to_ascii_lowercase());
+            }
+            allowed_ports.extend(url.port_or_known_default());
+        }
+    }
+    let upstream = match &config.proxy {
+        Some(proxy) => {
+            let url = Url::parse(proxy)?;
+            if url.scheme() != "http" {
+                return Err(anyhow!(
+                    "Unsupported proxy for the restricted network: {}",
+                    proxy
+                ));
+            }
+            let host = url
+                .host_str()
+                .ok_or_else(|| anyhow!("No host specified in {}", proxy))?;
+            Some((host.to_string(), url.port_or_known_default().unwrap_or(80)))
+        }
+        None => None,
+    };
+    let (listener, netns) = create_build_netns()?;
+    let apt_override = rootfs.join(APT_OVERRIDE_LOCATION);
+    if let Some(parent) = apt_override.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(
+        &apt_override,
+        format!(
+            "Acquire::http::Proxy \"http://{0}/\";\nAcquire::https::Proxy \"http://{0}/\";\n",
+            PROXY_ADDRESS
+        ),
+    )?;
+    info!(
+        "Network of the builds restricted to: {} (ports {})",
+        allowed_hosts.join(", "),
+        allowed_ports
+            .iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let stopped = Arc::new(AtomicBool::new(false));
+    let policy = Policy {
+        allowed_hosts,
+        allowed_ports,
+        upstream,
+    };
+    let server = listener.try_clone()?;
+    let server_stopped = stopped.clone();
+    thread::spawn(move || serve(server, policy, server_stopped));
+    *BUILD_NETNS.lock().unwrap() = Some(netns);
+
+    Ok(RestrictedNetwork {
+        listener,
+        stopped,
+        apt_override,
+    })
+}
+
+/// Check if the network of the builds is restricted (see `restrict_build_network`)
+pub fn is_restricted() -> bool {
+    BUILD_NETNS.lock().unwrap().is_some()
+}
+
+/// Run the command (which starts a container) in the network namespace of the builds,
+/// if the network is restricted
+pub fn enter_build_netns(command: &mut Command) {
+    if let Some(netns) = BUILD_NETNS.lock().unwrap().as_ref() {
+        let fd = netns.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                setns(fd, CloneFlags::CLONE_NEWNET)
+                    .map_err(|e| io::Error::from_raw_os_error(e as i32))
+            });
+        }
+    }
+}
+
+#[test]
+fn test_proxy_request() {
+    let policy = Policy {
+        allowed_hosts: vec!["github.com".to_string(), "*.aosc.io".to_string()],
+        allowed_ports: vec![80, 443],
+        upstream: None,
+    };
+    assert!(policy.is_allowed("github.com", 443));
+    assert!(policy.is_allowed("GitHub.com.", 443));
+    assert!(policy.is_allowed("repo.aosc.io", 80));
+    assert!(!policy.is_allowed("repo.aosc.io", 22));
+    assert!(!policy.is_allowed("aosc.io", 443));
+    assert!(!policy.is_allowed("evilaosc.io", 443));
+    assert!(!policy.is_allowed("github.com.evil.org", 443));
+    assert_eq!(
+        parse_request_line("CONNECT github.com:443 HTTP/1.1").unwrap(),
+        ProxyRequest {
+            tunnel: true,
+            host: "github.com".to_string(),
+            port: 443,
+            origin_line: "CONNECT github.com:443 HTTP/1.1".to_string(),
+        }
+    );
+    let request = parse_request_line("GET http://repo.aosc.io/debs/?a=b HTTP/1.1").unwrap();
+    assert_eq!((request.host.as_str(), request.port), ("repo.aosc.io", 80));
+    assert_eq!(request.origin_line, "GET /debs/?a=b HTTP/1.1");
+    assert!(parse_request_line("GET https://repo.aosc.io/ HTTP/1.1").is_err());
+    assert!(parse_request_line("GET /").is_err());
+}
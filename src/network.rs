@@ -1,11 +1,20 @@
-use crate::make_progress_bar;
+use crate::{cli, debug, privsep, progress};
 use anyhow::{anyhow, Result};
 use fs3::FileExt;
 use lazy_static::lazy_static;
-use progress_streams::ProgressReader;
+use nix::unistd::{Gid, Uid};
+use progress_streams::ProgressWriter;
 use reqwest::blocking::{Client, Response};
-use serde::Deserialize;
-use std::{env::consts::ARCH, path::Path};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    env::consts::ARCH,
+    fs,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 use std::{
     sync::{
         atomic::{AtomicUsize, Ordering},
@@ -14,11 +23,30 @@ use std::{
     thread::{self, sleep},
     time::Duration,
 };
+use tempfile::NamedTempFile;
+// timeout of the reachability probes (used by `ciel doctor`)
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub const GIT_TREE_URL: &str = "https://github.com/AOSC-Dev/aosc-os-abbs.git";
-const MANIFEST_URL: &str = "https://releases.aosc.io/manifest/recipe.json";
+pub const MANIFEST_URL: &str = "https://releases.aosc.io/manifest/recipe.json";
+const DEFAULT_VARIANT: &str = "BuildKit";
+// the release manifest is fetched again after this long
+const RECIPE_CACHE_TTL: Duration = Duration::from_secs(3600);
+const RECIPE_CACHE_NAME: &str = "recipe.json";
+// AOSC OS architecture names and their aliases
+const AOSC_ARCHITECTURES: &[(&str, &[&str])] = &[
+    ("amd64", &["x86_64", "x86-64", "x64"]),
+    ("arm64", &["aarch64", "armv8"]),
+    ("armv7hf", &["armhf", "armv7", "arm"]),
+    ("i486", &["i386", "i686", "x86"]),
+    ("loongarch64", &["loong64", "la64"]),
+    ("loongson3", &["mips64el", "mips64"]),
+    ("ppc64el", &["ppc64le", "powerpc64le"]),
+    ("ppc64", &["powerpc64"]),
+    ("riscv64", &["rv64", "rv64gc"]),
+];
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Tarball {
     pub arch: String,
     pub date: String,
@@ -26,19 +54,80 @@ pub struct Tarball {
     pub sha256sum: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Variant {
     name: String,
     tarballs: Vec<Tarball>,
 }
 
 /// AOSC OS Tarball Recipe structure
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Recipe {
     pub version: usize,
     variants: Vec<Variant>,
 }
 
+/// The release manifest as cached in the user cache directory
+#[derive(Deserialize, Serialize)]
+struct CachedRecipe {
+    url: String,
+    /// When the manifest was fetched (seconds since the epoch)
+    fetched: u64,
+    recipe: Recipe,
+}
+
+impl CachedRecipe {
+    fn save(&self, path: &Path) -> Result<()> {
+        let parent = path.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(parent)?;
+        let mut file = NamedTempFile::new_in(parent)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.persist(path)?;
+
+        Ok(())
+    }
+}
+
+/// Fetch the release manifest, the one fetched less than `RECIPE_CACHE_TTL` ago is reused
+fn fetch_recipe() -> Result<Recipe> {
+    let path = cli::get_user_cache_dir().map(|dir| dir.join(RECIPE_CACHE_NAME));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let cached = path
+        .as_ref()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|data| serde_json::from_slice::<CachedRecipe>(&data).ok());
+    if let Some(cached) = cached {
+        let fresh = now
+            .checked_sub(cached.fetched)
+            .filter(|age| *age < RECIPE_CACHE_TTL.as_secs())
+            .is_some();
+        if cached.url == MANIFEST_URL && fresh {
+            debug!(
+                "Using the release manifest fetched {}s ago",
+                now - cached.fetched
+            );
+            return Ok(cached.recipe);
+        }
+    }
+    let recipe = Client::new()
+        .get(MANIFEST_URL)
+        .send()?
+        .error_for_status()?
+        .json()?;
+    let cached = CachedRecipe {
+        url: MANIFEST_URL.to_string(),
+        fetched: now,
+        recipe,
+    };
+    if let Some(path) = path {
+        if let Err(e) = cached.save(&path) {
+            debug!("Unable to cache the release manifest: {}", e);
+        }
+    }
+
+    Ok(cached.recipe)
+}
+
 lazy_static! {
     static ref GIT_PROGRESS: indicatif::ProgressStyle = indicatif::ProgressStyle::default_bar()
         .template("[{bar:25.cyan/blue}] {pos}/{len} {msg} ({eta})");
@@ -51,9 +140,14 @@ pub fn download_file(url: &str) -> Result<Response> {
     Ok(client)
 }
 
-/// Download a file with progress indicator
-pub fn download_file_progress(url: &str, file: &str) -> Result<u64> {
-    let mut output = std::fs::File::create(file)?;
+/// Download a file with progress indicator to `output` (as the invoking user), returns the size
+/// and the SHA-256 checksum (calculated while downloading, so that the file does not need to
+/// be read again)
+pub fn download_file_progress(url: &str, output: fs::File) -> Result<(u64, String)> {
+    privsep::run_unprivileged("download", move || write_download(url, output))
+}
+
+fn write_download(url: &str, mut output: fs::File) -> Result<(u64, String)> {
     let mut resp = download_file(url)?;
     let mut total: u64 = 0;
     if let Some(length) = resp.headers().get("content-length") {
@@ -64,25 +158,112 @@ pub fn download_file_progress(url: &str, file: &str) -> Result<u64> {
         // fails early when there is insufficient disk space available
         output.allocate(total)?;
     }
-    let progress_bar = indicatif::ProgressBar::new(total);
-    progress_bar.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template(make_progress_bar!("{bytes}/{total_bytes}")),
-    );
-    progress_bar.enable_steady_tick(500);
-    let mut reader = ProgressReader::new(&mut resp, |progress: usize| {
+    let progress_bar = progress::bytes_bar(total, "Downloading...");
+    let mut hasher = Sha256::new();
+    let mut output = ProgressWriter::new(&mut output, |progress: usize| {
         progress_bar.inc(progress as u64);
     });
-    std::io::copy(&mut reader, &mut output)?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut downloaded = 0u64;
+    loop {
+        let len = resp.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buffer[..len]);
+        output.write_all(&buffer[..len])?;
+        downloaded += len as u64;
+    }
     progress_bar.finish_and_clear();
 
-    Ok(total)
+    Ok((downloaded, format!("{:x}", hasher.finalize())))
+}
+
+/// Turn the request error into a human-readable diagnosis
+fn describe_request_error(err: reqwest::Error) -> anyhow::Error {
+    if err.is_timeout() {
+        return anyhow!("timed out after {}s", PROBE_TIMEOUT.as_secs());
+    }
+    // the TLS and DNS errors are only visible in the error sources
+    let mut root_cause: &dyn std::error::Error = &err;
+    while let Some(source) = root_cause.source() {
+        root_cause = source;
+    }
+    let details = root_cause.to_string();
+    let lower = details.to_lowercase();
+    if ["certificate", "tls", "ssl", "handshake"]
+        .iter()
+        .any(|k| lower.contains(k))
+    {
+        return anyhow!(
+            "TLS error ({}), check the system time and CA certificates",
+            details
+        );
+    }
+    if lower.contains("lookup address") || lower.contains("resolve") {
+        return anyhow!("unable to resolve the host name ({})", details);
+    }
+    if err.is_connect() {
+        return anyhow!("unable to connect ({})", details);
+    }
+
+    anyhow!("{}", err)
+}
+
+/// Check if the URL is reachable over HTTP(S), returns the response time
+pub fn probe_url(url: &str) -> Result<Duration> {
+    let client = Client::builder().timeout(PROBE_TIMEOUT).build()?;
+    let start = Instant::now();
+    let resp = client.head(url).send().map_err(describe_request_error)?;
+    let elapsed = start.elapsed();
+    if resp.status().is_server_error() {
+        return Err(anyhow!("server responded with {}", resp.status()));
+    }
+
+    Ok(elapsed)
+}
+
+/// Check if the TCP endpoint is reachable, returns the connection time
+pub fn probe_tcp(host: &str, port: u16) -> Result<Duration> {
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| anyhow!("unable to resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| anyhow!("unable to resolve {}", host))?;
+    let start = Instant::now();
+    TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)
+        .map_err(|e| anyhow!("unable to connect to {}:{}: {}", host, port, e))?;
+
+    Ok(start.elapsed())
+}
+
+/// Extract the host and port from the (non-HTTP) git remote URL,
+/// e.g. `ssh://git@host:2222/repo.git`, `git://host/repo.git` or `git@host:repo.git`
+pub fn parse_git_remote(url: &str) -> Option<(String, u16)> {
+    let (default_port, rest) = if let Some(rest) = url.strip_prefix("ssh://") {
+        (22, rest)
+    } else if let Some(rest) = url.strip_prefix("git://") {
+        (9418, rest)
+    } else if !url.contains("://") {
+        // scp-like syntax: [user@]host:path
+        let (host, _) = url.split_once(':')?;
+        let host = host.rsplit('@').next()?;
+        return Some((host.to_string(), 22));
+    } else {
+        return None;
+    };
+    let authority = rest.split('/').next()?;
+    let authority = authority.rsplit('@').next()?;
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None => Some((authority.to_string(), default_port)),
+    }
 }
 
 /// AOSC OS specific architecture mapping for ppc64
 #[cfg(target_arch = "powerpc64")]
 #[inline]
-fn get_arch_name() -> Option<&'static str> {
+pub fn get_arch_name() -> Option<&'static str> {
     let mut endian: libc::c_int = -1;
     let result;
     unsafe {
@@ -101,42 +282,103 @@ fn get_arch_name() -> Option<&'static str> {
 /// AOSC OS specific architecture mapping table
 #[cfg(not(target_arch = "powerpc64"))]
 #[inline]
-fn get_arch_name() -> Option<&'static str> {
+pub fn get_arch_name() -> Option<&'static str> {
     match ARCH {
         "x86_64" => Some("amd64"),
         "x86" => Some("i486"),
         "powerpc" => Some("powerpc"),
         "aarch64" => Some("arm64"),
         "mips64" => Some("loongson3"),
+        "riscv64" => Some("riscv64"),
+        "loongarch64" => Some("loongarch64"),
         _ => None,
     }
 }
 
-/// Pick the latest buildkit tarball according to the recipe
-pub fn pick_latest_tarball() -> Result<Tarball> {
-    let arch = get_arch_name().ok_or_else(|| anyhow!("Unsupported architecture"))?;
-    let resp = Client::new().get(MANIFEST_URL).send()?;
-    let recipe: Recipe = resp.json()?;
-    let buildkit = recipe
+/// Translate the commonly used architecture names (e.g. `x86_64`, `aarch64`, `loong64`)
+/// into the ones used by AOSC OS, other names are returned as is
+pub fn normalize_arch_name(arch: &str) -> &str {
+    let arch_lower = arch.to_ascii_lowercase();
+    AOSC_ARCHITECTURES
+        .iter()
+        .find(|(name, aliases)| *name == arch_lower || aliases.contains(&arch_lower.as_str()))
+        .map_or(arch, |(name, _)| name)
+}
+
+/// Pick the latest tarball of the variant (BuildKit by default) for the architecture
+/// (the host architecture by default) according to the recipe
+pub fn pick_latest_tarball(arch: Option<&str>, variant: Option<&str>) -> Result<Tarball> {
+    let arch = match arch {
+        Some(arch) => normalize_arch_name(arch),
+        None => get_arch_name().ok_or_else(|| anyhow!("Unsupported architecture"))?,
+    };
+    let variant = variant.unwrap_or(DEFAULT_VARIANT);
+    let recipe = privsep::run_unprivileged("release manifest download", fetch_recipe)?;
+    let names = recipe
+        .variants
+        .iter()
+        .map(|v| v.name.clone())
+        .collect::<Vec<_>>();
+    let variant = recipe
         .variants
         .into_iter()
-        .find(|v| v.name == "BuildKit")
-        .ok_or_else(|| anyhow!("Unable to find buildkit variant"))?;
-    let mut tarballs: Vec<Tarball> = buildkit
+        .find(|v| v.name.eq_ignore_ascii_case(variant))
+        .ok_or_else(|| {
+            anyhow!(
+                "Unable to find the {} variant (available: {})",
+                variant,
+                names.join(", ")
+            )
+        })?;
+    let mut arches = variant
+        .tarballs
+        .iter()
+        .map(|t| t.arch.clone())
+        .collect::<Vec<_>>();
+    arches.sort_unstable();
+    arches.dedup();
+    let mut tarballs: Vec<Tarball> = variant
         .tarballs
         .into_iter()
         .filter(|tarball| tarball.arch == arch)
         .collect();
     if tarballs.is_empty() {
-        return Err(anyhow!("No suitable tarball was found"));
+        return Err(anyhow!(
+            "No suitable tarball was found for {} (available: {})",
+            arch,
+            arches.join(", ")
+        ));
     }
     tarballs.sort_unstable_by_key(|x| x.date.clone());
 
     Ok(tarballs.last().unwrap().to_owned())
 }
 
-/// Clone the Git repository to `root`
+/// Clone the Git repository to `root` (as the invoking user, the tree is owned by root afterwards)
 pub fn download_git(uri: &str, root: &Path) -> Result<()> {
+    let (uid, gid) = match privsep::invoking_user_ids() {
+        Some(ids) => ids,
+        None => return clone_git(uri, root),
+    };
+    // cloned by the user where only the user can write, then handed over to root as a whole
+    let parent = match root.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let staging = privsep::private_temp_dir(parent, uid, gid)?;
+    let tree = staging.path().join("tree");
+    privsep::run_unprivileged("git clone", || clone_git(uri, &tree))?;
+    privsep::chown_tree(&tree, Uid::from_raw(0), Gid::from_raw(0))?;
+    // git only clones into an empty directory anyway
+    if root.exists() {
+        fs::remove_dir(root).map_err(|e| anyhow!("Unable to replace {}: {}", root.display(), e))?;
+    }
+    fs::rename(&tree, root)?;
+
+    Ok(())
+}
+
+fn clone_git(uri: &str, root: &Path) -> Result<()> {
     let mut callbacks = git2::RemoteCallbacks::new();
     let mut co_callback = git2::build::CheckoutBuilder::new();
     let current: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0usize));
@@ -213,3 +455,29 @@ pub fn download_git(uri: &str, root: &Path) -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_normalize_arch_name() {
+    assert_eq!(normalize_arch_name("x86_64"), "amd64");
+    assert_eq!(normalize_arch_name("AArch64"), "arm64");
+    assert_eq!(normalize_arch_name("loong64"), "loongarch64");
+    assert_eq!(normalize_arch_name("riscv64"), "riscv64");
+    assert_eq!(normalize_arch_name("sparc64"), "sparc64");
+}
+
+#[test]
+fn test_parse_git_remote() {
+    assert_eq!(
+        parse_git_remote("git@github.com:AOSC-Dev/aosc-os-abbs.git"),
+        Some(("github.com".to_string(), 22))
+    );
+    assert_eq!(
+        parse_git_remote("ssh://git@example.org:2222/abbs.git"),
+        Some(("example.org".to_string(), 2222))
+    );
+    assert_eq!(
+        parse_git_remote("git://example.org/abbs.git"),
+        Some(("example.org".to_string(), 9418))
+    );
+    assert_eq!(parse_git_remote("file:///srv/abbs.git"), None);
+}
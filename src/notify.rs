@@ -0,0 +1,171 @@
+//! Telling the user that a build is finished (`[notify]` in the config): the terminal bell,
+//! a desktop notification and/or a command
+use anyhow::{anyhow, Result};
+use dbus::{
+    arg::{PropMap, RefArg, Variant},
+    blocking::Connection,
+};
+use std::{io::Write, process::Command, time::Duration};
+
+use crate::{common::format_duration, config, warn};
+
+const NOTIFICATIONS_DEST: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+// urgency levels of the notification specification
+const URGENCY_NORMAL: u8 = 1;
+const URGENCY_CRITICAL: u8 = 2;
+
+/// The result of a build, as told to the user
+#[derive(Debug, Default)]
+pub struct BuildOutcome<'a> {
+    pub success: bool,
+    pub exit_code: i32,
+    pub packages: &'a [String],
+    /// Where the packages were built (an instance, a remote or the architectures of a matrix)
+    pub target: Option<&'a str>,
+    pub failed_package: Option<&'a str>,
+    /// Duration of the build in seconds
+    pub duration: u64,
+}
+
+impl BuildOutcome<'_> {
+    fn title(&self) -> &'static str {
+        if self.success {
+            "Build succeeded"
+        } else {
+            "Build failed"
+        }
+    }
+
+    fn message(&self) -> String {
+        let mut message = self.packages.join(" ");
+        if let Some(target) = self.target {
+            message.push_str(&format!(" in {}", target));
+        }
+        if self.success {
+            message.push_str(&format!(", took {}", format_duration(self.duration)));
+        } else if let Some(package) = self.failed_package {
+            message.push_str(&format!(
+                ", {} failed (exit code {})",
+                package, self.exit_code
+            ));
+        } else {
+            message.push_str(&format!(", exit code {}", self.exit_code));
+        }
+
+        message
+    }
+}
+
+fn send_desktop_notification(outcome: &BuildOutcome) -> Result<()> {
+    let conn = Connection::new_session().map_err(|e| {
+        anyhow!(
+            "{} (the session bus is not reachable when run with sudo, use `command` instead)",
+            e
+        )
+    })?;
+    let proxy = conn.with_proxy(
+        NOTIFICATIONS_DEST,
+        NOTIFICATIONS_PATH,
+        Duration::from_secs(5),
+    );
+    let (urgency, icon) = if outcome.success {
+        (URGENCY_NORMAL, "dialog-information")
+    } else {
+        (URGENCY_CRITICAL, "dialog-error")
+    };
+    let mut hints = PropMap::new();
+    hints.insert(
+        "urgency".to_string(),
+        Variant(Box::new(urgency) as Box<dyn RefArg>),
+    );
+    let _: (u32,) = proxy.method_call(
+        NOTIFICATIONS_DEST,
+        "Notify",
+        (
+            "ciel",
+            0u32,
+            icon,
+            format!("ciel: {}", outcome.title()),
+            outcome.message(),
+            Vec::<String>::new(),
+            hints,
+            -1i32,
+        ),
+    )?;
+
+    Ok(())
+}
+
+fn run_command(command: &str, outcome: &BuildOutcome) -> Result<()> {
+    let status = Command::new("sh")
+        .args(&["-c", command])
+        .env(
+            "CIEL_BUILD_STATUS",
+            if outcome.success {
+                "success"
+            } else {
+                "failure"
+            },
+        )
+        .env("CIEL_BUILD_EXIT_CODE", outcome.exit_code.to_string())
+        .env("CIEL_BUILD_PACKAGES", outcome.packages.join(" "))
+        .env("CIEL_BUILD_TARGET", outcome.target.unwrap_or_default())
+        .env(
+            "CIEL_BUILD_FAILED_PACKAGE",
+            outcome.failed_package.unwrap_or_default(),
+        )
+        .env("CIEL_BUILD_DURATION", outcome.duration.to_string())
+        .env(
+            "CIEL_BUILD_MESSAGE",
+            format!("{}: {}", outcome.title(), outcome.message()),
+        )
+        .env("CIEL_WORKSPACE", std::env::current_dir()?)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("the command exited with {}", status));
+    }
+
+    Ok(())
+}
+
+/// Notify the user of the finished build as configured (the bell is rung on `terminal`),
+/// failures are only warned about
+pub fn build_finished(outcome: &BuildOutcome, terminal: &mut dyn Write) {
+    // outside of a workspace (or with a broken config), only the bell is rung
+    let config = config::read_config().map(|c| c.notify).unwrap_or_default();
+    if config.failure_only && outcome.success {
+        return;
+    }
+    if config.bell {
+        // bell character
+        writeln!(terminal, "\x07").ok();
+    }
+    if config.desktop {
+        if let Err(e) = send_desktop_notification(outcome) {
+            warn!("Unable to show the desktop notification: {}", e);
+        }
+    }
+    if let Some(command) = &config.command {
+        if let Err(e) = run_command(command, outcome) {
+            warn!("Unable to run the notification command: {}", e);
+        }
+    }
+}
+
+#[test]
+fn test_build_message() {
+    let packages = vec!["bash".to_string(), "zsh".to_string()];
+    let outcome = BuildOutcome {
+        success: false,
+        exit_code: 1,
+        packages: &packages,
+        target: Some("main"),
+        failed_package: Some("zsh"),
+        duration: 61,
+    };
+    assert_eq!(
+        outcome.message(),
+        "bash zsh in main, zsh failed (exit code 1)"
+    );
+}
@@ -1,17 +1,39 @@
-use crate::common;
+use crate::{common, debug, progress};
 use anyhow::{anyhow, Result};
 use libmount::{mountinfo::Parser, Overlay};
-use nix::mount::{umount2, MntFlags};
+use nix::{
+    mount::{umount2, MntFlags},
+    sys::{
+        stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags},
+        time::TimeSpec,
+    },
+    unistd::{fchownat, FchownatFlags, Gid, Uid},
+};
+use rayon::prelude::*;
+use std::collections::HashMap;
 use std::fs;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
 use std::{
     ffi::OsStr,
-    io::{BufRead, BufReader},
+    io::{self, BufRead, BufReader},
 };
 
+// directories of the layers, relative to the instance directory
+pub(crate) const LOWER_DIR: &str = "layers/local";
+pub(crate) const UPPER_DIR: &str = "layers/diff";
+pub(crate) const WORK_DIR: &str = "layers/diff.tmp";
+const COMMIT_SPACE_PER_CHANGE: u64 = 4096;
+const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+const REDIRECT_XATTR: &str = "trusted.overlay.redirect";
+
+/// The first copy of every hard-linked file, by the device and inode of the source
+type HardLinks = Mutex<HashMap<(u64, u64), PathBuf>>;
+
 pub trait LayerManager {
     /// Return the name of the layer manager, e.g. "overlay".
     /// This name should be the same as the fs_type listed in the /proc/<>/mountinfo file
@@ -31,10 +53,13 @@ pub trait LayerManager {
     fn mount(&mut self, to: &Path) -> Result<()>;
     /// Return if the filesystem is mounted
     fn is_mounted(&self, target: &Path) -> Result<bool>;
+    /// Return what is mounted on the target, according to the mount table
+    fn mount_state(&self, target: &Path) -> Result<MountState>;
     /// Rollback the filesystem to the distribution state
     fn rollback(&mut self) -> Result<()>;
-    /// Commit the current state of the instance filesystem to the distribution state
-    fn commit(&mut self) -> Result<()>;
+    /// Commit the current state of the instance filesystem to the distribution state,
+    /// the changes are copied (and kept in the instance) if `keep_upper` is set
+    fn commit(&mut self, keep_upper: bool) -> Result<()>;
     /// Un-mount the filesystem
     fn unmount(&mut self, target: &Path) -> Result<()>;
     /// Return the directory where the configuration layer is located
@@ -44,10 +69,24 @@ pub trait LayerManager {
     fn get_base_layer(&mut self) -> Result<PathBuf>;
     /// Set the volatile state of the instance filesystem
     fn set_volatile(&mut self, volatile: bool) -> Result<()>;
+    /// Set the extra mount options of the instance filesystem (e.g. the SELinux context)
+    fn set_mount_options(&mut self, options: Option<String>) -> Result<()>;
     /// Destroy the filesystem of the current instance
     fn destroy(&mut self) -> Result<()>;
 }
 
+/// What is mounted at the mount point of an instance
+#[derive(Debug, PartialEq)]
+pub enum MountState {
+    NotMounted,
+    /// Mounted with the layers of the instance
+    Mounted,
+    /// Several filesystems are mounted on top of each other
+    Stacked(usize),
+    /// Mounted with other layers (e.g. those of another workspace), the upper layer is given
+    Foreign(PathBuf),
+}
+
 struct OverlayFS {
     inst: PathBuf,
     base: PathBuf,
@@ -55,6 +94,7 @@ struct OverlayFS {
     upper: PathBuf,
     work: PathBuf,
     volatile: bool,
+    mount_options: Option<String>,
 }
 
 /// Create a new overlay filesystem on the host system
@@ -64,6 +104,41 @@ pub fn create_new_instance_fs<P: AsRef<Path>>(inst_path: P, inst_name: P) -> Res
     Ok(())
 }
 
+/// Create the filesystem of a new instance with a copy of the layers of the `source` instance
+/// (the files are cloned if the filesystem supports it)
+pub fn clone_instance_fs<P: AsRef<Path>>(inst_path: P, source: P, inst_name: P) -> Result<()> {
+    let source = inst_path.as_ref().join(source.as_ref());
+    let inst = inst_path.as_ref().join(inst_name.as_ref());
+    fs::create_dir(&inst)?;
+    let links = HardLinks::default();
+    let result = [LOWER_DIR, UPPER_DIR].iter().try_for_each(|dir| {
+        let from = source.join(dir);
+        if !from.is_dir() {
+            return Ok(());
+        }
+        let to = inst.join(dir);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // the whiteouts and the opaque directories are kept as-is
+        copy_entry(&from, &to, true, &links)
+    });
+    if result.is_err() {
+        common::remove_workspace_dir(&inst).ok();
+    }
+
+    result
+}
+
+/// Return the layer directories missing from the instance directory
+pub fn get_missing_layer_dirs(inst_dir: &Path) -> Vec<PathBuf> {
+    [LOWER_DIR, UPPER_DIR, WORK_DIR]
+        .iter()
+        .map(|dir| inst_dir.join(dir))
+        .filter(|dir| !dir.is_dir())
+        .collect()
+}
+
 /// OverlayFS operations
 #[derive(Debug)]
 enum Diff {
@@ -77,72 +152,78 @@ enum Diff {
 }
 
 impl OverlayFS {
-    /// Generate a list of changes made in the upper layer
+    /// Generate a list of changes made in the upper layer, in the order of a depth-first walk
+    /// (the directories come before their content)
     fn diff(&self) -> Result<Vec<Diff>> {
-        let mut mods: Vec<Diff> = Vec::new();
-        let mut processed_dirs: Vec<PathBuf> = Vec::new();
+        self.diff_dir(&self.upper, Path::new(""))
+    }
 
-        for entry in walkdir::WalkDir::new(&self.upper).into_iter().skip(1) {
-            // SKip the root
-            let path: PathBuf = entry?.path().to_path_buf();
-            let rel_path = path.strip_prefix(&self.upper)?.to_path_buf();
-            let lower_path = self.lower.join(&rel_path).to_path_buf();
+    /// Generate the changes under the directory `path` (`rel_path` in the layers),
+    /// the entries are examined in parallel
+    fn diff_dir(&self, path: &Path, rel_path: &Path) -> Result<Vec<Diff>> {
+        let entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+        let changes = entries
+            .into_par_iter()
+            .map(|entry| self.diff_entry(&entry, rel_path))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(changes.into_iter().flatten().collect())
+    }
 
-            if has_prefix(&rel_path, &processed_dirs) {
-                continue; // We already dealt with it
+    /// Generate the changes of the entry (and everything under it if it is a directory)
+    fn diff_entry(&self, entry: &fs::DirEntry, parent: &Path) -> Result<Vec<Diff>> {
+        let rel_path = parent.join(entry.file_name());
+        // the file type comes from the directory listing, no need to stat every file
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            // Just move the symlink
+            return Ok(vec![Diff::Symlink(rel_path)]);
+        }
+        if !file_type.is_dir() {
+            // a whiteout is a character device with the device number 0/0
+            if file_type.is_char_device() && entry.metadata()?.rdev() == 0 {
+                return Ok(vec![Diff::WhiteoutFile(rel_path)]);
             }
-            let meta = fs::symlink_metadata(&path)?;
-            let file_type = meta.file_type();
-
-            if file_type.is_symlink() {
-                // Just move the symlink
-                mods.push(Diff::Symlink(rel_path.clone()));
-            } else if meta.is_dir() {
-                // Deal with dirs
-                let opaque = xattr::get(&path, "trusted.overlay.opaque")?;
-                let redirect = xattr::get(&path, "trusted.overlay.redirect")?;
-                // let metacopy = xattr::get(&path, "trusted.overlay.metacopy")?;
-
-                if let Some(text) = opaque {
-                    // the new dir (completely) replace the old one
-                    if text == b"y" {
-                        // Delete corresponding dir
-                        mods.push(Diff::OverrideDir(rel_path.clone()));
-                        processed_dirs.push(rel_path.clone());
-                    }
-                } else if let Some(from_utf8) = redirect {
-                    // Renamed
-                    let mut from_rel_path = PathBuf::from(OsStr::from_bytes(&from_utf8));
-                    if from_rel_path.is_absolute() {
-                        // abs path from root of OverlayFS
-                        from_rel_path = from_rel_path.strip_prefix("/")?.to_path_buf();
-                    } else {
-                        // rel path, same parent dir as the origin
-                        let mut from_path = path.clone();
-                        from_path.pop();
-                        from_path.push(PathBuf::from(&from_rel_path));
-                        from_rel_path = from_path.strip_prefix(&self.upper)?.to_path_buf();
-                    }
-                    mods.push(Diff::RenamedDir(from_rel_path, rel_path));
-                } else if !lower_path.is_dir() {
-                    // New dir
-                    mods.push(Diff::NewDir(rel_path.clone()));
-                } else {
-                    // Modified
-                    mods.push(Diff::ModifiedDir(rel_path.clone()));
-                }
-            } else {
-                // Deal with files
-                if file_type.is_char_device() && meta.rdev() == 0 {
-                    // Whiteout file!
-                    mods.push(Diff::WhiteoutFile(rel_path.clone()));
-                } else {
-                    mods.push(Diff::File(rel_path.clone()));
-                }
+            // Simple modified or new file
+            return Ok(vec![Diff::File(rel_path)]);
+        }
+        let path = entry.path();
+        let mut opaque = None;
+        let mut redirect = None;
+        // most directories have none of the attributes, list them in one go first
+        for name in xattr::list(&path)? {
+            if name == OPAQUE_XATTR {
+                opaque = xattr::get(&path, OPAQUE_XATTR)?;
+            } else if name == REDIRECT_XATTR {
+                redirect = xattr::get(&path, REDIRECT_XATTR)?;
             }
         }
-
-        Ok(mods)
+        let change = if let Some(text) = opaque {
+            if text == b"y" {
+                // the new dir (completely) replace the old one, along with its content
+                return Ok(vec![Diff::OverrideDir(rel_path)]);
+            }
+            None
+        } else if let Some(from) = redirect {
+            // Renamed
+            let from = Path::new(OsStr::from_bytes(&from));
+            let from_rel_path = match from.strip_prefix("/") {
+                // abs path from root of OverlayFS
+                Ok(from) => from.to_path_buf(),
+                // rel path, same parent dir as the origin
+                Err(_) => parent.join(from),
+            };
+            Some(Diff::RenamedDir(from_rel_path, rel_path.clone()))
+        } else if !self.lower.join(&rel_path).is_dir() {
+            Some(Diff::NewDir(rel_path.clone()))
+        } else {
+            // Modified
+            Some(Diff::ModifiedDir(rel_path.clone()))
+        };
+        let mut changes = change.into_iter().collect::<Vec<_>>();
+        changes.extend(self.diff_dir(&path, &rel_path)?);
+
+        Ok(changes)
     }
 }
 
@@ -171,10 +252,11 @@ impl LayerManager for OverlayFS {
         Ok(Box::new(OverlayFS {
             inst: inst.to_owned(),
             base: dist.to_owned(),
-            lower: inst.join("layers/local"),
-            upper: inst.join("layers/diff"),
-            work: inst.join("layers/diff.tmp"),
+            lower: inst.join(LOWER_DIR),
+            upper: inst.join(UPPER_DIR),
+            work: inst.join(WORK_DIR),
             volatile: false,
+            mount_options: None,
         }))
     }
     fn mount(&mut self, to: &Path) -> Result<()> {
@@ -192,8 +274,15 @@ impl LayerManager for OverlayFS {
         fs::create_dir_all(&self.lower)?;
         // check overlay usability
         load_overlayfs_support()?;
+        let mut options = Vec::new();
         if self.volatile {
-            overlay.set_options(b"volatile".to_vec());
+            options.push("volatile");
+        }
+        if let Some(extra) = &self.mount_options {
+            options.push(extra);
+        }
+        if !options.is_empty() {
+            overlay.set_options(options.join(",").into_bytes());
         }
         let dirty_flag = self.work.join("work/incompat");
         if dirty_flag.exists() {
@@ -202,6 +291,13 @@ impl LayerManager for OverlayFS {
             ));
         }
         // let's mount them
+        debug!(
+            "Mounting overlay on {} (upper: {}, lower: {}, {})",
+            to.display(),
+            self.upper.display(),
+            self.lower.display(),
+            self.base.display()
+        );
         overlay.mount().map_err(|e| anyhow!("{}", e.to_string()))?;
 
         Ok(())
@@ -212,43 +308,90 @@ impl LayerManager for OverlayFS {
         is_mounted(target, OsStr::new("overlay"))
     }
 
+    fn mount_state(&self, target: &Path) -> Result<MountState> {
+        let mountinfo_content: Vec<u8> = fs::read("/proc/self/mountinfo")?;
+        let mut upper_dirs = Vec::new();
+        for mount in Parser::new(&mountinfo_content) {
+            let mount = mount?;
+            if mount.mount_point == target && mount.fstype == OsStr::new("overlay") {
+                upper_dirs.push(get_upper_dir(mount.super_options.as_bytes()));
+            }
+        }
+        let upper_dir = match upper_dirs.len() {
+            0 => return Ok(MountState::NotMounted),
+            1 => upper_dirs.pop().unwrap(),
+            n => return Ok(MountState::Stacked(n)),
+        };
+        // the kernel shows the absolute path with the symlinks resolved
+        let expected = fs::canonicalize(&self.upper)?;
+        match upper_dir {
+            Some(upper_dir) if upper_dir == expected => Ok(MountState::Mounted),
+            Some(upper_dir) => Ok(MountState::Foreign(upper_dir)),
+            None => Ok(MountState::Foreign(PathBuf::new())),
+        }
+    }
+
     fn rollback(&mut self) -> Result<()> {
-        fs::remove_dir_all(&self.upper)?;
-        fs::remove_dir_all(&self.work)?;
+        common::remove_workspace_dir(&self.upper)?;
+        common::remove_workspace_dir(&self.work)?;
         fs::create_dir(&self.upper)?;
         fs::create_dir(&self.work)?;
 
         Ok(())
     }
 
-    fn commit(&mut self) -> Result<()> {
+    fn commit(&mut self, keep_upper: bool) -> Result<()> {
         if self.volatile {
             // for safety reasons
             nix::unistd::sync();
         }
+        let spinner = progress::spinner("Scanning for changes...");
         let mods = self.diff()?;
+        spinner.finish_and_clear();
+        // moving the files into the base layer does not need more space for the data,
+        // but the directories may grow (estimated as one block for every change)
+        let mut required = mods.len() as u64 * COMMIT_SPACE_PER_CHANGE;
+        let cross_device = fs::metadata(&self.upper)?.dev() != fs::metadata(&self.base)?.dev();
+        if keep_upper || cross_device {
+            // the data is copied, unless the filesystem can clone it
+            required += common::get_dir_size(&self.upper);
+        }
+        common::ensure_free_space(&self.base, required, "committing the instance")?;
+        let action = CommitAction {
+            overlay: self,
+            keep_upper,
+            links: HardLinks::default(),
+        };
+        let progress_bar = progress::count_bar(mods.len() as u64, "Committing changes...");
         // FIXME: use drain_filter in the future
         // first pass to execute all the deletion actions
         for i in mods.iter() {
             match i {
-                Diff::WhiteoutFile(_) => overlay_exec_action(i, self)?,
+                Diff::WhiteoutFile(_) => action.exec(i)?,
                 _ => continue,
             }
+            progress_bar.inc(1);
         }
         // second pass for everything else
         for i in mods.iter() {
             match i {
                 Diff::WhiteoutFile(_) => continue,
-                _ => overlay_exec_action(i, self)?,
+                _ => action.exec(i)?,
             }
+            progress_bar.inc(1);
+        }
+        if !keep_upper {
+            progress_bar.set_message("Cleaning up upper layer...");
+            // clear all the remnant items in the upper layer
+            self.rollback()?;
         }
-        // clear all the remnant items in the upper layer
-        self.rollback()?;
+        progress_bar.finish_and_clear();
 
         Ok(())
     }
 
     fn unmount(&mut self, target: &Path) -> Result<()> {
+        debug!("Un-mounting {}", target.display());
         umount2(target, MntFlags::MNT_DETACH)?;
 
         Ok(())
@@ -263,7 +406,7 @@ impl LayerManager for OverlayFS {
     }
 
     fn destroy(&mut self) -> Result<()> {
-        fs::remove_dir_all(&self.inst)?;
+        common::remove_workspace_dir(&self.inst)?;
 
         Ok(())
     }
@@ -273,6 +416,12 @@ impl LayerManager for OverlayFS {
 
         Ok(())
     }
+
+    fn set_mount_options(&mut self, options: Option<String>) -> Result<()> {
+        self.mount_options = options;
+
+        Ok(())
+    }
 }
 
 /// is_mounted: check if a path is a mountpoint with corresponding fs_type
@@ -290,17 +439,120 @@ pub(crate) fn is_mounted(mountpoint: &Path, fs_type: &OsStr) -> Result<bool> {
     Ok(false)
 }
 
-/// A convenience function for getting a overlayfs type LayerManager
-pub(crate) fn get_overlayfs_manager(inst_name: &str) -> Result<Box<dyn LayerManager>> {
-    OverlayFS::from_inst_dir(common::CIEL_DIST_DIR, common::CIEL_INST_DIR, inst_name)
+/// Mount an overlay of `lower` on `target` (with the extra mount options), the changes go to `upper`
+pub(crate) fn mount_overlay(
+    lower: &Path,
+    upper: &Path,
+    work: &Path,
+    target: &Path,
+    options: Option<String>,
+) -> Result<()> {
+    for dir in [upper, work, target] {
+        fs::create_dir_all(dir)?;
+    }
+    debug!("Mounting {} on {}", lower.display(), target.display());
+    let mut overlay = Overlay::writable(std::iter::once(lower), upper, work, target);
+    if let Some(options) = options {
+        overlay.set_options(options.into_bytes());
+    }
+    overlay.mount().map_err(|e| anyhow!("{}", e.to_string()))?;
+
+    Ok(())
 }
 
-/// Check if path have all specified prefixes (with order)
-#[inline]
-fn has_prefix(path: &Path, prefixes: &[PathBuf]) -> bool {
-    prefixes
+/// Get the upper layer from the options of an overlay mount in the mount table
+fn get_upper_dir(super_options: &[u8]) -> Option<PathBuf> {
+    let value = super_options
+        .split(|c| *c == b',')
+        .find_map(|option| option.strip_prefix(b"upperdir="))?;
+    // the special characters (including the commas) are escaped as \ooo
+    let mut path = Vec::with_capacity(value.len());
+    let mut i = 0;
+    while i < value.len() {
+        let octal = value
+            .get(i + 1..i + 4)
+            .filter(|_| value[i] == b'\\')
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match octal {
+            Some(c) => {
+                path.push(c);
+                i += 4;
+            }
+            None => {
+                path.push(value[i]);
+                i += 1;
+            }
+        }
+    }
+
+    Some(PathBuf::from(OsStr::from_bytes(&path)))
+}
+
+/// A filesystem left mounted under the workspace (e.g. by a crashed run)
+#[derive(Debug)]
+pub(crate) struct StaleMount {
+    pub mount_point: PathBuf,
+    /// Name of the (possibly removed) instance the mount belongs to
+    pub instance: String,
+    /// Whether the instance of the mount no longer exists
+    pub orphaned: bool,
+}
+
+/// Find the stale mounts under the workspace `root`, sorted in the order they should be
+/// detached (nested mounts first).
+/// A mount is stale if it is an overlay mount of a removed instance, or if it is nested inside
+/// an instance mount point (ciel never mounts anything there in the host namespace).
+pub(crate) fn find_stale_mounts(root: &Path, instances: &[String]) -> Result<Vec<StaleMount>> {
+    let mountinfo_content: Vec<u8> = fs::read("/proc/self/mountinfo")?;
+    let mut mounts = Vec::new();
+    for mount in Parser::new(&mountinfo_content) {
+        let mount = mount?;
+        let mount_point = PathBuf::from(&*mount.mount_point);
+        let relative = match mount_point.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        let instance = match relative.components().next() {
+            Some(Component::Normal(name)) => name.to_string_lossy().to_string(),
+            _ => continue,
+        };
+        let depth = relative.components().count();
+        let is_overlay = mount.fstype == OsStr::new(OverlayFS::name().as_str());
+        mounts.push((mount_point, instance, depth, is_overlay));
+    }
+    let orphans: Vec<String> = mounts
         .iter()
-        .any(|prefix| path.strip_prefix(prefix).is_ok())
+        .filter(|(_, name, depth, is_overlay)| {
+            *depth == 1 && *is_overlay && !instances.contains(name)
+        })
+        .map(|(_, name, _, _)| name.clone())
+        .collect();
+    let mut stale: Vec<(usize, StaleMount)> = mounts
+        .into_iter()
+        .filter(|(_, name, depth, _)| {
+            orphans.contains(name) || (*depth > 1 && instances.contains(name))
+        })
+        .map(|(mount_point, instance, depth, _)| {
+            let orphaned = orphans.contains(&instance);
+            (
+                depth,
+                StaleMount {
+                    mount_point,
+                    instance,
+                    orphaned,
+                },
+            )
+        })
+        .collect();
+    // detach the deepest mounts first, otherwise the parent mounts are busy
+    stale.sort_by_key(|(depth, _)| std::cmp::Reverse(*depth));
+
+    Ok(stale.into_iter().map(|(_, mount)| mount).collect())
+}
+
+/// A convenience function for getting a overlayfs type LayerManager
+pub(crate) fn get_overlayfs_manager(inst_name: &str) -> Result<Box<dyn LayerManager>> {
+    OverlayFS::from_inst_dir(common::CIEL_DIST_DIR, common::CIEL_INST_DIR, inst_name)
 }
 
 fn load_overlayfs_support() -> Result<()> {
@@ -344,63 +596,251 @@ fn sync_permission(from: &Path, to: &Path) -> Result<()> {
     Ok(())
 }
 
-#[inline]
-fn overlay_exec_action(action: &Diff, overlay: &OverlayFS) -> Result<()> {
-    match action {
-        Diff::Symlink(path) => {
-            let upper_path = overlay.upper.join(&path);
-            let lower_path = overlay.base.join(&path);
-            // Replace lower dir with upper
-            fs::rename(&upper_path, &lower_path)?;
+// FICLONE from linux/fs.h, share the data of the file given as the argument
+nix::ioctl_write_int!(ioctl_ficlone, 0x94, 9);
+
+/// Copy the content of the regular file, cloning it if the filesystem supports it
+fn copy_file_data(from: &Path, to: &Path, mode: u32) -> Result<()> {
+    let mut source = fs::File::open(from)?;
+    let mut destination = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(to)?;
+    // only fails if the files are on different filesystems or cloning is not supported
+    if unsafe { ioctl_ficlone(destination.as_raw_fd(), source.as_raw_fd() as libc::c_ulong) }
+        .is_ok()
+    {
+        return Ok(());
+    }
+    // this uses copy_file_range(2) if possible, which is still done by the filesystem
+    io::copy(&mut source, &mut destination)?;
+
+    Ok(())
+}
+
+/// Copy the file, directory (recursively), symlink or special file with its attributes,
+/// the overlay attributes are only copied if `overlay_xattrs` is set.
+/// The files seen in `links` before are hard-linked to their first copy
+fn copy_entry(from: &Path, to: &Path, overlay_xattrs: bool, links: &HardLinks) -> Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let file_type = meta.file_type();
+    if file_type.is_dir() {
+        fs::create_dir(to)?;
+        fs::read_dir(from)?
+            .collect::<io::Result<Vec<_>>>()?
+            .into_par_iter()
+            .try_for_each(|entry| {
+                copy_entry(
+                    &entry.path(),
+                    &to.join(entry.file_name()),
+                    overlay_xattrs,
+                    links,
+                )
+            })?;
+    } else if file_type.is_symlink() {
+        symlink(fs::read_link(from)?, to)?;
+    } else if file_type.is_file() {
+        // looked up even without other links, as the links copied before may have been
+        // moved out of the upper layer already
+        let mut links = links.lock().unwrap();
+        let key = (meta.dev(), meta.ino());
+        if let Some(first) = links.get(&key) {
+            // the attributes belong to the inode, which already has them
+            fs::hard_link(first, to)?;
+            return Ok(());
         }
-        Diff::OverrideDir(path) => {
-            let upper_path = overlay.upper.join(&path);
-            let lower_path = overlay.base.join(&path);
-            // Replace lower dir with upper
-            if lower_path.is_dir() {
-                // If exists and was not removed already, then remove it
-                fs::remove_dir_all(&lower_path)?;
-            }
-            fs::rename(&upper_path, &lower_path)?;
+        if meta.nlink() > 1 {
+            // held during the copy, so that the other links never see a missing file
+            copy_file_data(from, to, meta.mode() & 0o7777)?;
+            links.insert(key, to.to_owned());
+        } else {
+            drop(links);
+            copy_file_data(from, to, meta.mode() & 0o7777)?;
         }
-        Diff::RenamedDir(from, to) => {
-            // TODO: Implement copy down
-            // Such dir will include diff files, so this
-            // section need more testing
-            let from_path = overlay.base.join(&from);
-            let to_path = overlay.base.join(&to);
-            // TODO: Merge files from upper to lower
-            // Replace lower dir with upper
-            fs::rename(&from_path, &to_path)?;
+    } else {
+        // devices, FIFOs and sockets
+        mknod(
+            to,
+            SFlag::from_bits_truncate(meta.mode() & libc::S_IFMT),
+            Mode::from_bits_truncate(meta.mode() & 0o7777),
+            meta.rdev(),
+        )?;
+    }
+    fchownat(
+        None,
+        to,
+        Some(Uid::from_raw(meta.uid())),
+        Some(Gid::from_raw(meta.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+    // e.g. security.capability, the overlay attributes only make sense in the upper layer
+    for name in xattr::list(from)? {
+        if !overlay_xattrs && name.as_bytes().starts_with(b"trusted.overlay.") {
+            continue;
         }
-        Diff::NewDir(path) => {
-            let lower_path = overlay.base.join(&path);
-            // Construct lower path
-            fs::create_dir_all(&lower_path)?;
+        if let Some(value) = xattr::get(from, &name)? {
+            xattr::set(to, &name, &value)?;
         }
-        Diff::ModifiedDir(path) => {
-            // Do nothing, just sync permission
-            let upper_path = overlay.upper.join(&path);
-            let lower_path = overlay.base.join(&path);
-            sync_permission(&upper_path, &lower_path)?;
+    }
+    if !file_type.is_symlink() {
+        // changing the owner clears the setuid and setgid bits
+        fs::set_permissions(to, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+    }
+    utimensat(
+        None,
+        to,
+        &TimeSpec::from(libc::timespec {
+            tv_sec: meta.atime(),
+            tv_nsec: meta.atime_nsec(),
+        }),
+        &TimeSpec::from(libc::timespec {
+            tv_sec: meta.mtime(),
+            tv_nsec: meta.mtime_nsec(),
+        }),
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+
+    Ok(())
+}
+
+/// Applies the changes to the base layer
+struct CommitAction<'a> {
+    overlay: &'a OverlayFS,
+    /// Copy the changes instead of moving them out of the upper layer
+    keep_upper: bool,
+    /// The hard-linked files copied so far, shared by all the changes
+    links: HardLinks,
+}
+
+impl CommitAction<'_> {
+    /// Move (or copy) the entry in the upper layer to the base layer, replacing what is there.
+    /// It is copied if the layers are on different filesystems.
+    fn transfer(&self, path: &Path) -> Result<()> {
+        let upper_path = self.overlay.upper.join(path);
+        let lower_path = self.overlay.base.join(path);
+        if !self.keep_upper {
+            match fs::rename(&upper_path, &lower_path) {
+                Err(e) if e.raw_os_error() == Some(libc::EXDEV) => (),
+                result => return Ok(result?),
+            }
+        }
+        // like rename(2), a file replaces the one in the way, but not a directory
+        match fs::symlink_metadata(&lower_path) {
+            Ok(meta) if !meta.is_dir() => fs::remove_file(&lower_path)?,
+            _ => (),
         }
-        Diff::WhiteoutFile(path) => {
-            let lower_path = overlay.base.join(&path);
-            if lower_path.is_dir() {
-                fs::remove_dir_all(&lower_path)?;
-            } else if lower_path.is_file() {
-                fs::remove_file(&lower_path)?;
+        copy_entry(&upper_path, &lower_path, false, &self.links)?;
+        if !self.keep_upper {
+            if fs::symlink_metadata(&upper_path)?.is_dir() {
+                common::remove_dir_within(&self.overlay.upper, &upper_path)?;
+            } else {
+                fs::remove_file(&upper_path)?;
             }
-            // remove the whiteout in the upper layer
-            fs::remove_file(overlay.upper.join(path))?;
         }
-        Diff::File(path) => {
-            let upper_path = overlay.upper.join(&path);
-            let lower_path = overlay.base.join(&path);
-            // Move upper file to overwrite the lower
-            fs::rename(&upper_path, &lower_path)?;
+
+        Ok(())
+    }
+
+    fn exec(&self, action: &Diff) -> Result<()> {
+        let overlay = self.overlay;
+        match action {
+            Diff::Symlink(path) => {
+                // Replace lower dir with upper
+                self.transfer(path)?;
+            }
+            Diff::OverrideDir(path) => {
+                let lower_path = overlay.base.join(&path);
+                // Replace lower dir with upper
+                if fs::symlink_metadata(&lower_path).map_or(false, |m| m.is_dir()) {
+                    // If exists and was not removed already, then remove it
+                    common::remove_dir_within(&overlay.base, &lower_path)?;
+                }
+                self.transfer(path)?;
+            }
+            Diff::RenamedDir(from, to) => {
+                // TODO: Implement copy down
+                // Such dir will include diff files, so this
+                // section need more testing
+                let from_path = overlay.base.join(&from);
+                let to_path = overlay.base.join(&to);
+                // TODO: Merge files from upper to lower
+                // Replace lower dir with upper
+                fs::rename(&from_path, &to_path)?;
+            }
+            Diff::NewDir(path) => {
+                let lower_path = overlay.base.join(&path);
+                // Construct lower path
+                fs::create_dir_all(&lower_path)?;
+            }
+            Diff::ModifiedDir(path) => {
+                // Do nothing, just sync permission
+                let upper_path = overlay.upper.join(&path);
+                let lower_path = overlay.base.join(&path);
+                sync_permission(&upper_path, &lower_path)?;
+            }
+            Diff::WhiteoutFile(path) => {
+                let lower_path = overlay.base.join(&path);
+                match fs::symlink_metadata(&lower_path) {
+                    Ok(meta) if meta.is_dir() => {
+                        common::remove_dir_within(&overlay.base, &lower_path)?
+                    }
+                    // a symlink is removed itself, not where it points to
+                    Ok(_) => fs::remove_file(&lower_path)?,
+                    Err(_) => (),
+                }
+                if !self.keep_upper {
+                    // remove the whiteout in the upper layer
+                    fs::remove_file(overlay.upper.join(path))?;
+                }
+            }
+            Diff::File(path) => {
+                // Move upper file to overwrite the lower
+                self.transfer(path)?;
+            }
         }
+
+        Ok(())
     }
+}
 
-    Ok(())
+#[test]
+fn test_get_upper_dir() {
+    assert_eq!(
+        get_upper_dir(b"rw,lowerdir=/a:/b,upperdir=/ws/my\\054dir/diff,workdir=/ws/w"),
+        Some(PathBuf::from("/ws/my,dir/diff"))
+    );
+    assert_eq!(get_upper_dir(b"rw,lowerdir=/a:/b"), None);
+}
+
+#[test]
+fn test_commit_hard_links_across_filesystems() {
+    // the upper layer on tmpfs, the base layer on the disk
+    let upper = tempfile::tempdir_in("/dev/shm").unwrap();
+    let base = tempfile::tempdir_in(env!("CARGO_MANIFEST_DIR")).unwrap();
+    if fs::metadata(upper.path()).unwrap().dev() == fs::metadata(base.path()).unwrap().dev() {
+        return;
+    }
+    fs::write(upper.path().join("a"), b"test").unwrap();
+    fs::hard_link(upper.path().join("a"), upper.path().join("b")).unwrap();
+    let overlay = OverlayFS {
+        inst: PathBuf::new(),
+        base: base.path().to_owned(),
+        lower: PathBuf::new(),
+        upper: upper.path().to_owned(),
+        work: PathBuf::new(),
+        volatile: false,
+        mount_options: None,
+    };
+    let action = CommitAction {
+        overlay: &overlay,
+        keep_upper: false,
+        links: HardLinks::default(),
+    };
+    action.exec(&Diff::File(PathBuf::from("a"))).unwrap();
+    action.exec(&Diff::File(PathBuf::from("b"))).unwrap();
+    let a = fs::metadata(base.path().join("a")).unwrap();
+    let b = fs::metadata(base.path().join("b")).unwrap();
+    assert_eq!(a.ino(), b.ino());
+    assert!(!upper.path().join("b").exists());
 }
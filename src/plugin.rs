@@ -0,0 +1,327 @@
+//! Running the plugins (`ciel-<name>` in `libexec/ciel-plugin`)
+//!
+//! A plugin may ship a manifest (`ciel-<name>.toml`) to describe itself:
+//!
+//! ```toml
+//! about = "Release the packages in OUTPUT"
+//! long-about = "..."
+//! # query ciel over stdin/stdout (see below)
+//! json-rpc = true
+//!
+//! [[args]]
+//! name = "TOPIC"
+//! help = "Topic to release"
+//! required = true
+//!
+//! [[args]]
+//! name = "dry-run"
+//! long = "dry-run"
+//! short = "n"
+//! help = "Only show what would be released"
+//! ```
+//!
+//! With the arguments declared, `ciel` validates the command line and shows the usage of the
+//! plugin. The plugin receives the options first (as `--long` or `-s`), then the positional
+//! arguments.
+//!
+//! The plugins run in the workspace with `CIEL_WORKSPACE` and `CIEL_VERSION` set. With
+//! `json-rpc`, `CIEL_PLUGIN_PROTOCOL` is set to `jsonrpc` and the plugin can send JSON-RPC 2.0
+//! requests to ciel, one per line on its stdout, e.g.
+//! `{"jsonrpc": "2.0", "method": "instance", "params": {"name": "main"}, "id": 1}`,
+//! the responses are written to its stdin, one per line. The methods are:
+//!
+//! - `list_instances`, `instance` (`name`), `status`, `workspace`: same as the JSON output of
+//!   `ciel list`, `ciel status` and the configuration of the workspace
+//! - `builds` (`package`), `build_log` (`package`, `previous`): the recorded builds and their logs
+//! - `repo`: the packages in the output directory
+//! - `log` (`level`: `info`, `warning` or `error`, `message`): show a message the way ciel does
+//!
+//! The other lines on its stdout (not starting with `{`) are printed as-is.
+//!
+//! If `/etc/ciel/plugins.sha256` exists, only the plugins listed there by their full paths (in
+//! the format of `sha256sum`, e.g. `sha256sum /usr/libexec/ciel-plugin/ciel-* >
+//! /etc/ciel/plugins.sha256`) are run, and only if they are unchanged since. The plugins are
+//! then run through `/proc/self/fd`, the same file as verified.
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd, process::CommandExt},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::{
+    cli::PluginManifest,
+    debug,
+    logging::{self, Level},
+    rpc::{self, parse_params, Handler, RpcError, INVALID_PARAMS},
+};
+
+#[derive(Debug, Deserialize)]
+struct LogParams {
+    level: String,
+    message: String,
+}
+
+struct PluginHost<'a> {
+    name: &'a str,
+}
+
+impl Handler for PluginHost<'_> {
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        if method != "log" {
+            return rpc::query(method, params);
+        }
+        let params: LogParams = parse_params(params)?;
+        let level = match params.level.as_str() {
+            "info" => Level::Info,
+            "warning" => Level::Warning,
+            "error" => Level::Error,
+            _ => {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!("Unknown level: {}", params.level),
+                ))
+            }
+        };
+        logging::log(
+            level,
+            module_path!(),
+            &format!("{}: {}", self.name, params.message),
+        );
+
+        Ok(Value::Null)
+    }
+}
+
+/// Rebuild the command line of the plugin from the arguments declared in the manifest
+pub fn get_plugin_args(manifest: &PluginManifest, matches: &ArgMatches) -> Vec<String> {
+    let declared = match &manifest.args {
+        Some(args) => args,
+        None => {
+            return matches
+                .values_of("COMMANDS")
+                .map(|v| v.map(String::from).collect())
+                .unwrap_or_default()
+        }
+    };
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    for arg in declared.iter() {
+        if arg.is_positional() {
+            if let Some(values) = matches.values_of(&arg.name) {
+                positionals.extend(values.map(String::from));
+            }
+            continue;
+        }
+        let flag = match (&arg.long, arg.short) {
+            (Some(long), _) => format!("--{}", long),
+            (None, Some(short)) => format!("-{}", short),
+            (None, None) => unreachable!(),
+        };
+        if !arg.takes_value {
+            for _ in 0..matches.occurrences_of(&arg.name) {
+                options.push(flag.clone());
+            }
+        } else if let Some(values) = matches.values_of(&arg.name) {
+            for value in values {
+                options.push(flag.clone());
+                options.push(value.to_string());
+            }
+        }
+    }
+    options.extend(positionals);
+
+    options
+}
+
+/// The hashes of the plugins allowed to run, see the module documentation
+const PLUGIN_ALLOWLIST: &str = "/etc/ciel/plugins.sha256";
+
+/// Check if the allowlist (the output of `sha256sum`) has the plugin (by its full path)
+/// with the hash
+fn is_plugin_allowed(allowlist: &str, plugin: &Path, hash: &str) -> bool {
+    allowlist.lines().any(|line| {
+        let (listed_hash, path) = match line.trim().split_once(char::is_whitespace) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        // `*` marks the binary mode of sha256sum
+        let path = path.trim_start().trim_start_matches('*');
+
+        listed_hash.eq_ignore_ascii_case(hash) && Path::new(path) == plugin
+    })
+}
+
+/// Refuse to run the plugin if it is not in the allowlist (when there is one), returning the
+/// plugin opened for verifying it, which is what should be run
+fn verify_plugin(plugin: &Path, name: &str) -> Result<Option<fs::File>> {
+    let meta = match fs::metadata(PLUGIN_ALLOWLIST) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // anyone able to modify the allowlist could allow any plugin
+    if meta.uid() != 0 || meta.mode() & 0o022 != 0 {
+        return Err(anyhow!(
+            "{} must be owned by root and not writable by others, refusing to run {}.",
+            PLUGIN_ALLOWLIST,
+            name
+        ));
+    }
+    // listed by the path of the file itself, not of a symlink to it
+    let path = fs::canonicalize(plugin)?;
+    let mut file = fs::File::open(&path)?;
+    // the directories may have been replaced in the meantime
+    if fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))? != path {
+        return Err(anyhow!("{} has moved, refusing to run it.", name));
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    let hash = format!("{:x}", hasher.finalize());
+    if !is_plugin_allowed(&fs::read_to_string(PLUGIN_ALLOWLIST)?, &path, &hash) {
+        return Err(anyhow!(
+            "{} (SHA-256: {}) is not allowed by {}, refusing to run it.",
+            path.display(),
+            hash,
+            PLUGIN_ALLOWLIST
+        ));
+    }
+    debug!("{} is allowed by {}", path.display(), PLUGIN_ALLOWLIST);
+
+    Ok(Some(file))
+}
+
+/// Run the plugin with the arguments, returning its exit code. The output of the plugins speaking
+/// JSON-RPC (other than the requests) is copied to `output`.
+pub fn run_plugin(
+    plugin: &Path,
+    args: &[String],
+    json_rpc: bool,
+    output: &mut dyn Write,
+) -> Result<i32> {
+    let name = plugin
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let verified = verify_plugin(plugin, &name)?;
+    let mut command = match &verified {
+        Some(file) => {
+            // run what was verified, even if the file at the path is replaced
+            let fd = file.as_raw_fd();
+            let mut command = Command::new(format!("/proc/self/fd/{}", fd));
+            command.arg0(plugin);
+            unsafe {
+                // kept open for the interpreter of the scripts, which reads it by the path
+                command.pre_exec(move || {
+                    fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                        .map(|_| ())
+                        .map_err(|e| io::Error::from_raw_os_error(e as i32))
+                });
+            }
+            command
+        }
+        None => Command::new(plugin),
+    };
+    command
+        .args(args)
+        .env("CIEL_WORKSPACE", std::env::current_dir()?)
+        .env("CIEL_VERSION", env!("CARGO_PKG_VERSION"));
+    if !json_rpc {
+        let status = command.status()?;
+        return status
+            .code()
+            .ok_or_else(|| anyhow!("{} was killed: {}", name, status));
+    }
+    let mut child = command
+        .env("CIEL_PLUGIN_PROTOCOL", "jsonrpc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut requests = BufReader::new(child.stdout.take().unwrap());
+    let mut responses = child.stdin.take().unwrap();
+    let host = PluginHost { name: &name };
+    let mut line = String::new();
+    while requests.read_line(&mut line)? > 0 {
+        if line.starts_with('{') {
+            if let Some(response) = rpc::handle_line(&host, line.trim_end()) {
+                // the plugin may exit without reading the response
+                writeln!(responses, "{}", response).ok();
+            }
+        } else {
+            output.write_all(line.as_bytes())?;
+        }
+        line.clear();
+    }
+    drop(responses);
+    let status = child.wait()?;
+
+    status
+        .code()
+        .ok_or_else(|| anyhow!("{} was killed: {}", name, status))
+}
+
+#[test]
+fn test_get_plugin_args() {
+    let manifest: PluginManifest = toml::from_str(
+        r#"
+        [[args]]
+        name = "TOPIC"
+        required = true
+        multiple = true
+
+        [[args]]
+        name = "dry-run"
+        long = "dry-run"
+        short = "n"
+
+        [[args]]
+        name = "arch"
+        short = "a"
+        takes-value = true
+        "#,
+    )
+    .unwrap();
+    let matches = crate::cli::plugin_command("ciel-release", &manifest)
+        .try_get_matches_from(&["release", "-n", "foo", "-a", "amd64", "bar"])
+        .unwrap();
+    assert_eq!(
+        get_plugin_args(&manifest, &matches),
+        vec!["--dry-run", "-a", "amd64", "foo", "bar"]
+    );
+    assert!(crate::cli::plugin_command("ciel-release", &manifest)
+        .try_get_matches_from(&["release", "-n"])
+        .is_err());
+}
+
+#[test]
+fn test_is_plugin_allowed() {
+    let allowlist = "3a7bd3e2360a3d29eea436fcfb7e44c735d117c42d1c1835420b6b9942dd4f1b  \
+        /usr/libexec/ciel-plugin/ciel-release\n\
+        ab12 */usr/libexec/ciel-plugin/ciel-generate\n\
+        cd34  ciel-relative\n";
+    let hash = "3a7bd3e2360a3d29eea436fcfb7e44c735d117c42d1c1835420b6b9942dd4f1b";
+    let release = Path::new("/usr/libexec/ciel-plugin/ciel-release");
+    let generate = Path::new("/usr/libexec/ciel-plugin/ciel-generate");
+    assert!(is_plugin_allowed(allowlist, release, hash));
+    assert!(is_plugin_allowed(allowlist, release, &hash.to_uppercase()));
+    assert!(is_plugin_allowed(allowlist, generate, "ab12"));
+    assert!(!is_plugin_allowed(allowlist, generate, hash));
+    // only the full path counts, not the name
+    assert!(!is_plugin_allowed(
+        allowlist,
+        Path::new("/tmp/ciel-release"),
+        hash
+    ));
+    assert!(!is_plugin_allowed(
+        allowlist,
+        Path::new("/usr/libexec/ciel-plugin/ciel-relative"),
+        "cd34"
+    ));
+}
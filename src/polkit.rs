@@ -0,0 +1,107 @@
+//! Authorizing the callers of `ciel daemon --dbus` with polkit (the same way machinectl does),
+//! so that the regular users can ask it to do the privileged operations
+use anyhow::{anyhow, Result};
+use dbus::{
+    arg::{PropMap, RefArg, Variant},
+    blocking::Connection,
+};
+use nix::unistd::{close, isatty, pipe, read};
+use std::{
+    collections::HashMap,
+    process::{Child, Command},
+    time::Duration,
+};
+
+use crate::debug;
+
+const POLKIT_DEST: &str = "org.freedesktop.PolicyKit1";
+const POLKIT_PATH: &str = "/org/freedesktop/PolicyKit1/Authority";
+const POLKIT_INTERFACE: &str = "org.freedesktop.PolicyKit1.Authority";
+// the user may be asked for the password (by the agent of the caller)
+const ALLOW_USER_INTERACTION: u32 = 1;
+// the user may take a while to type the password (only the worker thread of the caller waits)
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Mounting and un-mounting the instances
+pub const ACTION_MOUNT: &str = "io.aosc.ciel1.mount";
+/// Building the packages
+pub const ACTION_BUILD: &str = "io.aosc.ciel1.build";
+/// Throwing away the changes in the instances
+pub const ACTION_MANAGE: &str = "io.aosc.ciel1.manage";
+
+/// Check if the sender (the unique bus name) of a method call is authorized to do the action
+pub fn check_authorization(conn: &Connection, sender: &str, action: &str) -> Result<()> {
+    let proxy = conn.with_proxy(POLKIT_DEST, POLKIT_PATH, AUTHORIZATION_TIMEOUT);
+    let mut subject = PropMap::new();
+    subject.insert(
+        "name".to_string(),
+        Variant(Box::new(sender.to_string()) as Box<dyn RefArg>),
+    );
+    let ((authorized, challenge, _),): ((bool, bool, HashMap<String, String>),) = proxy
+        .method_call(
+            POLKIT_INTERFACE,
+            "CheckAuthorization",
+            (
+                ("system-bus-name", subject),
+                action,
+                HashMap::<String, String>::new(),
+                ALLOW_USER_INTERACTION,
+                "",
+            ),
+        )
+        .map_err(|e| anyhow!("Unable to check the authorization with polkit: {}", e))?;
+    debug!(
+        "polkit: {} {} {}",
+        sender,
+        if authorized { "may" } else { "may not" },
+        action
+    );
+    if authorized {
+        Ok(())
+    } else if challenge {
+        Err(anyhow!(
+            "Authentication is required for {} (is a polkit agent running?)",
+            action
+        ))
+    } else {
+        Err(anyhow!("Not authorized for {}", action))
+    }
+}
+
+/// A polkit agent on the terminal (`pkttyagent`), asking for the password if needed
+pub struct TtyAgent(Child);
+
+impl TtyAgent {
+    /// Start the agent if ciel is run on a terminal, `None` if it can not be started
+    pub fn start() -> Option<Self> {
+        if !isatty(0).unwrap_or(false) {
+            return None;
+        }
+        let (reader, writer) = pipe().ok()?;
+        let child = Command::new("pkttyagent")
+            .args(&["--notify-fd", &writer.to_string(), "--fallback"])
+            .spawn();
+        close(writer).ok();
+        let child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                debug!("Unable to start pkttyagent: {}", e);
+                close(reader).ok();
+                return None;
+            }
+        };
+        // the agent closes the fd once it is registered
+        let mut buf = [0u8; 1];
+        while let Ok(1) = read(reader, &mut buf) {}
+        close(reader).ok();
+
+        Some(TtyAgent(child))
+    }
+}
+
+impl Drop for TtyAgent {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
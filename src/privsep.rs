@@ -0,0 +1,126 @@
+//! Running the network operations as the user who invoked ciel (through sudo),
+//! so that the data from the network is not parsed by a process running as root
+use anyhow::{anyhow, Result};
+use nix::{
+    sys::wait::waitpid,
+    unistd::{
+        close, fchownat, fork, pipe, setgid, setgroups, setuid, FchownatFlags, ForkResult, Gid,
+        Uid, User,
+    },
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::unix::io::FromRawFd,
+    path::Path,
+};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+use crate::debug;
+
+/// The user (and group) who invoked ciel through sudo, `None` if ciel was run as root directly
+pub fn invoking_user_ids() -> Option<(Uid, Gid)> {
+    let uid = std::env::var("SUDO_UID").ok()?.parse().ok()?;
+    let gid = std::env::var("SUDO_GID").ok()?.parse().ok()?;
+    if uid == 0 {
+        return None;
+    }
+
+    Some((Uid::from_raw(uid), Gid::from_raw(gid)))
+}
+
+fn drop_privileges(uid: Uid, gid: Gid) -> Result<()> {
+    setgroups(&[gid])?;
+    setgid(gid)?;
+    setuid(uid)?;
+    // the privileges can not be gained back by running a setuid program either
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if setuid(Uid::from_raw(0)).is_ok() {
+        return Err(anyhow!("Unable to drop the root privileges"));
+    }
+    // sudo may keep the HOME of root, which is not readable by the user (e.g. for libgit2)
+    if let Some(user) = User::from_uid(uid)? {
+        std::env::set_var("HOME", user.dir);
+    }
+
+    Ok(())
+}
+
+/// Run `f` in a child process running as the invoking user (see `invoking_user_ids`),
+/// the result is passed back to ciel as JSON. `f` is run directly if there is no such user.
+/// The files to be written by `f` should be opened before, as the user can not create them.
+pub fn run_unprivileged<T, F>(what: &str, f: F) -> Result<T>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Result<T>,
+{
+    let (uid, gid) = match invoking_user_ids() {
+        Some(ids) => ids,
+        None => return f(),
+    };
+    debug!("Running {} as UID {}", what, uid);
+    let (reader, writer) = pipe()?;
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            close(reader).ok();
+            let result = drop_privileges(uid, gid)
+                .and_then(|_| f())
+                .map_err(|e| format!("{:#}", e));
+            let mut writer = unsafe { File::from_raw_fd(writer) };
+            let written = serde_json::to_vec(&result)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(writer.write_all(&data)?));
+            // do not run the destructors of the parent's state
+            unsafe { libc::_exit(if written.is_ok() { 0 } else { 1 }) }
+        }
+        ForkResult::Parent { child } => {
+            close(writer)?;
+            let mut data = Vec::new();
+            unsafe { File::from_raw_fd(reader) }.read_to_end(&mut data)?;
+            let status = waitpid(child, None)?;
+            if data.is_empty() {
+                return Err(anyhow!("The {} process failed: {:?}", what, status));
+            }
+            let result: Result<T, String> = serde_json::from_slice(&data)?;
+
+            result.map_err(|e| anyhow!(e))
+        }
+    }
+}
+
+/// Change the owner of `path` and everything inside (without following the symlinks)
+pub fn chown_tree(path: &Path, uid: Uid, gid: Gid) -> Result<()> {
+    for entry in WalkDir::new(path) {
+        fchownat(
+            None,
+            entry?.path(),
+            Some(uid),
+            Some(gid),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Create a private temporary directory in `parent` owned by the user (`uid`, `gid`), for
+/// the unprivileged process to write into, which is moved into place by root afterwards
+pub fn private_temp_dir(parent: &Path, uid: Uid, gid: Gid) -> Result<TempDir> {
+    // only accessible by its owner
+    let dir = tempfile::Builder::new()
+        .prefix(".ciel-unprivileged.")
+        .tempdir_in(parent)?;
+    fchownat(
+        None,
+        dir.path(),
+        Some(uid),
+        Some(gid),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+
+    Ok(dir)
+}
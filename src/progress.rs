@@ -0,0 +1,121 @@
+//! Progress indicators shared by all the long running operations.
+//! All the indicators are drawn on stderr and hidden in quiet mode.
+use crate::logging::{self, log_level, LEVEL_QUIET};
+use console::{style, Term};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use lazy_static::lazy_static;
+use std::{
+    borrow::Cow,
+    io,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+const TICK_RATE: u64 = 200;
+
+lazy_static! {
+    static ref SPINNER_STYLE: ProgressStyle = ProgressStyle::default_spinner()
+        .tick_chars("⠋⠙⠸⠴⠦⠇ ")
+        .template("{spinner:.green} {prefix}{wide_msg}");
+    static ref BYTES_STYLE: ProgressStyle = ProgressStyle::default_bar().template(
+        "{spinner:.green} {prefix}[{bar:25.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, eta {eta}) {wide_msg}"
+    );
+    static ref COUNT_STYLE: ProgressStyle = ProgressStyle::default_bar()
+        .template("{spinner:.green} {prefix}[{bar:25.cyan/blue}] {pos}/{len} (eta {eta}) {wide_msg}");
+    // the group being drawn (with its overall progress bar), see `group`
+    static ref GROUP: Mutex<Option<(Arc<MultiProgress>, ProgressBar)>> = Mutex::new(None);
+}
+
+fn setup<S: Into<Cow<'static, str>>>(bar: ProgressBar, msg: S) -> ProgressBar {
+    let bar = match &*GROUP.lock().unwrap() {
+        _ if log_level() == LEVEL_QUIET => {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
+            bar
+        }
+        Some((multi, _)) => {
+            let bar = multi.add(bar);
+            // tell apart the indicators of the instances operated on in parallel
+            if let Some(instance) = logging::current_instance() {
+                bar.set_prefix(format!("{} ", style(instance).cyan().bold()));
+            }
+            bar
+        }
+        None => bar,
+    };
+    bar.set_message(msg);
+    bar.enable_steady_tick(TICK_RATE);
+
+    bar
+}
+
+/// Create a spinner for operations with unknown length
+pub fn spinner<S: Into<Cow<'static, str>>>(msg: S) -> ProgressBar {
+    setup(
+        ProgressBar::new_spinner().with_style(SPINNER_STYLE.clone()),
+        msg,
+    )
+}
+
+/// Create a progress bar for operations measured in bytes (downloads, extraction, etc.)
+pub fn bytes_bar<S: Into<Cow<'static, str>>>(total: u64, msg: S) -> ProgressBar {
+    setup(ProgressBar::new(total).with_style(BYTES_STYLE.clone()), msg)
+}
+
+/// Create a progress bar for operations measured in items (files, packages, etc.)
+pub fn count_bar<S: Into<Cow<'static, str>>>(total: u64, msg: S) -> ProgressBar {
+    setup(ProgressBar::new(total).with_style(COUNT_STYLE.clone()), msg)
+}
+
+/// The indicators of the operations running in parallel, drawn together until it is finished
+pub struct Group {
+    overall: ProgressBar,
+    drawer: Option<JoinHandle<io::Result<()>>>,
+}
+
+/// Draw all the indicators created (by any thread) from now on together, below a progress bar
+/// of the `total` operations
+pub fn group<S: Into<Cow<'static, str>>>(total: u64, msg: S) -> Group {
+    let multi = Arc::new(MultiProgress::new());
+    let overall = multi.add(ProgressBar::new(total).with_style(COUNT_STYLE.clone()));
+    overall.set_message(msg);
+    overall.enable_steady_tick(TICK_RATE);
+    if log_level() == LEVEL_QUIET {
+        multi.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    *GROUP.lock().unwrap() = Some((multi.clone(), overall.clone()));
+    // nothing is drawn without joining, which returns when all the indicators are finished
+    let drawer = thread::spawn(move || multi.join_and_clear());
+
+    Group {
+        overall,
+        drawer: Some(drawer),
+    }
+}
+
+impl Group {
+    /// Count one more operation as finished
+    pub fn inc(&self) {
+        self.overall.inc(1);
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        GROUP.lock().unwrap().take();
+        self.overall.finish_and_clear();
+        if let Some(drawer) = self.drawer.take() {
+            drawer.join().ok();
+        }
+    }
+}
+
+/// Print the line to stderr, above the indicators of the group being drawn (if any)
+pub fn eprintln(line: &str) {
+    match &*GROUP.lock().unwrap() {
+        // the group is not drawn (nor the line) in quiet mode or if stderr is not a terminal
+        Some((_, overall)) if log_level() != LEVEL_QUIET && Term::stderr().is_term() => {
+            overall.println(line)
+        }
+        _ => eprintln!("{}", line),
+    }
+}
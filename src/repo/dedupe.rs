@@ -0,0 +1,166 @@
+//! Hard linking the identical packages across the output directories
+use anyhow::Result;
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use super::scan::collect_all_packages;
+use crate::debug;
+
+/// What was (or would be) done by deduplicating the output directories
+#[derive(Debug, Default, Serialize)]
+pub struct DedupeResult {
+    /// Packages replaced with hard links
+    pub linked: usize,
+    /// Space freed (in bytes)
+    pub freed: u64,
+}
+
+/// A file (which may have several names) in the output directories
+struct Inode {
+    paths: Vec<PathBuf>,
+    /// Number of links outside of the output directories
+    external_links: u64,
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+
+    Ok(hasher.finalize().into())
+}
+
+/// Replace `path` with a hard link to `target`, atomically
+fn replace_with_link(target: &Path, path: &Path) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".ciel-dedupe");
+    let temp = PathBuf::from(temp);
+    fs::hard_link(target, &temp)?;
+    if let Err(e) = fs::rename(&temp, path) {
+        fs::remove_file(&temp).ok();
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Find the output directories (`OUTPUT` and `OUTPUT-*`) of the workspace
+pub fn find_output_dirs(workspace: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(workspace)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_dir() && (name == "OUTPUT" || name.starts_with("OUTPUT-")) {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+/// Replace the identical packages in the output directories with hard links to one of them
+/// (e.g. the same package built on several branches), only count them if `dry_run` is set
+pub fn dedupe_outputs(roots: &[PathBuf], dry_run: bool) -> Result<DedupeResult> {
+    // (device, size) -> inode -> file, only the files of the same size may be identical
+    let mut candidates: HashMap<(u64, u64), HashMap<u64, Inode>> = HashMap::new();
+    for root in roots {
+        for entry in collect_all_packages(root.join("debs"))? {
+            let meta = entry.metadata()?;
+            if meta.len() == 0 {
+                continue;
+            }
+            let inode = candidates
+                .entry((meta.dev(), meta.len()))
+                .or_default()
+                .entry(meta.ino())
+                .or_insert_with(|| Inode {
+                    paths: Vec::new(),
+                    external_links: meta.nlink(),
+                });
+            inode.paths.push(entry.into_path());
+            inode.external_links = inode.external_links.saturating_sub(1);
+        }
+    }
+    let inodes = candidates
+        .into_iter()
+        .filter(|(_, inodes)| inodes.len() > 1)
+        .flat_map(|((_, size), inodes)| inodes.into_values().map(move |inode| (size, inode)))
+        .collect::<Vec<_>>();
+    let hashed = inodes
+        .into_par_iter()
+        .map(|(size, inode)| Ok((hash_file(&inode.paths[0])?, size, inode)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut duplicates: HashMap<([u8; 32], u64), Vec<Inode>> = HashMap::new();
+    for (hash, size, inode) in hashed {
+        duplicates.entry((hash, size)).or_default().push(inode);
+    }
+
+    let mut result = DedupeResult::default();
+    for ((_, size), mut inodes) in duplicates {
+        if inodes.len() < 2 {
+            continue;
+        }
+        // keep the file with the most names, so that the fewest are replaced
+        inodes.sort_by(|a, b| {
+            b.paths
+                .len()
+                .cmp(&a.paths.len())
+                .then(a.paths.cmp(&b.paths))
+        });
+        let target = inodes[0].paths[0].clone();
+        for inode in inodes.iter().skip(1) {
+            for path in inode.paths.iter() {
+                debug!("{} -> {}", path.display(), target.display());
+                if !dry_run {
+                    replace_with_link(&target, path)?;
+                }
+                result.linked += 1;
+            }
+            // still used elsewhere otherwise
+            if inode.external_links == 0 {
+                result.freed += size;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[test]
+fn test_dedupe_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let roots = find_output_dirs(dir.path()).unwrap();
+    assert!(roots.is_empty());
+    for (root, content) in [
+        ("OUTPUT", "foo"),
+        ("OUTPUT-stable", "foo"),
+        ("OUTPUT-main", "bar"),
+    ] {
+        let debs = dir.path().join(root).join("debs/f");
+        fs::create_dir_all(&debs).unwrap();
+        fs::write(debs.join("foo_1.0_amd64.deb"), content).unwrap();
+    }
+    let roots = find_output_dirs(dir.path()).unwrap();
+    assert_eq!(roots.len(), 3);
+    let result = dedupe_outputs(&roots, true).unwrap();
+    assert_eq!((result.linked, result.freed), (1, 3));
+    let inode = |root: &str| {
+        fs::metadata(dir.path().join(root).join("debs/f/foo_1.0_amd64.deb"))
+            .unwrap()
+            .ino()
+    };
+    assert_ne!(inode("OUTPUT"), inode("OUTPUT-stable"));
+    dedupe_outputs(&roots, false).unwrap();
+    assert_eq!(inode("OUTPUT"), inode("OUTPUT-stable"));
+    assert_ne!(inode("OUTPUT"), inode("OUTPUT-main"));
+    let result = dedupe_outputs(&roots, false).unwrap();
+    assert_eq!((result.linked, result.freed), (0, 0));
+}
@@ -1,19 +1,25 @@
 //! Local repository
 
-use crate::info;
+use crate::{common::CIEL_CACHE_DIR, info, warn};
 use anyhow::Result;
-use console::style;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::io::Write;
-use std::{fs, io, path::Path};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
 
+mod dedupe;
 mod scan;
 
+pub use self::dedupe::{dedupe_outputs, find_output_dirs, DedupeResult};
+
 /// Debian 822 date: "%a, %d %b %Y %H:%M:%S %z"
 const DEB822_DATE: &[FormatItem] = format_description!("[weekday repr:short], [day] [month repr:short] [year] [hour repr:24]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]");
 
-fn generate_release(path: &Path) -> Result<String> {
+fn generate_release(path: &Path, arch: Option<&str>) -> Result<String> {
     let mut f = fs::File::open(path.join("Packages"))?;
     let mut hasher = Sha256::new();
     io::copy(&mut f, &mut hasher)?;
@@ -21,35 +27,116 @@ fn generate_release(path: &Path) -> Result<String> {
     let meta = f.metadata()?;
     let timestamp = OffsetDateTime::now_utc().format(&DEB822_DATE)?;
 
+    // tell apt not to look for the packages of the other architectures
+    let architectures =
+        arch.map_or_else(String::new, |arch| format!("Architectures: {} all\n", arch));
+
     Ok(format!(
-        "Date: {}\nSHA256:\n {:x} {} Packages\n",
+        "Date: {}\n{}SHA256:\n {:x} {} Packages\n",
         timestamp,
+        architectures,
         result,
         meta.len()
     ))
 }
 
-/// Refresh the local repository (Update Packages file)
-pub fn refresh_repo(root: &Path) -> Result<()> {
+/// Where the scan results of the repository are cached, `None` outside of a workspace
+fn scan_cache_path(root: &Path) -> Option<PathBuf> {
+    let cache_dir = Path::new(CIEL_CACHE_DIR);
+    if !cache_dir.parent()?.is_dir() {
+        return None;
+    }
+    // e.g. OUTPUT-stable, there is one cache for each output directory
+    let name = root.file_name()?.to_string_lossy();
+
+    Some(cache_dir.join(format!("repo-{}.bin", name)))
+}
+
+/// Refresh the local repository (Update Packages file), only including the packages
+/// installable on `arch` if specified
+pub fn refresh_repo(root: &Path, arch: Option<&str>) -> Result<()> {
     let path = root.join("debs");
     fs::create_dir_all(&path)?;
     let mut output = fs::File::create(path.join("Packages"))?;
     let entries = scan::collect_all_packages(&path)?;
+    let cache_path = scan_cache_path(root);
+    let mut cache = cache_path
+        .as_deref()
+        .map(scan::ScanCache::load)
+        .unwrap_or_default();
     info!("Scanning {} packages...", entries.len());
-    output.write_all(&scan::scan_packages_simple(&entries, &path))?;
-    println!();
+    output.write_all(&scan::scan_packages_simple(
+        &entries, &path, arch, &mut cache,
+    ))?;
+    if let Some(cache_path) = cache_path {
+        let result = fs::create_dir_all(CIEL_CACHE_DIR)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| cache.save(&cache_path));
+        if let Err(e) = result {
+            warn!("Unable to save the scan results: {}", e);
+        }
+    }
 
-    let release = generate_release(&path)?;
+    let release = generate_release(&path, arch)?;
     let mut release_file = fs::File::create(path.join("Release"))?;
     release_file.write_all(release.as_bytes())?;
 
     Ok(())
 }
 
+/// A package in the output directory
+#[derive(Debug, Clone, Serialize)]
+pub struct RepoPackage {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    /// Relative to the output directory
+    pub path: String,
+    pub size: u64,
+}
+
+/// Split the file name of the package (`NAME_VERSION_ARCH.deb`)
+fn parse_deb_name(file_name: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = file_name.strip_suffix(".deb")?.splitn(3, '_');
+
+    Some((parts.next()?, parts.next()?, parts.next()?))
+}
+
+/// List the packages in the output directory `root`
+pub fn list_packages(root: &Path) -> Result<Vec<RepoPackage>> {
+    let path = root.join("debs");
+    if !path.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut packages = Vec::new();
+    for entry in scan::collect_all_packages(&path)? {
+        let file_name = entry.file_name().to_string_lossy();
+        let (name, version, arch) = match parse_deb_name(&file_name) {
+            Some(parts) => parts,
+            None => continue,
+        };
+        packages.push(RepoPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            arch: arch.to_string(),
+            path: entry
+                .path()
+                .strip_prefix(root)
+                .unwrap_or_else(|_| entry.path())
+                .to_string_lossy()
+                .to_string(),
+            size: entry.metadata()?.len(),
+        });
+    }
+    packages.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.version.cmp(&b.version)));
+
+    Ok(packages)
+}
+
 /// Initialize local repository and add entries to sources.list
-pub fn init_repo(repo_root: &Path, rootfs: &Path) -> Result<()> {
+pub fn init_repo(repo_root: &Path, rootfs: &Path, arch: Option<&str>) -> Result<()> {
     // trigger a refresh, since the metadata is probably out of date
-    refresh_repo(repo_root)?;
+    refresh_repo(repo_root, arch)?;
     fs::create_dir_all(rootfs.join("etc/apt/sources.list.d/"))?;
     fs::write(
         rootfs.join("etc/apt/sources.list.d/ciel-local.list"),
@@ -65,3 +152,12 @@ pub fn deinit_repo(rootfs: &Path) -> Result<()> {
         rootfs.join("etc/apt/sources.list.d/ciel-local.list"),
     )?)
 }
+
+#[test]
+fn test_parse_deb_name() {
+    assert_eq!(
+        parse_deb_name("bash_5.2.15-0_amd64.deb"),
+        Some(("bash", "5.2.15-0", "amd64"))
+    );
+    assert_eq!(parse_deb_name("Packages"), None);
+}
@@ -1,21 +1,76 @@
-use crate::error;
+use crate::{error, progress, warn};
 use anyhow::{anyhow, Result};
 use ar::Archive as ArArchive;
-use console::style;
 use faster_hex::hex_string;
 use flate2::read::GzDecoder;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::io::SeekFrom;
 use std::{
-    fs::File,
-    io::{Read, Seek, Write},
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek},
+    os::unix::fs::MetadataExt,
     path::Path,
 };
 use tar::Archive as TarArchive;
+use tempfile::NamedTempFile;
 use walkdir::{DirEntry, WalkDir};
 use xz2::read::XzDecoder;
 
+// bump this when the content of the stanzas changes
+const SCAN_CACHE_VERSION: u32 = 1;
+
+/// The stanza of a package, valid as long as the file is not changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CachedPackage {
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    stanza: Vec<u8>,
+}
+
+impl CachedPackage {
+    fn is_fresh(&self, meta: &fs::Metadata) -> bool {
+        self.inode == meta.ino()
+            && self.size == meta.len()
+            && self.mtime == meta.mtime()
+            && self.mtime_nsec == meta.mtime_nsec()
+    }
+}
+
+/// The results of the previous scan, so that the unchanged packages are not opened again
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScanCache {
+    version: u32,
+    /// Indexed by the path relative to the repository
+    packages: HashMap<String, CachedPackage>,
+}
+
+impl ScanCache {
+    /// Load the cache, an empty one is returned if it is missing or unusable
+    pub fn load(path: &Path) -> Self {
+        let cache = File::open(path)
+            .ok()
+            .and_then(|f| bincode::deserialize_from::<_, ScanCache>(f).ok());
+        match cache {
+            Some(cache) if cache.version == SCAN_CACHE_VERSION => cache,
+            _ => ScanCache::default(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // replaced atomically, the refreshes may run concurrently
+        let mut file = NamedTempFile::new_in(path.parent().unwrap_or_else(|| Path::new(".")))?;
+        bincode::serialize_into(&mut file, self)?;
+        file.persist(path)?;
+
+        Ok(())
+    }
+}
+
 enum TarFormat {
     Xzip,
     Gzip,
@@ -108,23 +163,93 @@ fn is_tarball(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-pub fn scan_packages_simple(entries: &[DirEntry], root: &Path) -> Vec<u8> {
-    entries
+/// Check if the package (described by its control stanza) is installable on `arch`
+fn is_arch_compatible(control: &[u8], arch: &str) -> bool {
+    let control = String::from_utf8_lossy(control);
+    match control
+        .lines()
+        .find_map(|line| line.strip_prefix("Architecture:"))
+    {
+        Some(package_arch) => {
+            let package_arch = package_arch.trim();
+            package_arch == arch || package_arch == "all"
+        }
+        None => true,
+    }
+}
+
+/// Scan the package, or take its stanza from the cache if it is unchanged
+fn scan_or_reuse(path: &Path, root: &Path, cache: &ScanCache) -> Result<(String, CachedPackage)> {
+    let meta = fs::metadata(path)?;
+    let key = path.strip_prefix(root)?.to_string_lossy().to_string();
+    if let Some(cached) = cache.packages.get(&key).filter(|c| c.is_fresh(&meta)) {
+        return Ok((key, cached.clone()));
+    }
+    let stanza = scan_single_deb_simple(path, root)?;
+
+    Ok((
+        key,
+        CachedPackage {
+            inode: meta.ino(),
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            stanza,
+        },
+    ))
+}
+
+/// Scan the packages, leaving out the ones not installable on `arch` (if specified).
+/// The packages unchanged since the previous scan are taken from `cache`, which is updated.
+pub fn scan_packages_simple(
+    entries: &[DirEntry],
+    root: &Path,
+    arch: Option<&str>,
+    cache: &mut ScanCache,
+) -> Vec<u8> {
+    let progress_bar = progress::count_bar(entries.len() as u64, "Scanning packages...");
+    let results: Vec<Result<(String, CachedPackage)>> = entries
         .par_iter()
-        .map(|entry| -> Vec<u8> {
-            let path = entry.path();
-            print!(".");
-            std::io::stderr().flush().ok();
-            match scan_single_deb_simple(path, root) {
-                Ok(entry) => entry,
-                Err(err) => {
-                    error!("{:?}", err);
-                    Vec::new()
+        .map(|entry| {
+            let result = scan_or_reuse(entry.path(), root, cache);
+            progress_bar.inc(1);
+            result
+        })
+        .collect();
+    progress_bar.finish_and_clear();
+
+    let mut packages = Vec::new();
+    let mut skipped = 0;
+    let mut scanned = HashMap::with_capacity(results.len());
+    for result in results {
+        match result {
+            Ok((key, package)) => {
+                if arch
+                    .iter()
+                    .all(|arch| is_arch_compatible(&package.stanza, arch))
+                {
+                    packages.extend(&package.stanza);
+                } else {
+                    skipped += 1;
                 }
+                scanned.insert(key, package);
             }
-        })
-        .flatten()
-        .collect()
+            Err(err) => error!("{:?}", err),
+        }
+    }
+    // the removed packages are forgotten
+    *cache = ScanCache {
+        version: SCAN_CACHE_VERSION,
+        packages: scanned,
+    };
+    if let (Some(arch), true) = (arch, skipped > 0) {
+        warn!(
+            "{} packages not built for {} are left out of the repository.",
+            skipped, arch
+        );
+    }
+
+    packages
 }
 
 pub fn collect_all_packages<P: AsRef<Path>>(path: P) -> Result<Vec<DirEntry>> {
@@ -138,3 +263,43 @@ pub fn collect_all_packages<P: AsRef<Path>>(path: P) -> Result<Vec<DirEntry>> {
 
     Ok(files)
 }
+
+#[test]
+fn test_is_arch_compatible() {
+    let control = b"Package: foo\nVersion: 1.0\nArchitecture: arm64\n";
+    assert!(is_arch_compatible(control, "arm64"));
+    assert!(!is_arch_compatible(control, "amd64"));
+    assert!(is_arch_compatible(
+        b"Package: bar\nArchitecture: all\n",
+        "amd64"
+    ));
+}
+
+#[test]
+fn test_scan_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo_1.0_amd64.deb");
+    fs::write(&path, b"not a package").unwrap();
+    let meta = fs::metadata(&path).unwrap();
+    let mut cache = ScanCache::default();
+    cache.packages.insert(
+        "foo_1.0_amd64.deb".to_string(),
+        CachedPackage {
+            inode: meta.ino(),
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            stanza: b"Package: foo\n\n".to_vec(),
+        },
+    );
+    // the file is not opened while it is unchanged
+    let (key, package) = scan_or_reuse(&path, dir.path(), &cache).unwrap();
+    assert_eq!(key, "foo_1.0_amd64.deb");
+    assert_eq!(package.stanza, b"Package: foo\n\n");
+    fs::write(&path, b"still not a package").unwrap();
+    assert!(scan_or_reuse(&path, dir.path(), &cache).is_err());
+    let cache_path = dir.path().join("cache.bin");
+    cache.version = SCAN_CACHE_VERSION;
+    cache.save(&cache_path).unwrap();
+    assert_eq!(ScanCache::load(&cache_path).packages, cache.packages);
+}
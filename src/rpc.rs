@@ -0,0 +1,443 @@
+//! JSON-RPC 2.0 interface of the workspace on a Unix socket (`ciel daemon --socket`)
+//!
+//! One request (or response) per line. Access is controlled by the permissions of the socket:
+//! only the owner (and the members of the group, if specified) can connect.
+//!
+//! The queries (`list_instances`, `instance`, `status`, `builds`, `build_log`, `repo` and
+//! `workspace`) are shared with the plugins, see [`crate::plugin`].
+use anyhow::{anyhow, Result};
+use nix::{
+    sys::stat::{umask, Mode},
+    unistd::{chown, Group},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    actions::{self, BuildSummary},
+    common::is_instance_exists,
+    config, info,
+    lock::{lock_workspace, LockMode},
+    machine, repo,
+    service::run_build,
+    warn,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+pub(crate) const METHOD_NOT_FOUND: i64 = -32601;
+pub(crate) const INVALID_PARAMS: i64 = -32602;
+// failures of the operations themselves
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    // absent for notifications
+    #[serde(default)]
+    id: Option<Value>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RpcError {
+    pub(crate) code: i64,
+    pub(crate) message: String,
+}
+
+impl RpcError {
+    pub(crate) fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        RpcError::new(SERVER_ERROR, e)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BuildParams {
+    instance: String,
+    packages: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JobParams {
+    job: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct InstanceParams {
+    name: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BuildLogParams {
+    #[serde(default)]
+    package: Option<String>,
+    #[serde(default)]
+    previous: usize,
+}
+
+/// Answers the requests (the framing is done by [`handle_line`])
+pub(crate) trait Handler {
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A build enqueued over the socket
+#[derive(Debug, Clone, Serialize)]
+struct Job {
+    id: u32,
+    instance: String,
+    packages: Vec<String>,
+    state: JobState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<BuildSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Runs the builds enqueued, shared by the socket and the HTTP API (see [`crate::api`])
+pub(crate) struct Daemon {
+    jobs: Mutex<Vec<Job>>,
+    // the builds run one at a time, in the order they are enqueued
+    queue: Mutex<mpsc::Sender<u32>>,
+}
+
+pub(crate) fn to_value<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(SERVER_ERROR, e))
+}
+
+pub(crate) fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": error.code, "message": error.message },
+        "id": id,
+    })
+}
+
+/// The read-only queries about the workspace
+pub(crate) fn query(method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "list_instances" => to_value(machine::list_instances()?),
+        "instance" => {
+            let params: InstanceParams = parse_params(params)?;
+            let instance = machine::list_instances()?
+                .into_iter()
+                .find(|i| i.name == params.name)
+                .ok_or_else(|| {
+                    RpcError::new(INVALID_PARAMS, format!("No such instance: {}", params.name))
+                })?;
+            to_value(instance)
+        }
+        "status" => to_value(actions::get_workspace_status()?),
+        "builds" => {
+            let params: BuildLogParams = parse_params(params)?;
+            to_value(actions::get_build_logs(params.package.as_deref())?)
+        }
+        "build_log" => {
+            let params: BuildLogParams = parse_params(params)?;
+            let package = params
+                .package
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "No package specified"))?;
+            let mut log = Vec::new();
+            let entry = actions::find_build_log(&package, params.previous)?;
+            actions::show_build_log(&entry, &mut log)?;
+            Ok(Value::from(String::from_utf8_lossy(&log)))
+        }
+        "repo" => {
+            let conf = config::read_config()?;
+            let output = actions::get_output_directory(conf.sep_mount, conf.sep_arch);
+            to_value(repo::list_packages(Path::new(&output))?)
+        }
+        "workspace" => Ok(json!({
+            "path": std::env::current_dir().map_err(anyhow::Error::from)?,
+            "version": env!("CARGO_PKG_VERSION"),
+            "config": config::read_config().ok(),
+        })),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method: {}", method),
+        )),
+    }
+}
+
+/// Handle one line of request, `None` if nothing is to be sent back
+pub(crate) fn handle_line<H: Handler>(handler: &H, line: &str) -> Option<Value> {
+    let value: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+    };
+    let request: Request = match serde_json::from_value(value) {
+        Ok(r) => r,
+        Err(e) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, e),
+            ))
+        }
+    };
+    if request.jsonrpc != "2.0" {
+        return Some(error_response(
+            request.id.unwrap_or_default(),
+            RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported"),
+        ));
+    }
+    let result = handler.call(&request.method, request.params);
+    // notifications are carried out, but not answered
+    let id = request.id?;
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    };
+
+    Some(response)
+}
+
+impl Daemon {
+    fn new(queue: mpsc::Sender<u32>) -> Self {
+        Daemon {
+            jobs: Mutex::new(Vec::new()),
+            queue: Mutex::new(queue),
+        }
+    }
+
+    fn update_job<F: FnOnce(&mut Job)>(&self, id: u32, f: F) {
+        if let Some(job) = self.jobs.lock().unwrap().iter_mut().find(|j| j.id == id) {
+            f(job);
+        }
+    }
+
+    fn enqueue(&self, params: BuildParams) -> Result<u32, RpcError> {
+        if params.packages.is_empty() {
+            return Err(RpcError::new(INVALID_PARAMS, "No packages to build"));
+        }
+        if !is_instance_exists(&params.instance) {
+            return Err(RpcError::new(
+                SERVER_ERROR,
+                format!("Instance {} does not exist", params.instance),
+            ));
+        }
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.last().map_or(1, |j| j.id + 1);
+        info!(
+            "Job #{}: {} in {} (enqueued)",
+            id,
+            params.packages.join(" "),
+            params.instance
+        );
+        jobs.push(Job {
+            id,
+            instance: params.instance,
+            packages: params.packages,
+            state: JobState::Queued,
+            summary: None,
+            error: None,
+        });
+        self.queue
+            .lock()
+            .unwrap()
+            .send(id)
+            .map_err(|_| RpcError::new(SERVER_ERROR, "The build queue is gone"))?;
+
+        Ok(id)
+    }
+
+    fn run_queue(&self, queue: Receiver<u32>) {
+        for id in queue {
+            let job = match self.jobs.lock().unwrap().iter().find(|j| j.id == id) {
+                Some(job) => job.clone(),
+                None => continue,
+            };
+            self.update_job(id, |j| j.state = JobState::Running);
+            info!("Job #{}: building ...", id);
+            match run_build(&job.instance, &job.packages) {
+                Ok(summary) => {
+                    let state = if summary.success {
+                        JobState::Succeeded
+                    } else {
+                        JobState::Failed
+                    };
+                    info!("Job #{}: {:?}", id, state);
+                    self.update_job(id, |j| {
+                        j.state = state;
+                        j.summary = Some(summary);
+                    });
+                }
+                Err(e) => {
+                    warn!("Job #{}: {}", id, e);
+                    self.update_job(id, |j| {
+                        j.state = JobState::Failed;
+                        j.error = Some(e.to_string());
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Handler for Daemon {
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "build" => Ok(json!({ "job": self.enqueue(parse_params(params)?)? })),
+            "job" => {
+                let params: JobParams = parse_params(params)?;
+                let jobs = self.jobs.lock().unwrap();
+                let job = jobs.iter().find(|j| j.id == params.job).ok_or_else(|| {
+                    RpcError::new(INVALID_PARAMS, format!("No such job: {}", params.job))
+                })?;
+                to_value(job)
+            }
+            "jobs" => to_value(&*self.jobs.lock().unwrap()),
+            "rollback" => {
+                let params: InstanceParams = parse_params(params)?;
+                machine::check_instance_name(&params.name)
+                    .map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+                let _lock = lock_workspace(LockMode::Shared, false)?;
+                actions::rollback_container(&params.name)?;
+                Ok(Value::Null)
+            }
+            _ => query(method, params),
+        }
+    }
+}
+
+fn handle_client(daemon: &Daemon, stream: UnixStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = handle_line(daemon, &line) {
+            writeln!(writer, "{}", response)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Create the socket, only accessible by the owner (and the members of `group`)
+fn bind_socket(path: &Path, group: Option<&str>) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "{} is in use (is another daemon running?)",
+                path.display()
+            ));
+        }
+        // left behind by a daemon killed earlier
+        fs::remove_file(path)?;
+    }
+    let gid = match group {
+        Some(name) => Some(
+            Group::from_name(name)?
+                .ok_or_else(|| anyhow!("No such group: {}", name))?
+                .gid,
+        ),
+        None => None,
+    };
+    let mask = if gid.is_some() { 0o117 } else { 0o177 };
+    let old_mask = umask(Mode::from_bits_truncate(mask));
+    let listener = UnixListener::bind(path);
+    umask(old_mask);
+    let listener = listener?;
+    if gid.is_some() {
+        chown(path, None, gid)?;
+    }
+
+    Ok(listener)
+}
+
+/// Start running the builds enqueued in the background
+pub(crate) fn start_daemon() -> Arc<Daemon> {
+    let (sender, queue) = mpsc::channel();
+    let daemon = Arc::new(Daemon::new(sender));
+    let worker = daemon.clone();
+    thread::spawn(move || worker.run_queue(queue));
+
+    daemon
+}
+
+/// Serve the workspace on the Unix socket at `path` until killed
+pub fn serve_socket(path: &Path, group: Option<&str>) -> Result<()> {
+    let listener = bind_socket(path, group)?;
+    // nobody is there to answer the prompts
+    std::env::set_var("CIEL_BATCH", "1");
+    let daemon = start_daemon();
+    info!(
+        "Serving {} on {} ...",
+        std::env::current_dir()?.display(),
+        path.display()
+    );
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Unable to accept the connection: {}", e);
+                continue;
+            }
+        };
+        let daemon = daemon.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_client(&daemon, stream) {
+                warn!("Connection closed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_handle_line() {
+    let (sender, _queue) = mpsc::channel();
+    let daemon = Daemon::new(sender);
+    let response = handle_line(&daemon, "{").unwrap();
+    assert_eq!(response["error"]["code"], PARSE_ERROR);
+    let response =
+        handle_line(&daemon, r#"{"jsonrpc": "2.0", "method": "nope", "id": 3}"#).unwrap();
+    assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+    assert_eq!(response["id"], 3);
+    let response = handle_line(
+        &daemon,
+        r#"{"jsonrpc": "2.0", "method": "build", "params": {"instance": "a"}, "id": "x"}"#,
+    )
+    .unwrap();
+    assert_eq!(response["error"]["code"], INVALID_PARAMS);
+    assert!(handle_line(&daemon, r#"{"jsonrpc": "2.0", "method": "jobs"}"#).is_none());
+    let response =
+        handle_line(&daemon, r#"{"jsonrpc": "2.0", "method": "jobs", "id": 4}"#).unwrap();
+    assert_eq!(response["result"], json!([]));
+}
@@ -0,0 +1,345 @@
+//! D-Bus interface of the workspace (`ciel daemon --dbus`)
+//!
+//! On the system bus, the callers are authorized with polkit (see `dbus-xml/io.aosc.ciel1.policy`),
+//! so that the regular users can mount and build (the members of the `ciel` group authenticating
+//! once in a while, with `dbus-xml/io.aosc.ciel1.rules`), while rolling back the instances always
+//! requires the authentication of an administrator. `ciel` talks to the daemon through [`Client`]
+//! when it is not run as root.
+use anyhow::{anyhow, Result};
+use dbus::{
+    blocking::{Connection, Proxy},
+    channel::{MatchingReceiver, Sender},
+    message::MatchRule,
+    strings::ErrorName,
+    Message,
+};
+use serde::Serialize;
+use std::{
+    ffi::CString,
+    path::Path,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    actions, info,
+    lock::{lock_workspace, LockMode},
+    machine,
+    polkit::{self, TtyAgent},
+    warn,
+};
+
+/// Well-known name of the service
+pub const SERVICE_NAME: &str = "io.aosc.Ciel1";
+const OBJECT_PATH: &str = "/io/aosc/Ciel1";
+const INTERFACE_NAME: &str = "io.aosc.Ciel1.Workspace";
+const ERROR_NAME: &str = "io.aosc.Ciel1.Error";
+const INTROSPECTION: &str = include_str!("../dbus-xml/io.aosc.Ciel1.xml");
+
+/// A build running in the background: (job, success, summary as JSON)
+type FinishedBuild = (u32, bool, String);
+
+struct Service {
+    next_job: u32,
+    finished: mpsc::Sender<FinishedBuild>,
+    /// The replies of the calls handled in the background
+    replies: mpsc::Sender<Message>,
+    /// Ask polkit (on the system bus only), which may wait for the caller to authenticate
+    polkit: bool,
+}
+
+#[inline]
+fn to_json<T: Serialize>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+/// Run `f` while holding the workspace lock (failing instead of waiting if it is busy)
+fn with_lock<T, F: FnOnce() -> Result<T>>(mode: LockMode, f: F) -> Result<T> {
+    let _lock = lock_workspace(mode, false)?;
+
+    f()
+}
+
+/// Build the packages with the default settings, waiting for the workspace lock
+pub(crate) fn run_build(instance: &str, packages: &[String]) -> Result<actions::BuildSummary> {
+    let _lock = lock_workspace(LockMode::Shared, true)?;
+    let settings = actions::BuildSettings::default();
+
+    actions::package_build(
+        instance,
+        packages.iter().map(|p| p.as_str()),
+        None,
+        &settings,
+    )
+}
+
+/// Run the build in the background, reporting it through `finished` once done
+fn start_build(
+    job: u32,
+    instance: String,
+    packages: Vec<String>,
+    finished: mpsc::Sender<FinishedBuild>,
+) {
+    thread::spawn(move || {
+        info!("Build #{}: {} in {}", job, packages.join(" "), instance);
+        let report = match run_build(&instance, &packages) {
+            Ok(summary) => (
+                summary.success,
+                to_json(&summary).unwrap_or_else(|e| e.to_string()),
+            ),
+            Err(e) => (false, to_json(&e.to_string()).unwrap_or_default()),
+        };
+        finished.send((job, report.0, report.1)).ok();
+    });
+}
+
+/// Check if the caller is authorized to do the action (if `polkit` is set)
+fn authorize(polkit: bool, msg: &Message, action: &str) -> Result<()> {
+    if !polkit {
+        return Ok(());
+    }
+    let sender = msg
+        .sender()
+        .ok_or_else(|| anyhow!("Unable to identify the caller"))?;
+
+    polkit::check_authorization(&Connection::new_system()?, &sender, action)
+}
+
+impl Service {
+    /// Authorize the caller and run `f` (which makes the reply) in another thread, so that
+    /// the other callers are served while waiting for the caller to authenticate
+    fn in_background<F>(&self, msg: &Message, action: &'static str, f: F) -> Result<()>
+    where
+        F: FnOnce(&Message) -> Result<Message> + Send + 'static,
+    {
+        let msg = msg.duplicate().map_err(|e| anyhow!(e))?;
+        let polkit = self.polkit;
+        let replies = self.replies.clone();
+        thread::spawn(move || {
+            let reply = authorize(polkit, &msg, action)
+                .and_then(|_| f(&msg))
+                .unwrap_or_else(|e| error_reply(&msg, &e));
+            if !msg.get_no_reply() {
+                replies.send(reply).ok();
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Handle the method call, the reply is `None` if it is sent later (see `in_background`)
+    fn handle_call(&mut self, msg: &Message) -> Result<Option<Message>> {
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+        if interface == "org.freedesktop.DBus.Introspectable" && member == "Introspect" {
+            return Ok(Some(msg.method_return().append1(INTROSPECTION)));
+        }
+        if !interface.is_empty() && interface != INTERFACE_NAME {
+            return Err(anyhow!("Unknown interface: {}", interface));
+        }
+        let reply = match member.as_str() {
+            "ListInstances" => msg
+                .method_return()
+                .append1(to_json(&machine::list_instances()?)?),
+            "Status" => msg
+                .method_return()
+                .append1(to_json(&actions::get_workspace_status()?)?),
+            "Workspace" => msg
+                .method_return()
+                .append1(std::env::current_dir()?.to_string_lossy().to_string()),
+            "Mount" => {
+                let instance = msg.read1::<&str>()?.to_string();
+                machine::check_instance_name(&instance)?;
+                self.in_background(msg, polkit::ACTION_MOUNT, move |msg| {
+                    with_lock(LockMode::Shared, || actions::mount_fs(&instance))?;
+                    Ok(msg.method_return())
+                })?;
+                return Ok(None);
+            }
+            "Down" => {
+                let instance = msg.read1::<&str>()?.to_string();
+                machine::check_instance_name(&instance)?;
+                self.in_background(msg, polkit::ACTION_MOUNT, move |msg| {
+                    with_lock(LockMode::Shared, || actions::container_down(&instance))?;
+                    Ok(msg.method_return())
+                })?;
+                return Ok(None);
+            }
+            "Rollback" => {
+                let instance = msg.read1::<&str>()?.to_string();
+                machine::check_instance_name(&instance)?;
+                self.in_background(msg, polkit::ACTION_MANAGE, move |msg| {
+                    with_lock(LockMode::Shared, || actions::rollback_container(&instance))?;
+                    Ok(msg.method_return())
+                })?;
+                return Ok(None);
+            }
+            "Build" => {
+                let (instance, packages): (String, Vec<String>) = msg.read2()?;
+                machine::check_instance_name(&instance)?;
+                if packages.is_empty() {
+                    return Err(anyhow!("No packages to build"));
+                }
+                let job = self.next_job;
+                self.next_job += 1;
+                let finished = self.finished.clone();
+                self.in_background(msg, polkit::ACTION_BUILD, move |msg| {
+                    start_build(job, instance, packages, finished);
+                    Ok(msg.method_return().append1(job))
+                })?;
+                return Ok(None);
+            }
+            _ => return Err(anyhow!("Unknown method: {}", member)),
+        };
+
+        Ok(Some(reply))
+    }
+}
+
+/// Turn the error into a D-Bus error reply
+fn error_reply(msg: &Message, error: &anyhow::Error) -> Message {
+    let message = CString::new(error.to_string().replace('\0', ""))
+        .unwrap_or_else(|_| CString::new("unknown error").unwrap());
+
+    msg.error(&ErrorName::from(ERROR_NAME), &message)
+}
+
+/// Emit the signals of the builds finished since the last call
+fn notify_finished(conn: &Connection, finished: &Receiver<FinishedBuild>) {
+    while let Ok((job, success, summary)) = finished.try_recv() {
+        info!(
+            "Build #{} {}",
+            job,
+            if success { "succeeded" } else { "failed" }
+        );
+        match Message::new_signal(OBJECT_PATH, INTERFACE_NAME, "BuildFinished") {
+            Ok(signal) => {
+                conn.send(signal.append3(job, success, summary)).ok();
+            }
+            Err(e) => warn!("Unable to create the signal: {}", e),
+        }
+    }
+}
+
+/// Serve the workspace on D-Bus (the system bus unless `session` is set) until killed
+pub fn serve_dbus(session: bool) -> Result<()> {
+    let conn = if session {
+        Connection::new_session()?
+    } else {
+        Connection::new_system()?
+    };
+    conn.request_name(SERVICE_NAME, false, false, true)
+        .map_err(|e| {
+            anyhow!(
+                "Unable to own {} (is another daemon running?): {}",
+                SERVICE_NAME,
+                e
+            )
+        })?;
+    // nobody is there to answer the prompts
+    std::env::set_var("CIEL_BATCH", "1");
+    let (sender, finished) = mpsc::channel();
+    let (reply_sender, replies) = mpsc::channel();
+    let mut service = Service {
+        next_job: 1,
+        finished: sender,
+        replies: reply_sender,
+        polkit: !session,
+    };
+    conn.start_receive(
+        MatchRule::new_method_call().with_path(OBJECT_PATH),
+        Box::new(move |msg, conn| {
+            let reply = match service.handle_call(&msg) {
+                Ok(Some(reply)) => reply,
+                Ok(None) => return true,
+                Err(e) => error_reply(&msg, &e),
+            };
+            if !msg.get_no_reply() {
+                conn.send(reply).ok();
+            }
+            true
+        }),
+    );
+    info!(
+        "Serving {} on the {} bus at {} ...",
+        std::env::current_dir()?.display(),
+        if session { "session" } else { "system" },
+        OBJECT_PATH
+    );
+    loop {
+        conn.process(Duration::from_secs(1))?;
+        while let Ok(reply) = replies.try_recv() {
+            conn.send(reply).ok();
+        }
+        notify_finished(&conn, &finished);
+    }
+}
+
+/// Client of the daemon serving the workspace on the system bus,
+/// for doing the privileged operations as a regular user
+pub struct Client {
+    conn: Connection,
+    // the daemon may ask the user for the password through polkit
+    _agent: Option<TtyAgent>,
+}
+
+impl Client {
+    /// Connect to the daemon, which must be serving the current workspace
+    pub fn connect() -> Result<Client> {
+        let client = Client {
+            conn: Connection::new_system()?,
+            _agent: TtyAgent::start(),
+        };
+        let (workspace,): (String,) = client
+            .proxy()
+            .method_call(INTERFACE_NAME, "Workspace", ())
+            .map_err(|e| anyhow!("Unable to reach `ciel daemon --dbus`: {}", e))?;
+        let current = std::env::current_dir()?.canonicalize()?;
+        if Path::new(&workspace) != current {
+            return Err(anyhow!(
+                "The daemon is serving {}, not {}.",
+                workspace,
+                current.display()
+            ));
+        }
+
+        Ok(client)
+    }
+
+    fn proxy(&self) -> Proxy<'_, &Connection> {
+        // long enough for the authentication and the operation
+        self.conn
+            .with_proxy(SERVICE_NAME, OBJECT_PATH, Duration::from_secs(600))
+    }
+
+    /// Call a method taking the name of the instance
+    pub fn call_on_instance(&self, method: &str, instance: &str) -> Result<()> {
+        self.proxy()
+            .method_call(INTERFACE_NAME, method, (instance,))
+            .map_err(|e| anyhow!("{}", e.message().unwrap_or("unknown error")))
+    }
+
+    /// Build the packages and wait for the build, returns if it succeeded
+    /// and the summary (as JSON)
+    pub fn build(&self, instance: &str, packages: &[String]) -> Result<(bool, String)> {
+        let (sender, finished) = mpsc::channel();
+        self.conn.add_match(
+            MatchRule::new_signal(INTERFACE_NAME, "BuildFinished"),
+            move |build: FinishedBuild, _, _| sender.send(build).is_ok(),
+        )?;
+        let (job,): (u32,) = self
+            .proxy()
+            .method_call(INTERFACE_NAME, "Build", (instance, packages))
+            .map_err(|e| anyhow!("{}", e.message().unwrap_or("unknown error")))?;
+        info!("Build #{} started by the daemon.", job);
+        loop {
+            self.conn.process(Duration::from_secs(1))?;
+            while let Ok((finished_job, success, summary)) = finished.try_recv() {
+                if finished_job == job {
+                    return Ok((success, summary));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Notify the webhooks (`[[webhooks]]` in the config) of the builds and commits,
+//! e.g. to post messages to a chat room
+use anyhow::{anyhow, Result};
+use reqwest::{blocking::Client, Url};
+use serde_json::Value;
+use std::{fs, time::Duration};
+
+use crate::{
+    config::{self, Webhook},
+    warn,
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+// lines at the end of the build log included in the notification
+const LOG_EXCERPT_LINES: usize = 20;
+
+/// Name of the event as seen by the webhooks, `None` if it is not sent to them
+fn webhook_event(event: &Value) -> Option<&'static str> {
+    match event["event"].as_str()? {
+        "build-started" => Some("build-started"),
+        "build-finished" if event["success"].as_bool() == Some(true) => Some("build-succeeded"),
+        "build-finished" => Some("build-failed"),
+        "instance-committed" => Some("instance-committed"),
+        _ => None,
+    }
+}
+
+fn read_log_excerpt(path: &str) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    let content = String::from_utf8_lossy(&content);
+    let lines = content.lines().collect::<Vec<_>>();
+    let start = lines.len().saturating_sub(LOG_EXCERPT_LINES);
+
+    Some(lines[start..].join("\n"))
+}
+
+fn value_to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(value_to_text)
+            .collect::<Vec<_>>()
+            .join(", "),
+        _ => value.to_string(),
+    }
+}
+
+/// Replace `{field}` in the template by the field of the event (escaped for a JSON string
+/// if `json` is set), the braces without a matching field are kept as-is
+fn render_template(template: &str, event: &Value, json: bool) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let field = rest[1..]
+            .find('}')
+            .map(|end| &rest[1..end + 1])
+            .filter(|name| !name.is_empty())
+            .and_then(|name| event.get(name).map(|value| (name, value)));
+        match field {
+            Some((name, value)) => {
+                let text = value_to_text(value);
+                if json {
+                    let quoted = Value::String(text).to_string();
+                    output.push_str(&quoted[1..quoted.len() - 1]);
+                } else {
+                    output.push_str(&text);
+                }
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                output.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+/// The host of the webhook URL, for the messages and logs. The rest of the URL is never shown
+/// as it often carries the token (e.g. `/bot<TOKEN>/` or `?access_token=`).
+pub(crate) fn url_host(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_else(|| "(invalid URL)".to_string())
+}
+
+fn post(client: &Client, webhook: &Webhook, event: &Value) -> Result<()> {
+    let content_type = webhook
+        .content_type
+        .as_deref()
+        .unwrap_or("application/json");
+    let body = match &webhook.template {
+        Some(template) => render_template(template, event, content_type.contains("json")),
+        None => event.to_string(),
+    };
+    // the errors of reqwest include the whole URL
+    client
+        .post(&webhook.url)
+        .header("Content-Type", content_type)
+        .body(body)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            let message = e.to_string();
+            match e.url() {
+                Some(url) => anyhow!(message.replace(&format!(" for url ({})", url), "")),
+                None => anyhow!(message),
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Send the event to the webhooks subscribing to it, failures are only warned about
+pub fn notify(event: &Value) {
+    let name = match webhook_event(event) {
+        Some(name) => name,
+        None => return,
+    };
+    let webhooks = match config::read_config() {
+        Ok(config) => config.webhooks,
+        Err(_) => return,
+    };
+    let webhooks = webhooks
+        .iter()
+        .filter(|w| w.events.is_empty() || w.events.iter().any(|e| e == name))
+        .collect::<Vec<_>>();
+    if webhooks.is_empty() {
+        return;
+    }
+    let mut event = event.clone();
+    event["event"] = Value::from(name);
+    if let Ok(workspace) = std::env::current_dir() {
+        event["workspace"] = Value::from(workspace.to_string_lossy());
+    }
+    if let Some(excerpt) = event["log"].as_str().and_then(read_log_excerpt) {
+        event["log_excerpt"] = Value::from(excerpt);
+    }
+    let client = match Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Unable to notify the webhooks: {}", e);
+            return;
+        }
+    };
+    for webhook in webhooks {
+        if let Err(e) = post(&client, webhook, &event) {
+            warn!(
+                "Unable to notify the webhook at {}: {}",
+                url_host(&webhook.url),
+                e
+            );
+        }
+    }
+}
+
+#[test]
+fn test_url_host() {
+    assert_eq!(
+        url_host("https://api.telegram.org/bot123:secret/sendMessage"),
+        "api.telegram.org"
+    );
+    assert_eq!(url_host("matrix.org/?access_token=secret"), "(invalid URL)");
+}
+
+#[test]
+fn test_render_template() {
+    let event = serde_json::json!({
+        "event": "build-failed",
+        "packages": ["bash", "zsh"],
+        "instance": "main",
+        "exit_code": 1,
+        "log_excerpt": "line 1\n\"line\" 2",
+    });
+    assert_eq!(
+        render_template(
+            r#"{"text": "{event}: {packages} in {instance} ({exit_code}) {nope}"}"#,
+            &event,
+            false
+        ),
+        r#"{"text": "build-failed: bash, zsh in main (1) {nope}"}"#
+    );
+    assert_eq!(
+        render_template(r#"{"text": "{log_excerpt}"}"#, &event, true),
+        r#"{"text": "line 1\n\"line\" 2"}"#
+    );
+}